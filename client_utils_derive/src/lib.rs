@@ -1,57 +1,1001 @@
 use proc_macro::{self, TokenStream};
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use proc_macro_crate::FoundCrate;
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use syn::{parse_macro_input, punctuated::Punctuated, spanned::Spanned, DeriveInput, Ident, Member, Token, Type};
 
 #[proc_macro_derive(CrdtContainer, attributes(crdt))]
 pub fn crdt_container(input: TokenStream) -> TokenStream {
-    let DeriveInput { ident, data, .. } = parse_macro_input!(input);
-    let data = if let syn::Data::Struct(data) = data {
-        data
-    } else {
-        unimplemented!()
-    };
+    expand(parse_macro_input!(input)).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// A field marked `#[crdt]`, merged along with the others.
+struct CrdtField<'a> {
+    /// The field's name, or its index in a tuple struct.
+    member: Member,
+    ty: &'a Type,
+    /// Where it is in the struct, which frames it in `serialize_within`.
+    index: u16,
+    /// From `#[crdt(priority = n)]`, and where that was written.
+    priority: Option<(i64, Span)>,
+    /// From `#[crdt(merge_with = "..")]`, a `fn(&mut T, &T) -> anyhow::Result<()>`
+    /// that merges the field instead of `Crdt`, so it needn't be one.
+    merge_with: Option<syn::Path>,
+    cleanup: Cleanup,
+    /// From `#[crdt(container)]`, for a field that's a derived container
+    /// itself, so `merge_report` can name the fields that changed in it.
+    container: bool,
+}
+
+/// What `cleanup` does with a field.
+enum Cleanup {
+    /// `Crdt::cleanup`, unless the field is merged with a function.
+    Trait,
+    /// `#[crdt(skip_cleanup)]` leaves it alone.
+    Skip,
+    /// `#[crdt(cleanup_with = "..")]` calls a `fn(&mut T, i64)` instead.
+    With(syn::Path),
+}
+
+// Each of these writes the field's part of a `Crdt` method, given where the
+// field is in `self` and `other`. Fields merged with a function aren't
+// `Crdt`, so they're hashed and cloned instead, and report every merge as a
+// change like other types that can't tell.
+impl CrdtField<'_> {
+    /// An `anyhow::Result<()>`.
+    fn merge(&self, this: &TokenStream2, other: &TokenStream2) -> TokenStream2 {
+        match &self.merge_with {
+            Some(merge) => quote! { #merge(&mut #this, &#other) },
+            None => quote! { #this.merge(&#other) },
+        }
+    }
+
+    /// An `anyhow::Result<bool>`.
+    fn merge_changed(&self, this: &TokenStream2, other: &TokenStream2) -> TokenStream2 {
+        match &self.merge_with {
+            Some(merge) => quote! { #merge(&mut #this, &#other).map(|()| true) },
+            None => quote! { #this.merge_changed(&#other) },
+        }
+    }
+
+    fn cleanup(&self, this: &TokenStream2) -> Option<TokenStream2> {
+        match &self.cleanup {
+            Cleanup::Trait => self.merge_with.is_none().then(|| quote! { #this.cleanup(now); }),
+            Cleanup::Skip => None,
+            Cleanup::With(cleanup) => Some(quote! { #cleanup(&mut #this, now); }),
+        }
+    }
+
+    fn compact(&self, this: &TokenStream2) -> Option<TokenStream2> {
+        self.merge_with.is_none().then(|| quote! { #this.compact(horizon); })
+    }
+
+    fn version(&self, this: &TokenStream2) -> Option<TokenStream2> {
+        self.merge_with.is_none().then(|| quote! { let version = version.max(#this.version()); })
+    }
+
+    fn digest(&self, krate: &syn::Path, this: &TokenStream2) -> TokenStream2 {
+        match &self.merge_with {
+            Some(_) => quote! { #krate::crdt::digest_of(&#this) },
+            None => quote! { #this.digest() },
+        }
+    }
+
+    fn delta_since(&self, this: &TokenStream2) -> TokenStream2 {
+        match &self.merge_with {
+            Some(_) => quote! { Clone::clone(&#this) },
+            None => quote! { #this.delta_since(version)? },
+        }
+    }
+}
+
+/// The type's own `#[crdt(...)]` options.
+#[derive(Default)]
+struct Options {
+    /// `delta` builds deltas field by field.
+    delta: bool,
+    /// `report` adds `merge_report`, only for structs.
+    report: bool,
+    /// `default` implements `Default`, only for structs.
+    default: bool,
+    /// `on_variant_mismatch = ".."`, only for enums.
+    mismatch: Mismatch,
+    /// `bound = ".."` replaces the bounds inferred for generic fields, so it
+    /// has to cover what the impls use on them, `serde::Serialize` included
+    /// for digests.
+    bound: Option<Punctuated<syn::WherePredicate, Token![,]>>,
+    /// `crate = ".."` is where `client_utils` is, when it can't be found.
+    krate: Option<syn::Path>,
+}
+
+impl Options {
+    /// The path the impls name `client_utils` items by. Unless it's given,
+    /// it's looked up in the manifest being built, which finds renamed
+    /// dependencies. Within `client_utils` the name still works, since the
+    /// crate aliases itself, but its own derives give `crate` anyway.
+    fn krate(&self) -> syn::Path {
+        if let Some(krate) = &self.krate {
+            return krate.clone();
+        }
+        let name = match proc_macro_crate::crate_name("client_utils") {
+            Ok(FoundCrate::Name(name)) => format_ident!("{name}"),
+            Ok(FoundCrate::Itself) | Err(_) => format_ident!("client_utils"),
+        };
+        syn::parse_quote! { ::#name }
+    }
+}
 
-    let merges = data.fields.iter().filter_map(|field| {
-        if field.attrs.iter().any(|a| a.path().is_ident("crdt")) {
-            let ident = if let Some(ident) = &field.ident {
-                ident
+/// What an enum does when merging in a different variant than its own.
+#[derive(Default)]
+enum Mismatch {
+    /// `"keep_self"` ignores the other variant.
+    #[default]
+    KeepSelf,
+    /// `"prefer_other"` becomes a clone of it, so the enum has to be `Clone`.
+    PreferOther,
+    /// Any other string is the path of a
+    /// `fn(&mut Self, &Self) -> anyhow::Result<bool>` that settles it,
+    /// returning whether `self` changed.
+    Call(syn::Path),
+}
+
+/// Reads the type's own `#[crdt(...)]` options. Fields are marked with a
+/// bare `#[crdt]`, so one on the type is a mistake.
+fn container_options(attrs: &[syn::Attribute], is_enum: bool) -> syn::Result<Options> {
+    let mut options = Options::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("crdt")) {
+        if !matches!(attr.meta, syn::Meta::List(_)) {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "`#[crdt]` marks fields to merge; on the type it takes options, e.g. `#[crdt(delta)]`",
+            ));
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("delta") {
+                options.delta = true;
+                Ok(())
+            } else if meta.path.is_ident("report") {
+                if is_enum {
+                    return Err(meta.error("`report` only applies to structs"));
+                }
+                options.report = true;
+                Ok(())
+            } else if meta.path.is_ident("default") {
+                if is_enum {
+                    return Err(meta.error("`default` only applies to structs"));
+                }
+                options.default = true;
+                Ok(())
+            } else if meta.path.is_ident("bound") {
+                let bound: syn::LitStr = meta.value()?.parse()?;
+                options.bound = Some(bound.parse_with(Punctuated::parse_terminated)?);
+                Ok(())
+            } else if meta.path.is_ident("crate") {
+                let krate: syn::LitStr = meta.value()?.parse()?;
+                options.krate = Some(krate.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("on_variant_mismatch") {
+                if !is_enum {
+                    return Err(meta.error("`on_variant_mismatch` only applies to enums"));
+                }
+                let policy: syn::LitStr = meta.value()?.parse()?;
+                options.mismatch = match policy.value().as_str() {
+                    "keep_self" => Mismatch::KeepSelf,
+                    "prefer_other" => Mismatch::PreferOther,
+                    _ => Mismatch::Call(policy.parse().map_err(|_| {
+                        syn::Error::new_spanned(
+                            &policy,
+                            "expected \"keep_self\", \"prefer_other\" or the path of a function",
+                        )
+                    })?),
+                };
+                Ok(())
             } else {
-                unimplemented!("Not currently working with unnamed fiends");
-            };
-            Some(quote! {
-                self.#ident.merge(&other.#ident)?;
-            })
-        } else {
-            None
+                Err(meta.error("unknown crdt option, expected `delta`, `report`, `default`, `bound`, `crate` or `on_variant_mismatch`"))
+            }
+        })?;
+    }
+    // Only the attributes after this derive reach it, so a `Default`
+    // derived earlier, in the same list or above, isn't seen here and
+    // shows as the conflicting impls instead (see fail_default_derived_first).
+    if options.default {
+        for attr in attrs.iter().filter(|a| a.path().is_ident("derive")) {
+            let derives = attr.parse_args_with(Punctuated::<syn::Path, Token![,]>::parse_terminated)?;
+            if let Some(derive) = derives.iter().find(|d| d.segments.last().is_some_and(|s| s.ident == "Default")) {
+                return Err(syn::Error::new_spanned(
+                    derive,
+                    "`#[crdt(default)]` already implements `Default`, so don't derive it too",
+                ));
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Collects errors so every bad field is reported, not just the first.
+fn push_error(errors: &mut Option<syn::Error>, e: syn::Error) {
+    match errors {
+        Some(errors) => errors.combine(e),
+        None => *errors = Some(e),
+    }
+}
+
+/// What a field's `#[crdt]` attributes say besides how it's merged. These
+/// options don't mark a field merged, so plain fields can have them too;
+/// merged fields add a bare `#[crdt]` or another option.
+#[derive(Default)]
+struct FieldExtras {
+    /// From `#[crdt(default = "..")]`.
+    default: Option<syn::Expr>,
+    /// From `#[crdt(expires_with = "..")]`, the sibling `i64` whose turn
+    /// has `cleanup` reset the field to its default. The string is kept
+    /// to point errors at.
+    expires_with: Option<(Member, syn::LitStr)>,
+}
+
+/// The fields marked `#[crdt]`, in order, and every field's extras.
+fn crdt_fields<'a>(fields: &'a syn::Fields, errors: &mut Option<syn::Error>) -> (Vec<CrdtField<'a>>, Vec<FieldExtras>) {
+    let (mut merged, mut extras) = (Vec::new(), Vec::new());
+    for (index, field) in fields.iter().enumerate() {
+        match crdt_field(index, field) {
+            Ok((field, field_extras)) => {
+                merged.extend(field);
+                extras.push(field_extras);
+            }
+            Err(e) => {
+                push_error(errors, e);
+                extras.push(FieldExtras::default());
+            }
+        }
+    }
+    (merged, extras)
+}
+
+/// The field's `#[crdt]` attributes, with `None` if it isn't merged.
+/// `expires_with` marks a field merged too, unless it's `local`.
+fn crdt_field(index: usize, field: &syn::Field) -> syn::Result<(Option<CrdtField<'_>>, FieldExtras)> {
+    let (mut marked, mut local, mut extras) = (false, None, FieldExtras::default());
+    let (mut priority, mut merge_with, mut cleanup, mut container) = (None, None, Cleanup::Trait, false);
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("crdt")) {
+        match &attr.meta {
+            syn::Meta::Path(_) => marked = true,
+            syn::Meta::List(_) => attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("default") {
+                    let lit: syn::LitStr = meta.value()?.parse()?;
+                    extras.default =
+                        Some(lit.parse().map_err(|_| syn::Error::new_spanned(&lit, "expected an expression"))?);
+                    return Ok(());
+                } else if meta.path.is_ident("expires_with") {
+                    let lit: syn::LitStr = meta.value()?.parse()?;
+                    let member = lit.parse().map_err(|_| syn::Error::new_spanned(&lit, "expected a field name"))?;
+                    extras.expires_with = Some((member, lit));
+                    return Ok(());
+                } else if meta.path.is_ident("local") {
+                    local = Some(meta.path.span());
+                    return Ok(());
+                }
+                marked = true;
+                if meta.path.is_ident("priority") {
+                    let lit = meta.value()?.parse::<syn::LitInt>()?;
+                    priority = Some((lit.base10_parse::<i64>()?, lit.span()));
+                } else if meta.path.is_ident("container") {
+                    if merge_with.is_some() {
+                        return Err(meta.error("`container` conflicts with `merge_with`"));
+                    }
+                    container = true;
+                } else if meta.path.is_ident("merge_with") {
+                    if merge_with.is_some() {
+                        return Err(meta.error("`merge_with` is given twice"));
+                    }
+                    if container {
+                        return Err(meta.error("`merge_with` conflicts with `container`"));
+                    }
+                    merge_with = Some(function_path(&meta)?);
+                } else if meta.path.is_ident("skip_cleanup") {
+                    if matches!(cleanup, Cleanup::With(_)) {
+                        return Err(meta.error("`skip_cleanup` conflicts with `cleanup_with`"));
+                    }
+                    cleanup = Cleanup::Skip;
+                } else if meta.path.is_ident("cleanup_with") {
+                    if !matches!(cleanup, Cleanup::Trait) {
+                        return Err(meta.error("`cleanup_with` conflicts with `skip_cleanup` or another `cleanup_with`"));
+                    }
+                    cleanup = Cleanup::With(function_path(&meta)?);
+                } else {
+                    return Err(meta.error(
+                        "unknown crdt field option, expected `priority`, `merge_with`, `skip_cleanup`, `cleanup_with`, `container`, `default`, `expires_with` or `local`",
+                    ));
+                }
+                Ok(())
+            })?,
+            syn::Meta::NameValue(_) => {
+                return Err(syn::Error::new_spanned(attr, "expected `#[crdt]` or `#[crdt(option, ..)]`"));
+            }
+        }
+    }
+    if let Some(local) = local {
+        if marked {
+            return Err(syn::Error::new(local, "`local` fields aren't merged, so take no merge options"));
+        }
+        if extras.expires_with.is_none() {
+            return Err(syn::Error::new(local, "`local` only goes with `expires_with`"));
+        }
+    } else if extras.expires_with.is_some() {
+        marked = true;
+    }
+    if !marked {
+        return Ok((None, extras));
+    }
+    let frame = u16::try_from(index).map_err(|_| syn::Error::new_spanned(field, "too many fields to frame"))?;
+    let (member, ty) = (member(index, field), &field.ty);
+    let field = CrdtField { member, ty, index: frame, priority, merge_with, cleanup, container };
+    Ok((Some(field), extras))
+}
+
+/// The function named by a `name = "path::to::function"` option.
+fn function_path(meta: &syn::meta::ParseNestedMeta) -> syn::Result<syn::Path> {
+    let lit: syn::LitStr = meta.value()?.parse()?;
+    lit.parse().map_err(|_| syn::Error::new_spanned(&lit, "expected the path of a function"))
+}
+
+/// How the field is named in `self.#member`, which also works in a struct
+/// expression for tuple structs, as `Self { 0: .. }`.
+fn member(index: usize, field: &syn::Field) -> Member {
+    match &field.ident {
+        Some(ident) => Member::Named(ident.clone()),
+        None => Member::Unnamed(index.into()),
+    }
+}
+
+/// Whether `ty` names any of `params`, and so needs bounds of its own.
+fn mentions(ty: &Type, params: &[&Ident]) -> bool {
+    fn scan(tokens: TokenStream2, params: &[&Ident]) -> bool {
+        tokens.into_iter().any(|token| match token {
+            TokenTree::Ident(ident) => params.contains(&&ident),
+            TokenTree::Group(group) => scan(group.stream(), params),
+            _ => false,
+        })
+    }
+    scan(ty.to_token_stream(), params)
+}
+
+/// The type's where clause with bounds for a generated impl: those from
+/// `#[crdt(bound = "..")]` if given, or else the `(field type, bounds)`
+/// for each field type that mentions a type parameter, as serde infers
+/// them. Other field types are checked where they're used.
+fn where_clause<'t>(
+    generics: &syn::Generics,
+    options: &Options,
+    bounds: impl IntoIterator<Item = (&'t Type, TokenStream2)>,
+) -> syn::WhereClause {
+    let params: Vec<_> = generics.type_params().map(|param| &param.ident).collect();
+    let mut generics = generics.clone();
+    let where_clause = generics.make_where_clause();
+    match &options.bound {
+        Some(bound) => where_clause.predicates.extend(bound.iter().cloned()),
+        None => {
+            for (ty, bounds) in bounds.into_iter().filter(|(ty, _)| mentions(ty, &params)) {
+                where_clause.predicates.push(syn::parse_quote! { #ty: #bounds });
+            }
+        }
+    }
+    where_clause.clone()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let DeriveInput { ident, generics, data, attrs, .. } = input;
+    let options = container_options(&attrs, matches!(data, syn::Data::Enum(_)));
+    match &data {
+        // Tuple structs index their fields, and unit structs merge nothing.
+        syn::Data::Struct(syn::DataStruct { fields, .. }) => expand_struct(&ident, &generics, options, fields),
+        syn::Data::Enum(data) => expand_enum(&ident, &generics, options, data),
+        syn::Data::Union(_) => Err(syn::Error::new_spanned(&ident, "CrdtContainer can't be derived for unions")),
+    }
+}
+
+fn expand_struct(
+    ident: &Ident,
+    generics: &syn::Generics,
+    options: syn::Result<Options>,
+    fields: &syn::Fields,
+) -> syn::Result<TokenStream2> {
+    let mut errors = options.as_ref().err().cloned();
+    let (merged, extras) = crdt_fields(fields, &mut errors);
+    if !options.as_ref().is_ok_and(|options| options.default) {
+        for default in extras.iter().filter_map(|extras| extras.default.as_ref()) {
+            push_error(&mut errors, syn::Error::new_spanned(default, "`default` needs `#[crdt(default)]` on the struct"));
         }
+    }
+    let members: Vec<_> = fields.iter().enumerate().map(|(index, field)| member(index, field)).collect();
+    for (deadline, lit) in extras.iter().filter_map(|extras| extras.expires_with.as_ref()) {
+        if !members.contains(deadline) {
+            push_error(&mut errors, syn::Error::new_spanned(lit, "no such field in the struct"));
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+    let options = options?;
+    let krate = options.krate();
+    let delta = options.delta;
+
+    let this = |field: &CrdtField| {
+        let member = &field.member;
+        quote! { self.#member }
+    };
+    let that = |field: &CrdtField| {
+        let member = &field.member;
+        quote! { other.#member }
+    };
+
+    let merges = merged.iter().map(|field| {
+        let merge = field.merge(&this(field), &that(field));
+        quote! { #merge?; }
     });
 
-    let cleanups = data.fields.iter().filter_map(|field| {
-        if field.attrs.iter().any(|a| a.path().is_ident("crdt")) {
-            let ident = if let Some(ident) = &field.ident {
-                ident
-            } else {
-                unimplemented!("Not currently working with unnamed fiends");
+    let merges_changed = merged.iter().map(|field| {
+        let merge = field.merge_changed(&this(field), &that(field));
+        quote! { let changed = #merge? | changed; }
+    });
+
+    let digests = merged.iter().map(|field| field.digest(&krate, &this(field)));
+
+    let bounded_merges = merged.iter().map(|field| {
+        let (this, that) = (this(field), that(field));
+        match &field.merge_with {
+            // One step, like the default `Crdt::merge_bounded`.
+            Some(merge) => quote! {
+                &mut |progress: MergeProgress, max_entries| {
+                    if progress.done == 0 && max_entries > 0 {
+                        #merge(&mut #this, &#that)?;
+                    }
+                    Ok(MergeProgress::through(progress.done, max_entries, 1))
+                },
+            },
+            None => quote! {
+                &mut |progress, max_entries| #this.merge_bounded(&#that, progress, max_entries),
+            },
+        }
+    });
+
+    let cleanups = merged.iter().filter_map(|field| field.cleanup(&this(field)));
+    // `#[crdt(expires_with = "..")]` fields are reset once their deadline
+    // comes, after the rest are cleaned up.
+    let expiring: Vec<_> = fields
+        .iter()
+        .zip(&extras)
+        .enumerate()
+        .filter_map(|(index, (field, extras))| {
+            let (deadline, _) = extras.expires_with.as_ref()?;
+            let member = member(index, field);
+            let reset = quote! {
+                if now >= self.#deadline {
+                    self.#member = Default::default();
+                }
             };
-            Some(quote! {
-                self.#ident.cleanup(now);
+            Some((&field.ty, reset))
+        })
+        .collect();
+    let resets = expiring.iter().map(|(_, reset)| reset);
+
+    let compacts = merged.iter().filter_map(|field| field.compact(&this(field)));
+
+    // A merged `HorizonTracker` field says how far every replica has caught
+    // up, so merges compact the other fields by it.
+    let tracker = merged.iter().find_map(|CrdtField { member, ty, merge_with, .. }| {
+        let (Type::Path(ty), None) = (ty, merge_with) else {
+            return None;
+        };
+        ty.path.segments.last().is_some_and(|s| s.ident == "HorizonTracker").then_some(member)
+    });
+    let compact_after_merge = tracker.map(|tracker| {
+        quote! {
+            let horizon = self.#tracker.min();
+            #krate::crdt::Crdt::compact(self, horizon);
+        }
+    });
+    let horizon_tracker = tracker.map(|tracker| {
+        quote! {
+            fn horizon_tracker(&mut self) -> Option<&mut #krate::crdt::HorizonTracker> {
+                Some(&mut self.#tracker)
+            }
+        }
+    });
+
+    // Snapshots are tuples of the `#[crdt]` fields' snapshots, in order.
+    // Fields merged with a function are cloned.
+    let snap_types = merged.iter().map(|CrdtField { ty, merge_with, .. }| match merge_with {
+        Some(_) => quote! { #ty, },
+        None => quote! { <#ty as #krate::crdt::CrdtSnapshot>::Snap, },
+    });
+    let snapshots = merged.iter().map(|field| {
+        let this = this(field);
+        match field.merge_with {
+            Some(_) => quote! { Clone::clone(&#this), },
+            None => quote! { #krate::crdt::CrdtSnapshot::snapshot(&#this), },
+        }
+    });
+    let snap_vars: Vec<_> = (0..merged.len()).map(|i| format_ident!("snap_{}", i)).collect();
+    let restores = merged.iter().zip(&snap_vars).map(|(field, var)| {
+        let this = this(field);
+        match field.merge_with {
+            Some(_) => quote! { #this = #var; },
+            None => quote! { #krate::crdt::CrdtSnapshot::restore(&mut #this, #var); },
+        }
+    });
+
+    let versions = merged.iter().filter_map(|field| field.version(&this(field)));
+
+    // `#[crdt(delta)]` on the struct builds deltas field by field, leaving
+    // fields that aren't merged at their defaults.
+    let delta_since = delta.then(|| {
+        let fields = fields.iter().enumerate().map(|(index, field)| {
+            let member = member(index, field);
+            match merged.iter().find(|merged| merged.member == member) {
+                Some(field) => {
+                    let delta = field.delta_since(&this(field));
+                    quote! { #member: #delta, }
+                }
+                None => quote! { #member: Default::default(), },
+            }
+        });
+        quote! {
+            fn delta_since(&self, version: u64) -> Option<Self> {
+                Some(Self { #(#fields)* })
+            }
+        }
+    });
+
+    // `#[crdt(priority = n)]` on any field has `serialize_within` send the
+    // fields highest priority first, framed by their position in the struct.
+    // Fields without one have priority 0.
+    let any_priority = merged.iter().any(|field| field.priority.is_some());
+    let partial = any_priority.then(|| {
+        let mut prioritized: Vec<_> = merged.iter().collect();
+        prioritized.sort_by_key(|field| (std::cmp::Reverse(field.priority.map_or(0, |(p, _)| p)), field.index));
+        let writes = prioritized.iter().map(|&field| {
+            let (index, this) = (field.index, this(field));
+            quote! { #krate::crdt::write_field(&mut out, #index, &#this, max_bytes); }
+        });
+        let merges = prioritized.iter().map(|&field| {
+            let (index, this) = (field.index, this(field));
+            match &field.merge_with {
+                Some(merge) => quote! { #index => #krate::crdt::merge_field_with(&mut #this, field, #merge), },
+                None => quote! { #index => #krate::crdt::merge_field(&mut #this, field), },
+            }
+        });
+        quote! {
+            fn serialize_within(&self, max_bytes: usize) -> Vec<u8>
+            where
+                Self: serde::Serialize,
+            {
+                let mut out = Vec::new();
+                #(#writes)*
+                out
+            }
+
+            fn merge_partial(&mut self, bytes: &[u8]) -> bool
+            where
+                Self: serde::de::DeserializeOwned,
+            {
+                let mut changed = false;
+                for (index, field) in #krate::crdt::read_fields(bytes) {
+                    changed |= match index {
+                        #(#merges)*
+                        _ => false,
+                    };
+                }
+                changed
+            }
+        }
+    });
+
+    // Generic fields need the traits the impls use on them.
+    let (impl_generics, ty_generics, _) = generics.split_for_impl();
+    let deserialize = any_priority.then(|| quote! { + serde::de::DeserializeOwned });
+    let crdt_where = where_clause(
+        generics,
+        &options,
+        merged
+            .iter()
+            .map(|field| match field.merge_with {
+                Some(_) => (field.ty, quote! { Clone + serde::Serialize #deserialize }),
+                None => (field.ty, quote! { #krate::crdt::Crdt + serde::Serialize #deserialize }),
             })
-        } else {
-            None
+            .chain(expiring.iter().map(|(ty, _)| (*ty, quote! { Default }))),
+    );
+    let snapshot_where = where_clause(
+        generics,
+        &options,
+        merged.iter().map(|field| match field.merge_with {
+            Some(_) => (field.ty, quote! { Clone }),
+            None => (field.ty, quote! { #krate::crdt::CrdtSnapshot }),
+        }),
+    );
+
+    // `#[crdt(default)]` implements `Default` too, each field taking its
+    // `#[crdt(default = "..")]` or else its own default.
+    let default = options.default.then(|| {
+        let values = fields.iter().zip(&extras).enumerate().map(|(index, (field, extras))| {
+            let member = member(index, field);
+            match &extras.default {
+                Some(default) => quote! { #member: #default, },
+                None => quote! { #member: Default::default(), },
+            }
+        });
+        let default_where = where_clause(
+            generics,
+            &options,
+            fields
+                .iter()
+                .zip(&extras)
+                .filter(|(_, extras)| extras.default.is_none())
+                .map(|(field, _)| (&field.ty, quote! { Default })),
+        );
+        quote! {
+            impl #impl_generics Default for #ident #ty_generics #default_where {
+                fn default() -> Self {
+                    Self { #(#values)* }
+                }
+            }
+        }
+    });
+
+    // `#[crdt(report)]` adds an inherent `merge_report`, for logging which
+    // fields a merge changed. `#[crdt(container)]` fields are named by the
+    // fields that changed in them, as `field.inner`, so they need `report`
+    // too.
+    let merge_report = options.report.then(|| {
+        let merges = merged.iter().map(|field| {
+            let (this, that) = (this(field), that(field));
+            let name = field.member.to_token_stream().to_string();
+            if field.container {
+                return quote! {
+                    let inner = #this.merge_report(&#that).map_err(|e| e.within(#name))?;
+                    changed.extend(inner.into_iter().map(|path| path.within(#name)));
+                };
+            }
+            let merge = field.merge_changed(&this, &that);
+            quote! {
+                if #merge.map_err(|e| #krate::crdt::FieldMergeError::new(#name, e))? {
+                    changed.push(#krate::crdt::FieldPath(vec![#name]));
+                }
+            }
+        });
+        quote! {
+            impl #impl_generics #ident #ty_generics #crdt_where {
+                /// Merges like `Crdt::merge_changed`, naming the fields
+                /// that changed, or the one that failed to merge.
+                pub fn merge_report(&mut self, other: &Self) -> Result<Vec<#krate::crdt::FieldPath>, #krate::crdt::FieldMergeError> {
+                    let mut changed = Vec::new();
+                    #(#merges)*
+                    #compact_after_merge
+                    Ok(changed)
+                }
+            }
         }
     });
 
-    let output = quote! {
-        impl client_utils::crdt::Crdt for #ident {
+    Ok(quote! {
+        impl #impl_generics #krate::crdt::Crdt for #ident #ty_generics #crdt_where {
             fn merge(&mut self, other: &Self) -> anyhow::Result<()> {
                 #(#merges)*
+                #compact_after_merge
                 Ok(())
             }
 
+            fn merge_changed(&mut self, other: &Self) -> anyhow::Result<bool> {
+                let changed = false;
+                #(#merges_changed)*
+                #compact_after_merge
+                Ok(changed)
+            }
+
             fn cleanup(&mut self, now: i64) {
                 #(#cleanups)*
+                #(#resets)*
+            }
+
+            fn compact(&mut self, horizon: i64) {
+                #(#compacts)*
+            }
+
+            #horizon_tracker
+
+            // Runs through the fields in order, counting their entries one
+            // after another.
+            fn merge_bounded(
+                &mut self,
+                other: &Self,
+                progress: #krate::crdt::MergeProgress,
+                max_entries: usize,
+            ) -> anyhow::Result<#krate::crdt::MergeProgress> {
+                use #krate::crdt::MergeProgress;
+                let fields: &mut [&mut dyn FnMut(MergeProgress, usize) -> anyhow::Result<MergeProgress>] =
+                    &mut [#(#bounded_merges)*];
+                let (mut offset, mut budget) = (0, max_entries);
+                for field in fields.iter_mut() {
+                    let start = progress.done.saturating_sub(offset);
+                    let merged = field(MergeProgress { done: start, finished: false }, budget)?;
+                    if !merged.finished {
+                        return Ok(MergeProgress { done: offset + merged.done, finished: false });
+                    }
+                    budget -= merged.done.saturating_sub(start);
+                    offset += merged.done;
+                }
+                Ok(MergeProgress { done: offset, finished: true })
+            }
+
+            fn version(&self) -> u64 {
+                let version = 0;
+                #(#versions)*
+                version
+            }
+
+            fn digest(&self) -> u64
+            where
+                Self: serde::Serialize,
+            {
+                let digests: &[u64] = &[#(#digests,)*];
+                let bytes: Vec<u8> = digests.iter().flat_map(|d| d.to_le_bytes()).collect();
+                #krate::crdt::digest_bytes(&bytes)
+            }
+
+            #delta_since
+
+            #partial
+        }
+
+        #merge_report
+
+        #default
+
+        impl #impl_generics #krate::crdt::CrdtSnapshot for #ident #ty_generics #snapshot_where {
+            type Snap = (#(#snap_types)*);
+
+            fn snapshot(&self) -> Self::Snap {
+                (#(#snapshots)*)
+            }
+
+            fn restore(&mut self, snap: Self::Snap) {
+                let (#(#snap_vars,)*) = snap;
+                #(#restores)*
+            }
+        }
+    })
+}
+
+/// A variant's `#[crdt]` fields, bound both as `self_n` and `other_n`.
+struct CrdtVariant<'a> {
+    ident: &'a Ident,
+    /// Where it is in the enum, which seeds its digest.
+    index: u64,
+    fields: &'a syn::Fields,
+    merged: Vec<CrdtField<'a>>,
+    self_vars: Vec<Ident>,
+    other_vars: Vec<Ident>,
+}
+
+impl<'a> CrdtVariant<'a> {
+    /// `Self::Variant { field: var, .. }`, which fits tuple and unit
+    /// variants too.
+    fn pattern(&self, vars: &[Ident]) -> TokenStream2 {
+        let ident = self.ident;
+        let members = self.merged.iter().map(|field| &field.member);
+        quote! { Self::#ident { #(#members: #vars,)* .. } }
+    }
+
+    /// The `#[crdt]` fields, each with where `pattern(vars)` bound it.
+    fn fields_in<'s>(&'s self, vars: &'s [Ident]) -> impl Iterator<Item = (&'s CrdtField<'a>, TokenStream2)> + 's {
+        self.merged.iter().zip(vars).map(|(field, var)| (field, quote! { (*#var) }))
+    }
+}
+
+/// Enums merge field by field while both sides are the same variant. A
+/// different variant is settled by `#[crdt(on_variant_mismatch = "..")]`,
+/// which no CRDT law covers, so pick one every replica can agree on.
+fn expand_enum(
+    ident: &Ident,
+    generics: &syn::Generics,
+    options: syn::Result<Options>,
+    data: &syn::DataEnum,
+) -> syn::Result<TokenStream2> {
+    let mut errors = options.as_ref().err().cloned();
+    if data.variants.is_empty() {
+        push_error(&mut errors, syn::Error::new_spanned(ident, "CrdtContainer needs an enum with variants"));
+    }
+    let variants: Vec<_> = data
+        .variants
+        .iter()
+        .zip(0..)
+        .map(|(variant, index)| {
+            let (merged, extras) = crdt_fields(&variant.fields, &mut errors);
+            for extras in extras {
+                if let Some(default) = extras.default {
+                    push_error(&mut errors, syn::Error::new_spanned(default, "`default` only applies to struct fields"));
+                }
+                if let Some((_, lit)) = extras.expires_with {
+                    push_error(&mut errors, syn::Error::new_spanned(lit, "`expires_with` only applies to struct fields"));
+                }
+            }
+            // Frames are per struct, so priorities can't say which variant
+            // they're from.
+            for (_, span) in merged.iter().filter_map(|field| field.priority) {
+                push_error(&mut errors, syn::Error::new(span, "`priority` only applies to struct fields"));
+            }
+            CrdtVariant {
+                ident: &variant.ident,
+                index,
+                fields: &variant.fields,
+                self_vars: (0..merged.len()).map(|i| format_ident!("self_{}", i)).collect(),
+                other_vars: (0..merged.len()).map(|i| format_ident!("other_{}", i)).collect(),
+                merged,
+            }
+        })
+        .collect();
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+    let options = options?;
+    let krate = options.krate();
+
+    let merges = variants.iter().map(|variant| {
+        let (self_pattern, other_pattern) = (variant.pattern(&variant.self_vars), variant.pattern(&variant.other_vars));
+        let merges = variant.fields_in(&variant.self_vars).zip(variant.fields_in(&variant.other_vars)).map(
+            |((field, this), (_, that))| {
+                let merge = field.merge_changed(&this, &that);
+                quote! { let changed = #merge? | changed; }
+            },
+        );
+        quote! {
+            (#self_pattern, #other_pattern) => {
+                let changed = false;
+                #(#merges)*
+                Some(changed)
+            }
+        }
+    });
+    let other_variants = (variants.len() > 1).then(|| quote! { _ => None, });
+    let mismatch = match &options.mismatch {
+        Mismatch::KeepSelf => quote! { false },
+        Mismatch::PreferOther => quote! {
+            {
+                *self = Clone::clone(other);
+                true
+            }
+        },
+        Mismatch::Call(resolve) => quote! { #resolve(self, other)? },
+    };
+
+    // The rest only look at the variant `self` is.
+    let each_variant = |body: &dyn Fn(&CrdtVariant) -> TokenStream2| {
+        let arms = variants.iter().map(|variant| {
+            let pattern = variant.pattern(&variant.self_vars);
+            let body = body(variant);
+            quote! { #pattern => #body, }
+        });
+        quote! {
+            match self {
+                #(#arms)*
             }
         }
     };
-    output.into()
+    let cleanups = each_variant(&|variant| {
+        let cleanups = variant.fields_in(&variant.self_vars).filter_map(|(field, this)| field.cleanup(&this));
+        quote! {{ #(#cleanups)* }}
+    });
+    let compacts = each_variant(&|variant| {
+        let compacts = variant.fields_in(&variant.self_vars).filter_map(|(field, this)| field.compact(&this));
+        quote! {{ #(#compacts)* }}
+    });
+    let versions = each_variant(&|variant| {
+        let versions = variant.fields_in(&variant.self_vars).filter_map(|(field, this)| field.version(&this));
+        quote! {{
+            let version = 0;
+            #(#versions)*
+            version
+        }}
+    });
+    // Seeded by the variant, so equal fields in different variants differ.
+    let digests = each_variant(&|variant| {
+        let index = variant.index;
+        let digests = variant.fields_in(&variant.self_vars).map(|(field, this)| field.digest(&krate, &this));
+        quote! { (#index, vec![#(#digests,)*]) }
+    });
+    let delta_since = options.delta.then(|| {
+        let deltas = each_variant(&|variant| {
+            let ident = variant.ident;
+            let fields = variant.fields.iter().enumerate().map(|(index, field)| {
+                let member = member(index, field);
+                match variant.fields_in(&variant.self_vars).find(|(merged, _)| merged.member == member) {
+                    Some((field, this)) => {
+                        let delta = field.delta_since(&this);
+                        quote! { #member: #delta, }
+                    }
+                    None => quote! { #member: Default::default(), },
+                }
+            });
+            quote! { Some(Self::#ident { #(#fields)* }) }
+        });
+        quote! {
+            fn delta_since(&self, version: u64) -> Option<Self> {
+                #deltas
+            }
+        }
+    });
+
+    let (impl_generics, ty_generics, _) = generics.split_for_impl();
+    let fields = variants.iter().flat_map(|variant| &variant.merged);
+    let mut crdt_where = where_clause(
+        generics,
+        &options,
+        fields.map(|field| match field.merge_with {
+            Some(_) => (field.ty, quote! { Clone + serde::Serialize }),
+            None => (field.ty, quote! { #krate::crdt::Crdt + serde::Serialize }),
+        }),
+    );
+    let mut snapshot_where = where_clause(generics, &options, []);
+    if matches!(options.mismatch, Mismatch::PreferOther) {
+        crdt_where.predicates.push(syn::parse_quote! { Self: Clone });
+    }
+    snapshot_where.predicates.push(syn::parse_quote! { Self: Clone });
+
+    Ok(quote! {
+        impl #impl_generics #krate::crdt::Crdt for #ident #ty_generics #crdt_where {
+            fn merge(&mut self, other: &Self) -> anyhow::Result<()> {
+                #krate::crdt::Crdt::merge_changed(self, other).map(|_| ())
+            }
+
+            fn merge_changed(&mut self, other: &Self) -> anyhow::Result<bool> {
+                let merged = match (&mut *self, other) {
+                    #(#merges)*
+                    #other_variants
+                };
+                Ok(match merged {
+                    Some(changed) => changed,
+                    None => #mismatch,
+                })
+            }
+
+            fn cleanup(&mut self, now: i64) {
+                #cleanups
+            }
+
+            fn compact(&mut self, horizon: i64) {
+                #compacts
+            }
+
+            fn version(&self) -> u64 {
+                #versions
+            }
+
+            fn digest(&self) -> u64
+            where
+                Self: serde::Serialize,
+            {
+                let (variant, digests): (u64, Vec<u64>) = #digests;
+                let bytes: Vec<u8> = std::iter::once(variant)
+                    .chain(digests)
+                    .flat_map(|d| d.to_le_bytes())
+                    .collect();
+                #krate::crdt::digest_bytes(&bytes)
+            }
+
+            #delta_since
+        }
+
+        // A snapshot has to remember the variant, and with it the fields
+        // that aren't merged, so it's the whole value.
+        impl #impl_generics #krate::crdt::CrdtSnapshot for #ident #ty_generics #snapshot_where {
+            type Snap = Self;
+
+            fn snapshot(&self) -> Self {
+                Clone::clone(self)
+            }
+
+            fn restore(&mut self, snap: Self) {
+                *self = snap;
+            }
+        }
+    })
 }
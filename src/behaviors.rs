@@ -1,20 +1,26 @@
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 use std::collections::VecDeque;
 
 use bindings::{
-    actions, actor, game::auto_rogue::types::ConvertParams, inventory, visible_creatures,
-    visible_items, ActionTarget, AttackParams, Command, Loc, MicroAction, EquipmentSlot,
-    get_equipment_state, Direction, ConvertCost,
+    game::auto_rogue::types::ConvertParams, ActionTarget, AttackParams, Command, Loc, MicroAction, EquipmentSlot,
+    Direction, ConvertCost,
 };
 
-use crate::{distance, LocMap, LocSet};
+use crate::{
+    crdt::WindowedCounter,
+    host::{actions, actor, get_equipment_state, inventory, visible_creatures, visible_items},
+    DeterministicRng,
+    bresenham_line, distance, distance_squared, line_of_sight, resample_path, smooth_path, theta_star, ArrivalCondition,
+    AstarOptions, FlowField, FnLocSet, LocExt, LocMap, LocSet, MovementKind, PathCache, PathPlanner, PathfindingStats, PenaltyMap,
+    UnionAll, WithPenalties,
+};
 
 #[macro_export]
 macro_rules! find_action {
     ($pattern:pat $(if $guard:expr)?) => {
         {
             let mut found = None;
-            'outer: for (i, action) in actions().into_iter().enumerate() {
+            'outer: for (i, action) in $crate::host::actions().into_iter().enumerate() {
                 for m in &action.micro_actions {
                     if match m {
                         $pattern $(if $guard)? => true,
@@ -53,14 +59,7 @@ pub fn avoidance_sets(creature_margin: u32, target: Option<Loc>) -> (IndexSet<Lo
     for (loc, creature) in visible_creatures() {
         blocked.insert(loc);
         if creature_margin > 0 && creature.faction != actor.faction {
-            for dx in -(creature_margin as i32)..creature_margin as i32 + 1 {
-                for dy in -(creature_margin as i32)..creature_margin as i32 + 1 {
-                    creature_margins.insert(Loc {
-                        x: loc.x + dx,
-                        y: loc.y + dy,
-                    });
-                }
-            }
+            creature_margins.extend((0..=creature_margin).flat_map(|radius| loc.ring(radius)));
         }
     }
 
@@ -75,6 +74,57 @@ pub fn avoidance_sets(creature_margin: u32, target: Option<Loc>) -> (IndexSet<Lo
     (blocked, creature_margins)
 }
 
+/// Penalties that fall off linearly with Chebyshev distance from each
+/// creature, reaching zero one tile past `margin`, scaled by `threat`.
+/// Overlapping creatures add up. A creature at `target` is ignored so
+/// closing in on it isn't discouraged, as are ones with no threat.
+pub fn creature_penalties<C>(
+    creatures: impl IntoIterator<Item = (Loc, C)>,
+    margin: u32,
+    target: Option<Loc>,
+    threat: impl Fn(&C) -> f32,
+) -> IndexMap<Loc, f32> {
+    let mut penalties = IndexMap::new();
+    for (loc, creature) in creatures {
+        let threat = threat(&creature);
+        if Some(loc) == target || threat <= 0.0 {
+            continue;
+        }
+        for radius in 0..=margin {
+            let penalty = threat * (margin + 1 - radius) as f32 / (margin + 1) as f32;
+            for n in loc.ring(radius) {
+                *penalties.entry(n).or_insert(0.0) += penalty;
+            }
+        }
+    }
+    penalties
+}
+
+/// Graded version of the creature margins from `avoidance_sets`: every
+/// visible creature of another faction weighs as much as the default
+/// `avoid_penalty`. Use `creature_penalties` directly to weigh them by threat.
+pub fn avoidance_penalties(creature_margin: u32, target: Option<Loc>) -> IndexMap<Loc, f32> {
+    let (_, actor) = actor();
+    let avoid_penalty = AstarOptions::default().avoid_penalty;
+    creature_penalties(visible_creatures(), creature_margin, target, |creature| {
+        if creature.faction != actor.faction {
+            avoid_penalty
+        } else {
+            0.0
+        }
+    })
+}
+
+thread_local! {
+    static PATHFINDING_STATS: std::cell::RefCell<PathfindingStats> = Default::default();
+}
+
+/// Returns and resets the totals for every search the `move_towards` family
+/// has run since the last call, e.g. to log once per turn.
+pub fn take_pathfinding_stats() -> PathfindingStats {
+    PATHFINDING_STATS.with(|totals| totals.take())
+}
+
 pub fn move_towards(
     current_path: &mut Option<VecDeque<Loc>>,
     level_map: &dyn LocMap,
@@ -82,14 +132,95 @@ pub fn move_towards(
     avoid: &dyn LocSet,
     loc: Loc,
 ) -> Option<Command> {
-    if let Some(path) = current_path {
-        if path.iter().last() != Some(&Loc { x: loc.x, y: loc.y }) {
-            *current_path = None;
-        }
+    move_towards_with_options(current_path, level_map, blocked, avoid, loc, &AstarOptions::default())
+}
+
+pub fn move_towards_with_options(
+    current_path: &mut Option<VecDeque<Loc>>,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    loc: Loc,
+    options: &AstarOptions,
+) -> Option<Command> {
+    step_towards(current_path, None, level_map, blocked, avoid, loc, options)
+}
+
+/// Like `move_towards_with_options`, with `penalties` added to step costs.
+/// A current path that runs through any penalized tile is replanned.
+pub fn move_towards_with_penalties(
+    current_path: &mut Option<VecDeque<Loc>>,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    penalties: &dyn PenaltyMap,
+    loc: Loc,
+    options: &AstarOptions,
+) -> Option<Command> {
+    if current_path.as_ref().is_some_and(|path| path.iter().any(|l| penalties.penalty_at(l) > 0.0)) {
+        *current_path = None;
+    }
+    let level_map = WithPenalties(level_map, penalties);
+    move_towards_with_options(current_path, &level_map, blocked, avoid, loc, options)
+}
+
+/// Walks to a tile next to `loc` rather than onto it, for targets that are
+/// themselves blocked.
+pub fn move_towards_adjacent(
+    current_path: &mut Option<VecDeque<Loc>>,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    loc: Loc,
+) -> Option<Command> {
+    let options = AstarOptions {
+        arrival: ArrivalCondition::WithinChebyshev(1),
+        ..Default::default()
+    };
+    move_towards_with_options(current_path, level_map, blocked, avoid, loc, &options)
+}
+
+/// Like `move_towards_with_options`, but looks new paths up in `cache`
+/// before searching and stores what it finds there.
+pub fn move_towards_with_cache(
+    current_path: &mut Option<VecDeque<Loc>>,
+    cache: &mut PathCache,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    loc: Loc,
+    options: &AstarOptions,
+) -> Option<Command> {
+    step_towards(current_path, Some(cache), level_map, blocked, avoid, loc, options)
+}
+
+fn step_towards(
+    current_path: &mut Option<VecDeque<Loc>>,
+    cache: Option<&mut PathCache>,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    loc: Loc,
+    options: &AstarOptions,
+) -> Option<Command> {
+    if let Some(path) = current_path
+        && !path.back().is_some_and(|end| options.arrival.is_met(*end, loc))
+    {
+        *current_path = None;
     }
     let (current_loc, _) = actor();
-    astar_update_path(current_path, current_loc, loc, level_map, blocked, avoid);
-    if let Some(loc) = current_path.as_mut().and_then(|locs| locs.pop_front()) {
+    if needs_plan(current_path, blocked, avoid) {
+        *current_path = cached_plan(cache, current_loc, loc, level_map, blocked, avoid, options);
+    }
+    // Waypoints can be several tiles apart, as `theta_star`'s are, so each
+    // is only done with once the actor stands on it.
+    let next = current_path.as_mut().and_then(|locs| {
+        while locs.front() == Some(&current_loc) {
+            locs.pop_front();
+        }
+        locs.front().copied()
+    });
+    if let Some(loc) = next {
         if let Some((id, _, _)) = find_action!(MicroAction::Walk) {
             return Some(Command::UseAction((
                 id as u32,
@@ -101,24 +232,84 @@ pub fn move_towards(
     None
 }
 
-fn astar_update_path(
-    path: &mut Option<VecDeque<Loc>>,
+/// Like `move_towards_with_options`, but replans through `planner` so that
+/// a path invalidated by map changes or by the agent's own moves is repaired
+/// rather than searched again from scratch.
+pub fn move_towards_with_planner(
+    planner: &mut PathPlanner,
+    level_map: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    loc: Loc,
+    options: &AstarOptions,
+) -> Option<Command> {
+    let (current_loc, _) = actor();
+    let still_valid = planner.path.as_ref().is_some_and(|path| {
+        path.back() == Some(&loc) && !path.iter().any(|l| blocked.contains_loc(l) || avoid.contains_loc(l))
+    });
+    if !still_valid {
+        planner.plan(current_loc, loc, level_map, blocked, avoid, options);
+    }
+    if let Some(next) = planner.path.as_mut().and_then(|locs| locs.pop_front())
+        && let Some((id, _, _)) = find_action!(MicroAction::Walk)
+    {
+        return Some(Command::UseAction((id as u32, Some(ActionTarget::Location(next)))));
+    }
+    println!("no path");
+    None
+}
+
+fn needs_plan(path: &Option<VecDeque<Loc>>, blocked: &dyn LocSet, avoid: &dyn LocSet) -> bool {
+    match path {
+        Some(locs) => locs.iter().any(|loc| blocked.contains_loc(loc) || avoid.contains_loc(loc)),
+        None => true,
+    }
+}
+
+fn cached_plan(
+    cache: Option<&mut PathCache>,
     current_location: Loc,
     goal: Loc,
     explored_tiles: &dyn LocMap,
     blocked: &dyn LocSet,
     avoid: &dyn LocSet,
-) {
-    if let Some(locs) = path {
-        for loc in locs {
-            if blocked.contains_loc(loc) || avoid.contains_loc(loc) {
-                *path = crate::astar(current_location, goal, explored_tiles, blocked, avoid);
-                return;
-            }
-        }
-    } else {
-        *path = crate::astar(current_location, goal, explored_tiles, blocked, avoid);
+    options: &AstarOptions,
+) -> Option<VecDeque<Loc>> {
+    let Some(cache) = cache else {
+        return plan_path(current_location, goal, explored_tiles, blocked, avoid, options);
+    };
+    if let Some(path) = cache.get(current_location, goal, explored_tiles, blocked, options)
+        && !path.iter().any(|loc| avoid.contains_loc(loc))
+    {
+        return Some(path);
+    }
+    let path = plan_path(current_location, goal, explored_tiles, blocked, avoid, options)?;
+    cache.insert(current_location, goal, options, path.clone());
+    Some(path)
+}
+
+fn plan_path(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> Option<VecDeque<Loc>> {
+    if options.movement == MovementKind::AnyAngle {
+        return theta_star(current_location, goal, explored_tiles, blocked, avoid, options);
+    }
+    let (path, stats) = crate::astar_with_stats(current_location, goal, explored_tiles, blocked, avoid, options);
+    PATHFINDING_STATS.with(|totals| totals.borrow_mut().add(&stats));
+    let mut path = path?.steps;
+    if options.smooth {
+        let impassable = FnLocSet(|loc: &Loc| !options.unknown_tiles.allows(explored_tiles.get_loc(loc)));
+        let blocking = UnionAll(vec![blocked, avoid, &impassable]);
+        path.push_front(current_location);
+        path = resample_path(&smooth_path(&path, &blocking));
+        path.pop_front();
     }
+    Some(path)
 }
 
 pub fn convert() -> Option<Command> {
@@ -173,10 +364,10 @@ pub fn attack_nearest(exclude_factions: &[i64]) -> Option<Command> {
     let (current_loc, _) = actor();
 
     let mut nearest = None;
-    let mut nearest_dist = f32::MAX;
+    let mut nearest_dist = i64::MAX;
     for (loc, creature) in visible_creatures() {
         if !exclude_factions.contains(&creature.faction) {
-            let d = distance(loc, current_loc);
+            let d = distance_squared(loc, current_loc);
             if d < nearest_dist {
                 nearest_dist = d;
                 nearest = Some(loc);
@@ -208,13 +399,122 @@ pub fn attack_target(target: Loc) -> Option<Command> {
     None
 }
 
+/// Like `attack_target`, but only fires if nothing known to be impassable on
+/// `level_map`, and no visible impassable item, lies between us and the target.
+pub fn attack_target_los(target: Loc, level_map: &dyn LocMap) -> Option<Command> {
+    let (current_loc, _) = actor();
+    let mut blocking: IndexSet<Loc> = bresenham_line(current_loc, target)
+        .filter(|loc| level_map.get_loc(loc) == Some(false))
+        .collect();
+    blocking.extend(
+        visible_items()
+            .into_iter()
+            .filter(|(_, item)| !item.is_passable)
+            .map(|(loc, _)| loc),
+    );
+    if line_of_sight(current_loc, target, &blocking) {
+        attack_target(target)
+    } else {
+        None
+    }
+}
+
+/// Takes the field's next step towards its goal, or wanders if the agent
+/// isn't on the field. `None` once at the goal.
+pub fn move_along_field(field: &FlowField) -> Option<Command> {
+    let (current_loc, _) = actor();
+    match field.next_step(current_loc) {
+        Some(next) => {
+            let (id, _, _) = find_action!(MicroAction::Walk)?;
+            Some(Command::UseAction((id as u32, Some(ActionTarget::Location(next)))))
+        }
+        None if current_loc == field.goal => None,
+        None => wander(),
+    }
+}
+
+/// Records a use of something shared by `counter` and says to go ahead,
+/// unless it's been used `limit` times in the last `window` turns. Allies
+/// acting on the same turn before merging can still go over together.
+pub fn try_rate_limited(counter: &mut WindowedCounter, replica: u64, limit: u64, window: i64, now: i64) -> bool {
+    if counter.count_in_window(now, window) >= limit {
+        return false;
+    }
+    counter.record(replica, now);
+    true
+}
+
+const DIRECTIONS: [Direction; 8] = [Direction::North, Direction::NorthEast, Direction::East, Direction::SouthEast, Direction::South, Direction::SouthWest, Direction::West, Direction::NorthWest];
+
+/// Walks a random way, drawn from `fastrand`, which starts over each turn.
 pub fn wander() -> Option<Command> {
-    if let Some((id, _, _)) = find_action!(MicroAction::Walk) {
-        let dir = [Direction::North, Direction::NorthEast, Direction::East, Direction::SouthEast, Direction::South, Direction::SouthWest, Direction::West, Direction::NorthWest][fastrand::usize(0..8)];
-        return Some(Command::UseAction((
-            id as u32,
-            Some(ActionTarget::Direction(dir)),
-        )));
+    let (id, _, _) = find_action!(MicroAction::Walk)?;
+    Some(wander_command(id, None))
+}
+
+/// Like `wander`, drawing from `rng`. Kept in the `State`, the same seed
+/// picks the same ways every replay.
+pub fn wander_with_rng(rng: &mut DeterministicRng) -> Option<Command> {
+    let (id, _, _) = find_action!(MicroAction::Walk)?;
+    Some(wander_command(id, Some(rng)))
+}
+
+fn wander_command(walk_action: usize, rng: Option<&mut DeterministicRng>) -> Command {
+    let dir = match rng {
+        Some(rng) => DIRECTIONS[rng.below(DIRECTIONS.len())],
+        None => DIRECTIONS[fastrand::usize(0..DIRECTIONS.len())],
+    };
+    Command::UseAction((walk_action as u32, Some(ActionTarget::Direction(dir))))
+}
+
+#[cfg(test)]
+mod wander_tests {
+    use super::*;
+
+    #[test]
+    fn seeded_wandering_replays_the_same_way() {
+        let walks = |rng: &mut DeterministicRng| (0..20).map(|_| wander_command(3, Some(rng))).collect::<Vec<_>>();
+        let first = walks(&mut DeterministicRng::from_key(b"actor 7, level 2"));
+        assert_eq!(first, walks(&mut DeterministicRng::from_key(b"actor 7, level 2")));
+
+        // The directions are the generator's draws, so they carry on from
+        // wherever the stored one left off.
+        let mut twin = DeterministicRng::from_key(b"actor 7, level 2");
+        let dirs: Vec<_> = (0..20).map(|_| DIRECTIONS[twin.below(DIRECTIONS.len())]).collect();
+        let expected: Vec<_> = dirs.iter().map(|dir| Command::UseAction((3, Some(ActionTarget::Direction(*dir))))).collect();
+        assert_eq!(first, expected);
+        assert!(dirs.iter().any(|dir| *dir != dirs[0]), "{dirs:?}");
+    }
+}
+
+#[cfg(test)]
+mod move_tests {
+    use super::*;
+    use crate::host::{with_host, MockHost};
+    use crate::EmptyLocSet;
+    use bindings::{Action, Creature};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn at(x: i32) -> Rc<MockHost> {
+        let host = MockHost::default()
+            .with_actor(Loc { x, y: 0 }, Creature { faction: 1, broadcast: None })
+            .with_action(Action { micro_actions: vec![MicroAction::Walk] });
+        Rc::new(host)
+    }
+
+    #[test]
+    fn waypoints_are_kept_until_reached() {
+        let corridor: HashMap<Loc, bool> = (0..6).map(|x| (Loc { x, y: 0 }, true)).collect();
+        let goal = Loc { x: 5, y: 0 };
+        let options = AstarOptions { movement: MovementKind::AnyAngle, ..Default::default() };
+        let mut path = None;
+        let mut step = |x| with_host(at(x), || move_towards_with_options(&mut path, &corridor, &EmptyLocSet, &EmptyLocSet, goal, &options));
+        let walk = Some(Command::UseAction((0, Some(ActionTarget::Location(goal)))));
+        // One waypoint, the goal, walked towards a tile a turn.
+        for x in 0..5 {
+            assert_eq!(step(x), walk, "from {x}");
+        }
+        assert_eq!(step(5), None);
     }
-    None
 }
@@ -0,0 +1,200 @@
+//! How `Component::step` turns its store and broadcasts into bytes. Every
+//! encoding starts with its codec's tag, so a reader can decode whatever
+//! codec it was built with, and allies halfway through switching codecs can
+//! still merge each other's broadcasts.
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub trait Codec {
+    /// Written in front of everything encoded with this codec. Below
+    /// `DEFLATED`, and unique among codecs.
+    const TAG: u8;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// The default, and what stores and broadcasts were before codecs.
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const TAG: u8 = 1;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Smaller than bincode, as integers are varints.
+#[cfg(feature = "postcard")]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const TAG: u8 = 2;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        Ok(postcard::to_allocvec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+/// Readable, for debugging. JSON keys have to be strings, so maps keyed by
+/// `Loc`, like `ExplorableMap`'s levels, can't be encoded, and
+/// `encode_or_bincode` falls back to bincode for them.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const TAG: u8 = 3;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Set in the tag of a `Deflate` encoding, over the tag of what it wraps.
+pub const DEFLATED: u8 = 0x80;
+
+/// Compresses what `C` encodes, for big stores and broadcasts.
+#[cfg(feature = "deflate")]
+pub struct Deflate<C>(std::marker::PhantomData<C>);
+
+#[cfg(feature = "deflate")]
+impl<C: Codec> Codec for Deflate<C> {
+    const TAG: u8 = C::TAG | DEFLATED;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        Ok(miniz_oxide::deflate::compress_to_vec(&C::encode(value)?, 6))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        C::decode(&inflate(bytes)?)
+    }
+}
+
+#[cfg(feature = "deflate")]
+fn inflate(bytes: &[u8]) -> Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec(bytes).map_err(|e| anyhow::anyhow!("can't inflate: {:?}", e.status))
+}
+
+/// `C`'s tag followed by its encoding of `value`.
+pub fn encode_tagged<C: Codec, T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = vec![C::TAG];
+    bytes.extend(C::encode(value)?);
+    Ok(bytes)
+}
+
+/// `encode_tagged` with `C`, or with bincode when `C` can't encode `value`,
+/// which `decode_tagged` reads all the same.
+pub fn encode_or_bincode<C: Codec, T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    encode_tagged::<C, _>(value).or_else(|e| {
+        println!("Encoding with bincode instead: {e}");
+        encode_tagged::<Bincode, _>(value)
+    })
+}
+
+/// Reads `encode_tagged` with the codec its tag names, if it's one this was
+/// built with.
+pub fn decode_tagged<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let Some((&tag, bytes)) = bytes.split_first() else {
+        bail!("no codec tag");
+    };
+    decode_with(tag, bytes)
+}
+
+fn decode_with<T: DeserializeOwned>(tag: u8, bytes: &[u8]) -> Result<T> {
+    match tag {
+        Bincode::TAG => Bincode::decode(bytes),
+        #[cfg(feature = "postcard")]
+        Postcard::TAG => Postcard::decode(bytes),
+        #[cfg(feature = "json")]
+        Json::TAG => Json::decode(bytes),
+        #[cfg(feature = "deflate")]
+        tag if tag & DEFLATED != 0 => decode_with(tag & !DEFLATED, &inflate(bytes)?),
+        _ => bail!("unknown codec tag {tag}"),
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+    use crate::framework::ExplorableMap;
+    use crate::Loc;
+
+    fn explored(with_levels: bool) -> ExplorableMap {
+        let loc = |x| Loc { x, y: 0 };
+        let mut map = ExplorableMap::default().with_tile_ttl(50);
+        map.register_portal(1, loc(1), 2, loc(2));
+        map.unexplored_locs.insert(loc(3));
+        if with_levels {
+            let (tiles, items, _) = map.maps.entry(1).or_default();
+            tiles.insert(loc(4), true, 1, 60);
+            items.insert(loc(4), Some("key".into()), 1, 60);
+        }
+        map
+    }
+
+    fn round_trips<C: Codec>(with_levels: bool) {
+        let map = explored(with_levels);
+        let bytes = encode_tagged::<C, _>(&map).unwrap();
+        assert_eq!(bytes[0], C::TAG);
+        let decoded: ExplorableMap = decode_tagged(&bytes).unwrap();
+        // `ExplorableMap` isn't `PartialEq`, so its encodings are compared.
+        assert_eq!(encode_tagged::<C, _>(&decoded).unwrap(), bytes);
+        assert_eq!(decoded.portal_route(1, 2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn bincode_round_trips() {
+        round_trips::<Bincode>(true);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips() {
+        round_trips::<Postcard>(true);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trips() {
+        round_trips::<Json>(false);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_falls_back_to_bincode_for_levels() {
+        let map = explored(true);
+        assert!(encode_tagged::<Json, _>(&map).is_err());
+        let bytes = encode_or_bincode::<Json, _>(&map).unwrap();
+        assert_eq!(bytes[0], Bincode::TAG);
+        let decoded: ExplorableMap = decode_tagged(&bytes).unwrap();
+        assert_eq!(decoded.maps[&1].0.get(&Loc { x: 4, y: 0 }), Some(&true));
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_round_trips() {
+        round_trips::<Deflate<Bincode>>(true);
+    }
+
+    #[test]
+    fn unknown_tags_fail() {
+        assert!(decode_tagged::<u32>(&[0x7f, 0, 0, 0, 0]).is_err());
+        assert!(decode_tagged::<u32>(&[]).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+//! A denser encoding than bincode for the Loc-keyed CRDT maps, which tend to
+//! dominate the store and broadcasts. Locs are delta-encoded in order, values
+//! packed into a bitset and turns varint-encoded relative to the smallest.
+//! Change tracking for `Crdt::delta_since` isn't kept, so it starts over
+//! after decoding.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+
+use crate::crdt::{ChunkedLocCrdtMap, CrdtMap, ExpiringCrdtMap, LocChunk};
+use crate::Loc;
+
+/// Starts every compact encoding. As the first 8 bytes of bincode's encoding
+/// of a map are its length, legacy encodings never start like this.
+const MAGIC: &[u8; 4] = b"LOCM";
+const FORMAT: u8 = 1;
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn coord(n: i64) -> Result<i32> {
+    i32::try_from(n).context("coordinate out of range")
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn header() -> Self {
+        let mut w = Self(MAGIC.to_vec());
+        w.0.push(FORMAT);
+        w
+    }
+
+    fn varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.0.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.0.push(n as u8);
+    }
+
+    fn signed(&mut self, n: i64) {
+        self.varint(zigzag(n));
+    }
+
+    /// Locs in ascending order. A loc straight after one with the same `x`
+    /// usually costs a single byte.
+    fn locs<'a>(&mut self, locs: impl ExactSizeIterator<Item = &'a Loc>) {
+        self.varint(locs.len() as u64);
+        let mut prev: Option<Loc> = None;
+        for loc in locs {
+            match prev {
+                Some(p) if p.x == loc.x && p.y < loc.y => self.varint((loc.y as i64 - p.y as i64 - 1) as u64 * 2),
+                Some(p) => {
+                    self.varint(zigzag(loc.x as i64 - p.x as i64) * 2 + 1);
+                    self.signed(loc.y as i64);
+                }
+                None => {
+                    self.signed(loc.x as i64);
+                    self.signed(loc.y as i64);
+                }
+            }
+            prev = Some(*loc);
+        }
+    }
+
+    fn bits(&mut self, bits: impl Iterator<Item = bool>) {
+        let mut byte = 0;
+        let mut n = 0;
+        for bit in bits {
+            byte |= (bit as u8) << (n % 8);
+            n += 1;
+            if n % 8 == 0 {
+                self.0.push(byte);
+                byte = 0;
+            }
+        }
+        if n % 8 != 0 {
+            self.0.push(byte);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// `None` if `bytes` isn't a compact encoding at all.
+    fn open(bytes: &'a [u8]) -> Result<Option<Self>> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            return Ok(None);
+        };
+        let mut r = Self(rest);
+        match r.byte()? {
+            FORMAT => Ok(Some(r)),
+            format => bail!("unknown compact format {format}"),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let (byte, rest) = self.0.split_first().context("compact encoding ended early")?;
+        self.0 = rest;
+        Ok(*byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return Ok(n);
+            }
+        }
+        bail!("varint too long")
+    }
+
+    fn signed(&mut self) -> Result<i64> {
+        Ok(unzigzag(self.varint()?))
+    }
+
+    fn locs(&mut self) -> Result<Vec<Loc>> {
+        let len = self.varint()? as usize;
+        let mut locs: Vec<Loc> = Vec::with_capacity(len.min(self.0.len()));
+        for _ in 0..len {
+            let loc = match locs.last() {
+                None => Loc { x: coord(self.signed()?)?, y: coord(self.signed()?)? },
+                Some(p) => {
+                    let token = self.varint()?;
+                    if token % 2 == 0 {
+                        Loc { x: p.x, y: coord(p.y as i64 + (token / 2) as i64 + 1)? }
+                    } else {
+                        let x = coord(p.x as i64 + unzigzag(token / 2))?;
+                        Loc { x, y: coord(self.signed()?)? }
+                    }
+                }
+            };
+            locs.push(loc);
+        }
+        Ok(locs)
+    }
+
+    fn bits(&mut self, len: usize) -> Result<Vec<bool>> {
+        let mut bits = Vec::with_capacity(len);
+        for i in 0..len.div_ceil(8) {
+            let byte = self.byte()?;
+            bits.extend((0..8.min(len - i * 8)).map(|n| byte & (1 << n) != 0));
+        }
+        Ok(bits)
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.0.is_empty() {
+            bail!("{} bytes left over after compact encoding", self.0.len());
+        }
+        Ok(())
+    }
+}
+
+impl<P> CrdtMap<Loc, bool, P> {
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::header();
+        self.write_compact(&mut w);
+        w.0
+    }
+
+    fn write_compact(&self, w: &mut Writer) {
+        w.signed(self.tombstone_horizon);
+        w.locs(self.entries.keys());
+        w.bits(self.entries.values().map(|(passable, _)| *passable));
+        let base = self.entries.values().map(|(_, written)| *written).chain(self.tombstones.values().copied()).min().unwrap_or(0);
+        w.signed(base);
+        for (_, written) in self.entries.values() {
+            w.varint(written.wrapping_sub(base) as u64);
+        }
+        w.locs(self.tombstones.keys());
+        for removed in self.tombstones.values() {
+            w.varint(removed.wrapping_sub(base) as u64);
+        }
+        // Only a handful of replicas write, so each entry names its writer by
+        // an index into a table of them, with 0 for none.
+        let writers: BTreeSet<u64> = self.writers.values().copied().collect();
+        w.varint(writers.len() as u64);
+        for writer in &writers {
+            w.varint(*writer);
+        }
+        for loc in self.entries.keys() {
+            let index = self.writers.get(loc).map_or(0, |writer| writers.range(..writer).count() + 1);
+            w.varint(index as u64);
+        }
+    }
+
+    /// Reads `to_compact_bytes`, or bincode for maps saved before it existed.
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some(mut r) = Reader::open(bytes)? else {
+            return Ok(bincode::deserialize(bytes)?);
+        };
+        let map = Self::read_compact(&mut r)?;
+        r.finish()?;
+        Ok(map)
+    }
+
+    fn read_compact(r: &mut Reader) -> Result<Self> {
+        let mut map = Self::with_tombstone_horizon(r.signed()?);
+        let locs = r.locs()?;
+        let values = r.bits(locs.len())?;
+        let base = r.signed()?;
+        for (loc, passable) in locs.iter().zip(values) {
+            let written = base.wrapping_add(r.varint()? as i64);
+            map.entries.insert(*loc, (passable, written));
+        }
+        for loc in r.locs()? {
+            map.tombstones.insert(loc, base.wrapping_add(r.varint()? as i64));
+        }
+        let writers = (0..r.varint()?).map(|_| r.varint()).collect::<Result<Vec<_>>>()?;
+        for loc in locs {
+            match r.varint()? as usize {
+                0 => {}
+                index => {
+                    map.writers.insert(loc, *writers.get(index - 1).context("unknown writer")?);
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl<P> ChunkedLocCrdtMap<bool, P> {
+    /// Each chunk is encoded like a `CrdtMap`, along with its digest.
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::header();
+        w.signed(self.1);
+        w.varint(self.0.len() as u64);
+        for (key, chunk) in &self.0 {
+            w.signed(key.x as i64);
+            w.signed(key.y as i64);
+            match chunk.digest {
+                Some(digest) => {
+                    w.0.push(1);
+                    w.0.extend(digest.to_le_bytes());
+                }
+                None => w.0.push(0),
+            }
+            chunk.map.write_compact(&mut w);
+        }
+        w.0
+    }
+
+    /// Reads `to_compact_bytes`, or bincode.
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some(mut r) = Reader::open(bytes)? else {
+            return Ok(bincode::deserialize(bytes)?);
+        };
+        let mut map = Self::with_tombstone_horizon(r.signed()?);
+        for _ in 0..r.varint()? {
+            let key = Loc { x: coord(r.signed()?)?, y: coord(r.signed()?)? };
+            let digest = match r.byte()? {
+                0 => None,
+                _ => {
+                    let mut digest = [0; 8];
+                    for byte in &mut digest {
+                        *byte = r.byte()?;
+                    }
+                    Some(u64::from_le_bytes(digest))
+                }
+            };
+            let chunk = CrdtMap::read_compact(&mut r)?;
+            map.0.insert(key, LocChunk { map: chunk, digest });
+        }
+        r.finish()?;
+        Ok(map)
+    }
+}
+
+/// `expires` is usually either never or a fixed time after `written`, so
+/// it's stored as that offset, with 0 meaning the same as the last entry.
+fn expiry_token(written: i64, expires: i64, last: &mut Option<u64>) -> u64 {
+    let offset = if expires == i64::MAX { 0 } else { zigzag(expires.wrapping_sub(written)).wrapping_add(1) };
+    if last.replace(offset) == Some(offset) {
+        0
+    } else {
+        offset.wrapping_add(1)
+    }
+}
+
+fn expiry_from_token(written: i64, token: u64, last: &mut Option<u64>) -> Result<i64> {
+    let offset = match token {
+        0 => last.context("repeated expiry without a previous one")?,
+        token => token.wrapping_sub(1),
+    };
+    *last = Some(offset);
+    Ok(match offset {
+        0 => i64::MAX,
+        offset => written.wrapping_add(unzigzag(offset.wrapping_sub(1))),
+    })
+}
+
+impl<P> ExpiringCrdtMap<Loc, bool, P> {
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::header();
+        w.locs(self.0.keys());
+        w.bits(self.0.values().map(|(passable, _, _)| *passable));
+        let base = self.0.values().map(|(_, written, _)| *written).min().unwrap_or(0);
+        w.signed(base);
+        let mut last = None;
+        for (_, written, expires) in self.0.values() {
+            w.varint(written.wrapping_sub(base) as u64);
+            w.varint(expiry_token(*written, *expires, &mut last));
+        }
+        w.0
+    }
+
+    /// Reads `to_compact_bytes`, or bincode for maps saved before it existed.
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some(mut r) = Reader::open(bytes)? else {
+            return Ok(bincode::deserialize(bytes)?);
+        };
+        let locs = r.locs()?;
+        let values = r.bits(locs.len())?;
+        let base = r.signed()?;
+        let mut map = Self::default();
+        let mut last = None;
+        for (loc, passable) in locs.into_iter().zip(values) {
+            let written = base.wrapping_add(r.varint()? as i64);
+            map.insert(loc, passable, written, expiry_from_token(written, r.varint()?, &mut last)?);
+        }
+        r.finish()?;
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod compact_tests {
+    use super::*;
+    use crate::crdt::{replica_id, Lww};
+    use crate::Rect;
+
+    fn random_map() -> CrdtMap<Loc, bool, Lww> {
+        let mut map = CrdtMap::with_tombstone_horizon(fastrand::i64(-5..500));
+        for _ in 0..fastrand::usize(0..200) {
+            let loc = Loc { x: fastrand::i32(-40..40), y: fastrand::i32(-40..40) };
+            let now = fastrand::i64(-1000..1000);
+            match fastrand::u32(0..4) {
+                0 => map.remove(loc, now),
+                1 => map.insert_with_writer(loc, fastrand::bool(), now, fastrand::u64(0..u64::MAX)),
+                _ => map.insert(loc, fastrand::bool(), now),
+            }
+        }
+        map.insert(Loc { x: i32::MIN, y: i32::MAX }, true, i64::MIN);
+        map.insert(Loc { x: i32::MAX, y: i32::MIN }, false, i64::MAX);
+        map
+    }
+
+    #[test]
+    fn round_trips() {
+        fastrand::seed(308);
+        for _ in 0..100 {
+            let map = random_map();
+            let decoded = CrdtMap::<Loc, bool, Lww>::from_compact_bytes(&map.to_compact_bytes()).unwrap();
+            assert_eq!((&decoded.entries, &decoded.tombstones, decoded.tombstone_horizon, &decoded.writers), (&map.entries, &map.tombstones, map.tombstone_horizon, &map.writers));
+
+            let mut expiring: ExpiringCrdtMap<Loc, bool, Lww> = ExpiringCrdtMap::default();
+            for (loc, (passable, written)) in &map.entries {
+                let expires = if fastrand::bool() { i64::MAX } else { fastrand::i64(-1 << 40..1 << 40) };
+                expiring.insert(*loc, *passable, *written, expires);
+            }
+            let decoded = ExpiringCrdtMap::<Loc, bool, Lww>::from_compact_bytes(&expiring.to_compact_bytes()).unwrap();
+            assert_eq!(decoded.0, expiring.0);
+        }
+    }
+
+    #[test]
+    fn chunked_maps_round_trip() {
+        fastrand::seed(312);
+        let map = random_map();
+        let mut chunked: ChunkedLocCrdtMap<bool, Lww> = ChunkedLocCrdtMap::with_tombstone_horizon(map.tombstone_horizon);
+        for (loc, (passable, written)) in &map.entries {
+            chunked.insert(*loc, *passable, *written);
+        }
+        chunked.remove(Loc { x: 100, y: -100 }, 5);
+        chunked.0.values_mut().next().unwrap().digest = Some(u64::MAX);
+        let decoded = ChunkedLocCrdtMap::<bool, Lww>::from_compact_bytes(&chunked.to_compact_bytes()).unwrap();
+        assert_eq!(decoded.1, chunked.1);
+        assert_eq!(decoded.0.len(), chunked.0.len());
+        for ((key, chunk), (decoded_key, decoded_chunk)) in chunked.0.iter().zip(&decoded.0) {
+            assert_eq!((key, chunk.digest), (decoded_key, decoded_chunk.digest));
+            assert_eq!((&chunk.map.entries, &chunk.map.tombstones), (&decoded_chunk.map.entries, &decoded_chunk.map.tombstones));
+        }
+    }
+
+    #[test]
+    fn explored_regions_are_small() {
+        let mut map: CrdtMap<Loc, bool, Lww> = CrdtMap::default();
+        let mut expiring: ExpiringCrdtMap<Loc, bool, Lww> = ExpiringCrdtMap::default();
+        let region = Rect::new(Loc { x: 0, y: 0 }, Loc { x: 63, y: 63 });
+        for (i, loc) in region.iter().enumerate() {
+            let now = 800 + i as i64 / 50;
+            map.insert_with_writer(loc, i % 7 != 0, now, replica_id(b"me"));
+            expiring.insert(loc, i % 7 != 0, now, now + 100);
+        }
+        let tiles = region.iter().count();
+        assert!(map.to_compact_bytes().len() < tiles * 4, "{}", map.to_compact_bytes().len());
+        assert!(expiring.to_compact_bytes().len() < tiles * 4);
+    }
+
+    #[test]
+    fn rejects_truncated_and_unknown_formats() {
+        let mut map: CrdtMap<Loc, bool, Lww> = CrdtMap::default();
+        map.insert(Loc { x: 1, y: 2 }, true, 3);
+        let bytes = map.to_compact_bytes();
+        assert!(CrdtMap::<Loc, bool, Lww>::from_compact_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()] = FORMAT + 1;
+        assert!(CrdtMap::<Loc, bool, Lww>::from_compact_bytes(&newer).is_err());
+    }
+}
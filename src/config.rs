@@ -0,0 +1,306 @@
+//! Knobs a state shows in the game's editor. `Component::editor_config`
+//! gives the editor a `ConfigSchema`, and the `Config` it makes of it is put
+//! in front of the store with `with_config_prefix`, for `Component::step` to
+//! apply before `State::run` every turn.
+
+use anyhow::{anyhow, bail, Result};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+pub trait Configurable {
+    /// A `ConfigSchema`, as encoded by `ConfigSchema::to_bytes`.
+    fn config_schema() -> Vec<u8>;
+    /// Takes the values of a `Config`, as encoded by `Config::to_bytes`.
+    /// `ConfigSchema::read` decodes and checks it.
+    fn apply_config(&mut self, bytes: &[u8]) -> Result<()>;
+}
+
+/// `State::editor_config` and `State::configure` for a `Configurable`
+/// state, forwarding to it. Goes in its `impl State`.
+#[macro_export]
+macro_rules! configurable_state {
+    () => {
+        fn editor_config() -> Option<Vec<u8>> {
+            Some(<Self as $crate::config::Configurable>::config_schema())
+        }
+
+        fn configure(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            $crate::config::Configurable::apply_config(self, bytes)
+        }
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConfigKind {
+    Bool,
+    Int { min: i64, max: i64 },
+    Float { min: f64, max: f64 },
+    Text,
+    /// Text that has to be one of these.
+    Choice(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConfigValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigField {
+    pub name: String,
+    pub kind: ConfigKind,
+    pub default: ConfigValue,
+}
+
+impl ConfigField {
+    /// Whether `value` is of this field's kind, and in its range.
+    pub fn allows(&self, value: &ConfigValue) -> bool {
+        match (&self.kind, value) {
+            (ConfigKind::Bool, ConfigValue::Bool(_)) | (ConfigKind::Text, ConfigValue::Text(_)) => true,
+            (ConfigKind::Int { min, max }, ConfigValue::Int(v)) => (min..=max).contains(&v),
+            (ConfigKind::Float { min, max }, ConfigValue::Float(v)) => (min..=max).contains(&v),
+            (ConfigKind::Choice(choices), ConfigValue::Text(v)) => choices.contains(v),
+            _ => false,
+        }
+    }
+}
+
+/// The fields of a config in the order the editor shows them, built with
+/// the `with_*` methods.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigSchema {
+    pub fields: Vec<ConfigField>,
+}
+
+impl ConfigSchema {
+    pub fn with_field(mut self, name: &str, kind: ConfigKind, default: ConfigValue) -> Self {
+        self.fields.push(ConfigField {
+            name: name.to_string(),
+            kind,
+            default,
+        });
+        self
+    }
+
+    pub fn with_bool(self, name: &str, default: bool) -> Self {
+        self.with_field(name, ConfigKind::Bool, ConfigValue::Bool(default))
+    }
+
+    pub fn with_int(self, name: &str, min: i64, max: i64, default: i64) -> Self {
+        self.with_field(name, ConfigKind::Int { min, max }, ConfigValue::Int(default))
+    }
+
+    pub fn with_float(self, name: &str, min: f64, max: f64, default: f64) -> Self {
+        self.with_field(name, ConfigKind::Float { min, max }, ConfigValue::Float(default))
+    }
+
+    pub fn with_text(self, name: &str, default: &str) -> Self {
+        self.with_field(name, ConfigKind::Text, ConfigValue::Text(default.to_string()))
+    }
+
+    pub fn with_choice(self, name: &str, choices: &[&str], default: &str) -> Self {
+        let choices = choices.iter().map(|c| c.to_string()).collect();
+        self.with_field(name, ConfigKind::Choice(choices), ConfigValue::Text(default.to_string()))
+    }
+
+    pub fn field(&self, name: &str) -> Option<&ConfigField> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    /// Every field at its default.
+    pub fn defaults(&self) -> Config {
+        Config(self.fields.iter().map(|f| (f.name.clone(), f.default.clone())).collect())
+    }
+
+    /// `config` with the fields it leaves out at their defaults, checking
+    /// it has no others and that its values are allowed.
+    pub fn complete(&self, config: Config) -> Result<Config> {
+        let mut complete = self.defaults();
+        for (name, value) in config.0 {
+            let Some(field) = self.field(&name) else {
+                bail!("no config field {name}");
+            };
+            if !field.allows(&value) {
+                bail!("{value:?} isn't allowed for config field {name}");
+            }
+            complete.0.insert(name, value);
+        }
+        Ok(complete)
+    }
+
+    /// Decodes a `Config` for this schema and `complete`s it.
+    pub fn read(&self, bytes: &[u8]) -> Result<Config> {
+        self.complete(Config::from_bytes(bytes)?)
+    }
+}
+
+/// Values by field name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Config(pub IndexMap<String, ConfigValue>);
+
+impl Config {
+    pub fn with(mut self, name: &str, value: ConfigValue) -> Self {
+        self.0.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    fn value(&self, name: &str) -> Result<&ConfigValue> {
+        self.0.get(name).ok_or_else(|| anyhow!("no config field {name}"))
+    }
+
+    pub fn bool(&self, name: &str) -> Result<bool> {
+        match self.value(name)? {
+            ConfigValue::Bool(v) => Ok(*v),
+            v => bail!("config field {name} is {v:?}, not a bool"),
+        }
+    }
+
+    pub fn int(&self, name: &str) -> Result<i64> {
+        match self.value(name)? {
+            ConfigValue::Int(v) => Ok(*v),
+            v => bail!("config field {name} is {v:?}, not an int"),
+        }
+    }
+
+    pub fn float(&self, name: &str) -> Result<f64> {
+        match self.value(name)? {
+            ConfigValue::Float(v) => Ok(*v),
+            v => bail!("config field {name} is {v:?}, not a float"),
+        }
+    }
+
+    pub fn text(&self, name: &str) -> Result<&str> {
+        match self.value(name)? {
+            ConfigValue::Text(v) => Ok(v),
+            v => bail!("config field {name} is {v:?}, not text"),
+        }
+    }
+}
+
+/// Starts a store with a config in front, where a `StoreHeader` would
+/// start with `STORE_MAGIC`.
+const CONFIG_MAGIC: &[u8; 4] = b"CNFG";
+
+/// `store` with `config` in front, for `Component::step` to apply and keep
+/// in front of the stores it saves.
+pub fn with_config_prefix(config: &[u8], store: &[u8]) -> Vec<u8> {
+    let mut bytes = CONFIG_MAGIC.to_vec();
+    bytes.extend((config.len() as u32).to_le_bytes());
+    bytes.extend(config);
+    bytes.extend(store);
+    bytes
+}
+
+/// The config in front of `store`, if any, and the rest of it.
+pub fn split_config_prefix(store: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    let Some(rest) = store.strip_prefix(CONFIG_MAGIC) else {
+        return (None, store);
+    };
+    let Some((len, rest)) = rest.split_first_chunk::<4>() else {
+        return (None, store);
+    };
+    let len = u32::from_le_bytes(*len) as usize;
+    if len > rest.len() {
+        return (None, store);
+    }
+    let (config, rest) = rest.split_at(len);
+    (Some(config), rest)
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Bot {
+        aggression: i64,
+        explore_radius: f64,
+        loot: String,
+    }
+
+    fn schema() -> ConfigSchema {
+        ConfigSchema::default()
+            .with_int("aggression", 0, 10, 5)
+            .with_float("explore_radius", 1.0, 50.0, 20.0)
+            .with_choice("loot", &["gold", "gems", "anything"], "anything")
+    }
+
+    impl Configurable for Bot {
+        fn config_schema() -> Vec<u8> {
+            schema().to_bytes()
+        }
+
+        fn apply_config(&mut self, bytes: &[u8]) -> Result<()> {
+            let config = schema().read(bytes)?;
+            self.aggression = config.int("aggression")?;
+            self.explore_radius = config.float("explore_radius")?;
+            self.loot = config.text("loot")?.to_string();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn schema_round_trips() {
+        assert_eq!(ConfigSchema::from_bytes(&Bot::config_schema()).unwrap(), schema());
+    }
+
+    #[test]
+    fn applied_config_round_trips() {
+        let mut bot = Bot::default();
+        let config = Config::default().with("aggression", ConfigValue::Int(9));
+        bot.apply_config(&config.to_bytes()).unwrap();
+        assert_eq!((bot.aggression, bot.explore_radius, bot.loot.as_str()), (9, 20.0, "anything"));
+
+        let too_aggressive = Config::default().with("aggression", ConfigValue::Int(11));
+        assert!(bot.apply_config(&too_aggressive.to_bytes()).is_err());
+        assert_eq!(bot.aggression, 9);
+    }
+
+    #[test]
+    fn configs_are_checked_against_the_schema() {
+        let schema = schema();
+        let config = Config::default().with("loot", ConfigValue::Text("gems".into()));
+        let complete = schema.complete(config).unwrap();
+        assert_eq!(complete.text("loot").unwrap(), "gems");
+        assert_eq!(complete.int("aggression").unwrap(), 5);
+        assert!(complete.float("aggression").is_err());
+
+        for (name, value) in [
+            ("loot", ConfigValue::Text("rocks".into())),
+            ("explore_radius", ConfigValue::Float(0.5)),
+            ("explore_radius", ConfigValue::Int(10)),
+            ("speed", ConfigValue::Int(1)),
+        ] {
+            assert!(schema.complete(Config::default().with(name, value)).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn config_prefix_splits_off() {
+        let store = [1, 2, 3];
+        let prefixed = with_config_prefix(&[7, 8], &store);
+        assert_eq!(split_config_prefix(&prefixed), (Some(&[7, 8][..]), &store[..]));
+        assert_eq!(split_config_prefix(&store), (None, &store[..]));
+        let truncated = &prefixed[..7];
+        assert_eq!(split_config_prefix(truncated), (None, truncated));
+    }
+}
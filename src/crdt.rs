@@ -1,21 +1,537 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::marker::PhantomData;
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{Loc, LocMap, LocSet, LocSetIter};
+use crate::{Loc, LocMap, LocSet, LocSetIter, Rect};
 
 pub use client_utils_derive::CrdtContainer;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 pub trait Crdt {
     fn merge(&mut self, _other: &Self) -> Result<()> {
         Ok(())
     }
     fn cleanup(&mut self, _now: i64) {}
+
+    /// Forgets the tombstones removed before `horizon`, a turn every
+    /// replica is known to have caught up to, e.g. `HorizonTracker::min`.
+    /// Unlike the fixed horizons of `cleanup`, that can't bring removed
+    /// entries back. Types without tombstones keep everything.
+    fn compact(&mut self, _horizon: i64) {}
+
+    /// The `HorizonTracker` that `Component::step` keeps up to date and
+    /// compacts by, if the state has one.
+    fn horizon_tracker(&mut self) -> Option<&mut HorizonTracker> {
+        None
+    }
+
+    /// Like `merge`, also saying whether `self` changed. Types that can't
+    /// tell report that it did.
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        self.merge(other)?;
+        Ok(true)
+    }
+
+    /// Grows with every change. Types that don't track changes stay at 0.
+    fn version(&self) -> u64 {
+        0
+    }
+
+    /// A smaller copy holding everything that changed after `version`, for
+    /// merging into replicas that already have the rest. `None` means the
+    /// type can't tell and the whole state has to be sent.
+    fn delta_since(&self, _version: u64) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Changes whenever the state does, so an unchanged broadcast can be
+    /// recognised without deserializing it. Defaults to hashing the
+    /// serialized form, which is the same in every build.
+    fn digest(&self) -> u64
+    where
+        Self: Serialize,
+    {
+        digest_of(self)
+    }
+
+    /// Deltas are ordinary states, so applying one is just a merge. Says
+    /// whether `self` changed, like `merge_changed`.
+    fn apply_delta(&mut self, delta: &Self) -> Result<bool> {
+        self.merge_changed(delta)
+    }
+
+    /// Merges at most `max_entries` of `other`'s entries, starting where
+    /// `progress` left off, so a big merge can be spread over several turns.
+    /// Each step merges part of `other`, which a later full merge completes.
+    /// Types without entries to count merge in one step.
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        if progress.done > 0 {
+            return Ok(MergeProgress::through(progress.done, 0, 1));
+        }
+        if max_entries > 0 {
+            self.merge(other)?;
+        }
+        Ok(MergeProgress::through(0, max_entries, 1))
+    }
+
+    /// As many fields as fit in `max_bytes`, each framed by `write_field`,
+    /// so `merge_partial` can merge a payload that's missing some.
+    /// `#[derive(CrdtContainer)]` sends the fields in order of their
+    /// `#[crdt(priority = n)]`, highest first. Other types send their whole
+    /// state as field 0, or nothing if it doesn't fit.
+    fn serialize_within(&self, max_bytes: usize) -> Vec<u8>
+    where
+        Self: Serialize,
+    {
+        let mut out = Vec::new();
+        write_field(&mut out, 0, self, max_bytes);
+        out
+    }
+
+    /// Merges whichever fields `serialize_within` fit in, skipping ones
+    /// that are cut off or don't decode. Says whether `self` changed.
+    fn merge_partial(&mut self, bytes: &[u8]) -> bool
+    where
+        Self: DeserializeOwned,
+    {
+        let mut changed = false;
+        for (index, field) in read_fields(bytes) {
+            if index == 0 {
+                changed |= merge_field(self, field);
+            }
+        }
+        changed
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Bytes framing each field in `Crdt::serialize_within`: its index, then
+/// its length.
+const FIELD_HEADER_BYTES: usize = 6;
+
+/// Appends `field` to `out` with its index and length, unless that would
+/// take `out` past `max_bytes`. Says whether it fit.
+pub fn write_field<T: Serialize + ?Sized>(out: &mut Vec<u8>, index: u16, field: &T, max_bytes: usize) -> bool {
+    let Ok(bytes) = bincode::serialize(field) else {
+        return false;
+    };
+    if out.len() + FIELD_HEADER_BYTES + bytes.len() > max_bytes {
+        return false;
+    }
+    out.extend(index.to_le_bytes());
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+    true
+}
+
+/// The fields `write_field` framed, as `(index, bytes)`, stopping at the
+/// first one that's cut off.
+pub fn read_fields(mut bytes: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let index = u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?);
+        let len = u32::from_le_bytes(bytes.get(2..FIELD_HEADER_BYTES)?.try_into().ok()?) as usize;
+        let field = bytes.get(FIELD_HEADER_BYTES..FIELD_HEADER_BYTES + len)?;
+        bytes = &bytes[FIELD_HEADER_BYTES + len..];
+        Some((index, field))
+    })
+}
+
+/// Merges a field read by `read_fields` into `field`, saying whether that
+/// changed it. Bytes that don't decode change nothing.
+pub fn merge_field<T: Crdt + DeserializeOwned>(field: &mut T, bytes: &[u8]) -> bool {
+    bincode::deserialize::<T>(bytes).is_ok_and(|other| field.merge_changed(&other).unwrap_or(false))
+}
+
+/// Like `merge_field`, for fields merged by a function rather than as a
+/// `Crdt`. Those can't tell whether they changed, so any that decode and
+/// merge did.
+pub fn merge_field_with<T: DeserializeOwned>(field: &mut T, bytes: &[u8], merge: impl FnOnce(&mut T, &T) -> Result<()>) -> bool {
+    bincode::deserialize::<T>(bytes).is_ok_and(|other| merge(field, &other).is_ok())
+}
+
+#[cfg(test)]
+mod partial_tests {
+    use super::*;
+
+    /// Sends `seen` before `claims`.
+    #[derive(Clone, Default, Serialize, Deserialize, CrdtContainer)]
+    #[crdt(crate = "crate")]
+    struct Broadcast {
+        #[crdt]
+        claims: CrdtMap<u32, u32, Lww>,
+        #[crdt(priority = 1)]
+        seen: GrowOnlySet<u32>,
+    }
+
+    #[test]
+    fn frames_survive_truncation() {
+        let frames = [(1u16, &b"ab"[..]), (4, b""), (2, b"xyz")];
+        let mut bytes = Vec::new();
+        for (index, field) in frames {
+            bytes.extend(index.to_le_bytes());
+            bytes.extend((field.len() as u32).to_le_bytes());
+            bytes.extend(field);
+        }
+        assert_eq!(read_fields(&bytes).collect::<Vec<_>>(), frames);
+        assert_eq!(read_fields(&bytes[..bytes.len() - 1]).count(), 2);
+        assert_eq!(read_fields(&bytes[..9]).count(), 1);
+        assert_eq!(read_fields(&[0xff; 7]).count(), 0);
+    }
+
+    #[test]
+    fn partial_payloads_only_merge_what_fit() {
+        let mut sender = Broadcast::default();
+        sender.seen.insert(7);
+        for k in 0..50 {
+            sender.claims.insert(k, k, 1);
+        }
+        let full = sender.serialize_within(usize::MAX);
+        let seen_only = sender.serialize_within(full.len() - 1);
+        assert!(seen_only.len() < full.len() / 2);
+
+        let mut receiver = Broadcast::default();
+        assert!(receiver.merge_partial(&seen_only));
+        assert!(receiver.seen.contains(&7) && receiver.claims.is_empty());
+        for len in 0..full.len() {
+            receiver.clone().merge_partial(&full[..len]);
+        }
+        assert!(receiver.merge_partial(&full));
+        assert_eq!(receiver.claims.len(), 50);
+        assert!(!receiver.merge_partial(&full));
+    }
+}
+
+/// A saved copy of a state to go back to, so changes made by code that then
+/// panics can be undone before anything is saved or broadcast.
+/// `#[derive(CrdtContainer)]` snapshots every `#[crdt]` field.
+pub trait CrdtSnapshot {
+    type Snap;
+    fn snapshot(&self) -> Self::Snap;
+    fn restore(&mut self, snap: Self::Snap);
+}
+
+/// Snapshots the types in `crdt` by cloning them.
+macro_rules! snapshot_by_clone {
+    ($([$($generics:tt)*] $ty:ty;)*) => {
+        $(
+            impl<$($generics)*> CrdtSnapshot for $ty {
+                type Snap = Self;
+
+                fn snapshot(&self) -> Self {
+                    self.clone()
+                }
+
+                fn restore(&mut self, snap: Self) {
+                    *self = snap;
+                }
+            }
+        )*
+    };
+}
+
+snapshot_by_clone! {
+    [T: Clone] ExpiringFWWRegister<T>;
+    [T: Clone] ExpiringLWWRegister<T>;
+    [K: Ord + Clone, A: Ord + Clone] ClaimMap<K, A>;
+    [T: Ord + Clone] GrowOnlySet<T>;
+    [T: Ord + Clone] TwoPSet<T>;
+    [T: Ord + Clone] ExpiringSet<T>;
+    [T: Ord + Clone] SizedFWWExpiringSet<T>;
+    [T: Ord + Clone] HorizonSet<T>;
+    [] GCounter;
+    [] PnCounter;
+    [] WindowedCounter;
+    [] VClock;
+    [T: Clone] Versioned<T>;
+    [] HorizonTracker;
+    [T: Ord + Clone] OrSet<T>;
+    [] Dots;
+    [] EwFlag;
+    [] DwFlag;
+    [T: Clone] GrowOnlyLog<T>;
+    [T: Clone] MvRegister<T>;
+    [T: Clone] MaxRegister<T>;
+    [T: Clone] MinRegister<T>;
+    [T: Clone] ExpiringMaxRegister<T>;
+    [T: Clone] ExpiringMinRegister<T>;
+    [T: Clone, P: Clone] CrdtValue<T, P>;
+    [K: Ord + Clone, V: Clone, P] CrdtMap<K, V, P>;
+    [K: Ord + Clone, V: Clone, P] ExpiringCrdtMap<K, V, P>;
+    [K: Ord + Clone, V: Clone, P] BoundedCrdtMap<K, V, P>;
+    [V: Clone, P] ChunkedLocCrdtMap<V, P>;
+    [T: Clone] Option<T>;
+    [K: Ord + Clone, V: Clone] BTreeMap<K, V>;
+    [K: Eq + std::hash::Hash + Clone, V: Clone] HashMap<K, V>;
+    [T: Clone, const N: usize] [T; N];
+    [T: Clone] Vec<T>;
+}
+
+impl<T: CrdtSnapshot> CrdtSnapshot for Box<T> {
+    type Snap = T::Snap;
+
+    fn snapshot(&self) -> T::Snap {
+        (**self).snapshot()
+    }
+
+    fn restore(&mut self, snap: T::Snap) {
+        (**self).restore(snap);
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[test]
+    fn restoring_undoes_changes_since_the_snapshot() {
+        let mut map: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        map.insert(1, 1, 0);
+        map.insert(2, 2, 0);
+        let version = map.version();
+        let snap = map.snapshot();
+        map.insert(1, 10, 1);
+        map.remove(2, 1);
+        map.insert(3, 3, 1);
+        map.restore(snap);
+        assert_eq!(map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), [(1, 1), (2, 2)]);
+        assert!(map.tombstones.is_empty());
+        assert_eq!(map.version(), version);
+
+        let mut boxed = Box::new(GrowOnlySet::default());
+        let snap = boxed.snapshot();
+        boxed.insert(1);
+        boxed.restore(snap);
+        assert!(boxed.is_empty());
+    }
+}
+
+/// How far through the other side's entries `Crdt::merge_bounded` got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeProgress {
+    pub done: usize,
+    pub finished: bool,
+}
+
+impl MergeProgress {
+    /// After merging up to `max_entries` of `total` entries, starting at
+    /// `start`.
+    pub fn through(start: usize, max_entries: usize, total: usize) -> Self {
+        let done = start.saturating_add(max_entries).min(total);
+        Self {
+            done,
+            finished: done >= total,
+        }
+    }
+}
+
+/// A field a derived `merge_report` found changed, outermost first, so
+/// `squad.target.reg` is `["squad", "target", "reg"]`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FieldPath(pub Vec<&'static str>);
+
+impl FieldPath {
+    /// This path as one inside the field `outer`.
+    pub fn within(mut self, outer: &'static str) -> Self {
+        self.0.insert(0, outer);
+        self
+    }
+}
+
+impl std::fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join("."))
+    }
+}
+
+impl PartialEq<&str> for FieldPath {
+    fn eq(&self, other: &&str) -> bool {
+        self.0.iter().copied().eq(other.split('.'))
+    }
+}
+
+/// Why a derived `merge_report` stopped: merging `field` failed with
+/// `source`. Fields before it have been merged already.
+#[derive(Debug)]
+pub struct FieldMergeError {
+    pub field: FieldPath,
+    pub source: anyhow::Error,
+}
+
+impl FieldMergeError {
+    pub fn new(field: &'static str, source: anyhow::Error) -> Self {
+        Self {
+            field: FieldPath(vec![field]),
+            source,
+        }
+    }
+
+    /// This error as one inside the field `outer`.
+    pub fn within(self, outer: &'static str) -> Self {
+        Self {
+            field: self.field.within(outer),
+            source: self.source,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldMergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "merging {}", self.field)
+    }
+}
+
+impl std::error::Error for FieldMergeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod field_path_tests {
+    use super::*;
+
+    #[test]
+    fn nested_failures_name_the_whole_path() {
+        let error = FieldMergeError::new("reg", anyhow::anyhow!("bad")).within("target").within("squad");
+        assert_eq!(error.field, FieldPath(vec!["squad", "target", "reg"]));
+        assert_eq!(error.field, "squad.target.reg");
+        assert_eq!(error.to_string(), "merging squad.target.reg");
+        assert_eq!(std::error::Error::source(&error).unwrap().to_string(), "bad");
+    }
+}
+
+/// What a merge did to one key, as reported by `merge_with_events`. Sets
+/// report `()` values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MergeEvent<K, V> {
+    Inserted { key: K, value: V },
+    Updated { key: K, old: V, new: V },
+    /// Removed by a tombstone from the other side.
+    Removed { key: K, old: V },
+    ExpiryExtended { key: K, expires: i64 },
+}
+
+thread_local! {
+    static VERSION_CLOCK: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Makes every version handed out from now on larger than `floor`.
+/// `Component::step` does this with `turn_version` each turn, so versions
+/// of different types can be compared and carry across process restarts.
+pub fn advance_version_clock(floor: u64) {
+    VERSION_CLOCK.with(|clock| clock.set(clock.get().max(floor)));
+}
+
+/// The smallest version that can be handed out during `turn`.
+pub fn turn_version(turn: i64) -> u64 {
+    (turn.max(0) as u64) << 20
+}
+
+thread_local! {
+    static FOR_ALLIES: Cell<bool> = const { Cell::new(false) };
+    static READING_BARE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Puts a flag back as it was, even if what ran with it set panicked.
+struct ResetFlag(&'static std::thread::LocalKey<Cell<bool>>, bool);
+
+impl Drop for ResetFlag {
+    fn drop(&mut self) {
+        self.0.with(|flag| flag.set(self.1));
+    }
+}
+
+fn with_flag<T>(flag: &'static std::thread::LocalKey<Cell<bool>>, f: impl FnOnce() -> T) -> T {
+    let _reset = ResetFlag(flag, flag.with(|flag| flag.replace(true)));
+    f()
+}
+
+/// Runs `f` serializing for allies rather than the store: `Versions` leave
+/// out their stamps, which only mean anything to the replica that made
+/// them.
+pub fn encoding_for_allies<T>(f: impl FnOnce() -> T) -> T {
+    with_flag(&FOR_ALLIES, f)
+}
+
+/// Runs `f` deserializing what clients wrote before the CRDTs here kept
+/// versions, tombstones and writers, i.e. headerless stores and bare
+/// broadcasts. The fields added since are read as their defaults.
+pub fn decoding_bare<T>(f: impl FnOnce() -> T) -> T {
+    with_flag(&READING_BARE, f)
+}
+
+/// Reads a field clients didn't write before it was added, see
+/// `decoding_bare`.
+fn added_field<'de, D: Deserializer<'de>, T: Deserialize<'de> + Default>(deserializer: D) -> Result<T, D::Error> {
+    if READING_BARE.with(Cell::get) {
+        Ok(T::default())
+    } else {
+        T::deserialize(deserializer)
+    }
+}
+
+fn serialize_stamps<K: Ord + Serialize, S: Serializer>(stamps: &BTreeMap<K, u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    if FOR_ALLIES.with(Cell::get) {
+        BTreeMap::<K, u64>::new().serialize(serializer)
+    } else {
+        stamps.serialize(serializer)
+    }
+}
+
+/// When each key of a CRDT last changed, see `Crdt::delta_since`. Changes
+/// made by writing to a CRDT's fields directly aren't tracked. The stamps
+/// are kept in the store but not sent to allies, see `encoding_for_allies`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versions<K: Ord> {
+    pub version: u64,
+    #[serde(serialize_with = "serialize_stamps", bound(serialize = "K: Serialize"))]
+    pub stamps: BTreeMap<K, u64>,
+}
+
+impl<K: Ord> Default for Versions<K> {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            stamps: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord> Versions<K> {
+    pub fn touch(&mut self, k: K) {
+        self.version = VERSION_CLOCK.with(|clock| {
+            let version = clock.get().max(self.version) + 1;
+            clock.set(version);
+            version
+        });
+        self.stamps.insert(k, self.version);
+    }
+
+    pub fn changed_since(&self, version: u64) -> bool {
+        self.version > version
+    }
+
+    pub fn is_newer(&self, k: &K, version: u64) -> bool {
+        self.stamps.get(k).is_some_and(|stamp| *stamp > version)
+    }
+
+    /// Stops tracking keys that are gone, leaving `version` alone.
+    pub fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        self.stamps.retain(|k, _| keep(k));
+    }
+}
+
+/// The first write wins, with ties going to the smaller value. Copies of
+/// the same write keep the latest expiry.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ExpiringFWWRegister<T> {
     pub value: Option<T>,
     pub written: i64,
@@ -37,31 +553,61 @@ impl<T: PartialOrd + PartialEq> ExpiringFWWRegister<T> {
         self.value.as_ref()
     }
 
-    pub fn set(&mut self, value: T, now: i64, expires: i64) {
+    pub fn expires_at(&self) -> Option<i64> {
+        self.value.as_ref().map(|_| self.expires)
+    }
+
+    /// When the current value was first written.
+    pub fn written_at(&self) -> Option<i64> {
+        self.value.as_ref().map(|_| self.written)
+    }
+
+    /// Says whether `value` is the current value afterwards, e.g. whether a
+    /// claim was won or is still held.
+    pub fn set(&mut self, value: T, now: i64, expires: i64) -> bool {
         if Some(&value) == self.value.as_ref() {
             self.written = self.written.min(now);
             self.expires = self.expires.max(expires);
+            true
         } else if self.value.is_none() || now < self.written || (now == self.written && self.value.is_some() && &value < self.value.as_ref().unwrap()) {
             self.value = Some(value);
             self.written = now;
             self.expires = expires;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Pushes the expiry of whatever value is held back to `new_expires`,
+    /// without changing who holds it. Does nothing when empty.
+    pub fn refresh(&mut self, new_expires: i64) {
+        if self.value.is_some() {
+            self.expires = self.expires.max(new_expires);
         }
     }
 }
 
 impl<T: Clone + PartialEq + PartialOrd> Crdt for ExpiringFWWRegister<T> {
     fn merge(&mut self, other: &Self) -> Result<()> {
-        if other.value.is_some() {
-            if other.written < self.written || (other.written == self.written && other.value < self.value) {
-                self.value = other.value.clone();
-                self.written = other.written;
-                self.expires = other.expires;
-            } else if self.value == other.value {
-                self.written = self.written.min(other.written);
-                self.expires = self.expires.max(other.expires);
-            }
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        if other.value.is_none() {
+            return Ok(false);
+        }
+        if self.value.is_none() || (other.written, &other.value) < (self.written, &self.value) {
+            self.value = other.value.clone();
+            self.written = other.written;
+            self.expires = other.expires;
+            Ok(true)
+        } else if (other.written, &other.value) == (self.written, &self.value) && other.expires > self.expires {
+            self.expires = other.expires;
+            Ok(true)
+        } else {
+            Ok(false)
         }
-        Ok(())
     }
 
     fn cleanup(&mut self, now: i64) {
@@ -112,361 +658,4875 @@ mod expiring_register_tests {
         let mut b = ExpiringFWWRegister::default();
         b.set("b".to_string(), 1, 3);
         b.cleanup(1);
+        let mut refreshed = a.clone();
+        refreshed.set("a".to_string(), 2, 6);
+        let mut later = ExpiringFWWRegister::default();
+        later.set("a".to_string(), 2, 8);
 
         let mut na = a.clone();
-        let mut nb = b.clone();
-
         na.merge(&b).unwrap();
         na.cleanup(2);
         assert_eq!(na.get().unwrap(), "a");
+        na.merge(&later).unwrap();
+        assert_eq!(na.expires_at(), Some(3));
 
-        nb.merge(&a).unwrap();
-        nb.cleanup(2);
-        assert_eq!(na.value, nb.value);
-        assert_eq!(na.written, nb.written);
-        assert_eq!(na.expires, nb.expires);
+        testing::assert_crdt_laws(vec![a, b, refreshed, later, ExpiringFWWRegister::default()]);
     }
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct GrowOnlySet<T: Ord>(pub BTreeSet<T>);
 
-impl <T: Ord> Default for GrowOnlySet<T> {
-    fn default() -> Self {
-        GrowOnlySet(BTreeSet::new())
+    #[test]
+    fn replicas_converge() {
+        testing::assert_converges(
+            ExpiringFWWRegister::default(),
+            4,
+            50,
+            &[
+                &|r, _, now| {
+                    r.set(fastrand::u32(0..4), now - fastrand::i64(0..3), now + fastrand::i64(0..6));
+                },
+                &|r, _, now| r.refresh(now + fastrand::i64(0..6)),
+            ],
+            310,
+        );
     }
-}
 
-impl<T: Ord> GrowOnlySet<T> {
-    pub fn insert(&mut self, v: T) {
-        self.0.insert(v);
-    }
+    #[test]
+    fn claims_report_who_holds_them() {
+        let mut mine = ExpiringFWWRegister::default();
+        assert_eq!((mine.written_at(), mine.expires_at()), (None, None));
+        mine.refresh(10);
+        assert_eq!(mine.expires_at(), None);
 
-    pub fn contains(&mut self, v: &T) -> bool {
-        self.0.contains(v)
+        assert!(mine.set("me", 4, 6));
+        assert!(mine.set("me", 5, 7));
+        assert_eq!((mine.written_at(), mine.expires_at()), (Some(4), Some(7)));
+
+        let mut peer = ExpiringFWWRegister::default();
+        peer.set("peer", 2, 6);
+        mine.merge(&peer).unwrap();
+        assert!(!mine.set("me", 5, 9));
+        mine.refresh(9);
+        assert_eq!(mine.get(), Some(&"peer"));
+        assert_eq!((mine.written_at(), mine.expires_at()), (Some(2), Some(9)));
+        mine.refresh(3);
+        assert_eq!(mine.expires_at(), Some(9));
+
+        mine.cleanup(9);
+        assert!(mine.set("me", 9, 12));
     }
 
-    pub fn len(&self) -> usize {
-        self.0.len()
+    #[test]
+    fn merge_reports_changes() {
+        let mut a = ExpiringFWWRegister::default();
+        a.set("a".to_string(), 0, 3);
+        let mut b = ExpiringFWWRegister::default();
+        b.set("b".to_string(), 1, 3);
+        assert!(!a.merge_changed(&b).unwrap());
+        b.set("a".to_string(), 0, 5);
+        assert!(a.merge_changed(&b).unwrap());
+        assert!(!a.merge_changed(&b).unwrap());
+        assert!(b.merge_changed(&ExpiringFWWRegister::default()).is_ok_and(|changed| !changed));
     }
 }
 
-impl<T: Ord + Clone> Crdt for GrowOnlySet<T> {
-    fn merge(&mut self, other: &Self) -> Result<()> {
-        self.0.extend(other.0.iter().cloned());
-        Ok(())
-    }
+/// What `ClaimMap::claim` found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimResult {
+    Won,
+    AlreadyOurs,
+    HeldByOther,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ExpiringSet<T: Ord>(pub BTreeMap<T, i64>);
+/// One key's claim in a `ClaimMap`. `holder` is `None` once released.
+/// Releasing starts a new `epoch`, which beats every claim from before it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claim<A> {
+    pub holder: Option<A>,
+    pub epoch: u64,
+    pub written: i64,
+    pub expires: i64,
+}
 
-impl <T: Ord> Default for ExpiringSet<T> {
-    fn default() -> Self {
-        ExpiringSet(BTreeMap::new())
+impl<A: Ord> Claim<A> {
+    /// Whether merging should take `other` over `self`: a later epoch, then
+    /// a claim over a release, then the earlier claim with ties going to
+    /// the smaller actor, as `ExpiringFWWRegister` does.
+    fn beaten_by(&self, other: &Self) -> bool {
+        let rank = |c: &Self| (c.epoch, c.holder.is_some());
+        match rank(self).cmp(&rank(other)) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Greater => false,
+            std::cmp::Ordering::Equal => (&other.written, &other.holder) < (&self.written, &self.holder),
+        }
     }
 }
 
-impl<T: Ord> ExpiringSet<T> {
-    pub fn insert(&mut self, v: T, expires: i64) {
-        self.0.insert(v, expires);
-    }
+/// A first-write-wins claim per key, e.g. for dividing tasks between allies
+/// without a register per task. Claims lapse at their expiry, when
+/// `cleanup` drops them. Releasing a claim elsewhere only sticks once the
+/// release has been merged everywhere; until then a replica that hasn't
+/// heard of it still sees the old holder.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClaimMap<K: Ord, A: Ord>(pub BTreeMap<K, Claim<A>>);
 
-    pub fn contains(&mut self, v: &T) -> bool {
-        self.0.contains_key(v)
+impl<K: Ord, A: Ord> Default for ClaimMap<K, A> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
     }
 }
 
-impl<T: Ord + Clone> Crdt for ExpiringSet<T> {
-    fn merge(&mut self, other: &Self) -> Result<()> {
-        for (v, e) in &other.0 {
-            if let Some(expires) = self.0.get_mut(v) {
-                if e > expires {
-                    *expires = *e;
-                }
-            } else {
-                self.0.insert(v.clone(), *e);
+impl<K: Ord, A: Ord> ClaimMap<K, A> {
+    /// Claims `key` for `actor` until `expires`, or pushes the expiry back
+    /// if it holds it already.
+    pub fn claim(&mut self, key: K, actor: A, now: i64, expires: i64) -> ClaimResult {
+        let Some(claim) = self.0.get_mut(&key) else {
+            self.0.insert(key, Claim { holder: Some(actor), epoch: 0, written: now, expires });
+            return ClaimResult::Won;
+        };
+        match &claim.holder {
+            Some(holder) if *holder == actor => {
+                claim.expires = claim.expires.max(expires);
+                ClaimResult::AlreadyOurs
+            }
+            Some(holder) if (claim.written, holder) <= (now, &actor) => ClaimResult::HeldByOther,
+            _ => {
+                *claim = Claim { holder: Some(actor), epoch: claim.epoch, written: now, expires };
+                ClaimResult::Won
             }
         }
-        Ok(())
     }
 
-    fn cleanup(&mut self, now: i64) {
-        self.0.retain(|_, expires| *expires < now);
-    }
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct SizedFWWExpiringSet<T: Ord>(pub BTreeMap<T, (i64, i64)>, pub usize);
-
-impl<T: Ord> SizedFWWExpiringSet<T> {
-    pub fn new(size: usize) -> Self {
-        Self(BTreeMap::new(), size)
+    /// Gives `key` up, if `actor` holds it.
+    pub fn release(&mut self, key: &K, actor: &A, now: i64) {
+        if let Some(claim) = self.0.get_mut(key)
+            && claim.holder.as_ref() == Some(actor)
+        {
+            claim.holder = None;
+            claim.epoch += 1;
+            claim.written = now;
+        }
     }
 
-    pub fn insert(&mut self, v: T, now: i64, expires: i64) {
-        if let Some((_, e)) = self.0.get_mut(&v) {
-            *e = expires;
-        } else if self.0.len() < self.1 {
-            self.0.insert(v, (now, expires));
-        }
+    pub fn holder(&self, key: &K) -> Option<&A> {
+        self.0.get(key).and_then(|claim| claim.holder.as_ref())
     }
 
-    pub fn contains<Q>(&mut self, v: &Q) -> bool
-    where
-        T: std::borrow::Borrow<Q>,
-        Q: Ord + ?Sized,
-        {
-        self.0.contains_key(v)
+    pub fn claims_of<'a>(&'a self, actor: &'a A) -> impl Iterator<Item = &'a K> + 'a {
+        self.0.iter().filter(move |(_, claim)| claim.holder.as_ref() == Some(actor)).map(|(k, _)| k)
     }
 }
 
-impl<T: Ord + Clone> Crdt for SizedFWWExpiringSet<T> {
+impl<K: Ord + Clone, A: Ord + Clone> Crdt for ClaimMap<K, A> {
     fn merge(&mut self, other: &Self) -> Result<()> {
-        for (other_value, (other_written, other_expires)) in &other.0 {
-            if let Some((local_written, local_expires)) = self.0.get_mut(other_value) {
-                *local_written = (*other_written).min(*local_written);
-                *local_expires = (*other_expires).max(*local_expires);
-            } else if self.0.len() < self.1 {
-                self.0.insert(other_value.clone(), (*other_written, *other_expires));
-            } else {
-                let mut oldest = None;
-                let mut oldest_written = None;
-                for (local_value, (local_written, _)) in &self.0 {
-                    if local_written > other_written || (local_written == other_written && local_value > other_value) {
-                        if let Some(t) = oldest_written {
-                            if t < local_written {
-                                oldest_written = Some(t);
-                                oldest = Some(local_value.clone());
-                            }
-                        } else {
-                            oldest_written = Some(local_written);
-                            oldest = Some(local_value.clone());
-                        }
-                    }
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (key, theirs) in &other.0 {
+            match self.0.get_mut(key) {
+                None => {
+                    self.0.insert(key.clone(), theirs.clone());
+                    changed = true;
+                }
+                Some(mine) if mine.beaten_by(theirs) => {
+                    *mine = theirs.clone();
+                    changed = true;
                 }
-                if let Some(oldest) = oldest {
-                    self.0.remove(&oldest);
-                    self.0.insert(other_value.clone(), (*other_written, *other_expires));
+                Some(mine) if !theirs.beaten_by(mine) && theirs.expires > mine.expires => {
+                    mine.expires = theirs.expires;
+                    changed = true;
                 }
+                Some(_) => {}
             }
         }
-        Ok(())
+        Ok(changed)
     }
 
     fn cleanup(&mut self, now: i64) {
-        self.0.retain(|_, (_, expires)| *expires > now);
+        self.0.retain(|_, claim| claim.expires > now);
     }
 }
 
+#[cfg(test)]
+mod claim_map_tests {
+    use super::*;
+    use indexmap::IndexSet;
+
+    #[test]
+    fn claims_and_releases() {
+        let mut m = ClaimMap::default();
+        assert_eq!(m.claim("a", 1, 0, 10), ClaimResult::Won);
+        assert_eq!(m.claim("a", 1, 1, 12), ClaimResult::AlreadyOurs);
+        assert_eq!(m.claim("a", 2, 1, 12), ClaimResult::HeldByOther);
+        m.release(&"a", &2, 2);
+        assert_eq!(m.holder(&"a"), Some(&1));
+        m.release(&"a", &1, 2);
+        assert_eq!(m.holder(&"a"), None);
+        assert_eq!(m.claim("a", 2, 3, 8), ClaimResult::Won);
+        assert_eq!(m.claims_of(&2).collect::<Vec<_>>(), [&"a"]);
+
+        // The release beats copies of the old claim that haven't heard of it.
+        let mut stale = ClaimMap::default();
+        stale.claim("a", 1, 0, 12);
+        stale.merge(&m).unwrap();
+        assert_eq!(stale.holder(&"a"), Some(&2));
+
+        m.cleanup(8);
+        assert!(m.0.is_empty());
+    }
+
+    #[test]
+    fn laws_and_convergence() {
+        let mut a = ClaimMap::default();
+        a.claim(0, 1, 0, 10);
+        let mut b = a.clone();
+        b.claim(0, 1, 0, 12);
+        let mut c = a.clone();
+        c.release(&0, &1, 3);
+        let mut d = ClaimMap::default();
+        d.claim(0, 2, 0, 5);
+        d.claim(1, 2, 0, 5);
+        testing::assert_crdt_laws(vec![a, b, c, d]);
+        testing::assert_converges(
+            ClaimMap::default(),
+            3,
+            40,
+            &[
+                &|m, i, now| {
+                    m.claim(fastrand::u32(0..4), i, now, now + 5);
+                },
+                &|m, i, now| m.release(&fastrand::u32(0..4), &i, now),
+            ],
+            321,
+        );
+    }
+
+    #[test]
+    fn allies_split_a_frontier() {
+        let frontier: IndexSet<Loc> = (0..6).map(|x| Loc { x, y: 0 }).collect();
+        let mut replicas = vec![ClaimMap::default(); 3];
+        for now in 0..12 {
+            for (actor, m) in replicas.iter_mut().enumerate() {
+                if m.claims_of(&actor).next().is_none() {
+                    let free = frontier.iter().find(|loc| m.holder(*loc).is_none()).copied();
+                    if let Some(loc) = free {
+                        m.claim(loc, actor, now, 100);
+                    }
+                }
+            }
+            // Only neighbours hear each other, one direction per turn.
+            let from = now as usize % 3;
+            let other = replicas[from].clone();
+            replicas[(from + 1) % 3].merge(&other).unwrap();
+        }
+        let all = replicas.clone();
+        for m in &mut replicas {
+            for other in &all {
+                m.merge(other).unwrap();
+            }
+        }
+        assert!(replicas.iter().all(|m| *m == replicas[0]));
+        // Actors that lost a clash picked another loc, so each ends up with
+        // one of its own that the others can't take.
+        let claimed: Vec<Vec<Loc>> = (0..3).map(|actor| replicas[0].claims_of(&actor).copied().collect()).collect();
+        assert!(claimed.iter().all(|claims| claims.len() == 1), "{claimed:?}");
+        for (actor, m) in replicas.iter().enumerate() {
+            for (holder, claims) in claimed.iter().enumerate() {
+                let expected = if holder == actor { ClaimResult::AlreadyOurs } else { ClaimResult::HeldByOther };
+                assert_eq!(m.clone().claim(claims[0], actor, 12, 100), expected);
+            }
+        }
+    }
+}
+
+/// Like `ExpiringFWWRegister`, but the latest write wins, with ties going
+/// to the larger value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExpiringLWWRegister<T> {
+    pub value: Option<T>,
+    pub written: i64,
+    pub expires: i64,
+}
+
+impl <T> Default for ExpiringLWWRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            written: i64::MIN,
+            expires: i64::MIN,
+        }
+    }
+}
+
+impl<T: PartialOrd + PartialEq> ExpiringLWWRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn expires_at(&self) -> Option<i64> {
+        self.value.as_ref().map(|_| self.expires)
+    }
+
+    pub fn set(&mut self, value: T, now: i64, expires: i64) {
+        if Some(&value) == self.value.as_ref() {
+            self.written = self.written.max(now);
+            self.expires = self.expires.max(expires);
+        } else if self.value.is_none() || now > self.written || (now == self.written && Some(&value) > self.value.as_ref()) {
+            self.value = Some(value);
+            self.written = now;
+            self.expires = expires;
+        }
+    }
+}
+
+impl<T: Clone + PartialEq + PartialOrd> Crdt for ExpiringLWWRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if other.value.is_none() {
+            return Ok(());
+        }
+        if self.value.is_none() || (other.written, &other.value) > (self.written, &self.value) {
+            self.value = other.value.clone();
+            self.written = other.written;
+            self.expires = other.expires;
+        } else if (other.written, &other.value) == (self.written, &self.value) {
+            self.expires = self.expires.max(other.expires);
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        if now >= self.expires {
+            self.value = None;
+            self.written = i64::MIN;
+            self.expires = i64::MIN;
+        }
+    }
+}
+
+#[cfg(test)]
+mod expiring_lww_register_tests {
+    use super::*;
+
+    #[test]
+    fn basic_setting() {
+        let mut r = ExpiringLWWRegister::default();
+        assert!(r.get().is_none());
+
+        r.set("test".to_string(), 0, 3);
+        r.cleanup(0);
+        assert_eq!(r.get().unwrap(), "test");
+        assert_eq!(r.expires_at(), Some(3));
+
+        r.set("newer".to_string(), 1, 3);
+        r.cleanup(1);
+        assert_eq!(r.get().unwrap(), "newer");
+
+        r.set("older".to_string(), 0, 3);
+        assert_eq!(r.get().unwrap(), "newer");
+    }
+
+    #[test]
+    fn basic_expiry() {
+        let mut r = ExpiringLWWRegister::default();
+        r.set("test".to_string(), 0, 3);
+        r.cleanup(3);
+        assert!(r.get().is_none());
+        assert_eq!(r.expires_at(), None);
+
+        r.set("newer".to_string(), 5, 8);
+        r.cleanup(5);
+        assert_eq!(r.get().unwrap(), "newer");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = ExpiringLWWRegister::default();
+        a.set("a".to_string(), 0, 3);
+        let mut b = ExpiringLWWRegister::default();
+        b.set("b".to_string(), 1, 3);
+        let mut c = ExpiringLWWRegister::default();
+        c.set("c".to_string(), 1, 4);
+
+        let mut na = a.clone();
+        na.merge(&b).unwrap();
+        na.merge(&c).unwrap();
+        na.cleanup(2);
+        assert_eq!(na.get().unwrap(), "c");
+
+        let mut refreshed = a.clone();
+        refreshed.set("a".to_string(), 2, 5);
+        testing::assert_crdt_laws(vec![a, b, c, refreshed, ExpiringLWWRegister::default()]);
+    }
+
+    #[test]
+    fn replicas_converge() {
+        testing::assert_converges(
+            ExpiringLWWRegister::default(),
+            4,
+            50,
+            &[&|r, _, now| r.set(fastrand::u32(0..4), now - fastrand::i64(0..3), now + fastrand::i64(0..6))],
+            3100,
+        );
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GrowOnlySet<T: Ord>(pub BTreeSet<T>, #[serde(deserialize_with = "added_field")] pub Versions<T>);
+
+impl <T: Ord> Default for GrowOnlySet<T> {
+    fn default() -> Self {
+        GrowOnlySet(BTreeSet::new(), Versions::default())
+    }
+}
+
+impl<T: Ord + Clone> GrowOnlySet<T> {
+    pub fn insert(&mut self, v: T) {
+        if self.0.insert(v.clone()) {
+            self.1.touch(v);
+        }
+    }
+
+    /// Like `merge`, calling `on_event` with each value added.
+    pub fn merge_with_events(&mut self, other: &Self, on_event: &mut impl FnMut(MergeEvent<T, ()>)) -> Result<()> {
+        for v in &other.0 {
+            if !self.0.contains(v) {
+                self.insert(v.clone());
+                on_event(MergeEvent::Inserted { key: v.clone(), value: () });
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves every value from `v` onwards into a new set. Grow-only sets
+    /// get big, but a peer that still has the values merges them back, so
+    /// this only helps once every replica has split at the same point.
+    /// Otherwise bound what's kept with `SizedFWWExpiringSet` instead.
+    pub fn split_off(&mut self, v: &T) -> Self {
+        let stamps = self.1.stamps.split_off(v);
+        Self(
+            self.0.split_off(v),
+            Versions {
+                version: self.1.version,
+                stamps,
+            },
+        )
+    }
+}
+
+impl<T: Ord> GrowOnlySet<T> {
+
+    pub fn contains<Q>(&self, v: &Q) -> bool
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.0.contains(v)
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.0.is_subset(&other.0)
+    }
+
+    pub fn is_superset(&self, other: &Self) -> bool {
+        self.0.is_superset(&other.0)
+    }
+
+    /// Values in `self` that aren't in `other`.
+    pub fn difference<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = &'a T> + 'a {
+        self.0.difference(&other.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.0.iter()
+    }
+}
+
+impl<T: Ord + Clone> Extend<T> for GrowOnlySet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for v in iter {
+            self.insert(v);
+        }
+    }
+}
+
+impl<T: Ord + Clone> FromIterator<T> for GrowOnlySet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self::default();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<'a, T: Ord> IntoIterator for &'a GrowOnlySet<T> {
+    type Item = &'a T;
+    type IntoIter = std::collections::btree_set::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<T: Ord + Clone> Crdt for GrowOnlySet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        self.merge_with_events(other, &mut |_| changed = true)?;
+        Ok(changed)
+    }
+
+    fn version(&self) -> u64 {
+        self.1.version
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        let changed = self.0.iter().filter(|v| self.1.is_newer(v, version)).cloned().collect();
+        Some(GrowOnlySet(changed, Versions::default()))
+    }
+
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        for v in other.0.iter().skip(progress.done).take(max_entries) {
+            self.insert(v.clone());
+        }
+        Ok(MergeProgress::through(progress.done, max_entries, other.0.len()))
+    }
+}
+
+#[cfg(test)]
+mod grow_only_set_tests {
+    use super::*;
+
+    #[test]
+    fn merge_events_name_new_values() {
+        let mut a: GrowOnlySet<u32> = [1, 3].into_iter().collect();
+        let b: GrowOnlySet<u32> = [0, 1, 2].into_iter().collect();
+        let mut events = Vec::new();
+        a.merge_with_events(&b, &mut |e| events.push(e)).unwrap();
+        assert_eq!(
+            events,
+            [MergeEvent::Inserted { key: 0, value: () }, MergeEvent::Inserted { key: 2, value: () }]
+        );
+        a.merge_with_events(&b, &mut |_| panic!("nothing new")).unwrap();
+    }
+
+    #[test]
+    fn set_algebra_and_bulk_inserts() {
+        let small: GrowOnlySet<String> = ["a", "b"].map(String::from).into_iter().collect();
+        let mut large = small.clone();
+        large.extend(["c".to_string()]);
+        assert!(large.contains("c"));
+        assert!(small.is_subset(&large) && large.is_superset(&small));
+        assert!(!large.is_subset(&small));
+        assert_eq!(large.difference(&small).collect::<Vec<_>>(), ["c"]);
+        assert_eq!((&large).into_iter().count(), 3);
+    }
+
+    #[test]
+    fn bounded_merges_resume() {
+        let other: GrowOnlySet<i32> = (0..10).collect();
+        let mut set = GrowOnlySet::default();
+        let progress = set.merge_bounded(&other, MergeProgress::default(), 6).unwrap();
+        assert_eq!((progress.done, progress.finished, set.len()), (6, false, 6));
+        let progress = set.merge_bounded(&other, progress, 6).unwrap();
+        assert_eq!((progress.done, progress.finished), (10, true));
+        assert!(set.is_superset(&other));
+
+        let mut flag = EwFlag::default();
+        let mut enabled = EwFlag::default();
+        enabled.enable(1);
+        assert!(!flag.merge_bounded(&enabled, MergeProgress::default(), 0).unwrap().finished);
+        assert!(flag.merge_bounded(&enabled, MergeProgress::default(), 1).unwrap().finished);
+        assert!(flag.value());
+    }
+
+    #[test]
+    fn split_off_moves_later_values() {
+        let mut set: GrowOnlySet<i32> = (0..5).collect();
+        let version = set.version();
+        let later = set.split_off(&3);
+        assert_eq!(set.iter().copied().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(later.iter().copied().collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(later.version(), version);
+        assert_eq!(later.delta_since(0).unwrap().len(), 2);
+    }
+}
+
+/// A set where removal is permanent: a removed value stays removed even if
+/// it's inserted again, here or on any replica.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TwoPSet<T: Ord> {
+    pub adds: GrowOnlySet<T>,
+    pub removed: GrowOnlySet<T>,
+}
+
+impl<T: Ord> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: GrowOnlySet::default(),
+            removed: GrowOnlySet::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> TwoPSet<T> {
+    /// Ignored if `v` was ever removed.
+    pub fn insert(&mut self, v: T) {
+        if !self.removed.contains(&v) {
+            self.adds.insert(v);
+        }
+    }
+
+    /// Also applies to values that haven't been inserted yet.
+    pub fn remove(&mut self, v: T) {
+        self.removed.insert(v);
+    }
+}
+
+impl<T: Ord> TwoPSet<T> {
+    pub fn contains<Q>(&self, v: &Q) -> bool
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.adds.contains(v) && !self.removed.contains(v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.adds.difference(&self.removed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// How many tombstones are kept, which only ever grows.
+    pub fn removed_len(&self) -> usize {
+        self.removed.len()
+    }
+}
+
+impl<T: Ord + Clone> Crdt for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let added = self.adds.merge_changed(&other.adds)?;
+        Ok(self.removed.merge_changed(&other.removed)? || added)
+    }
+
+    fn version(&self) -> u64 {
+        self.adds.version().max(self.removed.version())
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        Some(Self {
+            adds: self.adds.delta_since(version)?,
+            removed: self.removed.delta_since(version)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod two_p_set_tests {
+    use super::*;
+
+    #[test]
+    fn removal_is_permanent() {
+        let mut set = TwoPSet::default();
+        set.insert(1);
+        set.remove(1);
+        set.insert(1);
+        assert!(!set.contains(&1));
+        set.remove(2);
+        set.insert(2);
+        set.insert(3);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [&3]);
+        assert_eq!((set.len(), set.removed_len()), (1, 2));
+    }
+
+    #[test]
+    fn concurrent_insert_and_remove_converge() {
+        let mut a = TwoPSet::default();
+        a.insert("node".to_string());
+        let mut b = TwoPSet::default();
+        b.remove("node".to_string());
+        b.insert("other".to_string());
+
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab.iter().collect::<Vec<_>>(), ba.iter().collect::<Vec<_>>());
+        assert!(!ab.contains("node") && ab.contains("other"));
+        assert!(!ab.merge_changed(&ba).unwrap());
+    }
+}
+
+/// Values with an expiry turn each. Like the other expiring types, a value
+/// is dropped by `cleanup` once `now` reaches its expiry.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpiringSet<T: Ord>(pub BTreeMap<T, i64>, #[serde(deserialize_with = "added_field")] pub Versions<T>);
+
+impl <T: Ord> Default for ExpiringSet<T> {
+    fn default() -> Self {
+        ExpiringSet(BTreeMap::new(), Versions::default())
+    }
+}
+
+impl<T: Ord + Clone> ExpiringSet<T> {
+    pub fn insert(&mut self, v: T, expires: i64) {
+        if self.0.insert(v.clone(), expires) != Some(expires) {
+            self.1.touch(v);
+        }
+    }
+
+    /// Drops `v` from this replica only. Nothing records the removal, so
+    /// merging a replica that still has `v` brings it back with that
+    /// replica's expiry. Says whether `v` was here.
+    pub fn remove(&mut self, v: &T) -> bool {
+        let removed = self.0.remove(v).is_some();
+        if removed {
+            self.1.touch(v.clone());
+            self.1.retain(|k| k != v);
+        }
+        removed
+    }
+
+    /// Like `merge`, calling `on_event` with each value added or kept for
+    /// longer.
+    pub fn merge_with_events(&mut self, other: &Self, on_event: &mut impl FnMut(MergeEvent<T, ()>)) -> Result<()> {
+        for (v, e) in &other.0 {
+            let event = match self.0.get(v) {
+                None => MergeEvent::Inserted { key: v.clone(), value: () },
+                Some(expires) if e > expires => MergeEvent::ExpiryExtended { key: v.clone(), expires: *e },
+                Some(_) => continue,
+            };
+            self.insert(v.clone(), *e);
+            on_event(event);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Ord> ExpiringSet<T> {
+    pub fn contains<Q>(&self, v: &Q) -> bool
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.0.contains_key(v)
+    }
+
+    /// The stored value along with when it expires.
+    pub fn get(&self, v: &T) -> Option<(&T, i64)> {
+        self.0.get_key_value(v).map(|(v, expires)| (v, *expires))
+    }
+
+    /// The turn `cleanup` drops `v` at.
+    pub fn expires_at<Q>(&self, v: &Q) -> Option<i64>
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.0.get(v).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&T, i64)> + '_ {
+        self.0.iter().map(|(v, expires)| (v, *expires))
+    }
+}
+
+impl<T: Ord + Clone> Crdt for ExpiringSet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        self.merge_with_events(other, &mut |_| changed = true)?;
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.retain(|_, expires| *expires > now);
+        let live = &self.0;
+        self.1.retain(|v| live.contains_key(v));
+    }
+
+    fn version(&self) -> u64 {
+        self.1.version
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        let changed = self
+            .0
+            .iter()
+            .filter(|(v, _)| self.1.is_newer(v, version))
+            .map(|(v, e)| (v.clone(), *e))
+            .collect();
+        Some(ExpiringSet(changed, Versions::default()))
+    }
+
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        for (v, e) in other.0.iter().skip(progress.done).take(max_entries) {
+            if self.0.get(v).is_none_or(|expires| e > expires) {
+                self.insert(v.clone(), *e);
+            }
+        }
+        Ok(MergeProgress::through(progress.done, max_entries, other.0.len()))
+    }
+}
+
+impl LocSet for ExpiringSet<Loc> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_key(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::new(self.0.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod expiring_set_tests {
+    use super::*;
+
+    #[test]
+    fn accessors_and_local_removal() {
+        let mut s = ExpiringSet::default();
+        s.insert("a".to_string(), 5);
+        s.insert("b".to_string(), 7);
+        assert!(s.contains("a"));
+        assert_eq!(s.expires_at("b"), Some(7));
+        assert_eq!(s.expires_at("c"), None);
+        assert_eq!(s.iter().map(|(v, e)| (v.as_str(), e)).collect::<Vec<_>>(), [("a", 5), ("b", 7)]);
+
+        let other = s.clone();
+        let version = s.version();
+        assert!(s.remove(&"a".to_string()));
+        assert!(!s.remove(&"a".to_string()));
+        assert!(s.version() > version);
+        assert_eq!(s.len(), 1);
+        s.merge(&other).unwrap();
+        assert_eq!(s.expires_at("a"), Some(5));
+    }
+
+    #[test]
+    fn paths_avoid_the_set_as_a_loc_set() {
+        let mut explored = HashMap::new();
+        for x in 0..5 {
+            for y in 0..3 {
+                explored.insert(Loc { x, y }, true);
+            }
+        }
+        let mut blocked = ExpiringSet::default();
+        blocked.insert(Loc { x: 2, y: 0 }, 10);
+        blocked.insert(Loc { x: 2, y: 1 }, 10);
+        assert_eq!(LocSet::len(&blocked), 2);
+
+        let path = crate::astar(Loc { x: 0, y: 0 }, Loc { x: 4, y: 0 }, &explored, &blocked, &crate::EmptyLocSet).unwrap();
+        assert!(path.contains(&Loc { x: 2, y: 2 }));
+        assert!(path.iter().all(|loc| !blocked.contains(loc)));
+
+        blocked.cleanup(10);
+        let path = crate::astar(Loc { x: 0, y: 0 }, Loc { x: 4, y: 0 }, &explored, &blocked, &crate::EmptyLocSet).unwrap();
+        assert!(path.iter().all(|loc| loc.y == 0));
+    }
+
+    #[test]
+    fn merge_events_include_extensions() {
+        let mut a = ExpiringSet::default();
+        a.insert('a', 5);
+        a.insert('b', 5);
+        let mut b = ExpiringSet::default();
+        b.insert('a', 9);
+        b.insert('b', 2);
+        b.insert('c', 3);
+        let mut events = Vec::new();
+        a.merge_with_events(&b, &mut |e| events.push(e)).unwrap();
+        assert_eq!(
+            events,
+            [
+                MergeEvent::ExpiryExtended { key: 'a', expires: 9 },
+                MergeEvent::Inserted { key: 'c', value: () },
+            ]
+        );
+        assert_eq!(a.get(&'b'), Some((&'b', 5)));
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let mut s = ExpiringSet::default();
+        let now = 0;
+        s.insert("a".to_string(), now+1);
+        s.insert("b".to_string(), now+2);
+
+        s.cleanup(now);
+        assert!(s.contains(&"a".to_string()));
+        assert!(s.contains(&"b".to_string()));
+
+        let now = 1;
+        s.cleanup(now);
+        assert!(!s.contains(&"a".to_string()));
+        assert!(s.contains(&"b".to_string()));
+
+        let now = 2;
+        s.cleanup(now);
+        assert!(!s.contains(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = ExpiringSet::default();
+        a.insert("a".to_string(), 5);
+        a.insert("b".to_string(), 2);
+
+        let mut b = ExpiringSet::default();
+        b.insert("b".to_string(), 10);
+        b.insert("c".to_string(), 3);
+
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab.0, ba.0);
+
+        ab.cleanup(4);
+        assert!(ab.contains(&"a".to_string()));
+        assert!(ab.contains(&"b".to_string()));
+        assert!(!ab.contains(&"c".to_string()));
+    }
+
+    #[test]
+    fn deltas_hold_only_later_changes() {
+        let mut a = ExpiringSet::default();
+        a.insert("a".to_string(), 5);
+        let mut b = a.clone();
+        let version = a.version();
+        a.insert("a".to_string(), 5);
+        a.insert("b".to_string(), 7);
+        assert!(a.version() > version);
+
+        let delta = a.delta_since(version).unwrap();
+        assert_eq!(delta.0.keys().collect::<Vec<_>>(), ["b"]);
+        b.apply_delta(&delta).unwrap();
+        assert_eq!(b.0, a.0);
+        assert!(a.delta_since(a.version()).unwrap().is_empty());
+
+        let mut g = GrowOnlySet::default();
+        g.insert(1);
+        let version = g.version();
+        g.insert(1);
+        g.insert(2);
+        assert_eq!(g.delta_since(version).unwrap().0.into_iter().collect::<Vec<_>>(), [2]);
+    }
+}
+
+/// An expiring set holding at most `.1` values, each with the turn it was
+/// first written and when it expires. When there are too many, the ones
+/// kept are those with the smallest `(written, value)`. Copies of a value
+/// merge to the one written first, or the later expiry of those written on
+/// the same turn, so what an eviction drops never decides what's kept, and
+/// merging replicas in any order ends with the same contents.
+///
+/// Replicas with different capacities end up with the larger one once
+/// merged, so a smaller capacity only lasts until a larger one is merged in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SizedFWWExpiringSet<T: Ord>(pub BTreeMap<T, (i64, i64)>, pub usize);
+
+impl<T: Ord> SizedFWWExpiringSet<T> {
+    pub fn new(size: usize) -> Self {
+        Self(BTreeMap::new(), size)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.1
+    }
+
+    /// Evicts down to `capacity` the same way merges do.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.1 = capacity;
+        self.trim();
+    }
+
+    pub fn insert(&mut self, v: T, now: i64, expires: i64) {
+        if let Some((_, e)) = self.0.get_mut(&v) {
+            *e = expires;
+        } else {
+            self.0.insert(v, (now, expires));
+            self.trim();
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.0.len() > self.1 {
+            let newest = self
+                .0
+                .iter()
+                .enumerate()
+                .max_by(|(_, (a, (a_written, _))), (_, (b, (b_written, _)))| (a_written, a).cmp(&(b_written, b)))
+                .map(|(i, _)| i);
+            let mut i = 0;
+            self.0.retain(|_, _| {
+                i += 1;
+                Some(i - 1) != newest
+            });
+        }
+    }
+
+    pub fn contains<Q>(&self, v: &Q) -> bool
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+        {
+        self.0.contains_key(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Each value with the turn it was written and when it expires.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, i64, i64)> + '_ {
+        self.0.iter().map(|(v, (written, expires))| (v, *written, *expires))
+    }
+}
+
+impl<T: Ord + Clone> Crdt for SizedFWWExpiringSet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = other.1 > self.1;
+        self.1 = self.1.max(other.1);
+        let mut added = Vec::new();
+        for (other_value, (other_written, other_expires)) in &other.0 {
+            if let Some(local) = self.0.get_mut(other_value) {
+                if (other_written, Reverse(other_expires)) < (&local.0, Reverse(&local.1)) {
+                    *local = (*other_written, *other_expires);
+                    changed = true;
+                }
+            } else {
+                self.0.insert(other_value.clone(), (*other_written, *other_expires));
+                added.push(other_value);
+            }
+        }
+        self.trim();
+        // Only new values can have been trimmed if none of them survived.
+        Ok(changed || added.iter().any(|v| self.0.contains_key(*v)))
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+
+#[cfg(test)]
+mod sized_set_tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_with_capacity() {
+        let mut s = SizedFWWExpiringSet::new(3);
+        let now = 0;
+        s.insert("a".to_string(), now, now+10);
+        assert!(s.contains("a"));
+        s.insert("b".to_string(), now, now+10);
+        assert!(s.contains("b"));
+        s.insert("c".to_string(), now, now+10);
+        assert!(s.contains("c"));
+        s.insert("d".to_string(), now, now+10);
+        assert!(!s.contains("d"));
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let mut s = SizedFWWExpiringSet::new(3);
+        let now = 0;
+        s.insert("a".to_string(), now, now+1);
+        s.insert("b".to_string(), now, now+2);
+        s.insert("c".to_string(), now, now+3);
+
+        s.cleanup(now);
+        assert!(s.contains("a"));
+        assert!(s.contains("b"));
+        assert!(s.contains("c"));
+
+        let now = 1;
+        s.cleanup(now);
+        assert!(!s.contains("a"));
+        assert!(s.contains("b"));
+        assert!(s.contains("c"));
+
+        let now = 2;
+        s.cleanup(now);
+        assert!(!s.contains("a"));
+        assert!(!s.contains("b"));
+        assert!(s.contains("c"));
+
+        let now = 3;
+        s.cleanup(now);
+        assert!(!s.contains("a"));
+        assert!(!s.contains("b"));
+        assert!(!s.contains("c"));
+
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = SizedFWWExpiringSet::new(3);
+        a.insert("a".to_string(), 0, 10);
+        a.insert("b".to_string(), 1, 10);
+        a.insert("c".to_string(), 2, 10);
+
+        let mut b = SizedFWWExpiringSet::new(3);
+        b.insert("d".to_string(), 3, 10);
+
+        a.merge(&b).unwrap();
+        assert!(a.contains("a"));
+        assert!(a.contains("b"));
+        assert!(a.contains("c"));
+        assert!(!a.contains("d"));
+
+        let mut c = SizedFWWExpiringSet::new(3);
+        c.insert("d".to_string(), 0, 10);
+        a.merge(&c).unwrap();
+
+        assert!(a.contains("a"));
+        assert!(a.contains("b"));
+        assert!(!a.contains("c"));
+        assert!(a.contains("d"));
+    }
+
+    #[test]
+    fn merges_converge_in_any_order() {
+        fastrand::seed(287);
+        for _ in 0..200 {
+            let capacity = fastrand::usize(1..5);
+            let replicas: Vec<SizedFWWExpiringSet<u32>> = (0..4)
+                .map(|_| {
+                    let mut s = SizedFWWExpiringSet::new(capacity);
+                    for _ in 0..fastrand::usize(0..6) {
+                        s.insert(fastrand::u32(0..8), fastrand::i64(0..5), fastrand::i64(100..110));
+                    }
+                    s
+                })
+                .collect();
+            assert!(replicas.iter().all(|s| s.len() <= capacity));
+            testing::assert_crdt_laws(replicas);
+        }
+    }
+
+    #[test]
+    fn evicted_copies_merge_the_same_in_either_order() {
+        // `b` evicts `late`'s copy of "v", which `early`'s then replaces.
+        let mut late = SizedFWWExpiringSet::new(1);
+        late.insert("v", 5, 50);
+        let mut b = SizedFWWExpiringSet::new(1);
+        b.insert("x", 3, 10);
+        let mut early = SizedFWWExpiringSet::new(1);
+        early.insert("v", 1, 20);
+
+        let mut evicted_first = late.clone();
+        evicted_first.merge(&b).unwrap();
+        evicted_first.merge(&early).unwrap();
+        let mut merged_first = late.clone();
+        merged_first.merge(&early).unwrap();
+        merged_first.merge(&b).unwrap();
+        assert_eq!(evicted_first, merged_first);
+        assert_eq!(merged_first.iter().collect::<Vec<_>>(), [(&"v", 1, 20)]);
+
+        let mut again = merged_first.clone();
+        assert!(!again.merge_changed(&merged_first).unwrap());
+        assert_eq!(again, merged_first);
+    }
+
+    #[test]
+    fn replicas_converge() {
+        for capacity in 1..4 {
+            testing::assert_converges(
+                SizedFWWExpiringSet::new(capacity),
+                4,
+                60,
+                &[&|s, _, now| {
+                    let v = fastrand::u32(0..8);
+                    s.insert(v, now, 20 + 5 * v as i64);
+                }],
+                capacity as u64,
+            );
+        }
+    }
+
+    #[test]
+    fn multi_way_merge() {
+        let mut a = SizedFWWExpiringSet::new(3);
+        a.insert("a".to_string(), 0, 10);
+        a.insert("b".to_string(), 1, 10);
+        a.insert("c".to_string(), 2, 10);
+
+        let mut b = SizedFWWExpiringSet::new(3);
+        b.insert("d".to_string(), 2, 10);
+
+        let mut c = SizedFWWExpiringSet::new(3);
+        c.insert("e".to_string(), 2, 10);
+
+        let mut na = a.clone();
+        na.merge(&b).unwrap();
+        na.merge(&c).unwrap();
+        na.cleanup(4);
+        assert!(na.contains("a"));
+        assert!(na.contains("b"));
+        assert!(na.contains("c"));
+        assert!(!na.contains("d"));
+        assert!(!na.contains("e"));
+
+        testing::assert_crdt_laws(vec![a, b, c]);
+    }
+
+    #[test]
+    fn differing_capacities_converge() {
+        let mut small = SizedFWWExpiringSet::new(3);
+        let mut large = SizedFWWExpiringSet::new(5);
+        for v in 0..4 {
+            small.insert(v * 2, v as i64, 100);
+            large.insert(v * 2 + 1, v as i64, 100);
+        }
+        assert_eq!((small.len(), small.capacity()), (3, 3));
+        assert_eq!((large.len(), large.capacity()), (4, 5));
+
+        let mut small_first = small.clone();
+        assert!(small_first.merge_changed(&large).unwrap());
+        let mut large_first = large.clone();
+        large_first.merge(&small).unwrap();
+        assert_eq!(small_first, large_first);
+        assert_eq!(small_first.capacity(), 5);
+        assert_eq!(small_first.iter().map(|(v, _, _)| *v).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        testing::assert_crdt_laws(vec![small, large, SizedFWWExpiringSet::new(4)]);
+    }
+
+    #[test]
+    fn shrinking_evicts_like_merges() {
+        let mut s = SizedFWWExpiringSet::new(4);
+        s.insert("late", 3, 10);
+        s.insert("early", 1, 10);
+        s.insert("b", 2, 10);
+        s.insert("a", 2, 10);
+        s.set_capacity(2);
+        assert_eq!(s.iter().map(|(v, _, _)| *v).collect::<Vec<_>>(), ["a", "early"]);
+        s.set_capacity(3);
+        s.insert("c", 0, 10);
+        assert_eq!(s.len(), 3);
+    }
+
+    #[test]
+    fn merge_reports_changes() {
+        let mut a = SizedFWWExpiringSet::new(1);
+        a.insert("a".to_string(), 0, 10);
+        let mut b = SizedFWWExpiringSet::new(1);
+        b.insert("b".to_string(), 1, 10);
+        assert!(!a.merge_changed(&b).unwrap());
+        b.insert("a".to_string(), 0, 20);
+        assert!(a.merge_changed(&b).unwrap());
+        assert!(!a.merge_changed(&b).unwrap());
+    }
+}
+
+/// Values with the turn each was first written, for history like "tiles
+/// attacked on turn N". Merges keep the earliest turn for each value, so
+/// replicas agree on how old it is, and `cleanup` forgets values written
+/// more than `.1` turns before `now`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HorizonSet<T: Ord>(pub BTreeMap<T, i64>, pub i64, pub Versions<T>);
+
+impl<T: Ord> Default for HorizonSet<T> {
+    /// A horizon of `i64::MAX` never forgets anything.
+    fn default() -> Self {
+        Self::new(i64::MAX)
+    }
+}
+
+impl<T: Ord> HorizonSet<T> {
+    pub fn new(horizon: i64) -> Self {
+        Self(BTreeMap::new(), horizon, Versions::default())
+    }
+
+    pub fn contains<Q>(&self, v: &Q) -> bool
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.0.contains_key(v)
+    }
+
+    /// The turn `v` was first written.
+    pub fn written<Q>(&self, v: &Q) -> Option<i64>
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.0.get(v).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Each value with the turn it was first written.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, i64)> + '_ {
+        self.0.iter().map(|(v, written)| (v, *written))
+    }
+}
+
+impl<T: Ord + Clone> HorizonSet<T> {
+    /// Keeps the earlier turn if `v` is already there.
+    pub fn insert(&mut self, v: T, now: i64) -> bool {
+        if self.0.get(&v).is_some_and(|written| *written <= now) {
+            return false;
+        }
+        self.0.insert(v.clone(), now);
+        self.2.touch(v);
+        true
+    }
+}
+
+impl<T: Ord + Clone> Crdt for HorizonSet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (v, written) in &other.0 {
+            changed |= self.insert(v.clone(), *written);
+        }
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        if self.1 == i64::MAX {
+            return;
+        }
+        let oldest = now.saturating_sub(self.1);
+        self.0.retain(|_, written| *written >= oldest);
+        let live = &self.0;
+        self.2.retain(|v| live.contains_key(v));
+    }
+
+    fn version(&self) -> u64 {
+        self.2.version
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        let changed = self
+            .0
+            .iter()
+            .filter(|(v, _)| self.2.is_newer(v, version))
+            .map(|(v, written)| (v.clone(), *written))
+            .collect();
+        Some(HorizonSet(changed, self.1, Versions::default()))
+    }
+
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        for (v, written) in other.0.iter().skip(progress.done).take(max_entries) {
+            self.insert(v.clone(), *written);
+        }
+        Ok(MergeProgress::through(progress.done, max_entries, other.0.len()))
+    }
+}
+
+impl LocSet for HorizonSet<Loc> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_key(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::new(self.0.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod horizon_set_tests {
+    use super::*;
+
+    #[test]
+    fn cleanup_drops_values_older_than_the_horizon() {
+        let mut s = HorizonSet::new(5);
+        s.insert("old", 1);
+        s.insert("new", 4);
+        s.cleanup(6);
+        assert!(s.contains("old") && s.contains("new"));
+        s.cleanup(7);
+        assert!(!s.contains("old") && s.contains("new"));
+        assert_eq!(s.len(), 1);
+
+        let mut forever = HorizonSet::default();
+        forever.insert("old", i64::MIN);
+        forever.cleanup(i64::MAX);
+        assert!(forever.contains("old"));
+    }
+
+    #[test]
+    fn merges_keep_the_first_write() {
+        let mut a = HorizonSet::new(10);
+        a.insert("x", 5);
+        a.insert("y", 2);
+        assert!(!a.insert("x", 8));
+        let mut b = HorizonSet::new(10);
+        b.insert("x", 3);
+        b.insert("z", 9);
+
+        let mut ab = a.clone();
+        assert!(ab.merge_changed(&b).unwrap());
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab.0, ba.0);
+        assert_eq!(ab.written("x"), Some(3));
+        assert!(!ab.merge_changed(&a).unwrap());
+
+        let version = b.version();
+        b.insert("w", 1);
+        let delta = b.delta_since(version).unwrap();
+        assert_eq!(delta.iter().collect::<Vec<_>>(), [(&"w", 1)]);
+    }
+
+    #[test]
+    fn avoids_recent_locs_in_searches() {
+        let mut attacked = HorizonSet::new(3);
+        attacked.insert(Loc { x: 1, y: 0 }, 10);
+        attacked.insert(Loc { x: 2, y: 0 }, 12);
+        attacked.cleanup(14);
+        assert!(!attacked.contains_loc(&Loc { x: 1, y: 0 }));
+        assert_eq!(LocSet::iter(&attacked).collect::<Vec<_>>(), [Loc { x: 2, y: 0 }]);
+    }
+}
+
+/// Derives a replica id for the counters from something that tells the
+/// writer apart, e.g. a random number generated once and kept in the store.
+/// Every writer must use its own id and keep using it: increments made by
+/// two writers sharing an id are partly lost when they merge.
+pub fn replica_id(seed: &[u8]) -> u64 {
+    digest_bytes(seed)
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, which unlike `DefaultHasher` gives the same hash in every build.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+pub fn digest_bytes(bytes: &[u8]) -> u64 {
+    fnv(FNV_OFFSET, bytes)
+}
+
+/// Hashes `value`'s serialized form as allies are sent it, as the default
+/// `Crdt::digest` does. Panics if it doesn't serialize.
+pub fn digest_of<T: Serialize + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DigestWriter(FNV_OFFSET);
+    encoding_for_allies(|| bincode::serialize_into(&mut hasher, value)).expect("broadcast state must serialize");
+    hasher.0
+}
+
+/// Hashes whatever's written to it, so `Crdt::digest` needn't allocate.
+struct DigestWriter(u64);
+
+impl std::io::Write for DigestWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0 = fnv(self.0, bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod digest_tests {
+    use super::*;
+
+    fn round_trip<T: Serialize + serde::de::DeserializeOwned>(t: &T) -> T {
+        bincode::deserialize(&bincode::serialize(t).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn digest_follows_merges() {
+        let mut a: CrdtMap<i32, String, Lww> = CrdtMap::default();
+        a.insert(1, "one".to_string(), 0);
+        let mut b = a.clone();
+        b.insert(2, "two".to_string(), 1);
+        let before = a.digest();
+        assert!(!a.merge_changed(&a.clone()).unwrap());
+        assert_eq!(a.digest(), before);
+        assert!(a.merge_changed(&b).unwrap());
+        assert_ne!(a.digest(), before);
+        assert_eq!(round_trip(&a).digest(), a.digest());
+
+        let mut s = ExpiringSet::default();
+        s.insert("a".to_string(), 5);
+        let before = s.digest();
+        let mut other = ExpiringSet::default();
+        other.insert("a".to_string(), 9);
+        assert!(s.merge_changed(&other).unwrap());
+        assert_ne!(s.digest(), before);
+        assert_eq!(round_trip(&s).digest(), s.digest());
+
+        let mut g = GrowOnlySet::default();
+        g.insert(1);
+        let before = g.digest();
+        g.insert(2);
+        assert_ne!(g.digest(), before);
+        assert_eq!(round_trip(&g).digest(), g.digest());
+    }
+}
+
+/// A counter that only goes up, tracking each replica's own total.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter(pub BTreeMap<u64, u64>);
+
+impl GCounter {
+    pub fn increment(&mut self, replica: u64, by: u64) {
+        let count = self.0.entry(replica).or_default();
+        *count = count.saturating_add(by);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.values().fold(0, |total, count| total.saturating_add(*count))
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        for (replica, count) in &other.0 {
+            let local = self.0.entry(*replica).or_default();
+            *local = (*local).max(*count);
+        }
+        Ok(())
+    }
+}
+
+/// A counter that can also go down, as the difference of two `GCounter`s.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    pub increments: GCounter,
+    pub decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn increment(&mut self, replica: u64, by: u64) {
+        self.increments.increment(replica, by);
+    }
+
+    pub fn decrement(&mut self, replica: u64, by: u64) {
+        self.decrements.increment(replica, by);
+    }
+
+    pub fn value(&self) -> i64 {
+        (self.increments.value() as i128 - self.decrements.value() as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.increments.merge(&other.increments)?;
+        self.decrements.merge(&other.decrements)
+    }
+}
+
+#[cfg(test)]
+mod counter_tests {
+    use super::*;
+
+    #[test]
+    fn g_counter_merges_per_replica() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        assert_ne!(x, y);
+        let mut a = GCounter::default();
+        a.increment(x, 3);
+        let mut b = a.clone();
+        a.increment(x, 2);
+        b.increment(y, 4);
+
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 9);
+
+        ab.merge(&b).unwrap();
+        assert_eq!(ab.value(), 9);
+    }
+
+    #[test]
+    fn pn_counter_goes_both_ways() {
+        let mut a = PnCounter::default();
+        a.increment(1, 5);
+        let mut b = PnCounter::default();
+        b.decrement(2, 7);
+        a.merge(&b).unwrap();
+        b.merge(&a).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.value(), -2);
+    }
+}
+
+/// How many times each replica did something on each turn, keyed by
+/// `(replica, turn)`, to limit how often a faction does it between them.
+/// Counts only ever grow, so merging takes the larger of each. `cleanup`
+/// forgets turns more than `.1` turns old, so windows longer than that
+/// undercount.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowedCounter(pub BTreeMap<(u64, i64), u64>, pub i64);
+
+impl Default for WindowedCounter {
+    fn default() -> Self {
+        Self::new(DEFAULT_TOMBSTONE_HORIZON)
+    }
+}
+
+impl WindowedCounter {
+    pub fn new(window: i64) -> Self {
+        Self(BTreeMap::new(), window)
+    }
+
+    /// `replica` must be unique to the writer, see `replica_id`.
+    pub fn record(&mut self, replica: u64, now: i64) {
+        let count = self.0.entry((replica, now)).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    /// Everything recorded in the last `window` turns, up to `now`.
+    pub fn count_in_window(&self, now: i64, window: i64) -> u64 {
+        let oldest = now.saturating_sub(window);
+        self.0
+            .iter()
+            .filter(|((_, turn), _)| *turn > oldest && *turn <= now)
+            .fold(0, |total, (_, count)| total.saturating_add(*count))
+    }
+}
+
+impl Crdt for WindowedCounter {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (key, count) in &other.0 {
+            let local = self.0.entry(*key).or_default();
+            changed |= *count > *local;
+            *local = (*local).max(*count);
+        }
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        let oldest = now.saturating_sub(self.1);
+        self.0.retain(|(_, turn), _| *turn > oldest);
+    }
+
+    /// Only holds a window's worth, so its delta is all of it.
+    fn delta_since(&self, _version: u64) -> Option<Self> {
+        Some(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod windowed_counter_tests {
+    use super::*;
+    use crate::behaviors::try_rate_limited;
+
+    #[test]
+    fn same_turn_records_add_up_once_merged() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = WindowedCounter::new(10);
+        let mut b = a.clone();
+        a.record(x, 5);
+        b.record(y, 5);
+        b.record(y, 5);
+        let (before_a, before_b) = (a.clone(), b.clone());
+        a.merge(&before_b).unwrap();
+        b.merge(&before_a).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.count_in_window(5, 1), 3);
+        assert_eq!(a.count_in_window(4, 10), 0);
+        assert_eq!(a.count_in_window(14, 10), 3);
+        assert_eq!(a.count_in_window(15, 10), 0);
+
+        a.cleanup(15);
+        assert!(a.0.is_empty());
+    }
+
+    #[test]
+    fn laws_and_convergence() {
+        let mut replicas = vec![WindowedCounter::new(5); 3];
+        replicas[0].record(1, 0);
+        replicas[1].record(1, 0);
+        replicas[1].record(1, 0);
+        replicas[2].record(2, 3);
+        testing::assert_crdt_laws(replicas);
+        testing::assert_converges(WindowedCounter::new(5), 3, 30, &[&|c, i, now| c.record(i as u64, now)], 320);
+    }
+
+    #[test]
+    fn rate_limits_hold_across_replicas() {
+        let mut a = WindowedCounter::new(10);
+        let mut b = a.clone();
+        assert!(try_rate_limited(&mut a, 1, 2, 10, 0));
+        assert!(try_rate_limited(&mut b, 2, 2, 10, 0));
+        a.merge(&b).unwrap();
+        assert!(!try_rate_limited(&mut a, 1, 2, 10, 3));
+        assert_eq!(a.count_in_window(3, 10), 2);
+        assert!(try_rate_limited(&mut a, 1, 2, 10, 10));
+    }
+}
+
+/// How many changes each replica has made that this state has seen, to tell
+/// whether one replica's state is ahead of, behind or concurrent with
+/// another's. `partial_cmp` is `None` for concurrent states.
+#[derive(Clone, Debug, Default, Eq, Serialize, Deserialize)]
+pub struct VClock(pub BTreeMap<u64, u64>);
+
+impl VClock {
+    pub fn increment(&mut self, replica: u64) {
+        let count = self.0.entry(replica).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    pub fn get(&self, replica: u64) -> u64 {
+        self.0.get(&replica).copied().unwrap_or(0)
+    }
+
+    pub fn is_concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialEq for VClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+    }
+}
+
+impl PartialOrd for VClock {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        use std::cmp::Ordering;
+        let replicas: BTreeSet<_> = self.0.keys().chain(other.0.keys()).collect();
+        let (mut ahead, mut behind) = (false, false);
+        for replica in replicas {
+            match self.get(*replica).cmp(&other.get(*replica)) {
+                Ordering::Greater => ahead = true,
+                Ordering::Less => behind = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (ahead, behind) {
+            (true, true) => None,
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => Some(Ordering::Equal),
+        }
+    }
+}
+
+impl Crdt for VClock {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (replica, count) in &other.0 {
+            let local = self.0.entry(*replica).or_default();
+            changed |= count > local;
+            *local = (*local).max(*count);
+        }
+        Ok(changed)
+    }
+}
+
+/// A value stamped with a `VClock` that `mutate` bumps, so replicas can
+/// tell whether a peer has seen their latest changes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub clock: VClock,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            clock: VClock::default(),
+        }
+    }
+
+    /// Changes the value as `replica`, ticking its entry in the clock.
+    pub fn mutate<R>(&mut self, replica: u64, f: impl FnOnce(&mut T) -> R) -> R {
+        self.clock.increment(replica);
+        f(&mut self.value)
+    }
+
+    /// Whether `other` is missing changes this state has seen.
+    pub fn is_ahead_of(&self, other: &Self) -> bool {
+        other.clock < self.clock
+    }
+}
+
+impl<T: Crdt> Crdt for Versioned<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.value.merge(&other.value)?;
+        self.clock.merge(&other.clock)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let changed = self.value.merge_changed(&other.value)?;
+        Ok(self.clock.merge_changed(&other.clock)? || changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.value.cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.value.compact(horizon);
+    }
+
+    fn version(&self) -> u64 {
+        self.value.version()
+    }
+}
+
+#[cfg(test)]
+mod vclock_tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn clocks_order_causally() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = VClock::default();
+        a.increment(x);
+        let mut b = a.clone();
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Equal));
+        b.increment(y);
+        assert!(a < b);
+        a.increment(x);
+        assert!(a.is_concurrent(&b));
+
+        assert!(a.merge_changed(&b).unwrap());
+        assert!(a > b);
+        assert!(!a.merge_changed(&b).unwrap());
+        assert_eq!((a.get(x), a.get(y)), (2, 1));
+    }
+}
+
+/// How many turns a `HorizonTracker` waits to hear from a replica before it
+/// stops holding the horizon back. Like an expired tombstone, a replica
+/// back from longer than that can bring removed entries back.
+pub const DEFAULT_ACK_TIMEOUT: i64 = DEFAULT_TOMBSTONE_HORIZON;
+
+/// How far every replica has caught up, so `Crdt::compact` can drop the
+/// tombstones they've all seen. `.0` has, for each replica, the turn of the
+/// latest state of it heard of and the turn it acknowledges, having seen
+/// every replica's state from then. Replicas not heard of for `.1` turns
+/// are forgotten. `.2` is this replica's id, picked by `observe` and never
+/// merged.
+///
+/// The tracker vouches for the states sent along with it, so in a capped
+/// broadcast give it a lower `#[crdt(priority)]` than the fields it guards.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HorizonTracker(pub BTreeMap<u64, (i64, i64)>, pub i64, pub Option<u64>);
+
+impl Default for HorizonTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_ACK_TIMEOUT)
+    }
+}
+
+impl HorizonTracker {
+    pub fn new(timeout: i64) -> Self {
+        Self(BTreeMap::new(), timeout, None)
+    }
+
+    /// Records that `replica` has seen every replica's state from `turn`.
+    pub fn ack(&mut self, replica: u64, turn: i64) {
+        let (latest, acked) = self.0.entry(replica).or_insert((turn, turn));
+        *latest = (*latest).max(turn);
+        *acked = (*acked).max(turn);
+    }
+
+    /// Records this replica's state as of `now`, acknowledging the oldest
+    /// state it's heard of. `Component::step` calls it every turn.
+    pub fn observe(&mut self, now: i64) {
+        let replica = *self.2.get_or_insert_with(|| fastrand::u64(0..u64::MAX));
+        let latest = &mut self.0.entry(replica).or_insert((now, i64::MIN)).0;
+        *latest = (*latest).max(now);
+        let oldest = self.0.values().map(|(latest, _)| *latest).min().unwrap_or(now);
+        self.ack(replica, oldest);
+    }
+
+    /// The turn every replica has caught up to, before which tombstones
+    /// can safely be dropped. `i64::MIN` while no replica has acknowledged
+    /// anything.
+    pub fn min(&self) -> i64 {
+        self.0.values().map(|(_, acked)| *acked).min().unwrap_or(i64::MIN)
+    }
+
+    pub fn replica(&self) -> Option<u64> {
+        self.2
+    }
+}
+
+impl Crdt for HorizonTracker {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (replica, (latest, acked)) in &other.0 {
+            let local = self.0.entry(*replica).or_insert((i64::MIN, i64::MIN));
+            let merged = (local.0.max(*latest), local.1.max(*acked));
+            changed |= merged != *local;
+            *local = merged;
+        }
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        let oldest = now.saturating_sub(self.1);
+        self.0.retain(|_, (latest, _)| *latest >= oldest);
+    }
+
+    /// Small enough to send whole, so structs holding one can still
+    /// build deltas.
+    fn delta_since(&self, _version: u64) -> Option<Self> {
+        Some(self.clone())
+    }
+
+    fn horizon_tracker(&mut self) -> Option<&mut HorizonTracker> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod horizon_tracker_tests {
+    use super::*;
+
+    /// A map kept alongside a tracker, which compacts it after merges.
+    #[derive(Clone, Debug, Default, Serialize, Deserialize, CrdtContainer)]
+    #[crdt(crate = "crate")]
+    struct Replica {
+        #[crdt]
+        map: CrdtMap<i32, i32, Lww>,
+        #[crdt]
+        tracker: HorizonTracker,
+    }
+
+    #[test]
+    fn tombstones_last_until_every_replica_acks_past_them() {
+        let mut replicas = vec![Replica::default(); 3];
+        for (i, r) in replicas.iter_mut().enumerate() {
+            r.tracker.2 = Some(i as u64);
+            r.tracker.observe(0);
+        }
+        replicas[0].map.insert(1, 1, 0);
+        let all = replicas.clone();
+        for r in &mut replicas {
+            for other in &all {
+                r.merge(other).unwrap();
+            }
+        }
+        replicas[0].map.remove(1, 5);
+
+        // Replica 2 hears from nobody, so never acknowledges anything past
+        // the start.
+        for now in 6..20 {
+            let (a, b) = (replicas[0].clone(), replicas[1].clone());
+            replicas[0].merge(&b).unwrap();
+            replicas[1].merge(&a).unwrap();
+            for r in &mut replicas[..2] {
+                r.tracker.observe(now);
+            }
+        }
+        assert_eq!(replicas[0].tracker.min(), 0);
+        assert!(replicas[1].map.tombstones.contains_key(&1) && !replicas[1].map.contains_key(&1));
+
+        // Had the tombstone gone, replica 2 would bring the entry back.
+        for now in 20..30 {
+            let all = replicas.clone();
+            for r in &mut replicas {
+                for other in &all {
+                    r.merge(other).unwrap();
+                }
+                r.tracker.observe(now);
+            }
+            assert!(replicas.iter().all(|r| !r.map.contains_key(&1)));
+        }
+        assert!(replicas.iter().all(|r| r.tracker.min() > 5));
+        assert!(replicas.iter().all(|r| !r.map.tombstones.contains_key(&1)));
+    }
+
+    #[test]
+    fn silent_replicas_stop_holding_the_horizon_back() {
+        let mut tracker = HorizonTracker::new(10);
+        tracker.ack(7, 3);
+        tracker.2 = Some(1);
+        for now in 0..20 {
+            tracker.observe(now);
+            tracker.cleanup(now);
+        }
+        assert_eq!(tracker.0.len(), 1);
+        tracker.observe(20);
+        assert_eq!(tracker.min(), 20);
+    }
+
+    #[test]
+    fn trackers_converge() {
+        testing::assert_converges(
+            HorizonTracker::new(5),
+            4,
+            40,
+            &[&|t, i, now| t.ack(i as u64, now), &|t, i, now| t.ack(i as u64 + 10, now - 3)],
+            317,
+        );
+    }
+}
+
+/// Identifies one insertion into an `OrSet`: the inserting replica and how
+/// many insertions it had made before.
+pub type OrSetTag = (u64, u64);
+
+/// Observed-remove set. Removing a value cancels only the insertions this
+/// replica has seen, so an insertion made concurrently elsewhere survives it.
+/// Tombstones are kept for `horizon` turns; a replica out of contact for
+/// longer can bring removed values back.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet<T: Ord> {
+    pub adds: BTreeMap<T, BTreeSet<OrSetTag>>,
+    pub removed: BTreeMap<OrSetTag, i64>,
+    pub counters: BTreeMap<u64, u64>,
+    pub horizon: i64,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: BTreeMap::new(),
+            removed: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            horizon: DEFAULT_TOMBSTONE_HORIZON,
+        }
+    }
+}
+
+impl<T: Ord> OrSet<T> {
+    /// `replica` must be unique to the writer, see `replica_id`.
+    pub fn insert(&mut self, v: T, replica: u64) {
+        let counter = self.counters.entry(replica).or_default();
+        *counter += 1;
+        self.adds.entry(v).or_default().insert((replica, *counter));
+    }
+
+    pub fn remove(&mut self, v: &T, now: i64) {
+        for tag in self.adds.remove(v).into_iter().flatten() {
+            self.removed.insert(tag, now);
+        }
+    }
+
+    pub fn contains(&self, v: &T) -> bool {
+        self.adds.contains_key(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.adds.keys()
+    }
+}
+
+impl<T: Ord + Clone> Crdt for OrSet<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        for (tag, removed) in &other.removed {
+            let local = self.removed.entry(*tag).or_insert(*removed);
+            *local = (*local).max(*removed);
+        }
+        for (v, tags) in &other.adds {
+            self.adds.entry(v.clone()).or_default().extend(tags.iter().copied());
+        }
+        let removed = &self.removed;
+        self.adds.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains_key(tag));
+            !tags.is_empty()
+        });
+        for (replica, counter) in &other.counters {
+            let local = self.counters.entry(*replica).or_default();
+            *local = (*local).max(*counter);
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        let oldest = now.saturating_sub(self.horizon);
+        self.removed.retain(|_, removed| *removed >= oldest);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.removed.retain(|_, removed| *removed >= horizon);
+    }
+}
+
+#[cfg(test)]
+mod or_set_tests {
+    use super::*;
+
+    fn merged(a: &OrSet<i32>, b: &OrSet<i32>) -> OrSet<i32> {
+        let mut merged = a.clone();
+        merged.merge(b).unwrap();
+        merged
+    }
+
+    fn replicas() -> [OrSet<i32>; 3] {
+        let mut a = OrSet::default();
+        a.insert(1, 1);
+        a.insert(2, 1);
+        let mut b = a.clone();
+        let mut c = OrSet::default();
+        a.remove(&1, 3);
+        b.insert(3, 2);
+        b.remove(&2, 4);
+        c.insert(2, 3);
+        c.insert(4, 3);
+        c.remove(&4, 5);
+        [a, b, c]
+    }
+
+    #[test]
+    fn merge_laws() {
+        let [a, b, c] = replicas();
+        assert_eq!(merged(&a, &b), merged(&b, &a));
+        assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)));
+        assert_eq!(merged(&a, &a), a);
+        let all = merged(&merged(&a, &b), &c);
+        assert_eq!(all.iter().copied().collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn concurrent_claim_survives_release() {
+        let (x, replica_a, replica_b) = (7, 1, 2);
+        let mut a = OrSet::default();
+        let mut b = OrSet::default();
+        a.insert(x, replica_a);
+        b.insert(x, replica_b);
+        a.remove(&x, 1);
+        assert!(!a.contains(&x));
+        let (ab, ba) = (merged(&a, &b), merged(&b, &a));
+        assert_eq!(ab, ba);
+        assert!(ab.contains(&x));
+
+        // Once A has seen B's claim, releasing really releases it.
+        let mut a = ab;
+        a.remove(&x, 2);
+        let mut b = ba;
+        b.merge(&a).unwrap();
+        assert!(!b.contains(&x));
+    }
+
+    #[test]
+    fn old_tombstones_are_collected() {
+        let mut s = OrSet {
+            horizon: 10,
+            ..Default::default()
+        };
+        s.insert(1, 1);
+        s.insert(2, 1);
+        s.remove(&1, 0);
+        s.remove(&2, 5);
+        s.cleanup(12);
+        assert_eq!(s.removed.len(), 1);
+        s.insert(1, 1);
+        assert_eq!(s.adds[&1], BTreeSet::from([(1, 3)]));
+    }
+}
+
+/// The enables of an `EwFlag`, or disables of a `DwFlag`, tagged like
+/// `OrSet` insertions with the turn each expires. Clearing cancels only the
+/// dots this replica has seen.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dots {
+    pub dots: BTreeMap<OrSetTag, i64>,
+    pub removed: BTreeMap<OrSetTag, i64>,
+    pub counters: BTreeMap<u64, u64>,
+    pub horizon: i64,
+}
+
+impl Default for Dots {
+    fn default() -> Self {
+        Self {
+            dots: BTreeMap::new(),
+            removed: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            horizon: DEFAULT_TOMBSTONE_HORIZON,
+        }
+    }
+}
+
+impl Dots {
+    fn add(&mut self, replica: u64, expires: i64) {
+        let counter = self.counters.entry(replica).or_default();
+        *counter += 1;
+        self.dots.insert((replica, *counter), expires);
+    }
+
+    fn clear(&mut self, now: i64) {
+        for (tag, _) in std::mem::take(&mut self.dots) {
+            self.removed.insert(tag, now);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.dots.is_empty()
+    }
+}
+
+impl Crdt for Dots {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (tag, removed) in &other.removed {
+            if self.removed.get(tag).is_none_or(|local| local < removed) {
+                self.removed.insert(*tag, *removed);
+                changed |= self.dots.remove(tag).is_some();
+            }
+        }
+        for (tag, expires) in &other.dots {
+            if !self.removed.contains_key(tag) {
+                changed |= self.dots.insert(*tag, *expires).is_none();
+            }
+        }
+        for (replica, counter) in &other.counters {
+            let local = self.counters.entry(*replica).or_default();
+            *local = (*local).max(*counter);
+        }
+        Ok(changed)
+    }
+
+    /// Drops expired dots and old tombstones. Every replica drops a dot at
+    /// the same turn, so expiry needs no tombstone.
+    fn cleanup(&mut self, now: i64) {
+        self.dots.retain(|_, expires| *expires > now);
+        let oldest = now.saturating_sub(self.horizon);
+        self.removed.retain(|_, removed| *removed >= oldest);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.removed.retain(|_, removed| *removed >= horizon);
+    }
+}
+
+/// A shared switch where enabling wins: a disable only cancels the enables
+/// its replica had seen, so one made concurrently elsewhere keeps the flag
+/// on. Enables can expire, switching the flag off by themselves.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EwFlag(pub Dots);
+
+impl EwFlag {
+    /// `replica` must be unique to the writer, see `replica_id`.
+    pub fn enable(&mut self, replica: u64) {
+        self.0.add(replica, i64::MAX);
+    }
+
+    /// Enables until `cleanup` reaches `expires`.
+    pub fn enable_until(&mut self, replica: u64, expires: i64) {
+        self.0.add(replica, expires);
+    }
+
+    pub fn disable(&mut self, now: i64) {
+        self.0.clear(now);
+    }
+
+    pub fn value(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl Crdt for EwFlag {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.0.merge(&other.0)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        self.0.merge_changed(&other.0)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.0.compact(horizon);
+    }
+}
+
+/// The dual of `EwFlag`, where disabling wins. It starts enabled, and an
+/// enable only cancels the disables its replica had seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DwFlag(pub Dots);
+
+impl DwFlag {
+    /// `replica` must be unique to the writer, see `replica_id`.
+    pub fn disable(&mut self, replica: u64) {
+        self.0.add(replica, i64::MAX);
+    }
+
+    /// Disables until `cleanup` reaches `expires`.
+    pub fn disable_until(&mut self, replica: u64, expires: i64) {
+        self.0.add(replica, expires);
+    }
+
+    pub fn enable(&mut self, now: i64) {
+        self.0.clear(now);
+    }
+
+    pub fn value(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Crdt for DwFlag {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.0.merge(&other.0)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        self.0.merge_changed(&other.0)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.0.compact(horizon);
+    }
+}
+
+#[cfg(test)]
+mod flag_tests {
+    use super::*;
+
+    fn merged<T: Crdt + Clone>(a: &T, b: &T) -> (T, T) {
+        let mut ab = a.clone();
+        ab.merge(b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(a).unwrap();
+        (ab, ba)
+    }
+
+    #[test]
+    fn concurrent_enable_wins() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = EwFlag::default();
+        a.enable(x);
+        let mut b = a.clone();
+        b.disable(1);
+        a.enable(x);
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert!(ab.value());
+
+        let mut c = ab.clone();
+        c.disable(2);
+        let mut d = ab.clone();
+        d.enable(y);
+        d.merge(&c).unwrap();
+        assert!(d.value());
+    }
+
+    #[test]
+    fn disable_after_seeing_enables_wins() {
+        let x = replica_id(b"x");
+        let mut a = EwFlag::default();
+        a.enable(x);
+        let mut b = a.clone();
+        b.disable(1);
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert!(!ab.value());
+    }
+
+    #[test]
+    fn enables_expire() {
+        let mut flag = EwFlag::default();
+        flag.enable_until(replica_id(b"x"), 5);
+        flag.cleanup(4);
+        assert!(flag.value());
+        flag.cleanup(5);
+        assert!(!flag.value());
+    }
+
+    #[test]
+    fn concurrent_disable_wins() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = DwFlag::default();
+        assert!(a.value());
+        a.disable(x);
+        let mut b = a.clone();
+        b.enable(1);
+        assert!(b.value());
+        a.disable(y);
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert!(!ab.value());
+    }
+}
+
+/// Identifies a `GrowOnlyLog` entry and orders the log: the turn it was
+/// appended, the appending replica and how many entries it had appended
+/// before.
+pub type LogKey = (i64, u64, u64);
+
+/// An append-only log shared between replicas. `truncated` holds, for each
+/// replica, the last of its entries dropped by `truncate_before`, so merging
+/// with a peer that still has them doesn't bring them back.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrowOnlyLog<T> {
+    pub entries: BTreeMap<LogKey, T>,
+    pub counters: BTreeMap<u64, u64>,
+    pub truncated: BTreeMap<u64, u64>,
+}
+
+impl<T> Default for GrowOnlyLog<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            truncated: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> GrowOnlyLog<T> {
+    /// `replica` must be unique to the writer, see `replica_id`.
+    pub fn append(&mut self, replica: u64, now: i64, value: T) {
+        let counter = self.counters.entry(replica).or_default();
+        *counter += 1;
+        self.entries.insert((now, replica, *counter), value);
+    }
+
+    /// Entries in the order they were appended, with ties between replicas
+    /// broken by replica id.
+    pub fn iter(&self) -> impl Iterator<Item = (&LogKey, &T)> + '_ {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops entries appended before `turn`, here and, once merged, on every
+    /// replica. Assumes each replica appends with a `now` that never goes
+    /// backwards.
+    pub fn truncate_before(&mut self, turn: i64) {
+        let kept = self.entries.split_off(&(turn, 0, 0));
+        for (_, replica, seq) in std::mem::replace(&mut self.entries, kept).into_keys() {
+            let watermark = self.truncated.entry(replica).or_default();
+            *watermark = (*watermark).max(seq);
+        }
+    }
+
+    fn is_truncated(&self, (_, replica, seq): &LogKey) -> bool {
+        self.truncated.get(replica).is_some_and(|watermark| seq <= watermark)
+    }
+}
+
+impl<T: Clone> Crdt for GrowOnlyLog<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (replica, seq) in &other.truncated {
+            let watermark = self.truncated.entry(*replica).or_default();
+            changed |= seq > watermark;
+            *watermark = (*watermark).max(*seq);
+        }
+        let before = self.entries.len();
+        let truncated = &self.truncated;
+        self.entries
+            .retain(|(_, replica, seq), _| truncated.get(replica).is_none_or(|watermark| seq > watermark));
+        changed |= self.entries.len() != before;
+        for (key, value) in &other.entries {
+            if !self.is_truncated(key) && !self.entries.contains_key(key) {
+                self.entries.insert(*key, value.clone());
+                changed = true;
+            }
+        }
+        for (replica, counter) in &other.counters {
+            let local = self.counters.entry(*replica).or_default();
+            *local = (*local).max(*counter);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod grow_only_log_tests {
+    use super::*;
+
+    fn values(log: &GrowOnlyLog<&'static str>) -> Vec<&'static str> {
+        log.iter().map(|(_, v)| *v).collect()
+    }
+
+    #[test]
+    fn merges_in_append_order_without_duplicates() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = GrowOnlyLog::default();
+        a.append(x, 1, "door");
+        let mut b = a.clone();
+        b.append(y, 2, "boss");
+        a.append(x, 3, "loot");
+
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab, ba);
+        assert_eq!(values(&ab), ["door", "boss", "loot"]);
+        assert!(!ab.merge_changed(&a).unwrap());
+    }
+
+    #[test]
+    fn truncated_entries_stay_gone() {
+        let (x, y) = (replica_id(b"x"), replica_id(b"y"));
+        let mut a = GrowOnlyLog::default();
+        a.append(x, 1, "old");
+        a.append(y, 2, "older peer");
+        a.append(x, 5, "new");
+        let untruncated = a.clone();
+
+        a.truncate_before(3);
+        assert_eq!(values(&a), ["new"]);
+        assert!(!a.merge_changed(&untruncated).unwrap());
+        assert_eq!(values(&a), ["new"]);
+
+        let mut b = untruncated.clone();
+        b.append(y, 6, "newer");
+        assert!(b.merge_changed(&a).unwrap());
+        assert_eq!(values(&b), ["new", "newer"]);
+        a.merge(&b).unwrap();
+        assert_eq!(a, b);
+    }
+}
+
+/// Multi-value register. Writes made without seeing each other are all
+/// kept, for the application to resolve, until a write that has seen them
+/// replaces them. `dots` tags each of `values` like `OrSetTag`, and
+/// `context` is the latest counter seen from each replica.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MvRegister<T> {
+    pub values: Vec<T>,
+    pub dots: Vec<OrSetTag>,
+    pub context: BTreeMap<u64, u64>,
+}
+
+impl<T> Default for MvRegister<T> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            dots: Vec::new(),
+            context: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> MvRegister<T> {
+    /// Every concurrent value, ordered by the replica that wrote it.
+    pub fn get(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.values.len() > 1
+    }
+
+    /// Replaces every value this replica has seen. `replica` must be unique
+    /// to the writer, see `replica_id`.
+    pub fn set(&mut self, replica: u64, value: T) {
+        let counter = self.context.entry(replica).or_default();
+        *counter += 1;
+        self.values = vec![value];
+        self.dots = vec![(replica, *counter)];
+    }
+
+    fn has_seen(&self, (replica, counter): OrSetTag) -> bool {
+        self.context.get(&replica).is_some_and(|seen| *seen >= counter)
+    }
+}
+
+impl<T: Clone> Crdt for MvRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        let mut entries: Vec<(OrSetTag, T)> = self
+            .dots
+            .iter()
+            .zip(&self.values)
+            .filter(|(dot, _)| other.dots.contains(dot) || !other.has_seen(**dot))
+            .map(|(dot, v)| (*dot, v.clone()))
+            .collect();
+        for (dot, v) in other.dots.iter().zip(&other.values) {
+            if !self.dots.contains(dot) && !self.has_seen(*dot) {
+                entries.push((*dot, v.clone()));
+            }
+        }
+        entries.sort_by_key(|(dot, _)| *dot);
+        (self.dots, self.values) = entries.into_iter().unzip();
+        for (replica, counter) in &other.context {
+            let local = self.context.entry(*replica).or_default();
+            *local = (*local).max(*counter);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod mv_register_tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_writes_are_kept_until_overwritten() {
+        let mut a = MvRegister::default();
+        a.set(1, "north");
+        let mut b = a.clone();
+        a.set(1, "east");
+        b.set(2, "west");
+
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!(ab, ba);
+        assert_eq!(ab.get(), ["east", "west"]);
+        assert!(ab.is_conflicted());
+
+        let mut resolved = ab.clone();
+        let min = *resolved.get().iter().min().unwrap();
+        resolved.set(2, min);
+        ba.merge(&resolved).unwrap();
+        ab.merge(&resolved).unwrap();
+        assert_eq!(ab, ba);
+        assert_eq!(ab.get(), ["east"]);
+    }
+
+    #[test]
+    fn merging_is_idempotent() {
+        let mut a = MvRegister::default();
+        a.set(1, 5);
+        let before = a.clone();
+        a.merge(&before).unwrap();
+        assert_eq!(a, before);
+        let mut empty = MvRegister::default();
+        empty.merge(&a).unwrap();
+        assert_eq!(empty, a);
+    }
+}
+
+/// Keeps the largest value ever set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaxRegister<T>(pub Option<T>);
+
+/// Keeps the smallest value ever set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinRegister<T>(pub Option<T>);
+
+impl<T> Default for MaxRegister<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T> Default for MinRegister<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T: Ord> MaxRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.0.as_ref()
+    }
+
+    pub fn set(&mut self, v: T) {
+        if self.0.as_ref().is_none_or(|current| v > *current) {
+            self.0 = Some(v);
+        }
+    }
+}
+
+impl<T: Ord> MinRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.0.as_ref()
+    }
+
+    pub fn set(&mut self, v: T) {
+        if self.0.as_ref().is_none_or(|current| v < *current) {
+            self.0 = Some(v);
+        }
+    }
+}
+
+impl<T: Ord + Clone> Crdt for MaxRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if let Some(v) = &other.0 {
+            self.set(v.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<T: Ord + Clone> Crdt for MinRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if let Some(v) = &other.0 {
+            self.set(v.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A `MaxRegister` whose value is forgotten once `now` reaches its expiry.
+/// Setting the current value again extends the expiry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpiringMaxRegister<T> {
+    pub value: Option<T>,
+    pub expires: i64,
+}
+
+/// A `MinRegister` whose value is forgotten once `now` reaches its expiry.
+/// Setting the current value again extends the expiry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpiringMinRegister<T> {
+    pub value: Option<T>,
+    pub expires: i64,
+}
+
+impl<T> Default for ExpiringMaxRegister<T> {
+    fn default() -> Self {
+        Self { value: None, expires: i64::MIN }
+    }
+}
+
+impl<T> Default for ExpiringMinRegister<T> {
+    fn default() -> Self {
+        Self { value: None, expires: i64::MIN }
+    }
+}
+
+impl<T: Ord> ExpiringMaxRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn set(&mut self, v: T, expires: i64) {
+        match &self.value {
+            Some(current) if v == *current => self.expires = self.expires.max(expires),
+            Some(current) if v < *current => {}
+            _ => {
+                self.value = Some(v);
+                self.expires = expires;
+            }
+        }
+    }
+}
+
+impl<T: Ord> ExpiringMinRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn set(&mut self, v: T, expires: i64) {
+        match &self.value {
+            Some(current) if v == *current => self.expires = self.expires.max(expires),
+            Some(current) if v > *current => {}
+            _ => {
+                self.value = Some(v);
+                self.expires = expires;
+            }
+        }
+    }
+}
+
+impl<T: Ord + Clone> Crdt for ExpiringMaxRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if let Some(v) = &other.value {
+            self.set(v.clone(), other.expires);
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        if now >= self.expires {
+            *self = Self::default();
+        }
+    }
+}
+
+impl<T: Ord + Clone> Crdt for ExpiringMinRegister<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if let Some(v) = &other.value {
+            self.set(v.clone(), other.expires);
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        if now >= self.expires {
+            *self = Self::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod extremum_register_tests {
+    use super::*;
+
+    fn random_max() -> ExpiringMaxRegister<u8> {
+        let mut r = ExpiringMaxRegister::default();
+        for _ in 0..fastrand::usize(0..3) {
+            r.set(fastrand::u32(0..4) as u8, fastrand::i64(0..4));
+        }
+        r
+    }
+
+    #[test]
+    fn keeps_extremes() {
+        let mut max = MaxRegister::default();
+        let mut min = MinRegister::default();
+        for v in [3, 1, 4, 1, 5] {
+            max.set(v);
+            min.set(v);
+        }
+        assert_eq!((max.get(), min.get()), (Some(&5), Some(&1)));
+        let mut other = MinRegister::default();
+        other.set(0);
+        min.merge(&other).unwrap();
+        assert_eq!(min.get(), Some(&0));
+    }
+
+    #[test]
+    fn expiring_values_reset() {
+        let mut r = ExpiringMinRegister::default();
+        r.set(4, 10);
+        r.set(6, 20);
+        r.set(4, 12);
+        r.cleanup(11);
+        assert_eq!(r.get(), Some(&4));
+        r.cleanup(12);
+        assert_eq!(r.get(), None);
+    }
+
+    #[test]
+    fn merge_order_never_matters() {
+        fastrand::seed(294);
+        for _ in 0..500 {
+            let (a, b, c) = (random_max(), random_max(), random_max());
+            let merge_all = |order: [&ExpiringMaxRegister<u8>; 3]| {
+                let mut r = order[0].clone();
+                r.merge(order[1]).unwrap();
+                r.merge(order[2]).unwrap();
+                r
+            };
+            let abc = merge_all([&a, &b, &c]);
+            assert_eq!(abc, merge_all([&c, &a, &b]));
+            assert_eq!(abc, merge_all([&b, &c, &a]));
+            assert_eq!(abc, merge_all([&a, &a, &abc]));
+
+            let (v, expires) = (fastrand::u32(0..4) as u8, fastrand::i64(0..4));
+            let mut set_then_merge = a.clone();
+            set_then_merge.set(v, expires);
+            set_then_merge.merge(&b).unwrap();
+            let mut merge_then_set = a.clone();
+            merge_then_set.merge(&b).unwrap();
+            merge_then_set.set(v, expires);
+            assert_eq!(set_then_merge, merge_then_set);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lww;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fww;
+
+/// A plain value kept by the same rules as a `CrdtMap` entry: the latest
+/// write wins under `Lww`, the earliest under `Fww`, and writes on the same
+/// turn go to the larger or smaller value. Derefs to the value, if any.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CrdtValue<T, P> {
+    pub value: Option<T>,
+    pub written: i64,
+    policy: PhantomData<P>,
+}
+
+pub type LwwValue<T> = CrdtValue<T, Lww>;
+pub type FwwValue<T> = CrdtValue<T, Fww>;
+
+impl<T, P> Default for CrdtValue<T, P> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            written: 0,
+            policy: PhantomData,
+        }
+    }
+}
+
+impl<T, P> std::ops::Deref for CrdtValue<T, P> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Option<T> {
+        &self.value
+    }
+}
+
+impl<T: PartialOrd, P> CrdtValue<T, P> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// When the current value was written.
+    pub fn written_at(&self) -> Option<i64> {
+        self.value.as_ref().map(|_| self.written)
+    }
+
+    /// Takes `value` if it would win a merge against the current one.
+    fn write(&mut self, value: T, written: i64, keep: std::cmp::Ordering) -> bool {
+        let wins = self.value.as_ref().is_none_or(|current| {
+            written
+                .cmp(&self.written)
+                .then_with(|| value.partial_cmp(current).unwrap_or(std::cmp::Ordering::Equal))
+                == keep
+        });
+        if wins {
+            self.value = Some(value);
+            self.written = written;
+        }
+        wins
+    }
+
+    fn merge_with(&mut self, other: &Self, keep: std::cmp::Ordering) -> bool
+    where
+        T: Clone,
+    {
+        other.value.as_ref().is_some_and(|value| self.write(value.clone(), other.written, keep))
+    }
+}
+
+impl<T: PartialOrd> CrdtValue<T, Lww> {
+    /// Says whether `value` was taken, which it is unless something was
+    /// written later, or on the same turn with a larger value.
+    pub fn set(&mut self, value: T, now: i64) -> bool {
+        self.write(value, now, std::cmp::Ordering::Greater)
+    }
+}
+
+impl<T: PartialOrd> CrdtValue<T, Fww> {
+    /// Says whether `value` was taken, which it isn't once anything was
+    /// written before `now`, or on the same turn with a smaller value.
+    pub fn set(&mut self, value: T, now: i64) -> bool {
+        self.write(value, now, std::cmp::Ordering::Less)
+    }
+}
+
+impl<T: PartialOrd + Clone> Crdt for CrdtValue<T, Lww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        Ok(self.merge_with(other, std::cmp::Ordering::Greater))
+    }
+}
+
+impl<T: PartialOrd + Clone> Crdt for CrdtValue<T, Fww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        Ok(self.merge_with(other, std::cmp::Ordering::Less))
+    }
+}
+
+#[cfg(test)]
+mod crdt_value_tests {
+    use super::*;
+
+    #[test]
+    fn lww_values_take_the_latest_write() {
+        let mut v = LwwValue::default();
+        assert!(v.is_none());
+        assert!(v.set(3, 1));
+        assert!(v.set(1, 2));
+        assert!(!v.set(5, 1));
+        assert_eq!(*v, Some(1));
+        assert!(v.set(4, 2));
+        assert!(!v.set(2, 2));
+        assert_eq!((v.get(), v.written_at()), (Some(&4), Some(2)));
+    }
+
+    #[test]
+    fn fww_values_keep_the_first_write() {
+        let mut v = FwwValue::default();
+        assert!(v.set("b".to_string(), 5));
+        assert!(!v.set("c".to_string(), 6));
+        assert!(v.set("a".to_string(), 5));
+        assert!(v.set("z".to_string(), 4));
+        assert_eq!(v.as_deref(), Some("z"));
+    }
+
+    #[test]
+    fn laws_and_convergence() {
+        let mut lww: Vec<LwwValue<i32>> = vec![LwwValue::default(); 4];
+        let mut fww: Vec<FwwValue<i32>> = vec![FwwValue::default(); 4];
+        for (i, (value, now)) in [(1, 0), (2, 0), (1, 3)].into_iter().enumerate() {
+            lww[i].set(value, now);
+            fww[i].set(value, now);
+        }
+        testing::assert_crdt_laws(lww);
+        testing::assert_crdt_laws(fww);
+
+        let set = |v: &mut LwwValue<u32>, _: usize, now: i64| {
+            v.set(fastrand::u32(0..4), now - fastrand::i64(0..3));
+        };
+        testing::assert_converges(LwwValue::default(), 3, 40, &[&set], 322);
+        let set = |v: &mut FwwValue<u32>, _: usize, now: i64| {
+            v.set(fastrand::u32(0..4), now - fastrand::i64(0..3));
+        };
+        testing::assert_converges(FwwValue::default(), 3, 40, &[&set], 322);
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, CrdtContainer)]
+    #[crdt(crate = "crate")]
+    struct Plans {
+        #[crdt]
+        target: LwwValue<i64>,
+        #[crdt]
+        leader: FwwValue<String>,
+    }
+
+    #[test]
+    fn plain_values_inside_a_container() {
+        let mut a = Plans::default();
+        let mut b = Plans::default();
+        a.target.set(10, 1);
+        a.leader.set("ann".to_string(), 1);
+        b.target.set(20, 2);
+        b.leader.set("bob".to_string(), 2);
+        let before = a.clone();
+        a.merge(&b).unwrap();
+        b.merge(&before).unwrap();
+        assert_eq!(a, b);
+        assert_eq!((*a.target, a.leader.as_deref()), (Some(20), Some("ann")));
+    }
+}
+
+pub struct CrdtMapIter<'a, K, V>(std::collections::btree_map::Range<'a, K, (V, i64)>);
+
+impl<'a, K, V> Iterator for CrdtMapIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((k, (v, _))) = self.0.next() {
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+}
+
+/// How long `CrdtMap` keeps tombstones by default. A replica that's been
+/// out of contact for longer can bring removed entries back.
+pub const DEFAULT_TOMBSTONE_HORIZON: i64 = 1000;
+
+/// A map whose entries are kept or replaced by policy `P`. A tombstone
+/// removes every write made at or before it, under both policies.
+///
+/// Writes made on the same turn are ordered by writer, so the same ally's
+/// write wins every such tie. Values are only compared between entries
+/// from the same writer, or written with plain `insert`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrdtMap<K: Ord, V, P> {
+    /// Values with the turn they were written.
+    pub entries: BTreeMap<K, (V, i64)>,
+    policy: PhantomData<P>,
+    /// The turn each removed key was removed.
+    #[serde(deserialize_with = "added_field")]
+    pub tombstones: BTreeMap<K, i64>,
+    /// How many turns `cleanup` keeps tombstones for.
+    #[serde(deserialize_with = "added_horizon")]
+    pub tombstone_horizon: i64,
+    #[serde(deserialize_with = "added_field")]
+    pub versions: Versions<K>,
+    /// Who wrote the entries that say, see `insert_with_writer`. Maps from
+    /// older clients, read with `decoding_bare`, have none.
+    #[serde(deserialize_with = "added_field")]
+    pub writers: BTreeMap<K, u64>,
+}
+
+/// Like `added_field`, for maps from before tombstones had a horizon.
+fn added_horizon<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    if READING_BARE.with(Cell::get) {
+        Ok(DEFAULT_TOMBSTONE_HORIZON)
+    } else {
+        i64::deserialize(deserializer)
+    }
+}
+
+impl<K: Ord, V, P> Default for CrdtMap<K, V, P> {
+    fn default() -> Self {
+        Self::with_tombstone_horizon(DEFAULT_TOMBSTONE_HORIZON)
+    }
+}
+
+impl<K: Ord, V, P> CrdtMap<K, V, P> {
+    pub fn with_tombstone_horizon(horizon: i64) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            policy: PhantomData,
+            tombstones: BTreeMap::new(),
+            tombstone_horizon: horizon,
+            versions: Versions::default(),
+            writers: BTreeMap::new(),
+        }
+    }
+
+    fn is_removed(&self, k: &K, written: i64) -> bool {
+        self.tombstones.get(k).is_some_and(|removed| *removed >= written)
+    }
+
+    fn forget_tombstones(&mut self, now: i64) {
+        self.forget_tombstones_before(now.saturating_sub(self.tombstone_horizon));
+    }
+
+    fn forget_tombstones_before(&mut self, oldest: i64) {
+        self.tombstones.retain(|_, removed| *removed >= oldest);
+        let (entries, tombstones) = (&self.entries, &self.tombstones);
+        self.versions.retain(|k| entries.contains_key(k) || tombstones.contains_key(k));
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.entries.get(k).map(|(v, _)| v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> CrdtMapIter<K, V> {
+        self.range(..)
+    }
+
+    pub fn range(&self, range: impl std::ops::RangeBounds<K>) -> CrdtMapIter<K, V> {
+        CrdtMapIter(self.entries.range(range))
+    }
+
+    /// `k`'s value, or the default without inserting it.
+    pub fn get_or_default(&self, k: &K) -> std::borrow::Cow<'_, V>
+    where
+        V: Default + Clone,
+    {
+        self.get(k).map_or_else(|| std::borrow::Cow::Owned(V::default()), std::borrow::Cow::Borrowed)
+    }
+
+}
+
+impl<K: Ord + Clone, V: Clone, P> Clone for CrdtMap<K, V, P> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            policy: PhantomData,
+            tombstones: self.tombstones.clone(),
+            tombstone_horizon: self.tombstone_horizon,
+            versions: self.versions.clone(),
+            writers: self.writers.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V, P> CrdtMap<K, V, P> {
+    /// Ignored if `k` was removed at or after `now`. Writing what's already
+    /// there leaves the version alone.
+    pub fn insert(&mut self, k: K, v: V, now: i64)
+    where
+        V: PartialEq,
+    {
+        self.write(k, v, now, None);
+    }
+
+    /// Like `insert`, with ties against writes made on the same turn broken
+    /// by `writer`, see `replica_id`.
+    pub fn insert_with_writer(&mut self, k: K, v: V, now: i64, writer: u64)
+    where
+        V: PartialEq,
+    {
+        self.write(k, v, now, Some(writer));
+    }
+
+    fn write(&mut self, k: K, v: V, now: i64, writer: Option<u64>)
+    where
+        V: PartialEq,
+    {
+        if self.tombstones.get(&k).is_some_and(|removed| *removed >= now) {
+            return;
+        }
+        let unchanged = self.entries.get(&k).is_some_and(|(old, written)| *written == now && *old == v);
+        if unchanged && self.writer(&k) == writer {
+            return;
+        }
+        match writer {
+            Some(writer) => self.writers.insert(k.clone(), writer),
+            None => self.writers.remove(&k),
+        };
+        self.entries.insert(k.clone(), (v, now));
+        self.versions.touch(k);
+    }
+
+    pub fn writer(&self, k: &K) -> Option<u64> {
+        self.writers.get(k).copied()
+    }
+
+    pub fn remove(&mut self, k: K, now: i64) {
+        let mut changed = false;
+        if self.entries.get(&k).is_some_and(|(_, written)| *written <= now) {
+            self.entries.remove(&k);
+            self.writers.remove(&k);
+            changed = true;
+        }
+        if self.tombstones.get(&k).is_none_or(|removed| *removed < now) {
+            self.tombstones.insert(k.clone(), now);
+            changed = true;
+        }
+        if changed {
+            self.versions.touch(k);
+        }
+    }
+
+    /// Merges `other`'s tombstones in and drops the entries they remove,
+    /// returning whether any tombstone was new.
+    fn merge_tombstones(&mut self, other: &Self, on_event: &mut impl FnMut(MergeEvent<K, V>)) -> bool {
+        let mut changed = false;
+        for (k, removed) in &other.tombstones {
+            if self.tombstones.get(k).is_none_or(|local| local < removed) {
+                self.tombstones.insert(k.clone(), *removed);
+                self.versions.touch(k.clone());
+                changed = true;
+            }
+        }
+        if changed {
+            let removed: Vec<K> = self
+                .entries
+                .iter()
+                .filter(|(k, (_, written))| self.is_removed(k, *written))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in removed {
+                self.writers.remove(&k);
+                if let Some((old, _)) = self.entries.remove(&k) {
+                    on_event(MergeEvent::Removed { key: k, old });
+                }
+            }
+        }
+        changed
+    }
+
+    /// Merges `other` in, keeping whichever entry's `(written, writer,
+    /// value)` compares as `keep` against the other.
+    fn merge_entries(&mut self, other: &Self, keep: std::cmp::Ordering, on_event: &mut impl FnMut(MergeEvent<K, V>)) -> bool
+    where
+        V: PartialOrd + Clone,
+    {
+        let mut changed = self.merge_tombstones(other, on_event);
+        for (k, (v, written)) in &other.entries {
+            if self.is_removed(k, *written) {
+                continue;
+            }
+            let writer = other.writer(k);
+            let wins = self.entries.get(k).is_none_or(|(lv, lw)| {
+                (*written, writer)
+                    .cmp(&(*lw, self.writer(k)))
+                    .then_with(|| v.partial_cmp(lv).unwrap_or(std::cmp::Ordering::Equal))
+                    == keep
+            });
+            if wins {
+                let event = match self.merged(k, v.clone(), *written, writer) {
+                    Some(old) => MergeEvent::Updated { key: k.clone(), old, new: v.clone() },
+                    None => MergeEvent::Inserted { key: k.clone(), value: v.clone() },
+                };
+                on_event(event);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Sets an entry that won a merge, noting the change. Returns the value
+    /// it replaced.
+    fn merged(&mut self, k: &K, v: V, written: i64, writer: Option<u64>) -> Option<V> {
+        match writer {
+            Some(writer) => self.writers.insert(k.clone(), writer),
+            None => self.writers.remove(k),
+        };
+        self.versions.touch(k.clone());
+        self.entries.insert(k.clone(), (v, written)).map(|(old, _)| old)
+    }
+
+    /// Tombstones, then entries, from `start` on, for `merge_bounded`.
+    fn part(&self, start: usize, max_entries: usize) -> (Self, MergeProgress)
+    where
+        V: Clone,
+    {
+        let tombstones: BTreeMap<K, i64> =
+            self.tombstones.iter().skip(start).take(max_entries).map(|(k, removed)| (k.clone(), *removed)).collect();
+        let entries: BTreeMap<K, (V, i64)> = self
+            .entries
+            .iter()
+            .skip(start.saturating_sub(self.tombstones.len()))
+            .take(max_entries - tombstones.len())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let writers = entries.keys().filter_map(|k| Some((k.clone(), self.writer(k)?))).collect();
+        let progress = MergeProgress::through(start, max_entries, self.tombstones.len() + self.entries.len());
+        let part = Self {
+            entries,
+            policy: PhantomData,
+            tombstones,
+            tombstone_horizon: self.tombstone_horizon,
+            versions: Versions::default(),
+            writers,
+        };
+        (part, progress)
+    }
+
+    fn delta(&self, version: u64) -> Self
+    where
+        V: Clone,
+    {
+        let newer = |k: &&K| self.versions.is_newer(k, version);
+        Self {
+            entries: self.entries.iter().filter(|(k, _)| newer(k)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+            policy: PhantomData,
+            tombstones: self.tombstones.iter().filter(|(k, _)| newer(k)).map(|(k, removed)| (k.clone(), *removed)).collect(),
+            tombstone_horizon: self.tombstone_horizon,
+            versions: Versions::default(),
+            writers: self.writers.iter().filter(|(k, _)| newer(k)).map(|(k, writer)| (k.clone(), *writer)).collect(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> CrdtMap<K, V, Lww> {
+    /// Like `merge`, calling `on_event` with each entry added, replaced or
+    /// removed: removals first, then the rest in key order.
+    pub fn merge_with_events(&mut self, other: &Self, on_event: &mut impl FnMut(MergeEvent<K, V>)) -> Result<()> {
+        self.merge_entries(other, std::cmp::Ordering::Greater, on_event);
+        Ok(())
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> CrdtMap<K, V, Fww> {
+    /// Like `merge`, calling `on_event` with each entry added, replaced or
+    /// removed: removals first, then the rest in key order.
+    pub fn merge_with_events(&mut self, other: &Self, on_event: &mut impl FnMut(MergeEvent<K, V>)) -> Result<()> {
+        self.merge_entries(other, std::cmp::Ordering::Less, on_event);
+        Ok(())
+    }
+}
+
+impl<K: Ord, V, P> std::ops::Index<&K> for CrdtMap<K, V, P> {
+    type Output = V;
+
+    fn index(&self, k: &K) -> &V {
+        &self.entries.get(k).expect("key not present in CrdtMap").0
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for CrdtMap<K, V, Lww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        Ok(self.merge_entries(other, std::cmp::Ordering::Greater, &mut |_| {}))
+    }
+
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        let (part, progress) = other.part(progress.done, max_entries);
+        self.merge_entries(&part, std::cmp::Ordering::Greater, &mut |_| {});
+        Ok(progress)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.forget_tombstones(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.forget_tombstones_before(horizon);
+    }
+
+    fn version(&self) -> u64 {
+        self.versions.version
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        Some(self.delta(version))
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for CrdtMap<K, V, Fww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        Ok(self.merge_entries(other, std::cmp::Ordering::Less, &mut |_| {}))
+    }
+
+    fn merge_bounded(&mut self, other: &Self, progress: MergeProgress, max_entries: usize) -> Result<MergeProgress> {
+        let (part, progress) = other.part(progress.done, max_entries);
+        self.merge_entries(&part, std::cmp::Ordering::Less, &mut |_| {});
+        Ok(progress)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.forget_tombstones(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.forget_tombstones_before(horizon);
+    }
+
+    fn version(&self) -> u64 {
+        self.versions.version
+    }
+
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        Some(self.delta(version))
+    }
+}
+
+/// The key ranges covering `rect`, a column at a time. Locs are ordered by
+/// `x` and then `y`, so `y` is the fast axis and each column is one range.
+pub fn rect_columns(rect: Rect) -> impl Iterator<Item = std::ops::RangeInclusive<Loc>> {
+    let columns = (!rect.is_empty()).then_some(rect.min.x..=rect.max.x);
+    columns.into_iter().flatten().map(move |x| Loc { x, y: rect.min.y }..=Loc { x, y: rect.max.y })
+}
+
+/// Every loc with `x` in `min_x..=max_x`, the keys of which are contiguous.
+pub fn loc_columns(min_x: i32, max_x: i32) -> std::ops::RangeInclusive<Loc> {
+    Loc { x: min_x, y: i32::MIN }..=Loc { x: max_x, y: i32::MAX }
+}
+
+impl<V, P> CrdtMap<Loc, V, P> {
+    /// Only visits entries inside `rect`, column by column.
+    pub fn locs_in_rect(&self, rect: Rect) -> impl Iterator<Item = (&Loc, &V)> + '_ {
+        rect_columns(rect).flat_map(|column| self.range(column))
+    }
+}
+
+impl<V, P> LocSet for CrdtMap<Loc, V, P> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.entries.contains_key(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::new(self.entries.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl<P> LocMap for CrdtMap<Loc, bool, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.entries.get(loc).copied().map(|(l, _)| l)
+    }
+}
+
+impl<P> LocMap for CrdtMap<Loc, f32, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.entries.get(loc).map(|(cost, _)| cost.is_finite())
+    }
+
+    fn cost_at(&self, loc: &Loc) -> f32 {
+        self.entries.get(loc).map(|(cost, _)| *cost).unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod crdt_map_tests {
+    use super::*;
+
+    #[test]
+    fn range_queries_visit_only_matching_entries() {
+        let mut names: CrdtMap<String, u32, Lww> = CrdtMap::default();
+        for name in ["apple", "apricot", "banana", "ap", "aq"] {
+            names.insert(name.to_string(), 0, 0);
+        }
+        let prefixed: Vec<_> = names.range("ap".to_string().."aq".to_string()).map(|(k, _)| k.as_str()).collect();
+        assert_eq!(prefixed, ["ap", "apple", "apricot"]);
+
+        let mut seen: CrdtMap<Loc, u32, Lww> = CrdtMap::default();
+        let mut expiring: ExpiringCrdtMap<Loc, u32, Lww> = ExpiringCrdtMap::default();
+        for loc in Rect::new(Loc { x: -20, y: -20 }, Loc { x: 20, y: 20 }).iter() {
+            seen.insert(loc, 0, 0);
+            expiring.insert(loc, 0, 0, 10);
+        }
+        let rect = Rect::new(Loc { x: -3, y: 5 }, Loc { x: 2, y: 7 });
+        let inside: Vec<Loc> = seen.locs_in_rect(rect).map(|(loc, _)| *loc).collect();
+        assert_eq!(inside.len(), 18);
+        assert!(inside.iter().all(|loc| rect.contains(*loc)));
+        assert_eq!(expiring.locs_in_rect(rect).count(), 18);
+        assert_eq!(seen.locs_in_rect(Rect::new(Loc { x: 0, y: 1 }, Loc { x: 3, y: 0 })).count(), 0);
+        assert_eq!(seen.range(loc_columns(19, 30)).count(), 82);
+    }
+
+    #[test]
+    fn merge_events_describe_each_change() {
+        let mut mine: CrdtMap<&str, u32, Lww> = CrdtMap::default();
+        mine.insert("target", 1, 0);
+        mine.insert("kept", 2, 5);
+        mine.insert("lost", 3, 0);
+        let mut theirs: CrdtMap<&str, u32, Lww> = CrdtMap::default();
+        theirs.insert("target", 7, 2);
+        theirs.insert("kept", 9, 1);
+        theirs.insert("new", 4, 2);
+        theirs.remove("lost", 1);
+
+        let mut events = Vec::new();
+        mine.merge_with_events(&theirs, &mut |e| events.push(e)).unwrap();
+        assert_eq!(
+            events,
+            [
+                MergeEvent::Removed { key: "lost", old: 3 },
+                MergeEvent::Inserted { key: "new", value: 4 },
+                MergeEvent::Updated { key: "target", old: 1, new: 7 },
+            ]
+        );
+        assert_eq!(mine.get(&"kept"), Some(&2));
+
+        let mut fww: CrdtMap<&str, u32, Fww> = CrdtMap::default();
+        fww.insert("target", 1, 3);
+        let mut first = CrdtMap::default();
+        first.insert("target", 5, 1);
+        let mut events = Vec::new();
+        fww.merge_with_events(&first, &mut |e| events.push(e)).unwrap();
+        assert_eq!(events, [MergeEvent::Updated { key: "target", old: 1, new: 5 }]);
+    }
+
+    #[test]
+    fn index_and_default_access() {
+        let mut m: CrdtMap<i32, String, Lww> = CrdtMap::default();
+        m.insert(1, "one".to_string(), 0);
+        assert_eq!(m[&1], "one");
+        let version = m.version();
+        assert!(matches!(m.get_or_default(&1), std::borrow::Cow::Borrowed(v) if v == "one"));
+        assert_eq!(*m.get_or_default(&2), "");
+        assert_eq!((m.len(), m.version()), (1, version));
+    }
+
+    #[test]
+    fn lookups_through_shared_refs() {
+        let mut m: CrdtMap<i32, String, Lww> = CrdtMap::default();
+        m.insert(1, "one".to_string(), 0);
+        let shared = &m;
+        assert!(shared.contains_key(&1));
+        assert_eq!(shared.get(&1).map(String::as_str), Some("one"));
+        assert_eq!(shared.get(&2), None);
+        assert_eq!(shared.len(), 1);
+        assert!(!shared.is_empty());
+
+        let mut s = ExpiringSet::default();
+        s.insert(3, 10);
+        let shared = &s;
+        assert!(shared.contains(&3));
+        assert_eq!(shared.get(&3), Some((&3, 10)));
+        assert_eq!(shared.iter().collect::<Vec<_>>(), [(&3, 10)]);
+    }
+
+    #[test]
+    fn removals_skip_iteration() {
+        let mut m: CrdtMap<Loc, bool, Lww> = CrdtMap::default();
+        let (a, b) = (Loc { x: 0, y: 0 }, Loc { x: 1, y: 0 });
+        m.insert(a, true, 0);
+        m.insert(b, true, 0);
+        m.remove(a, 1);
+        assert!(!m.contains_key(&a));
+        assert_eq!(LocSet::iter(&m).collect::<Vec<_>>(), [b]);
+        assert_eq!(m.get_loc(&a), None);
+        m.insert(a, false, 1);
+        assert!(!m.contains_key(&a));
+        m.insert(a, false, 2);
+        assert_eq!(m.get(&a), Some(&false));
+    }
+
+    fn interleaved<P>() -> (CrdtMap<i32, &'static str, P>, CrdtMap<i32, &'static str, P>) {
+        let mut a = CrdtMap::default();
+        let mut b = CrdtMap::default();
+        a.insert(1, "a1", 0);
+        b.insert(1, "b1", 1);
+        a.remove(1, 2);
+        b.insert(2, "b2", 0);
+        a.insert(2, "a2", 3);
+        b.remove(2, 1);
+        a.insert(3, "a3", 1);
+        b.insert(3, "b3", 4);
+        b.remove(3, 2);
+        a.insert(4, "a4", 5);
+        b.remove(4, 5);
+        (a, b)
+    }
+
+    fn converge<P>(a: &CrdtMap<i32, &'static str, P>, b: &CrdtMap<i32, &'static str, P>) -> BTreeMap<i32, &'static str>
+    where
+        CrdtMap<i32, &'static str, P>: Crdt,
+    {
+        let mut ab = a.clone();
+        ab.merge(b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(a).unwrap();
+        assert_eq!(ab.entries, ba.entries);
+        assert_eq!(ab.tombstones, ba.tombstones);
+        ab.iter().map(|(k, v)| (*k, *v)).collect()
+    }
+
+    #[test]
+    fn lww_removals_converge() {
+        let (a, b) = interleaved::<Lww>();
+        assert_eq!(converge(&a, &b), BTreeMap::from([(2, "a2"), (3, "b3")]));
+    }
+
+    #[test]
+    fn fww_removals_converge() {
+        let (a, b) = interleaved::<Fww>();
+        assert_eq!(converge(&a, &b), BTreeMap::from([(2, "a2"), (3, "b3")]));
+    }
+
+    #[test]
+    fn old_tombstones_are_forgotten() {
+        let mut m: CrdtMap<i32, i32, Lww> = CrdtMap::with_tombstone_horizon(10);
+        m.remove(1, 0);
+        m.remove(2, 5);
+        m.cleanup(12);
+        assert_eq!(m.tombstones.keys().collect::<Vec<_>>(), [&2]);
+    }
+
+    #[test]
+    fn deltas_carry_entries_and_tombstones() {
+        let mut a: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        a.insert(1, 10, 0);
+        a.insert(2, 20, 0);
+        let mut b = a.clone();
+        let version = a.version();
+        a.insert(3, 30, 1);
+        a.remove(1, 1);
+
+        let delta = a.delta_since(version).unwrap();
+        assert_eq!(delta.entries.keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!(delta.tombstones.keys().collect::<Vec<_>>(), [&1]);
+        b.apply_delta(&delta).unwrap();
+        assert_eq!(b.entries, a.entries);
+        assert_eq!(b.tombstones, a.tombstones);
+        assert!(b.version() > version);
+    }
+
+    #[test]
+    fn rewrites_leave_the_version_alone() {
+        let mut m: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        m.insert(1, 10, 0);
+        m.remove(2, 0);
+        let version = m.version();
+        m.insert(1, 10, 0);
+        m.remove(2, 0);
+        assert_eq!(m.version(), version);
+        assert!(m.delta_since(version).unwrap().entries.is_empty());
+        m.insert(1, 10, 1);
+        assert!(m.version() > version);
+    }
+
+    #[test]
+    fn merge_reports_changes() {
+        let mut a: CrdtMap<i32, i32, Fww> = CrdtMap::default();
+        a.insert(1, 10, 0);
+        let mut b = a.clone();
+        assert!(!b.merge_changed(&a).unwrap());
+        a.insert(1, 5, 1);
+        assert!(!b.merge_changed(&a).unwrap());
+        a.remove(2, 1);
+        assert!(b.merge_changed(&a).unwrap());
+        a.insert(3, 30, 2);
+        assert!(b.merge_changed(&a).unwrap());
+        assert!(!b.merge_changed(&a).unwrap());
+    }
+
+    fn same_turn_writes<P>() -> Vec<CrdtMap<i32, Vec<i32>, P>> {
+        let writers = [replica_id(b"x"), replica_id(b"y"), replica_id(b"z")];
+        writers
+            .iter()
+            .enumerate()
+            .map(|(i, writer)| {
+                let mut m = CrdtMap::default();
+                m.insert_with_writer(1, vec![i as i32; 3 - i], 5, *writer);
+                m
+            })
+            .collect()
+    }
+
+    fn merge_in_every_order<P>(replicas: &[CrdtMap<i32, Vec<i32>, P>]) -> Vec<(Vec<i32>, Option<u64>)>
+    where
+        CrdtMap<i32, Vec<i32>, P>: Crdt,
+    {
+        let orders = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+        orders
+            .iter()
+            .map(|order| {
+                let mut m = replicas[order[0]].clone();
+                m.merge(&replicas[order[1]]).unwrap();
+                m.merge(&replicas[order[2]]).unwrap();
+                (m[&1].clone(), m.writer(&1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_turn_ties_go_to_the_writer() {
+        let writers = [replica_id(b"x"), replica_id(b"y"), replica_id(b"z")];
+        let lww = merge_in_every_order(&same_turn_writes::<Lww>());
+        assert!(lww.iter().all(|result| *result == lww[0]));
+        assert_eq!(lww[0].1, writers.iter().max().copied());
+        let fww = merge_in_every_order(&same_turn_writes::<Fww>());
+        assert!(fww.iter().all(|result| *result == fww[0]));
+        assert_eq!(fww[0].1, writers.iter().min().copied());
+    }
+
+    #[test]
+    fn entries_without_writers_still_tie_by_value() {
+        let mut a: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        a.insert(1, 10, 0);
+        let mut b: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        b.insert(1, 20, 0);
+        let mut ab = a.clone();
+        ab.merge(&b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        assert_eq!((ab[&1], ba[&1]), (20, 20));
+
+        b.insert_with_writer(1, 5, 0, replica_id(b"x"));
+        ab.merge(&b).unwrap();
+        assert_eq!((ab[&1], ab.writer(&1)), (5, Some(replica_id(b"x"))));
+    }
+
+    #[test]
+    fn bounded_merges_add_up_to_a_full_merge() {
+        let mut other: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        for k in 0..20 {
+            other.insert(k, k * 10, k as i64);
+        }
+        for k in 0..5 {
+            other.remove(k * 3, 30);
+        }
+        let mut local: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        local.insert(1, -1, 50);
+        local.insert(100, 0, 0);
+        let mut full = local.clone();
+        full.merge(&other).unwrap();
+
+        let mut progress = MergeProgress::default();
+        let mut steps = 0;
+        while !progress.finished {
+            progress = local.merge_bounded(&other, progress, 4).unwrap();
+            steps += 1;
+        }
+        assert_eq!(steps, 5);
+        assert_eq!(progress.done, 20);
+        assert_eq!((&local.entries, &local.tombstones), (&full.entries, &full.tombstones));
+
+        let mut partial = CrdtMap::default();
+        partial.merge_bounded(&other, MergeProgress::default(), 7).unwrap();
+        partial.merge(&other).unwrap();
+        let mut expected: CrdtMap<i32, i32, Lww> = CrdtMap::default();
+        expected.merge(&other).unwrap();
+        assert_eq!((&partial.entries, &partial.tombstones), (&expected.entries, &expected.tombstones));
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_missing_key_panics() {
+        let m: CrdtMap<i32, String, Lww> = CrdtMap::default();
+        let _ = &m[&1];
+    }
+}
+
+/// A `CrdtMap` whose entries also carry an expiry, after which `cleanup`
+/// drops them. Merging two copies of the same value keeps the later expiry,
+/// as `ExpiringSet` does; otherwise the policy picks the winner as usual.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExpiringCrdtMap<K: Ord, V, P>(pub BTreeMap<K, (V, i64, i64)>, PhantomData<P>);
+
+impl<K: Ord + Clone, V: Clone, P> Clone for ExpiringCrdtMap<K, V, P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<K: Ord, V, P> Default for ExpiringCrdtMap<K, V, P> {
+    fn default() -> Self {
+        Self(BTreeMap::new(), PhantomData)
+    }
+}
+
+impl<K: Ord, V, P> ExpiringCrdtMap<K, V, P> {
+    pub fn insert(&mut self, k: K, v: V, now: i64, expires: i64) {
+        self.0.insert(k, (v, now, expires));
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.0.contains_key(k)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.0.get(k).map(|(v, _, _)| v)
+    }
+
+    pub fn expires_at(&self, k: &K) -> Option<i64> {
+        self.0.get(k).map(|(_, _, expires)| *expires)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.0.iter().map(|(k, (v, _, _))| (k, v))
+    }
+
+    pub fn range(&self, range: impl std::ops::RangeBounds<K>) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.0.range(range).map(|(k, (v, _, _))| (k, v))
+    }
+
+    fn merge_with(&mut self, other: &Self, prefer_other: impl Fn(i64, &V, i64, &V) -> bool)
+    where
+        K: Clone,
+        V: PartialEq + Clone,
+    {
+        for (k, (v, written, expires)) in &other.0 {
+            if let Some((lv, lw, le)) = self.0.get_mut(k) {
+                if lv == v {
+                    if prefer_other(*lw, lv, *written, v) {
+                        *lw = *written;
+                    }
+                    *le = (*le).max(*expires);
+                } else if prefer_other(*lw, lv, *written, v) {
+                    *lv = v.clone();
+                    *lw = *written;
+                    *le = *expires;
+                }
+            } else {
+                self.0.insert(k.clone(), (v.clone(), *written, *expires));
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for ExpiringCrdtMap<K, V, Lww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_with(other, |lw, lv, w, v| lw < w || (lw == w && lv < v));
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.retain(|_, (_, _, expires)| *expires > now);
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for ExpiringCrdtMap<K, V, Fww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_with(other, |lw, lv, w, v| lw > w || (lw == w && lv > v));
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.retain(|_, (_, _, expires)| *expires > now);
+    }
+}
+
+impl<V, P> ExpiringCrdtMap<Loc, V, P> {
+    /// Only visits entries inside `rect`, column by column.
+    pub fn locs_in_rect(&self, rect: Rect) -> impl Iterator<Item = (&Loc, &V)> + '_ {
+        rect_columns(rect).flat_map(|column| self.range(column))
+    }
+}
+
+impl<V, P> LocSet for ExpiringCrdtMap<Loc, V, P> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_key(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::new(self.0.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<P> LocMap for ExpiringCrdtMap<Loc, bool, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.0.get(loc).map(|(passable, _, _)| *passable)
+    }
+}
+
+impl<P> LocMap for ExpiringCrdtMap<Loc, f32, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.0.get(loc).map(|(cost, _, _)| cost.is_finite())
+    }
+
+    fn cost_at(&self, loc: &Loc) -> f32 {
+        self.0.get(loc).map(|(cost, _, _)| *cost).unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod expiring_crdt_map_tests {
+    use super::*;
+
+    #[test]
+    fn cleanup_drops_expired_entries() {
+        let mut m: ExpiringCrdtMap<i32, &str, Lww> = ExpiringCrdtMap::default();
+        m.insert(1, "a", 0, 5);
+        m.insert(2, "b", 0, 10);
+        m.cleanup(5);
+        assert!(!m.contains_key(&1));
+        assert_eq!(m.get(&2), Some(&"b"));
+    }
+
+    #[test]
+    fn refreshed_entries_survive_merge_with_expired_copies() {
+        let mut stale: ExpiringCrdtMap<i32, &str, Lww> = ExpiringCrdtMap::default();
+        stale.insert(1, "sword", 0, 5);
+        let mut fresh: ExpiringCrdtMap<i32, &str, Lww> = ExpiringCrdtMap::default();
+        fresh.insert(1, "sword", 4, 20);
+
+        let mut a = ExpiringCrdtMap(stale.0.clone(), PhantomData);
+        a.merge(&fresh).unwrap();
+        let mut b = ExpiringCrdtMap(fresh.0.clone(), PhantomData);
+        b.merge(&stale).unwrap();
+        assert_eq!(a.0, b.0);
+        a.cleanup(10);
+        assert_eq!(a.expires_at(&1), Some(20));
+
+        // Once the stale copy has been cleaned up it doesn't come back.
+        stale.cleanup(10);
+        b.merge(&stale).unwrap();
+        stale.merge(&b).unwrap();
+        assert_eq!(stale.0, b.0);
+    }
+
+    #[test]
+    fn fww_keeps_first_value() {
+        let mut a: ExpiringCrdtMap<i32, &str, Fww> = ExpiringCrdtMap::default();
+        a.insert(1, "first", 0, 5);
+        let mut b: ExpiringCrdtMap<i32, &str, Fww> = ExpiringCrdtMap::default();
+        b.insert(1, "second", 1, 50);
+        a.merge(&b).unwrap();
+        assert_eq!(a.get(&1), Some(&"first"));
+        a.cleanup(5);
+        a.merge(&b).unwrap();
+        assert_eq!(a.get(&1), Some(&"second"));
+    }
+}
+
+/// A `CrdtMap` holding at most `.1` entries. When there are too many, the
+/// ones the policy would pick are kept: the latest written for `Lww` and
+/// the earliest for `Fww`, ties going to the larger or smaller key. Either
+/// way replicas merged in any order end up with the same contents.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BoundedCrdtMap<K: Ord, V, P>(pub CrdtMap<K, V, P>, pub usize);
+
+impl<K: Ord + Clone, V: Clone, P> Clone for BoundedCrdtMap<K, V, P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1)
+    }
+}
+
+impl<K: Ord, V, P> BoundedCrdtMap<K, V, P> {
+    pub fn new(capacity: usize) -> Self {
+        Self(CrdtMap::default(), capacity)
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.0.contains_key(k)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.0.get(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> CrdtMapIter<K, V> {
+        self.0.iter()
+    }
+
+    pub fn remove(&mut self, k: K, now: i64)
+    where
+        K: Clone,
+    {
+        self.0.remove(k, now);
+    }
+
+    fn trim(&mut self, keep_newest: bool)
+    where
+        K: Clone,
+    {
+        if self.0.len() <= self.1 {
+            return;
+        }
+        let mut order: Vec<(i64, K)> = self.0.entries.iter().map(|(k, (_, written))| (*written, k.clone())).collect();
+        order.sort();
+        if keep_newest {
+            order.reverse();
+        }
+        for (_, k) in order.drain(self.1..) {
+            self.0.entries.remove(&k);
+            self.0.versions.stamps.remove(&k);
+            self.0.writers.remove(&k);
+        }
+    }
+}
+
+impl<K: Ord + Clone, V> BoundedCrdtMap<K, V, Lww> {
+    /// Evicts the oldest entry when full.
+    pub fn insert(&mut self, k: K, v: V, now: i64)
+    where
+        V: PartialEq,
+    {
+        self.0.insert(k, v, now);
+        self.trim(true);
+    }
+}
+
+impl<K: Ord + Clone, V> BoundedCrdtMap<K, V, Fww> {
+    /// Refused when full, unless written before the newest entry.
+    pub fn insert(&mut self, k: K, v: V, now: i64)
+    where
+        V: PartialEq,
+    {
+        self.0.insert(k, v, now);
+        self.trim(false);
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for BoundedCrdtMap<K, V, Lww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.0.merge(&other.0)?;
+        self.trim(true);
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.0.compact(horizon);
+    }
+}
+
+impl<K: Ord + Clone, V: PartialOrd + Clone> Crdt for BoundedCrdtMap<K, V, Fww> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.0.merge(&other.0)?;
+        self.trim(false);
+        Ok(())
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.0.cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.0.compact(horizon);
+    }
+}
+
+impl<V, P> LocSet for BoundedCrdtMap<Loc, V, P> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_loc(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSet::iter(&self.0)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<P> LocMap for BoundedCrdtMap<Loc, bool, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.0.get_loc(loc)
+    }
+}
+
+impl<P> LocMap for BoundedCrdtMap<Loc, f32, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.0.get_loc(loc)
+    }
+
+    fn cost_at(&self, loc: &Loc) -> f32 {
+        self.0.cost_at(loc)
+    }
+}
+
+#[cfg(test)]
+mod bounded_crdt_map_tests {
+    use super::*;
+
+    fn copy<P>(m: &BoundedCrdtMap<u32, u32, P>) -> BoundedCrdtMap<u32, u32, P> {
+        BoundedCrdtMap(m.0.clone(), m.1)
+    }
+
+    fn converges<P>()
+    where
+        BoundedCrdtMap<u32, u32, P>: Crdt,
+    {
+        for _ in 0..200 {
+            let capacity = fastrand::usize(1..5);
+            let replicas: Vec<BoundedCrdtMap<u32, u32, P>> = (0..3)
+                .map(|_| {
+                    let mut m = BoundedCrdtMap::new(usize::MAX);
+                    for _ in 0..fastrand::usize(0..8) {
+                        m.0.insert(fastrand::u32(0..8), fastrand::u32(0..3), fastrand::i64(0..6));
+                    }
+                    m.1 = capacity;
+                    m
+                })
+                .collect();
+            let mut results = Vec::new();
+            for order in [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+                let mut merged = copy(&replicas[order[0]]);
+                merged.merge(&replicas[order[1]]).unwrap();
+                merged.merge(&replicas[order[2]]).unwrap();
+                assert!(merged.len() <= capacity);
+                results.push(merged.0.entries);
+            }
+            assert!(results.iter().all(|r| *r == results[0]), "{results:?}");
+        }
+    }
+
+    #[test]
+    fn lww_replicas_converge() {
+        fastrand::seed(296);
+        converges::<Lww>();
+    }
+
+    #[test]
+    fn fww_replicas_converge() {
+        fastrand::seed(2960);
+        converges::<Fww>();
+    }
+
+    #[test]
+    fn insertion_evicts_per_policy() {
+        let mut lww: BoundedCrdtMap<Loc, bool, Lww> = BoundedCrdtMap::new(2);
+        let mut fww: BoundedCrdtMap<Loc, bool, Fww> = BoundedCrdtMap::new(2);
+        for x in 0..3 {
+            lww.insert(Loc { x, y: 0 }, true, x as i64);
+            fww.insert(Loc { x, y: 0 }, true, x as i64);
+        }
+        let xs = |set: &dyn LocSet| set.iter().map(|l| l.x).collect::<Vec<_>>();
+        assert_eq!(xs(&lww), [1, 2]);
+        assert_eq!(xs(&fww), [0, 1]);
+        assert_eq!(lww.get_loc(&Loc { x: 2, y: 0 }), Some(true));
+    }
+}
+
+/// `ChunkedLocCrdtMap` chunks are this many tiles across.
+pub const CHUNK_SIZE: i32 = 16;
+
+/// The chunk `loc` falls in, in chunk coordinates.
+pub fn chunk_of(loc: Loc) -> Loc {
+    Loc {
+        x: loc.x.div_euclid(CHUNK_SIZE),
+        y: loc.y.div_euclid(CHUNK_SIZE),
+    }
+}
+
+/// One chunk of a `ChunkedLocCrdtMap`, with a digest of its entries,
+/// tombstones and writers that's `None` while out of date.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "V: Serialize", deserialize = "V: Deserialize<'de>"))]
+pub struct LocChunk<V, P> {
+    pub map: CrdtMap<Loc, V, P>,
+    pub digest: Option<u64>,
+}
+
+impl<V: Clone, P> Clone for LocChunk<V, P> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            digest: self.digest,
+        }
+    }
+}
+
+fn chunk_digest<V: Serialize, P>(map: &CrdtMap<Loc, V, P>) -> Option<u64> {
+    let mut hasher = DigestWriter(FNV_OFFSET);
+    bincode::serialize_into(&mut hasher, &(&map.entries, &map.tombstones, &map.writers)).ok()?;
+    Some(hasher.0)
+}
+
+/// A `CrdtMap` keyed by `Loc`, split into chunks `CHUNK_SIZE` tiles across
+/// so merges can skip the chunks both sides already agree on. Merges and
+/// `cleanup` bring the digests up to date, and a chunk changed since is
+/// merged in full. `.1` is the tombstone horizon of every chunk.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "V: Serialize", deserialize = "V: Deserialize<'de>"))]
+pub struct ChunkedLocCrdtMap<V, P>(pub BTreeMap<Loc, LocChunk<V, P>>, pub i64);
+
+impl<V, P> Default for ChunkedLocCrdtMap<V, P> {
+    fn default() -> Self {
+        Self::with_tombstone_horizon(DEFAULT_TOMBSTONE_HORIZON)
+    }
+}
+
+impl<V: Clone, P> Clone for ChunkedLocCrdtMap<V, P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1)
+    }
+}
+
+impl<V, P> ChunkedLocCrdtMap<V, P> {
+    pub fn with_tombstone_horizon(horizon: i64) -> Self {
+        Self(BTreeMap::new(), horizon)
+    }
+
+    fn chunk(&self, loc: &Loc) -> Option<&CrdtMap<Loc, V, P>> {
+        self.0.get(&chunk_of(*loc)).map(|chunk| &chunk.map)
+    }
+
+    /// The chunk `loc` falls in, marked as changed.
+    fn chunk_mut(&mut self, loc: &Loc) -> &mut CrdtMap<Loc, V, P> {
+        let horizon = self.1;
+        let chunk = self.0.entry(chunk_of(*loc)).or_insert_with(|| LocChunk {
+            map: CrdtMap::with_tombstone_horizon(horizon),
+            digest: None,
+        });
+        chunk.digest = None;
+        &mut chunk.map
+    }
+
+    pub fn contains_key(&self, loc: &Loc) -> bool {
+        self.chunk(loc).is_some_and(|map| map.contains_key(loc))
+    }
+
+    pub fn get(&self, loc: &Loc) -> Option<&V> {
+        self.chunk(loc)?.get(loc)
+    }
 
-#[cfg(test)]
-mod sized_set_tests {
-    use super::*;
+    pub fn writer(&self, loc: &Loc) -> Option<u64> {
+        self.chunk(loc)?.writer(loc)
+    }
 
-    #[test]
-    fn test_insert_with_capacity() {
-        let mut s = SizedFWWExpiringSet::new(3);
-        let now = 0;
-        s.insert("a".to_string(), now, now+10);
-        assert!(s.contains("a"));
-        s.insert("b".to_string(), now, now+10);
-        assert!(s.contains("b"));
-        s.insert("c".to_string(), now, now+10);
-        assert!(s.contains("c"));
-        s.insert("d".to_string(), now, now+10);
-        assert!(!s.contains("d"));
+    pub fn len(&self) -> usize {
+        self.0.values().map(|chunk| chunk.map.len()).sum()
     }
 
-    #[test]
-    fn test_cleanup() {
-        let mut s = SizedFWWExpiringSet::new(3);
-        let now = 0;
-        s.insert("a".to_string(), now, now+1);
-        s.insert("b".to_string(), now, now+2);
-        s.insert("c".to_string(), now, now+3);
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|chunk| chunk.map.is_empty())
+    }
 
-        s.cleanup(now);
-        assert!(s.contains("a"));
-        assert!(s.contains("b"));
-        assert!(s.contains("c"));
+    /// Chunk by chunk, so not in `Loc` order overall.
+    pub fn iter(&self) -> impl Iterator<Item = (&Loc, &V)> + '_ {
+        self.0.values().flat_map(|chunk| chunk.map.iter())
+    }
 
-        let now = 1;
-        s.cleanup(now);
-        assert!(!s.contains("a"));
-        assert!(s.contains("b"));
-        assert!(s.contains("c"));
+    /// Like `CrdtMap::get_or_default`.
+    pub fn get_or_default(&self, loc: &Loc) -> std::borrow::Cow<'_, V>
+    where
+        V: Default + Clone,
+    {
+        self.get(loc).map_or_else(|| std::borrow::Cow::Owned(V::default()), std::borrow::Cow::Borrowed)
+    }
 
-        let now = 2;
-        s.cleanup(now);
-        assert!(!s.contains("a"));
-        assert!(!s.contains("b"));
-        assert!(s.contains("c"));
+    /// Ignored if `loc` was removed at or after `now`.
+    pub fn insert(&mut self, loc: Loc, v: V, now: i64)
+    where
+        V: PartialEq,
+    {
+        self.chunk_mut(&loc).insert(loc, v, now);
+    }
 
-        let now = 3;
-        s.cleanup(now);
-        assert!(!s.contains("a"));
-        assert!(!s.contains("b"));
-        assert!(!s.contains("c"));
+    /// Like `CrdtMap::insert_with_writer`.
+    pub fn insert_with_writer(&mut self, loc: Loc, v: V, now: i64, writer: u64)
+    where
+        V: PartialEq,
+    {
+        self.chunk_mut(&loc).insert_with_writer(loc, v, now, writer);
+    }
 
+    pub fn remove(&mut self, loc: Loc, now: i64) {
+        self.chunk_mut(&loc).remove(loc, now);
     }
+}
 
-    #[test]
-    fn test_merge() {
-        let mut a = SizedFWWExpiringSet::new(3);
-        a.insert("a".to_string(), 0, 10);
-        a.insert("b".to_string(), 1, 10);
-        a.insert("c".to_string(), 2, 10);
+impl<V: Serialize, P> ChunkedLocCrdtMap<V, P> {
+    /// Works out the digest of every chunk changed since the last time.
+    pub fn refresh_digests(&mut self) {
+        for chunk in self.0.values_mut().filter(|chunk| chunk.digest.is_none()) {
+            chunk.digest = chunk_digest(&chunk.map);
+        }
+    }
 
-        let mut b = SizedFWWExpiringSet::new(3);
-        b.insert("d".to_string(), 3, 10);
+    /// Has `forget` drop tombstones from every chunk, then drops the chunks
+    /// left empty.
+    fn forget_tombstones(&mut self, forget: impl Fn(&mut CrdtMap<Loc, V, P>)) {
+        for chunk in self.0.values_mut() {
+            let tombstones = chunk.map.tombstones.len();
+            forget(&mut chunk.map);
+            if chunk.map.tombstones.len() != tombstones {
+                chunk.digest = None;
+            }
+        }
+        self.0.retain(|_, chunk| !chunk.map.entries.is_empty() || !chunk.map.tombstones.is_empty());
+        self.refresh_digests();
+    }
+}
 
-        a.merge(&b).unwrap();
-        assert!(a.contains("a"));
-        assert!(a.contains("b"));
-        assert!(a.contains("c"));
-        assert!(!a.contains("d"));
+impl<V: Clone + Serialize, P> Crdt for ChunkedLocCrdtMap<V, P>
+where
+    CrdtMap<Loc, V, P>: Crdt,
+{
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
 
-        let mut c = SizedFWWExpiringSet::new(3);
-        c.insert("d".to_string(), 0, 10);
-        a.merge(&c).unwrap();
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (key, theirs) in &other.0 {
+            let horizon = self.1;
+            let mine = self.0.entry(*key).or_insert_with(|| LocChunk {
+                map: CrdtMap::with_tombstone_horizon(horizon),
+                digest: None,
+            });
+            if mine.digest.is_none() {
+                mine.digest = chunk_digest(&mine.map);
+            }
+            if mine.digest.is_some() && mine.digest == theirs.digest {
+                continue;
+            }
+            if mine.map.merge_changed(&theirs.map)? {
+                mine.digest = chunk_digest(&mine.map);
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
 
-        assert!(a.contains("a"));
-        assert!(a.contains("b"));
-        assert!(!a.contains("c"));
-        assert!(a.contains("d"));
+    fn cleanup(&mut self, now: i64) {
+        self.forget_tombstones(|map| map.cleanup(now));
     }
 
-    #[test]
-    fn multi_way_merge() {
-        let mut a = SizedFWWExpiringSet::new(3);
-        a.insert("a".to_string(), 0, 10);
-        a.insert("b".to_string(), 1, 10);
-        a.insert("c".to_string(), 2, 10);
+    fn compact(&mut self, horizon: i64) {
+        self.forget_tombstones(|map| map.compact(horizon));
+    }
 
-        let mut b = SizedFWWExpiringSet::new(3);
-        b.insert("d".to_string(), 2, 10);
+    fn version(&self) -> u64 {
+        self.0.values().map(|chunk| chunk.map.version()).max().unwrap_or(0)
+    }
 
-        let mut c = SizedFWWExpiringSet::new(3);
-        c.insert("e".to_string(), 2, 10);
+    fn delta_since(&self, version: u64) -> Option<Self> {
+        let mut delta = Self::with_tombstone_horizon(self.1);
+        for (key, chunk) in &self.0 {
+            if chunk.map.version() > version {
+                let map = chunk.map.delta_since(version)?;
+                delta.0.insert(*key, LocChunk { map, digest: None });
+            }
+        }
+        Some(delta)
+    }
+}
 
-        let mut na = a.clone();
-        let mut nb = b.clone();
-        let mut nc = c.clone();
+impl<V, P> LocSet for ChunkedLocCrdtMap<V, P> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.contains_key(loc)
+    }
 
-        na.merge(&b).unwrap();
-        na.merge(&c).unwrap();
-        na.cleanup(4);
-        assert!(na.contains("a"));
-        assert!(na.contains("b"));
-        assert!(na.contains("c"));
-        assert!(!na.contains("d"));
-        assert!(!na.contains("e"));
+    fn is_empty(&self) -> bool {
+        ChunkedLocCrdtMap::is_empty(self)
+    }
 
-        nb.merge(&a).unwrap();
-        nb.merge(&c).unwrap();
-        nb.cleanup(4);
-        assert_eq!(nb.0, na.0);
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::with_len(ChunkedLocCrdtMap::iter(self).map(|(loc, _)| *loc), ChunkedLocCrdtMap::len(self))
+    }
 
-        nc.merge(&b).unwrap();
-        nc.merge(&a).unwrap();
-        nc.cleanup(4);
-        assert_eq!(nc.0, na.0);
-        assert_eq!(nc.0, nb.0);
+    fn len(&self) -> usize {
+        ChunkedLocCrdtMap::len(self)
     }
 }
 
-#[derive(Debug)]
-pub struct Lww;
-#[derive(Debug)]
-pub struct Fww;
+impl<P> LocMap for ChunkedLocCrdtMap<bool, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.get(loc).copied()
+    }
+}
 
-pub struct CrdtMapIter<'a, K, V>(std::collections::btree_map::Iter<'a, K, (V, i64)>);
+impl<P> LocMap for ChunkedLocCrdtMap<f32, P> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.get(loc).map(|cost| cost.is_finite())
+    }
 
-impl<'a, K, V> Iterator for CrdtMapIter<'a, K, V> {
-    type Item = (&'a K, &'a V);
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some((k, (v, _))) = self.0.next() {
-            Some((k, v))
-        } else {
-            None
+    fn cost_at(&self, loc: &Loc) -> f32 {
+        self.get(loc).copied().unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod chunked_loc_crdt_map_tests {
+    use super::*;
+
+    fn random_loc() -> Loc {
+        Loc {
+            x: fastrand::i32(-40..40),
+            y: fastrand::i32(-40..40),
+        }
+    }
+
+    fn sorted<'a>(entries: impl Iterator<Item = (&'a Loc, &'a bool)>) -> Vec<(Loc, bool)> {
+        let mut entries: Vec<_> = entries.map(|(loc, v)| (*loc, *v)).collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn behaves_like_crdt_map() {
+        fastrand::seed(312);
+        let mut plain: [CrdtMap<Loc, bool, Lww>; 2] = Default::default();
+        let mut chunked: [ChunkedLocCrdtMap<bool, Lww>; 2] = Default::default();
+        for now in 0..300 {
+            let (i, loc, v) = (fastrand::usize(0..2), random_loc(), fastrand::bool());
+            match fastrand::u32(0..6) {
+                0 => {
+                    plain[i].remove(loc, now);
+                    chunked[i].remove(loc, now);
+                }
+                1 => {
+                    let other = (plain[1 - i].clone(), chunked[1 - i].clone());
+                    assert_eq!(plain[i].merge_changed(&other.0).unwrap(), chunked[i].merge_changed(&other.1).unwrap());
+                }
+                _ => {
+                    plain[i].insert(loc, v, now);
+                    chunked[i].insert(loc, v, now);
+                }
+            }
+            plain[i].cleanup(now);
+            chunked[i].cleanup(now);
+            assert_eq!(sorted(plain[i].iter()), sorted(chunked[i].iter()));
+            assert_eq!(chunked[i].get_loc(&loc), plain[i].get_loc(&loc));
+        }
+        assert_eq!(LocSet::len(&chunked[0]), plain[0].len());
+        assert!(chunked[0].0.keys().all(|key| key.x.abs() <= 3 && key.y.abs() <= 3));
+    }
+
+    #[test]
+    fn deltas_hold_changed_chunks() {
+        let mut a: ChunkedLocCrdtMap<bool, Lww> = ChunkedLocCrdtMap::default();
+        a.insert(Loc { x: 0, y: 0 }, true, 0);
+        a.insert(Loc { x: 40, y: 0 }, true, 0);
+        let mut b = a.clone();
+        let version = a.version();
+        a.insert(Loc { x: 41, y: 1 }, false, 1);
+        let delta = a.delta_since(version).unwrap();
+        assert_eq!(delta.0.keys().collect::<Vec<_>>(), [&Loc { x: 2, y: 0 }]);
+        assert!(b.apply_delta(&delta).unwrap());
+        assert_eq!(sorted(b.iter()), sorted(a.iter()));
+    }
+
+    #[test]
+    fn matching_digests_make_merges_cheaper() {
+        let explored = |x_range: std::ops::Range<i32>, plain: &mut CrdtMap<Loc, bool, Lww>, chunked: &mut ChunkedLocCrdtMap<bool, Lww>| {
+            for x in x_range {
+                for y in 0..100 {
+                    plain.insert(Loc { x, y }, (x + y) % 5 != 0, 10);
+                    chunked.insert(Loc { x, y }, (x + y) % 5 != 0, 10);
+                }
+            }
+        };
+        let (mut plain, mut chunked) = (CrdtMap::default(), ChunkedLocCrdtMap::default());
+        explored(0..90, &mut plain, &mut chunked);
+        let (mut other_plain, mut other_chunked) = (plain.clone(), chunked.clone());
+        explored(90..100, &mut plain, &mut chunked);
+        explored(200..210, &mut other_plain, &mut other_chunked);
+        chunked.cleanup(10);
+        other_chunked.cleanup(10);
+        assert_eq!(plain.len(), 10_000);
+        assert!(other_chunked.0.values().all(|chunk| chunk.digest.is_some()));
+
+        // The chunks left of x = 80 that both sides agree on are skipped, so
+        // a write slipped into one behind its digest's back is never merged.
+        let agreed: Vec<Loc> = other_chunked
+            .0
+            .iter()
+            .filter(|(key, theirs)| chunked.0.get(key).is_some_and(|mine| mine.digest == theirs.digest))
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(agreed.len(), 5 * 7);
+        assert!(agreed.iter().all(|key| key.x < 5));
+        for key in &agreed {
+            let chunk = other_chunked.0.get_mut(key).unwrap();
+            let (&loc, &(passable, _)) = chunk.map.entries.iter().next().unwrap();
+            chunk.map.insert(loc, !passable, 11);
         }
+
+        plain.merge(&other_plain).unwrap();
+        assert!(chunked.merge_changed(&other_chunked).unwrap());
+        assert_eq!(sorted(plain.iter()), sorted(chunked.iter()));
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CrdtMap<K: Ord, V, P>(pub BTreeMap<K, (V, i64)>, PhantomData<P>);
+/// `None` is an empty state: merging copies the other side if there's
+/// nothing here yet.
+impl<T: Crdt + Clone> Crdt for Option<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
 
-impl<K: Ord, V, P> Default for CrdtMap<K, V, P> {
-    fn default() -> Self {
-        Self(BTreeMap::new(), PhantomData::default())
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        match (self.as_mut(), other) {
+            (Some(local), Some(other)) => local.merge_changed(other),
+            (None, Some(other)) => {
+                *self = Some(other.clone());
+                Ok(true)
+            }
+            (_, None) => Ok(false),
+        }
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        if let Some(inner) = self {
+            inner.cleanup(now);
+        }
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        if let Some(inner) = self {
+            inner.compact(horizon);
+        }
+    }
+
+    fn version(&self) -> u64 {
+        self.as_ref().map_or(0, Crdt::version)
     }
 }
 
-impl<K: Ord, V, P> CrdtMap<K, V, P> {
-    pub fn insert(&mut self, k: K, v: V, now: i64) {
-        self.0.insert(k, (v, now));
+impl<T: Crdt> Crdt for Box<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        (**self).merge(other)
     }
 
-    pub fn contains_key(&mut self, k: &K) -> bool {
-        self.0.contains_key(k)
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        (**self).merge_changed(other)
     }
 
-    pub fn iter(&self) -> CrdtMapIter<K, V> {
-        CrdtMapIter(self.0.iter())
+    fn cleanup(&mut self, now: i64) {
+        (**self).cleanup(now);
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        (**self).compact(horizon);
+    }
+
+    fn version(&self) -> u64 {
+        (**self).version()
     }
 }
 
-impl<K: Ord + Clone, V: Ord + Clone> Crdt for CrdtMap<K, V, Lww> {
+/// Merges key by key, copying keys only the other side has.
+impl<K: Ord + Clone, V: Crdt + Clone> Crdt for BTreeMap<K, V> {
     fn merge(&mut self, other: &Self) -> Result<()> {
-        for (k, (v, written)) in &other.0 {
-            if let Some((lv, lw)) = self.0.get_mut(k) {
-                if *lw < *written || (*lw == *written && *lv < *v) {
-                    *lw = *written;
-                    *lv = v.clone();
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (k, v) in other {
+            match self.get_mut(k) {
+                Some(local) => changed |= local.merge_changed(v)?,
+                None => {
+                    self.insert(k.clone(), v.clone());
+                    changed = true;
                 }
-            } else {
-                self.0.insert(k.clone(), (v.clone(), *written));
             }
         }
-        Ok(())
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.values_mut().for_each(|v| v.cleanup(now));
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.values_mut().for_each(|v| v.compact(horizon));
+    }
+
+    fn version(&self) -> u64 {
+        self.values().map(Crdt::version).max().unwrap_or(0)
     }
 }
 
-impl<K: Ord + Clone, V: Ord + Clone> Crdt for CrdtMap<K, V, Fww> {
+/// Like the `BTreeMap` impl.
+impl<K: Eq + std::hash::Hash + Clone, V: Crdt + Clone> Crdt for HashMap<K, V> {
     fn merge(&mut self, other: &Self) -> Result<()> {
-        for (k, (v, written)) in &other.0 {
-            if let Some((lv, lw)) = self.0.get_mut(k) {
-                if *lw > *written || (*lw == *written && *lv > *v) {
-                    *lw = *written;
-                    *lv = v.clone();
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (k, v) in other {
+            match self.get_mut(k) {
+                Some(local) => changed |= local.merge_changed(v)?,
+                None => {
+                    self.insert(k.clone(), v.clone());
+                    changed = true;
                 }
-            } else {
-                self.0.insert(k.clone(), (v.clone(), *written));
             }
         }
-        Ok(())
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.values_mut().for_each(|v| v.cleanup(now));
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.values_mut().for_each(|v| v.compact(horizon));
+    }
+
+    fn version(&self) -> u64 {
+        self.values().map(Crdt::version).max().unwrap_or(0)
     }
 }
 
-impl<V, P> LocSet for CrdtMap<Loc, V, P> {
-    fn contains_loc(&self, loc: &Loc) -> bool {
-        self.0.contains_key(loc)
+/// Slots merge with the slot at the same index.
+impl<T: Crdt, const N: usize> Crdt for [T; N] {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
     }
 
-    fn is_empty(&self) -> bool {
-        self.0.is_empty()
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        let mut changed = false;
+        for (local, other) in self.iter_mut().zip(other) {
+            changed |= local.merge_changed(other)?;
+        }
+        Ok(changed)
     }
 
-    fn iter(&self) -> LocSetIter {
-        LocSetIter {
-            inner: Box::new(self.0.keys().copied()),
+    fn cleanup(&mut self, now: i64) {
+        self.iter_mut().for_each(|v| v.cleanup(now));
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.iter_mut().for_each(|v| v.compact(horizon));
+    }
+
+    fn version(&self) -> u64 {
+        self.iter().map(Crdt::version).max().unwrap_or(0)
+    }
+}
+
+/// A fixed set of slots, like the array impl. Replicas must agree on the
+/// length: merging a different length is an error and changes nothing.
+impl<T: Crdt> Crdt for Vec<T> {
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        self.merge_changed(other).map(drop)
+    }
+
+    fn merge_changed(&mut self, other: &Self) -> Result<bool> {
+        anyhow::ensure!(
+            self.len() == other.len(),
+            "can't merge {} slots into {}",
+            other.len(),
+            self.len()
+        );
+        let mut changed = false;
+        for (local, other) in self.iter_mut().zip(other) {
+            changed |= local.merge_changed(other)?;
         }
+        Ok(changed)
+    }
+
+    fn cleanup(&mut self, now: i64) {
+        self.iter_mut().for_each(|v| v.cleanup(now));
+    }
+
+    fn compact(&mut self, horizon: i64) {
+        self.iter_mut().for_each(|v| v.compact(horizon));
+    }
+
+    fn version(&self) -> u64 {
+        self.iter().map(Crdt::version).max().unwrap_or(0)
     }
 }
 
-impl<P> LocMap for CrdtMap<Loc, bool, P> {
-    fn get_loc(&self, loc: &Loc) -> Option<bool> {
-        self.0.get(loc).copied().map(|(l, _)| l)
+#[cfg(test)]
+mod wrapper_tests {
+    use super::*;
+
+    #[test]
+    fn nested_maps_merge_across_replicas() {
+        let mut a: HashMap<String, ExpiringSet<Loc>> = HashMap::new();
+        a.entry("food".to_string()).or_default().insert(Loc { x: 0, y: 0 }, 10);
+        let mut b: HashMap<String, ExpiringSet<Loc>> = HashMap::new();
+        b.entry("food".to_string()).or_default().insert(Loc { x: 1, y: 0 }, 5);
+        b.entry("water".to_string()).or_default().insert(Loc { x: 2, y: 0 }, 20);
+
+        let mut ab = a.clone();
+        assert!(ab.merge_changed(&b).unwrap());
+        let mut ba = b.clone();
+        ba.merge(&a).unwrap();
+        for k in ["food", "water"] {
+            assert_eq!(ab[k].0, ba[k].0);
+        }
+        assert_eq!(ab["food"].len(), 2);
+        assert!(!ab.merge_changed(&b).unwrap());
+
+        ab.cleanup(6);
+        assert_eq!(ab["food"].iter().map(|(loc, _)| *loc).collect::<Vec<_>>(), [Loc { x: 0, y: 0 }]);
+        assert_eq!(ab["water"].len(), 1);
+    }
+
+    #[test]
+    fn options_and_boxes_merge_inside() {
+        let mut a: Option<ExpiringFWWRegister<i32>> = None;
+        let mut b = Some(ExpiringFWWRegister::default());
+        b.as_mut().unwrap().set(3, 0, 10);
+        assert!(a.merge_changed(&b).unwrap());
+        assert_eq!(a.as_ref().and_then(|r| r.get()), Some(&3));
+        assert!(!b.merge_changed(&None).unwrap());
+
+        let mut boxed = Box::new(GrowOnlySet::default());
+        let mut other = Box::new(GrowOnlySet::default());
+        other.insert(1);
+        boxed.merge(&other).unwrap();
+        assert!(boxed.contains(&1));
+    }
+
+    #[test]
+    fn slots_merge_by_index() {
+        let mut a = [GrowOnlySet::default(), GrowOnlySet::default()];
+        let mut b = a.clone();
+        a[0].insert(1);
+        b[1].insert(2);
+        a.merge(&b).unwrap();
+        assert!(a[0].contains(&1) && a[1].contains(&2));
+
+        let mut v = vec![GrowOnlySet::default(); 2];
+        v.merge(&a.to_vec()).unwrap();
+        assert!(v[1].contains(&2));
+        assert!(v.merge(&vec![GrowOnlySet::default(); 3]).is_err());
     }
 }
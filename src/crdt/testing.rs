@@ -0,0 +1,159 @@
+//! Checks that a `Crdt` implementation actually converges, for the tests of
+//! hand-written CRDTs. Enabled by the `testing` feature.
+
+use std::fmt::Debug;
+
+use super::Crdt;
+
+/// A change a replica can make, given its index and the current turn.
+pub type Mutation<'a, T> = &'a dyn Fn(&mut T, usize, i64);
+
+fn merged<T: Crdt + Clone>(a: &T, b: &T) -> T {
+    let mut a = a.clone();
+    a.merge(b).expect("merge failed");
+    a
+}
+
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![Vec::new()];
+    }
+    let mut all = Vec::new();
+    for shorter in permutations(n - 1) {
+        for i in 0..n {
+            let mut order = shorter.clone();
+            order.insert(i, n - 1);
+            all.push(order);
+        }
+    }
+    all
+}
+
+/// Panics unless merging `replicas` is idempotent, commutative and
+/// associative, and merging all of them in every possible order ends the
+/// same. That's factorial in the number of replicas, so keep it small.
+pub fn assert_crdt_laws<T: Crdt + Clone + PartialEq + Debug>(replicas: Vec<T>) {
+    assert!(replicas.len() <= 7, "too many replicas to try every order");
+    for a in &replicas {
+        assert_eq!(merged(a, a), *a, "merging with itself changed it");
+        for b in &replicas {
+            assert_eq!(merged(a, b), merged(b, a), "merging {a:?} and {b:?} depends on the order");
+            for c in &replicas {
+                assert_eq!(
+                    merged(&merged(a, b), c),
+                    merged(a, &merged(b, c)),
+                    "merging {a:?}, {b:?} and {c:?} depends on the grouping"
+                );
+            }
+        }
+    }
+
+    let Some(first) = replicas.first() else {
+        return;
+    };
+    let mut expected = first.clone();
+    for r in &replicas[1..] {
+        expected.merge(r).expect("merge failed");
+    }
+    for order in permutations(replicas.len()) {
+        let mut all = replicas[order[0]].clone();
+        for &i in &order[1..] {
+            all.merge(&replicas[i]).expect("merge failed");
+        }
+        assert_eq!(all, expected, "merging in the order {order:?} ended differently");
+    }
+}
+
+/// Runs `replicas` copies of `initial` for `turns` turns. Each turn every
+/// replica may apply one of `mutations`, some replicas merge in another's
+/// state and all of them clean up, as they would in `Component::step`.
+/// Panics unless they all agree after a final full sync and cleanup, or if
+/// `merge_changed` claims nothing changed when something did. `seed` picks
+/// the interleaving.
+pub fn assert_converges<T: Crdt + Clone + PartialEq + Debug>(
+    initial: T,
+    replicas: usize,
+    turns: i64,
+    mutations: &[Mutation<T>],
+    seed: u64,
+) {
+    assert!(replicas > 0 && !mutations.is_empty());
+    fastrand::seed(seed);
+    let mut states = vec![initial; replicas];
+    for now in 0..turns {
+        for (i, state) in states.iter_mut().enumerate() {
+            if fastrand::bool() {
+                mutations[fastrand::usize(0..mutations.len())](state, i, now);
+            }
+        }
+        for _ in 0..fastrand::usize(0..replicas + 1) {
+            let from = fastrand::usize(0..replicas);
+            let to = fastrand::usize(0..replicas);
+            let other = states[from].clone();
+            let before = states[to].clone();
+            if !states[to].merge_changed(&other).expect("merge failed") {
+                assert_eq!(states[to], before, "merge_changed said merging {other:?} changed nothing (seed {seed})");
+            }
+        }
+        for state in &mut states {
+            state.cleanup(now);
+        }
+    }
+
+    let mut all = states[0].clone();
+    for state in &states[1..] {
+        all.merge(state).expect("merge failed");
+    }
+    for state in &mut states {
+        state.merge(&all).expect("merge failed");
+        state.cleanup(turns);
+    }
+    for (i, state) in states.iter().enumerate() {
+        assert_eq!(*state, states[0], "replica {i} didn't converge (seed {seed})");
+    }
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use super::*;
+    use anyhow::Result;
+
+    /// Takes whatever it merges last, which is neither commutative nor
+    /// associative.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Clobber(i64);
+
+    impl Crdt for Clobber {
+        fn merge(&mut self, other: &Self) -> Result<()> {
+            self.0 = other.0;
+            Ok(())
+        }
+    }
+
+    /// Never takes anything from other replicas.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Local(i64);
+
+    impl Crdt for Local {}
+
+    #[test]
+    fn tries_every_order() {
+        assert_eq!(permutations(3).len(), 6);
+        let mut orders = permutations(4);
+        orders.sort();
+        orders.dedup();
+        assert_eq!(orders.len(), 24);
+    }
+
+    #[test]
+    #[should_panic(expected = "depends on the order")]
+    fn catches_order_dependent_merges() {
+        assert_crdt_laws(vec![Clobber(1), Clobber(2)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "didn't converge")]
+    fn catches_diverging_replicas() {
+        assert_converges(Local(0), 3, 20, &[&|c, i, now| c.0 = now * 10 + i as i64], 310);
+    }
+}
@@ -1,19 +1,24 @@
 use indexmap::IndexSet;
-use ordered_float::OrderedFloat;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::collections::{VecDeque, HashMap};
+use std::collections::{BTreeMap, VecDeque, HashMap};
 use std::marker::PhantomData;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use ordered_float::OrderedFloat;
 
-use bindings::{
-    actor, broadcast, get_game_state, item_at, load_store, save_store, visible_creatures,
-    visible_tiles, Command, Guest, Loc,
-};
+use bindings::{Command, Guest, Loc};
 
 use crate::{
-    behaviors::{avoidance_sets, move_towards},
-    crdt::{Crdt, CrdtMap, Lww},
-    distance,
+    codec::{decode_tagged, encode_or_bincode, Bincode, Codec},
+    config::{split_config_prefix, with_config_prefix},
+    host::{actor, broadcast, get_game_state, item_at, load_store, save_store, visible_creatures, visible_tiles},
+    behaviors::{avoidance_sets, move_towards, move_towards_with_cache},
+    crdt::{
+        advance_version_clock, decoding_bare, digest_bytes, encoding_for_allies, loc_columns, turn_version, Crdt, CrdtContainer, CrdtSnapshot,
+        ExpiringCrdtMap, Lww, MergeProgress,
+    },
+    astar_multi, astar_partial, chebyshev_distance, distance_field, distance_squared, nearest_matching, ArrivalCondition, AstarOptions,
+    EmptyLocSet, Heuristic, IncrementalAstar, LocExt, LocMap, LocSet, PathCache, PenaltyMap, Rect, Regions, SearchStatus,
+    WithPenalties,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -26,64 +31,507 @@ impl Map for DummyMap {
 pub struct DummyBroadcast;
 impl Crdt for DummyBroadcast {
 }
+impl CrdtSnapshot for DummyBroadcast {
+    type Snap = ();
+    fn snapshot(&self) {}
+    fn restore(&mut self, _snap: ()) {}
+}
+
+
+/// `SC` encodes the store and `BC` broadcasts. Allies need a codec for
+/// what they're sent, but read any this was built with, by its tag.
+pub struct Component<State, B = DummyBroadcast, M = DummyMap, SC = Bincode, BC = Bincode>(
+    PhantomData<State>,
+    PhantomData<B>,
+    PhantomData<M>,
+    PhantomData<SC>,
+    PhantomData<BC>,
+);
+
+/// Marks broadcasts wrapped in a `BroadcastMessage`, so they aren't mistaken
+/// for a bare broadcast struct from an older client.
+const BROADCAST_MAGIC: u32 = 0xC0DE_B10C;
+const BROADCAST_FORMAT: u8 = 2;
+/// Every this many turns the whole broadcast struct is sent; in between only
+/// what changed since the last full send.
+pub const FULL_BROADCAST_INTERVAL: i64 = 10;
+
+#[derive(Serialize, Deserialize)]
+struct BroadcastMessage<B> {
+    magic: u32,
+    format: u8,
+    /// The sender's `Crdt::digest` of `state`.
+    digest: u64,
+    /// `Some` for a delta holding the sender's changes after this version.
+    since: Option<u64>,
+    state: B,
+}
+
+/// Sent instead of a `BroadcastMessage` when `State::broadcast_max_bytes`
+/// caps its size, holding what `Crdt::serialize_within` fit in.
+const PARTIAL_BROADCAST_FORMAT: u8 = 3;
+/// The bytes a `PartialBroadcast` takes besides its fields, with its codec
+/// tag. Exact for bincode; other codecs may overshoot `broadcast_max_bytes`.
+const PARTIAL_HEADER_BYTES: usize = 22;
+
+#[derive(Serialize, Deserialize)]
+struct PartialBroadcast {
+    magic: u32,
+    format: u8,
+    /// Of `fields`, rather than of the whole state.
+    digest: u64,
+    fields: Vec<u8>,
+}
+
+/// The first fields of both `BroadcastMessage` and `PartialBroadcast`.
+#[derive(Deserialize)]
+struct BroadcastHeader {
+    magic: u32,
+    format: u8,
+    digest: u64,
+}
+
+/// Reads what `encode_broadcast` wrote with any codec, or else bincode
+/// without a codec tag from older clients.
+fn decode_message<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    decode_tagged(bytes).ok().or_else(|| bincode::deserialize(bytes).ok())
+}
+
+fn decode_partial(bytes: &[u8]) -> Option<Vec<u8>> {
+    match decode_message::<PartialBroadcast>(bytes) {
+        Some(message) if message.magic == BROADCAST_MAGIC && message.format == PARTIAL_BROADCAST_FORMAT => {
+            Some(message.fields)
+        }
+        _ => None,
+    }
+}
+
+fn decode_broadcast<B: Crdt + DeserializeOwned>(bytes: &[u8]) -> Option<BroadcastMessage<B>> {
+    match decode_message::<BroadcastMessage<B>>(bytes) {
+        Some(message) if message.magic == BROADCAST_MAGIC && message.format == BROADCAST_FORMAT => Some(message),
+        _ => decoding_bare(|| bincode::deserialize::<B>(bytes)).ok().map(|state| BroadcastMessage {
+            magic: BROADCAST_MAGIC,
+            format: BROADCAST_FORMAT,
+            digest: digest_bytes(bytes),
+            since: None,
+            state,
+        }),
+    }
+}
+
+/// Reads just the digest from the front of a broadcast. Bare states from
+/// older clients are hashed whole instead.
+fn broadcast_digest(bytes: &[u8]) -> u64 {
+    match decode_message::<BroadcastHeader>(bytes) {
+        Some(BroadcastHeader {
+            magic: BROADCAST_MAGIC,
+            format: BROADCAST_FORMAT | PARTIAL_BROADCAST_FORMAT,
+            digest,
+        }) => digest,
+        _ => digest_bytes(bytes),
+    }
+}
+
+/// The digest of what was last merged from each ally, by where it stood,
+/// so broadcasts that haven't changed since needn't be deserialized again.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct MergeCache(pub HashMap<Loc, u64>);
+
+impl MergeCache {
+    pub fn is_unchanged(&self, from: Loc, digest: u64) -> bool {
+        self.0.get(&from) == Some(&digest)
+    }
+}
+
+/// Broadcasts only partly merged, with how far each got.
+pub type PendingMerges = VecDeque<(Vec<u8>, MergeProgress)>;
+
+/// Caps how many entries `Component::step` merges from allies' broadcasts
+/// each turn, keeping a broadcast it couldn't finish to resume first next
+/// turn. Defaults to no cap.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MergeBudget {
+    pub max_entries: usize,
+    pub pending: PendingMerges,
+}
+
+impl MergeBudget {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Default for MergeBudget {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+/// Merges what allies broadcast into `broadcast`, skipping broadcasts
+/// `previous` says were merged already. Returns whether `broadcast` may
+/// have changed.
+fn merge_broadcasts<B: Crdt + DeserializeOwned>(
+    broadcast: &mut B,
+    allies: impl Iterator<Item = (Loc, Vec<u8>)>,
+    previous: Option<&MergeCache>,
+    cache: &mut MergeCache,
+    budget: Option<(usize, &mut PendingMerges)>,
+) -> bool {
+    let mut merged = false;
+    let mut remaining = budget.as_ref().map_or(usize::MAX, |(max_entries, _)| *max_entries);
+    let mut pending = budget.map(|(_, pending)| pending);
+    if let Some(pending) = pending.as_deref_mut() {
+        while remaining > 0 && let Some((bytes, progress)) = pending.pop_front() {
+            if let Some(message) = decode_broadcast::<B>(&bytes) {
+                let next = broadcast.merge_bounded(&message.state, progress, remaining).unwrap();
+                remaining -= next.done.saturating_sub(progress.done).min(remaining);
+                merged = true;
+                if !next.finished {
+                    pending.push_front((bytes, next));
+                }
+            }
+        }
+    }
+    for (loc, bytes) in allies {
+        let digest = broadcast_digest(&bytes);
+        if previous.is_some_and(|previous| previous.is_unchanged(loc, digest)) {
+            cache.0.insert(loc, digest);
+            continue;
+        }
+        // Left out of the cache, so it's merged on a later turn instead.
+        if remaining == 0 {
+            continue;
+        }
+        cache.0.insert(loc, digest);
+        if let Some(fields) = decode_partial(&bytes) {
+            merged |= broadcast.merge_partial(&fields);
+            continue;
+        }
+        let Some(message) = decode_broadcast::<B>(&bytes) else {
+            continue;
+        };
+        match pending.as_deref_mut() {
+            Some(pending) => {
+                let progress = broadcast.merge_bounded(&message.state, MergeProgress::default(), remaining).unwrap();
+                remaining -= progress.done.min(remaining);
+                merged = true;
+                if !progress.finished {
+                    pending.push_back((bytes, progress));
+                }
+            }
+            None if message.since.is_some() => merged |= broadcast.apply_delta(&message.state).unwrap(),
+            None => merged |= broadcast.merge_changed(&message.state).unwrap(),
+        }
+    }
+    merged
+}
+
+fn encode_broadcast<C: Codec, B: Crdt + Serialize>(state: &B, turn: i64, max_bytes: Option<usize>) -> Vec<u8> {
+    encoding_for_allies(|| encode_broadcast_state::<C, B>(state, turn, max_bytes))
+}
 
+fn encode_broadcast_state<C: Codec, B: Crdt + Serialize>(state: &B, turn: i64, max_bytes: Option<usize>) -> Vec<u8> {
+    let since = turn_version(turn - turn.rem_euclid(FULL_BROADCAST_INTERVAL));
+    let delta = (turn % FULL_BROADCAST_INTERVAL != 0).then(|| state.delta_since(since)).flatten();
+    let (since, state) = match &delta {
+        Some(delta) => (Some(since), delta),
+        None => (None, state),
+    };
+    // Deltas merge like any other state, so they can be cut short too.
+    if let Some(max_bytes) = max_bytes {
+        let fields = state.serialize_within(max_bytes.saturating_sub(PARTIAL_HEADER_BYTES));
+        return encode_or_bincode::<C, _>(&PartialBroadcast {
+            magic: BROADCAST_MAGIC,
+            format: PARTIAL_BROADCAST_FORMAT,
+            digest: digest_bytes(&fields),
+            fields,
+        })
+        .unwrap();
+    }
+    encode_or_bincode::<C, _>(&BroadcastMessage {
+        magic: BROADCAST_MAGIC,
+        format: BROADCAST_FORMAT,
+        digest: state.digest(),
+        since,
+        state,
+    })
+    .unwrap()
+}
+
+/// Marks a store written with a `StoreHeader`, followed by the state with its
+/// codec tag. Older stores are the bare bincode of the state.
+const STORE_MAGIC: u32 = 0xC0DE_5703;
+/// The bytes a `StoreHeader` takes in front of the state.
+const STORE_HEADER_BYTES: usize = 24;
+
+#[derive(Serialize, Deserialize)]
+struct StoreHeader {
+    magic: u32,
+    /// The `State::VERSION` the store was written at.
+    version: u32,
+    /// The level the actor was on, to notice it changing.
+    level_id: i64,
+    /// The `Crdt::digest` of the broadcast state allies were last sent.
+    broadcast_digest: u64,
+}
+
+impl StoreHeader {
+    fn new(version: u32, level_id: i64, broadcast_digest: u64) -> Self {
+        Self {
+            magic: STORE_MAGIC,
+            version,
+            level_id,
+            broadcast_digest,
+        }
+    }
+}
+
+/// The header in front of a store written by `encode_store`.
+fn stored_header(bytes: &[u8]) -> Option<StoreHeader> {
+    bincode::deserialize::<StoreHeader>(bytes).ok().filter(|header| header.magic == STORE_MAGIC)
+}
+
+fn encode_store<C: Codec, S: Serialize>(memory: &S, header: StoreHeader) -> Vec<u8> {
+    let mut bytes = bincode::serialize(&header).unwrap();
+    bytes.extend(encode_or_bincode::<C, _>(memory).unwrap());
+    bytes
+}
+
+/// Reads a store written by `encode_store`, or before it by an older
+/// client, which counts as version 0 and is read with `decoding_bare`,
+/// `migrate` included. One from another version than
+/// `S::VERSION` goes through `State::migrate`. Any codec this was built
+/// with is read, so the store's codec can change.
+fn decode_store<S: State<B, M> + DeserializeOwned, B, M>(bytes: &[u8]) -> Result<S> {
+    let Some(header) = stored_header(bytes) else {
+        return decoding_bare(|| decode_versioned(0, &[&[Bincode::TAG], bytes].concat()));
+    };
+    decode_versioned(header.version, &bytes[STORE_HEADER_BYTES..])
+}
+
+fn decode_versioned<S: State<B, M> + DeserializeOwned, B, M>(version: u32, payload: &[u8]) -> Result<S> {
+    if version == S::VERSION {
+        return decode_tagged(payload);
+    }
+    S::migrate(version, payload).ok_or_else(|| anyhow!("no migration from version {version} to {}", S::VERSION))
+}
 
-pub struct Component<State, B = DummyBroadcast, M = DummyMap>(PhantomData<State>, PhantomData<B>, PhantomData<M>);
+/// Whether to send a broadcast state hashing to `digest`, when `last` was
+/// the last one sent. The host keeps showing the last broadcast, so it only
+/// needs replacing when something changed, besides the full broadcast every
+/// `FULL_BROADCAST_INTERVAL` turns.
+fn should_broadcast(last: Option<u64>, digest: u64, turn: i64, forced: bool) -> bool {
+    forced || last != Some(digest) || turn % FULL_BROADCAST_INTERVAL == 0
+}
+
+/// Runs `f`, catching a panic so the turn can go on without whatever
+/// panicked. Returns the panic's message, after logging it.
+#[cfg(panic = "unwind")]
+fn catching<T>(what: &str, f: impl FnOnce() -> T) -> Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        let info = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+            (Some(info), _) => info.to_string(),
+            (_, Some(info)) => info.clone(),
+            _ => "no message".to_string(),
+        };
+        println!("{what} panicked: {info}");
+        info
+    })
+}
 
-impl<S, B, M> Guest for Component<S, B, M>
+/// Built with `panic = "abort"`, as on wasm32, a panic ends the turn there
+/// and then, with nothing saved or broadcast, so there's none to catch.
+#[cfg(not(panic = "unwind"))]
+fn catching<T>(_what: &str, f: impl FnOnce() -> T) -> Result<T, String> {
+    Ok(f())
+}
+
+/// Runs `memory`, putting its broadcast back as it was if `run` panics, so
+/// a half-made change isn't sent to allies. Only where panics unwind, see
+/// `catching`.
+fn run_restoring<S: State<B, M>, B: CrdtSnapshot, M>(memory: &mut S) -> Result<Command, String> {
+    let snapshot = memory.broadcast().map(|b| b.snapshot());
+    catching("State::run", || memory.run()).inspect_err(|_| {
+        if let (Some(broadcast), Some(snapshot)) = (memory.broadcast(), snapshot) {
+            broadcast.restore(snapshot);
+        }
+    })
+}
+
+impl<S, B, M, SC, BC> Guest for Component<S, B, M, SC, BC>
 where
     S: State<B, M> + Serialize + DeserializeOwned + Default,
-    B: Crdt + Serialize + DeserializeOwned,
+    B: Crdt + CrdtSnapshot + Serialize + DeserializeOwned,
     M: Map + Serialize + DeserializeOwned,
+    SC: Codec,
+    BC: Codec,
 {
     fn step() -> Command {
-        let mut memory = match bincode::deserialize::<S>(&load_store()) {
+        let loaded = load_store();
+        let (config, store) = split_config_prefix(&loaded);
+        let header = stored_header(store);
+        let last_broadcast = header.as_ref().map(|header| header.broadcast_digest);
+        let mut memory = match decode_store::<S, B, M>(store) {
             Ok(memory) => memory,
             Err(e) => {
                 println!("Reinitialized memory: {e}");
                 S::default()
             }
         };
-
-        if let Some(map) = memory.map() {
-            map.update();
+        if let Some(config) = config
+            && let Err(e) = memory.configure(config)
+        {
+            println!("Couldn't apply config: {e}");
         }
+
+        let game_state = get_game_state();
+        let turn = game_state.turn;
+        advance_version_clock(turn_version(turn));
+        let previous = memory.merge_cache().map(std::mem::take);
+        let mut cache = MergeCache::default();
+        let mut budget = memory.merge_budget().map(|b| (b.max_entries, std::mem::take(&mut b.pending)));
         if let Some(broadcast) = memory.broadcast() {
             let (_, actor) = actor();
-            for (_, creature) in visible_creatures() {
-                if actor.faction == creature.faction {
-                    if let Some(other) = creature.broadcast {
-                        if let Ok(other) = bincode::deserialize(&other) {
-                            broadcast.merge(&other).unwrap();
-                        }
-                    }
+            let allies = visible_creatures()
+                .into_iter()
+                .filter(|(_, creature)| creature.faction == actor.faction)
+                .filter_map(|(loc, creature)| Some((loc, creature.broadcast?)));
+            let budget = budget.as_mut().map(|(max_entries, pending)| (*max_entries, pending));
+            let _ = catching("Merging broadcasts", || {
+                merge_broadcasts(broadcast, allies, previous.as_ref(), &mut cache, budget);
+                broadcast.cleanup(turn);
+                if let Some(tracker) = broadcast.horizon_tracker() {
+                    tracker.observe(turn);
+                    let horizon = tracker.min();
+                    broadcast.compact(horizon);
                 }
-            }
-            broadcast.cleanup(get_game_state().turn);
+            });
         }
-        let command = memory.run();
+        if let Some(merge_cache) = memory.merge_cache() {
+            *merge_cache = cache;
+        }
+        // After merging, so it prunes what allies' maps brought back too. A
+        // panic in this or the merging leaves the map or broadcast part way
+        // through the turn's changes, which the next turn carries on from.
+        if let Some(map) = memory.map() {
+            let _ = catching("Map::update", || map.update());
+        }
+        if let (Some(merge_budget), Some((_, pending))) = (memory.merge_budget(), budget) {
+            merge_budget.pending = pending;
+        }
+        if let Some(StoreHeader { level_id, .. }) = header
+            && level_id != game_state.level_id
+        {
+            let _ = catching("State::on_level_change", || memory.on_level_change(level_id, game_state.level_id));
+        }
+        let max_bytes = memory.broadcast_max_bytes();
+        let new_header = |broadcast_digest| StoreHeader::new(S::VERSION, game_state.level_id, broadcast_digest);
+        // Saved instead when `run` panics, so the store doesn't keep half
+        // its changes and the same panic every turn. This encodes the store
+        // twice a turn, where panics can be caught at all.
+        let before_run = cfg!(panic = "unwind").then(|| encode_store::<SC, _>(&memory, new_header(last_broadcast.unwrap_or_default())));
+        let (command, saved) = match run_restoring(&mut memory) {
+            Ok(command) => (command, None),
+            Err(info) => {
+                let command = catching("State::on_panic", || memory.on_panic(&info)).unwrap_or(Command::Nothing);
+                (command, before_run)
+            }
+        };
+        let forced = memory.force_broadcast();
+        let mut sent = last_broadcast.unwrap_or_default();
         if let Some(to_broadcast) = memory.broadcast() {
-            broadcast(Some(&bincode::serialize(to_broadcast).unwrap()));
+            let digest = to_broadcast.digest();
+            if should_broadcast(last_broadcast, digest, turn, forced) {
+                broadcast(Some(&encode_broadcast::<BC, _>(&*to_broadcast, turn, max_bytes)));
+                sent = digest;
+            }
+        }
+        let saved = saved.unwrap_or_else(|| encode_store::<SC, _>(&memory, new_header(sent)));
+        match config {
+            Some(config) => save_store(&with_config_prefix(config, &saved)),
+            None => save_store(&saved),
         }
-        save_store(&bincode::serialize(&memory).unwrap());
         command
     }
 
     fn editor_config() -> Option<Vec<u8>> {
-        None
+        S::editor_config()
     }
 }
 
 pub trait State<Broadcast=DummyBroadcast, Map=DummyMap> {
+    /// Saved along with the state. Bump it when a change to the state's
+    /// layout means stores from before won't deserialize, and read them in
+    /// `migrate`.
+    const VERSION: u32 = 0;
+
+    /// Reads a store saved at another `VERSION`, given without its header
+    /// but with its codec tag, so it's read with `codec::decode_tagged`.
+    /// Stores from before versions were saved are version 0. `None` starts
+    /// over from `Default`.
+    fn migrate(_version: u32, _bytes: &[u8]) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// The knobs `Component::editor_config` shows in the editor. A
+    /// `config::Configurable` state gets this and `configure` from
+    /// `configurable_state!()`.
+    fn editor_config() -> Option<Vec<u8>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+    /// Takes the config the editor put in front of the store, every turn
+    /// before `run`.
+    fn configure(&mut self, _bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
     fn run(&mut self) -> Command {
         Command::Nothing
     }
+    /// Called before `run` on the first turn on a new level, e.g. to drop
+    /// paths and targets on the old one.
+    fn on_level_change(&mut self, _old: i64, _new: i64) {}
+    /// Chooses the command when `run` panics, given what it panicked with,
+    /// e.g. retreating. The store is saved as it was before `run`, so only
+    /// what this changes in the broadcast outlasts the turn. Never called
+    /// when built with `panic = "abort"`, as wasm32 targets are: the panic
+    /// aborts the turn instead.
+    fn on_panic(&mut self, _info: &str) -> Command {
+        Command::Nothing
+    }
     fn broadcast(&mut self) -> Option<&mut Broadcast> {
         None
     }
     fn map(&mut self) -> Option<&mut Map> {
         None
     }
+    /// Lets `Component::step` skip merging broadcasts it merged last turn.
+    fn merge_cache(&mut self) -> Option<&mut MergeCache> {
+        None
+    }
+    /// Spreads big merges over several turns.
+    fn merge_budget(&mut self) -> Option<&mut MergeBudget> {
+        None
+    }
+    /// Sends the broadcast this turn even if it's the same as the last one
+    /// sent, e.g. to announce being around every so often.
+    fn force_broadcast(&self) -> bool {
+        false
+    }
+    /// Caps the size of each broadcast, leaving out the fields of lowest
+    /// priority that don't fit, see `Crdt::serialize_within`.
+    fn broadcast_max_bytes(&mut self) -> Option<usize> {
+        None
+    }
 }
 
 pub trait Map {
@@ -91,12 +539,181 @@ pub trait Map {
 }
 
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+/// For each level, its tiles by whether they're passable, what was seen on
+/// them, and whether the level is stable. Kept in level order, like the
+/// other layers, so the same levels always serialize and digest the same.
+pub type LevelMaps = BTreeMap<i64, (ExpiringCrdtMap<Loc, bool, Lww>, ExpiringCrdtMap<Loc, Option<String>, Lww>, bool)>;
+
+/// For each level, the creature last seen on each tile. `None` once the
+/// tile's been seen empty since.
+pub type Sightings = BTreeMap<i64, ExpiringCrdtMap<Loc, Option<CreatureSighting>, Lww>>;
+
+/// A creature seen standing somewhere.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct CreatureSighting {
+    pub faction: i64,
+    pub turn: i64,
+}
+
+/// How many turns sightings last unless `ExplorableMap::with_sighting_ttl`
+/// says otherwise.
+pub const DEFAULT_SIGHTING_TTL: i64 = 100;
+
+/// For each level, how dangerous each tile was when it was last marked, see
+/// `ExplorableMap::mark_danger`.
+pub type DangerMap = BTreeMap<i64, ExpiringCrdtMap<Loc, f32, Lww>>;
+
+/// How many turns it takes a tile's danger to halve.
+pub const DANGER_HALF_LIFE: i64 = 50;
+
+/// Danger that's decayed below this is forgotten.
+pub const DANGER_FLOOR: f32 = 0.1;
+
+fn decayed(danger: f32, marked: i64, now: i64) -> f32 {
+    danger * 0.5f32.powf(now.saturating_sub(marked).max(0) as f32 / DANGER_HALF_LIFE as f32)
+}
+
+/// A level's danger as path costs, as of `now`, see
+/// `ExplorableMap::danger_penalties`.
+#[derive(Clone, Copy)]
+pub struct DangerPenalties<'a> {
+    pub danger: Option<&'a ExpiringCrdtMap<Loc, f32, Lww>>,
+    pub now: i64,
+}
+
+impl PenaltyMap for DangerPenalties<'_> {
+    fn penalty_at(&self, loc: &Loc) -> f32 {
+        self.danger
+            .and_then(|danger| danger.0.get(loc))
+            .map_or(0.0, |(danger, marked, _)| decayed(*danger, *marked, self.now))
+    }
+}
+
+/// Only the level maps, sightings, danger and portals are merged, and so
+/// snapshotted.
+#[derive(Default, Debug, Serialize, Deserialize, CrdtContainer)]
+#[crdt(crate = "crate")]
 pub struct ExplorableMap {
-    pub maps: HashMap<i64, (CrdtMap<Loc, bool, Lww>, CrdtMap<Loc, Option<String>, Lww>, bool)>,
+    #[crdt(merge_with = "merge_levels")]
+    pub maps: LevelMaps,
+    #[crdt(merge_with = "merge_layers")]
+    pub seen_creatures: Sightings,
+    #[crdt(merge_with = "merge_layers")]
+    pub danger: DangerMap,
     pub unexplored_locs: IndexSet<Loc>,
     pub explore_target: Option<Loc>,
     pub current_path: Option<VecDeque<Loc>>,
+    /// When set, `explore` spreads path searches over several turns, expanding
+    /// at most this many tiles per turn and waiting in place meanwhile.
+    pub explore_budget: Option<usize>,
+    pub explore_search: Option<IncrementalAstar>,
+    pub path_cache: PathCache,
+    #[crdt(merge_with = "merge_portals")]
+    pub portals: IndexSet<Portal>,
+    pub travel_plan: Option<TravelPlan>,
+    /// Where the actor was at the last update, to notice it changing level.
+    pub last_position: Option<(i64, Loc)>,
+    /// How many turns observed tiles are remembered for. `None` keeps them
+    /// forever.
+    pub tile_ttl: Option<i64>,
+    /// Like `tile_ttl`, for what was seen lying on each tile.
+    pub item_ttl: Option<i64>,
+    /// How many turns creatures are remembered where they were seen. `None`
+    /// is `DEFAULT_SIGHTING_TTL`.
+    pub sighting_ttl: Option<i64>,
+    /// How many levels `maps` keeps, see `with_limits`. `None` keeps all.
+    pub max_levels: Option<usize>,
+    /// How many entries each level keeps, see `with_limits`. `None` keeps
+    /// all.
+    pub max_tiles_per_level: Option<usize>,
+    /// Levels visited, the latest last, so the others are pruned first.
+    pub recent_levels: VecDeque<i64>,
+    pub explore_strategy: ExploreStrategy,
+    /// Explore targets that couldn't be reached, by the turn they can be
+    /// picked again.
+    pub failed_targets: HashMap<Loc, i64>,
+}
+
+/// How `ExplorableMap::explore` picks which unexplored tile to go to next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExploreStrategy {
+    /// The closest in a straight line.
+    #[default]
+    Nearest,
+    /// The closest to walk to.
+    NearestByPath,
+    /// The one nearest the middle of the biggest cluster of unexplored
+    /// tiles.
+    LargestFrontier,
+    /// The nearest in the same cluster as the last target while any is
+    /// left, so the actor doesn't go back and forth between two.
+    Sticky,
+}
+
+/// How many turns an explore target that couldn't be reached is left out.
+pub const EXPLORE_RETRY_TURNS: i64 = 20;
+
+/// Groups `locs` into clusters of touching tiles, counting diagonals.
+fn clusters(locs: &IndexSet<Loc>) -> Vec<Vec<Loc>> {
+    let mut unvisited = locs.clone();
+    let mut clusters = Vec::new();
+    while let Some(start) = unvisited.pop() {
+        let mut cluster = vec![start];
+        let mut i = 0;
+        while let Some(&loc) = cluster.get(i) {
+            cluster.extend(loc.neighbors8().filter(|n| unvisited.shift_remove(n)));
+            i += 1;
+        }
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// How much `ExplorableMap::maps` holds, see `ExplorableMap::footprint`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MapFootprint {
+    pub levels: usize,
+    /// Tiles whose passability is known, over all levels.
+    pub tiles: usize,
+    /// What the levels take in bincode, as stored.
+    pub approx_bytes: u64,
+}
+
+/// Drops the `n` entries of `map` written longest ago.
+fn drop_oldest<V, P>(map: &mut ExpiringCrdtMap<Loc, V, P>, n: usize) {
+    let mut written: Vec<_> = map.0.iter().map(|(loc, (_, written, _))| (*written, *loc)).collect();
+    written.sort_unstable();
+    for (_, loc) in written.into_iter().take(n) {
+        map.0.remove(&loc);
+    }
+}
+
+/// A known way from `from_loc` on one level to `to_loc` on another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Portal {
+    pub from_level: i64,
+    pub from_loc: Loc,
+    pub to_level: i64,
+    pub to_loc: Loc,
+}
+
+/// The remaining portals `move_towards_level` means to take to reach `loc`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TravelPlan {
+    pub level_id: i64,
+    pub loc: Loc,
+    pub portals: VecDeque<Portal>,
+}
+
+/// What `move_towards_level` does about getting to a place.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelMove {
+    /// A step towards it, or towards the next portal on the way.
+    Step(Command),
+    /// Standing on the next portal, left for the caller to take.
+    OnPortal(Portal),
+    /// No known portals lead there, or no path does.
+    Unreachable,
 }
 
 impl Map for ExplorableMap {
@@ -104,68 +721,438 @@ impl Map for ExplorableMap {
         let game_state = get_game_state();
         let (map, seen_items, _) = &mut self.maps.entry(game_state.level_id).or_insert_with(|| (Default::default(), Default::default(), game_state.level_is_stable));
         let now = get_game_state().turn;
-        for (loc, tile) in visible_tiles() {
+        let tile_expires = self.tile_ttl.map_or(i64::MAX, |ttl| now.saturating_add(ttl));
+        let item_expires = self.item_ttl.map_or(i64::MAX, |ttl| now.saturating_add(ttl));
+        let known = map.len();
+        map.cleanup(now);
+        seen_items.cleanup(now);
+        if map.len() != known {
+            self.path_cache.bump();
+        }
+        let (agent_loc, _) = actor();
+        let last_position = self.last_position.replace((game_state.level_id, agent_loc));
+        map.insert(agent_loc, true, now, tile_expires);
+        self.unexplored_locs.shift_remove(&agent_loc);
+        let tiles = visible_tiles();
+        for &(loc, ref tile) in &tiles {
             self.unexplored_locs.shift_remove(&loc);
-            map.insert(loc, tile.passable, now);
+            if map.get(&loc) != Some(&tile.passable) {
+                self.path_cache.bump();
+            }
+            map.insert(loc, tile.passable, now, tile_expires);
             if tile.passable {
-                for dx in -1..2 {
-                    for dy in -1..2 {
-                        let mut n = loc;
-                        n.x += dx;
-                        n.y += dy;
-                        if !map.contains_key(&n) {
-                            self.unexplored_locs.insert(n);
-                        }
+                for n in loc.neighbors8() {
+                    if !map.contains_key(&n) {
+                        self.unexplored_locs.insert(n);
                     }
                 }
                 if let Some(item) = item_at(loc) {
-                    seen_items.insert(loc, Some(item.name), now);
+                    seen_items.insert(loc, Some(item.name), now, item_expires);
                 } else {
-                    seen_items.insert(loc, None, now);
+                    seen_items.insert(loc, None, now, item_expires);
                 }
             }
         }
+        let creatures = visible_creatures().into_iter().map(|(loc, creature)| (loc, creature.faction));
+        self.record_sightings(game_state.level_id, now, agent_loc, creatures, tiles.iter().map(|(loc, _)| *loc));
 
         self.maps.retain(|id, (_, _, is_stable)| *id == game_state.level_id || *is_stable);
+        self.visit_level(game_state.level_id);
+        self.prune(game_state.level_id);
+        self.seen_creatures.retain(|id, _| self.maps.contains_key(id));
+        self.danger.retain(|id, _| self.maps.contains_key(id));
+        if let Some(danger) = self.danger.get_mut(&game_state.level_id) {
+            danger.cleanup(now);
+        }
+        if let Some((level, loc)) = last_position
+            && level != game_state.level_id
+        {
+            self.change_level(level, loc, game_state.level_id, agent_loc);
+        }
     }
 }
 
-impl Crdt for ExplorableMap {
-    fn merge(&mut self, other: &Self) -> Result<()> {
-        for (id, (map, seen_items, _)) in self.maps.iter_mut() {
-            if let Some((other_map, other_seen_items, _)) = other.maps.get(id) {
+/// Merges the levels both sides know, and takes stable levels only `other`
+/// does. Unstable ones would be dropped before getting there anyway.
+fn merge_levels(maps: &mut LevelMaps, other: &LevelMaps) -> Result<()> {
+    for (id, (other_map, other_seen_items, is_stable)) in other {
+        match maps.get_mut(id) {
+            Some((map, seen_items, _)) => {
                 map.merge(other_map)?;
                 seen_items.merge(other_seen_items)?;
             }
+            None if *is_stable => {
+                maps.insert(*id, (other_map.clone(), other_seen_items.clone(), true));
+            }
+            None => {}
         }
-        Ok(())
     }
+    Ok(())
+}
+
+/// Merges per level layers like `Sightings`, keeping levels only `other`
+/// knows, as they're dropped with the levels of `maps` anyway.
+fn merge_layers<V: PartialOrd + Clone>(
+    layers: &mut BTreeMap<i64, ExpiringCrdtMap<Loc, V, Lww>>,
+    other: &BTreeMap<i64, ExpiringCrdtMap<Loc, V, Lww>>,
+) -> Result<()> {
+    for (id, other_layer) in other {
+        layers.entry(*id).or_default().merge(other_layer)?;
+    }
+    Ok(())
 }
 
+fn merge_portals(portals: &mut IndexSet<Portal>, other: &IndexSet<Portal>) -> Result<()> {
+    portals.extend(other.iter().copied());
+    Ok(())
+}
+
+/// The closest seen item of the earliest type in `tys` that was seen at
+/// all. Scans columns spreading out from `current_loc`, so it can stop once
+/// nothing further away could beat what it's found.
+fn nearest_seen(
+    seen_items: &ExpiringCrdtMap<Loc, Option<String>, Lww>,
+    current_loc: Loc,
+    tys: &[impl AsRef<str>],
+    metric: Heuristic,
+) -> Option<Loc> {
+    let (first, last) = (seen_items.0.first_key_value()?.0.x, seen_items.0.last_key_value()?.0.x);
+    let mut best: Option<(usize, i64, Loc)> = None;
+    let mut scanned: Option<(i32, i32)> = None;
+    let mut radius = 0;
+    loop {
+        let lo = current_loc.x.saturating_sub(radius).max(first);
+        let hi = current_loc.x.saturating_add(radius).min(last);
+        if lo <= hi {
+            let strips = match scanned {
+                None => [Some((lo, hi)), None],
+                Some((scanned_lo, scanned_hi)) => {
+                    [(lo < scanned_lo).then(|| (lo, scanned_lo - 1)), (hi > scanned_hi).then(|| (scanned_hi + 1, hi))]
+                }
+            };
+            for (min_x, max_x) in strips.into_iter().flatten() {
+                for (loc, l_ty) in seen_items.range(loc_columns(min_x, max_x)) {
+                    let Some(i) = l_ty.as_ref().and_then(|l_ty| tys.iter().position(|ty| ty.as_ref() == l_ty)) else {
+                        continue;
+                    };
+                    let d = metric.rank(current_loc, *loc);
+                    if best.is_none_or(|(best_i, best_d, _)| (i, d) < (best_i, best_d)) {
+                        best = Some((i, d, *loc));
+                    }
+                }
+            }
+            scanned = Some((lo, hi));
+        }
+        // Everything not yet scanned is more than `radius` columns away.
+        let unscanned = metric.rank(current_loc, current_loc.offset(radius.saturating_add(1), 0));
+        if (lo <= first && hi >= last) || best.is_some_and(|(i, d, _)| i == 0 && d <= unscanned) {
+            break;
+        }
+        radius = radius.saturating_mul(2).max(8);
+    }
+    best.map(|(_, _, loc)| loc)
+}
+
+/// The seen item cheapest to walk to from `current_loc`, for at most
+/// `max_cost`, among those of the best class `filter` gives their names.
+fn nearest_seen_by_path(
+    map: &dyn LocMap,
+    seen_items: &ExpiringCrdtMap<Loc, Option<String>, Lww>,
+    current_loc: Loc,
+    filter: impl Fn(&str) -> Option<u32>,
+    max_cost: f32,
+) -> Option<(Loc, String)> {
+    let field = distance_field(current_loc, map, &EmptyLocSet, max_cost);
+    field
+        .costs
+        .iter()
+        .filter_map(|(loc, cost)| {
+            let name = seen_items.get(loc)?.as_ref()?;
+            Some((filter(name)?, OrderedFloat(*cost), *loc, name))
+        })
+        .min()
+        .map(|(_, _, loc, name)| (loc, name.clone()))
+}
+
+/// How far `ExplorableMap::nearest_by_path` walks looking for items.
+pub const NEAREST_MAX_COST: f32 = 256.0;
+
 impl ExplorableMap {
-    pub fn explore(&mut self) -> Option<Command> {
-        if let Some(loc) = self.explore_target {
-            if visible_tiles().into_iter().any(|(l, _)| l == loc) {
-                self.explore_target = None;
+    pub fn with_tile_ttl(mut self, ttl: i64) -> Self {
+        self.tile_ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_item_ttl(mut self, ttl: i64) -> Self {
+        self.item_ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_sighting_ttl(mut self, ttl: i64) -> Self {
+        self.sighting_ttl = Some(ttl);
+        self
+    }
+
+    /// Notes where creatures are on `level_id`, by their faction, other
+    /// than the actor at `agent_loc`, and that the rest of the `seen` tiles
+    /// are empty now. Drops the level's sightings older than `sighting_ttl`.
+    pub fn record_sightings(
+        &mut self,
+        level_id: i64,
+        now: i64,
+        agent_loc: Loc,
+        creatures: impl IntoIterator<Item = (Loc, i64)>,
+        seen: impl IntoIterator<Item = Loc>,
+    ) {
+        let expires = now.saturating_add(self.sighting_ttl.unwrap_or(DEFAULT_SIGHTING_TTL));
+        let sightings = self.seen_creatures.entry(level_id).or_default();
+        sightings.cleanup(now);
+        // Only tiles with a sighting are cleared, so empty ones take no room.
+        for loc in seen {
+            if sightings.get(&loc).is_some_and(Option::is_some) {
+                sightings.insert(loc, None, now, expires);
             }
         }
+        for (loc, faction) in creatures.into_iter().filter(|(loc, _)| *loc != agent_loc) {
+            sightings.insert(loc, Some(CreatureSighting { faction, turn: now }), now, expires);
+        }
+    }
 
+    /// Creatures not of `faction` seen on `level_id` at most `max_age` turns
+    /// before `now`.
+    pub fn enemies_seen(&self, level_id: i64, faction: i64, now: i64, max_age: i64) -> Vec<(Loc, &CreatureSighting)> {
+        let Some(sightings) = self.seen_creatures.get(&level_id) else {
+            return Vec::new();
+        };
+        sightings
+            .iter()
+            .filter_map(|(loc, sighting)| Some((*loc, sighting.as_ref()?)))
+            .filter(|(_, sighting)| sighting.faction != faction && now.saturating_sub(sighting.turn) <= max_age)
+            .collect()
+    }
+
+    /// Creatures of other factions than the actor's seen on this level at
+    /// most `max_age` turns ago.
+    pub fn last_seen_enemies(&self, max_age: i64) -> Vec<(Loc, &CreatureSighting)> {
+        let game_state = get_game_state();
+        let (_, actor) = actor();
+        self.enemies_seen(game_state.level_id, actor.faction, game_state.turn, max_age)
+    }
+
+    /// The closest enemy still remembered on this level.
+    pub fn nearest_known_enemy(&self) -> Option<(Loc, &CreatureSighting)> {
         let (current_loc, _) = actor();
+        self.last_seen_enemies(i64::MAX)
+            .into_iter()
+            .min_by_key(|(loc, _)| distance_squared(*loc, current_loc))
+    }
+
+    /// Notes that harm came to the actor, or an ally, at `loc` on the level
+    /// of the last update, so paths there cost `amount` more, halving every
+    /// `DANGER_HALF_LIFE` turns. Marks add up.
+    pub fn mark_danger(&mut self, loc: Loc, amount: f32, now: i64) {
+        let Some((level_id, _)) = self.last_position else {
+            return;
+        };
+        let danger = self.danger.entry(level_id).or_default();
+        let before = danger.0.get(&loc).map_or(0.0, |(danger, marked, _)| decayed(*danger, *marked, now));
+        let total = before + amount;
+        let lasts = if total > DANGER_FLOOR {
+            (DANGER_HALF_LIFE as f32 * (total / DANGER_FLOOR).log2()).ceil() as i64
+        } else {
+            0
+        };
+        danger.insert(loc, total, now, now.saturating_add(lasts.max(1)));
+        if self.current_path.as_ref().is_some_and(|path| path.contains(&loc)) {
+            self.current_path = None;
+        }
+        self.path_cache.bump();
+    }
+
+    /// How dangerous `loc` on `level_id` is at `now`.
+    pub fn danger_at(&self, level_id: i64, loc: Loc, now: i64) -> f32 {
+        self.danger_penalties(level_id, now).penalty_at(&loc)
+    }
 
-        if self.explore_target.is_none() {
-            if !self.unexplored_locs.is_empty() {
-                let loc = self
-                    .unexplored_locs
-                    .iter()
-                    .min_by_key(|loc| OrderedFloat(distance(**loc, current_loc)));
-                self.explore_target = loc.copied();
+    /// The danger on `level_id` as path costs, to go with its map in
+    /// `WithPenalties`. `move_towards` and the like already do.
+    pub fn danger_penalties(&self, level_id: i64, now: i64) -> DangerPenalties<'_> {
+        DangerPenalties {
+            danger: self.danger.get(&level_id),
+            now,
+        }
+    }
+
+    pub fn with_explore_strategy(mut self, strategy: ExploreStrategy) -> Self {
+        self.explore_strategy = strategy;
+        self
+    }
+
+    /// Picks the next explore target by `explore_strategy`, among the
+    /// unexplored tiles bordering the walkable region `current_loc` is in
+    /// that haven't recently failed. `previous` is the last target, for
+    /// `ExploreStrategy::Sticky`.
+    pub fn pick_explore_target(&mut self, level_id: i64, current_loc: Loc, turn: i64, previous: Option<Loc>) -> Option<Loc> {
+        self.failed_targets.retain(|_, until| *until > turn);
+        let map = self.maps.get(&level_id).map(|(map, _, _)| map);
+        // Ignoring creatures, since they move.
+        let regions = map.map(|map| Regions::new(map, &EmptyLocSet));
+        let region = regions.as_ref().and_then(|regions| regions.region_of(current_loc));
+        let candidates: IndexSet<Loc> = self
+            .unexplored_locs
+            .iter()
+            .filter(|loc| !self.failed_targets.contains_key(loc))
+            .filter(|loc| match (&regions, region) {
+                (Some(regions), Some(region)) => regions.borders(**loc, region),
+                _ => true,
+            })
+            .copied()
+            .collect();
+        let nearest = |locs: &mut dyn Iterator<Item = Loc>| locs.min_by_key(|loc| distance_squared(*loc, current_loc));
+        match self.explore_strategy {
+            ExploreStrategy::Nearest => nearest(&mut candidates.iter().copied()),
+            ExploreStrategy::NearestByPath => map
+                .and_then(|map| {
+                    astar_multi(current_loc, &candidates, map, &EmptyLocSet, &EmptyLocSet, &AstarOptions::default())
+                })
+                .map(|(goal, _)| goal)
+                .or_else(|| nearest(&mut candidates.iter().copied())),
+            ExploreStrategy::LargestFrontier => {
+                let largest = clusters(&candidates).into_iter().max_by_key(|cluster| cluster.len())?;
+                let n = largest.len() as i64;
+                let (x, y) = largest.iter().fold((0, 0), |(x, y), loc| (x + i64::from(loc.x), y + i64::from(loc.y)));
+                let centroid = Loc {
+                    x: (x / n) as i32,
+                    y: (y / n) as i32,
+                };
+                largest.into_iter().min_by_key(|loc| distance_squared(*loc, centroid))
+            }
+            ExploreStrategy::Sticky => {
+                let cluster = previous.and_then(|previous| {
+                    clusters(&candidates)
+                        .into_iter()
+                        .find(|cluster| cluster.iter().any(|loc| chebyshev_distance(*loc, previous) <= 1))
+                });
+                match cluster {
+                    Some(cluster) => nearest(&mut cluster.into_iter()),
+                    None => nearest(&mut candidates.iter().copied()),
+                }
             }
         }
+    }
+
+    /// Caps how many levels are kept, dropping the stable ones visited
+    /// longest ago, and entries on each level, counting both tiles and what
+    /// was seen on them. Seen items go first, oldest first, as passability
+    /// matters more; then the oldest tiles.
+    pub fn with_limits(mut self, max_levels: usize, max_tiles_per_level: usize) -> Self {
+        self.max_levels = Some(max_levels);
+        self.max_tiles_per_level = Some(max_tiles_per_level);
+        self
+    }
+
+    /// Marks `level_id` the latest level visited.
+    pub fn visit_level(&mut self, level_id: i64) {
+        if self.recent_levels.back() != Some(&level_id) {
+            self.recent_levels.retain(|id| *id != level_id);
+            self.recent_levels.push_back(level_id);
+        }
+    }
+
+    /// Holds `maps` to `with_limits`, never dropping `current_level`.
+    /// Levels never visited, such as ones merged from allies, are dropped
+    /// before any that were.
+    pub fn prune(&mut self, current_level: i64) {
+        if let Some(max_tiles) = self.max_tiles_per_level {
+            for (id, (tiles, seen_items, _)) in self.maps.iter_mut() {
+                let excess = (tiles.len() + seen_items.len()).saturating_sub(max_tiles);
+                let items = excess.min(seen_items.len());
+                drop_oldest(seen_items, items);
+                if excess > items {
+                    drop_oldest(tiles, excess - items);
+                    if *id == current_level {
+                        self.path_cache.bump();
+                    }
+                }
+            }
+        }
+        if let Some(max_levels) = self.max_levels {
+            while self.maps.len() > max_levels {
+                let oldest = self
+                    .maps
+                    .keys()
+                    .filter(|id| **id != current_level)
+                    .min_by_key(|id| self.recent_levels.iter().position(|r| r == *id))
+                    .copied();
+                let Some(oldest) = oldest else {
+                    break;
+                };
+                self.maps.remove(&oldest);
+            }
+        }
+        self.recent_levels.retain(|id| self.maps.contains_key(id));
+    }
+
+    pub fn footprint(&self) -> MapFootprint {
+        MapFootprint {
+            levels: self.maps.len(),
+            tiles: self.maps.values().map(|(tiles, _, _)| tiles.len()).sum(),
+            approx_bytes: bincode::serialized_size(&self.maps).unwrap_or(0),
+        }
+    }
+
+    pub fn explore(&mut self) -> Option<Command> {
+        let mut previous = None;
+        if let Some(loc) = self.explore_target
+            && visible_tiles().into_iter().any(|(l, _)| l == loc)
+        {
+            previous = self.explore_target.take();
+        }
+
+        let (current_loc, _) = actor();
+        let game_state = get_game_state();
+
+        if self.explore_target.is_none() && !self.unexplored_locs.is_empty() {
+            self.explore_target = self.pick_explore_target(game_state.level_id, current_loc, game_state.turn, previous);
+        }
 
         if let Some(loc) = self.explore_target {
             let (blocked, avoid) = avoidance_sets(1, None);
-            if let Some((map, _, _)) = self.maps.get(&get_game_state().level_id) {
-                move_towards(&mut self.current_path, map, &blocked, &avoid, loc)
+            if let Some((map, _, _)) = self.maps.get(&game_state.level_id) {
+                let has_path = self.current_path.as_ref().and_then(|path| path.back()) == Some(&loc);
+                let mut unreachable = false;
+                if let Some(budget) = self.explore_budget
+                    && !has_path
+                {
+                    let mut search = match self.explore_search.take() {
+                        Some(search) if search.start() == current_loc && search.goal() == loc => search,
+                        _ => IncrementalAstar::new(current_loc, loc, AstarOptions::default()),
+                    };
+                    match search.step(map, &blocked, &avoid, budget) {
+                        SearchStatus::InProgress => {
+                            self.explore_search = Some(search);
+                            return Some(Command::Nothing);
+                        }
+                        SearchStatus::Found(path) => self.current_path = Some(path),
+                        SearchStatus::Unreachable => unreachable = true,
+                    }
+                }
+                if !unreachable
+                    && let Some(command) = move_towards(&mut self.current_path, map, &blocked, &avoid, loc)
+                {
+                    return Some(command);
+                }
+                // The target can't be reached, so walk up to the obstruction
+                // and once there give up on it so another frontier gets picked.
+                let (path, _) = astar_partial(current_loc, loc, map, &blocked, &avoid, &AstarOptions::default());
+                if let Some(&end) = path.steps.back() {
+                    self.current_path = Some(path.steps);
+                    move_towards(&mut self.current_path, map, &blocked, &avoid, end)
+                } else {
+                    self.failed_targets.insert(loc, game_state.turn + EXPLORE_RETRY_TURNS);
+                    self.explore_target = None;
+                    None
+                }
             } else {
                 None
             }
@@ -174,47 +1161,898 @@ impl ExplorableMap {
         }
     }
 
-    pub fn nearest(&mut self, tys: &[impl AsRef<str>]) -> Option<Loc> {
-        let mut nearest = None;
-        let mut nearest_ty = None;
-        let mut nearest_d = std::f32::INFINITY;
-        let (current_loc, _) = actor();
-        if let Some((_, seen_items, _)) = self.maps.get(&get_game_state().level_id) {
-            for (loc, l_ty) in seen_items.iter() {
-                if let Some(l_ty) = l_ty {
-                    if let Some(i) = tys.iter().position(|ty| ty.as_ref() == l_ty) {
-                        if nearest_ty.map(|ty_i| ty_i >= i).unwrap_or(true) {
-                            let d = distance(current_loc, *loc);
-                            if nearest_ty.map(|ty_i| ty_i > i).unwrap_or(true) || d < nearest_d {
-                                nearest_ty = Some(i);
-                                nearest = Some(*loc);
-                                nearest_d = d;
-                            }
-                        }
-                    }
+    /// Portals are registered automatically with the tile the actor stood on
+    /// just before changing level. Exits that trigger on stepping into them
+    /// can be registered by hand with the exit tile instead.
+    pub fn register_portal(&mut self, from_level: i64, from_loc: Loc, to_level: i64, to_loc: Loc) {
+        self.portals.insert(Portal {
+            from_level,
+            from_loc,
+            to_level,
+            to_loc,
+        });
+    }
+
+    /// Notes the portal just taken, and forgets the path, target and search,
+    /// which were on the old level. What's left to explore is found again
+    /// from the new level's map, which may have come from allies.
+    pub fn change_level(&mut self, from_level: i64, from_loc: Loc, to_level: i64, to_loc: Loc) {
+        self.register_portal(from_level, from_loc, to_level, to_loc);
+        self.current_path = None;
+        self.explore_target = None;
+        self.explore_search = None;
+        self.unexplored_locs = self.frontier(to_level);
+    }
+
+    /// The unknown tiles next to known passable ones on a level.
+    pub fn frontier(&self, level_id: i64) -> IndexSet<Loc> {
+        let Some((map, _, _)) = self.maps.get(&level_id) else {
+            return IndexSet::new();
+        };
+        map.iter()
+            .filter(|(_, passable)| **passable)
+            .flat_map(|(loc, _)| loc.neighbors8())
+            .filter(|n| !map.contains_key(n))
+            .collect()
+    }
+
+    /// The fewest portals to take to get from one level to another.
+    pub fn portal_route(&self, from_level: i64, to_level: i64) -> Option<VecDeque<Portal>> {
+        let mut came_from: HashMap<i64, Portal> = HashMap::new();
+        let mut queue = VecDeque::from([from_level]);
+        while let Some(level) = queue.pop_front() {
+            if level == to_level {
+                let mut route = VecDeque::new();
+                let mut level = to_level;
+                while level != from_level {
+                    let portal = came_from[&level];
+                    route.push_front(portal);
+                    level = portal.from_level;
+                }
+                return Some(route);
+            }
+            for portal in self.portals.iter().filter(|p| p.from_level == level) {
+                if portal.to_level != from_level && !came_from.contains_key(&portal.to_level) {
+                    came_from.insert(portal.to_level, *portal);
+                    queue.push_back(portal.to_level);
                 }
             }
         }
+        None
+    }
 
-        nearest
+    /// Like `move_towards`, but `loc` may be on another level, reached through
+    /// registered portals.
+    pub fn move_towards_level(&mut self, level_id: i64, loc: Loc) -> LevelMove {
+        let step = |command: Option<Command>| command.map_or(LevelMove::Unreachable, LevelMove::Step);
+        let current_level = get_game_state().level_id;
+        if current_level == level_id {
+            self.travel_plan = None;
+            return step(self.move_towards(loc));
+        }
+        let planned = self
+            .travel_plan
+            .take()
+            .filter(|plan| plan.level_id == level_id && plan.loc == loc)
+            .and_then(|mut plan| {
+                let i = plan.portals.iter().position(|p| p.from_level == current_level)?;
+                plan.portals.drain(..i);
+                Some(plan.portals)
+            });
+        let Some(portals) = planned.or_else(|| self.portal_route(current_level, level_id)) else {
+            return LevelMove::Unreachable;
+        };
+        let Some(&portal) = portals.front() else {
+            return LevelMove::Unreachable;
+        };
+        self.travel_plan = Some(TravelPlan { level_id, loc, portals });
+        let (current_loc, _) = actor();
+        if current_loc == portal.from_loc {
+            return LevelMove::OnPortal(portal);
+        }
+        step(self.move_towards(portal.from_loc))
     }
 
-    pub fn move_towards_nearest(&mut self, tys: &[impl AsRef<str>]) -> Option<Command> {
-        let nearest = self.nearest(tys);
+    /// The seen item of the earliest type in `tys` that's closest to walk to.
+    pub fn nearest(&mut self, tys: &[impl AsRef<str>]) -> Option<Loc> {
+        let class = |name: &str| tys.iter().position(|ty| ty.as_ref() == name).map(|i| i as u32);
+        self.nearest_by_path(class).map(|(loc, _)| loc)
+    }
 
-        if let Some(loc) = nearest {
-            self.move_towards(loc)
-        } else {
-            None
+    /// The seen item on this level closest to walk to, with its name, among
+    /// those `filter` gives the lowest class. Items it gives `None` are
+    /// skipped, as are those further than `NEAREST_MAX_COST`.
+    pub fn nearest_by_path(&mut self, filter: impl Fn(&str) -> Option<u32>) -> Option<(Loc, String)> {
+        let (current_loc, _) = actor();
+        let (map, seen_items, _) = self.maps.get(&get_game_state().level_id)?;
+        nearest_seen_by_path(map, seen_items, current_loc, filter, NEAREST_MAX_COST)
+    }
+
+    /// Like `nearest`, by straight-line distance in `metric`, which is
+    /// cheaper but can pick items on the far side of walls.
+    pub fn nearest_by(&mut self, tys: &[impl AsRef<str>], metric: Heuristic) -> Option<Loc> {
+        let (current_loc, _) = actor();
+        let (_, seen_items, _) = self.maps.get(&get_game_state().level_id)?;
+        nearest_seen(seen_items, current_loc, tys, metric)
+    }
+
+    /// The explored passable tile closest to `loc`, which may be `loc` itself.
+    /// Useful for walking up to things standing on impassable tiles.
+    pub fn nearest_passable(&self, loc: Loc) -> Option<Loc> {
+        let (map, _, _) = self.maps.get(&get_game_state().level_id)?;
+        let bounds = Rect::bounding(map.iter().map(|(l, _)| *l))?;
+        let max_radius = chebyshev_distance(loc, bounds.min).max(chebyshev_distance(loc, bounds.max));
+        nearest_matching(loc, max_radius as u32, |l| map.get_loc(&l) == Some(true))
+    }
+
+    /// Walks to the closest seen item of the earliest type in `tys` that can
+    /// be reached, measuring closeness by path rather than straight-line distance.
+    pub fn move_towards_nearest(&mut self, tys: &[impl AsRef<str>]) -> Option<Command> {
+        let (current_loc, _) = actor();
+        let (map, seen_items, _) = self.maps.get(&get_game_state().level_id)?;
+        let (mut blocked, avoid) = avoidance_sets(1, None);
+        for ty in tys {
+            let goals: IndexSet<Loc> = seen_items
+                .iter()
+                .filter(|(_, l_ty)| l_ty.as_deref() == Some(ty.as_ref()))
+                .map(|(loc, _)| *loc)
+                .collect();
+            if let Some((goal, path)) = astar_multi(current_loc, &goals, map, &blocked, &avoid, &AstarOptions::default()) {
+                // Searches walk onto their goal even when it's blocked, by an
+                // Exit or a creature standing there, but the path kept for
+                // `move_towards` is checked against `blocked` every turn.
+                blocked.shift_remove(&goal);
+                self.current_path = Some(path);
+                return self.move_towards_avoiding(goal, &AstarOptions::default(), &blocked, &avoid);
+            }
         }
+        None
     }
 
     pub fn move_towards(&mut self, loc: Loc) -> Option<Command> {
-        if let Some((map, _, _)) = self.maps.get(&get_game_state().level_id) {
-            let (blocked, avoid) = avoidance_sets(1, Some(loc));
-            move_towards(&mut self.current_path, map, &blocked, &avoid, loc)
+        self.move_towards_with_options(loc, &AstarOptions::default())
+    }
+
+    pub fn move_adjacent_to(&mut self, loc: Loc) -> Option<Command> {
+        let options = AstarOptions {
+            arrival: ArrivalCondition::WithinChebyshev(1),
+            ..Default::default()
+        };
+        self.move_towards_with_options(loc, &options)
+    }
+
+    pub fn move_towards_with_options(&mut self, loc: Loc, options: &AstarOptions) -> Option<Command> {
+        let (blocked, avoid) = avoidance_sets(1, Some(loc));
+        self.move_towards_avoiding(loc, options, &blocked, &avoid)
+    }
+
+    fn move_towards_avoiding(&mut self, loc: Loc, options: &AstarOptions, blocked: &dyn LocSet, avoid: &dyn LocSet) -> Option<Command> {
+        let game_state = get_game_state();
+        if let Some((map, _, _)) = self.maps.get(&game_state.level_id) {
+            let penalties = DangerPenalties {
+                danger: self.danger.get(&game_state.level_id),
+                now: game_state.turn,
+            };
+            let map = WithPenalties(map, &penalties);
+            move_towards_with_cache(&mut self.current_path, &mut self.path_cache, &map, blocked, avoid, loc, options)
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod update_tests {
+    use super::*;
+    use crate::host::{with_host, MockHost};
+    use bindings::{Action, ActionTarget, Creature, GameState, MicroAction, Tile};
+    use std::rc::Rc;
+
+    const ORIGIN: Loc = Loc { x: 0, y: 0 };
+    const WALL: Loc = Loc { x: 1, y: 1 };
+
+    fn creature(faction: i64) -> Creature {
+        Creature { faction, broadcast: None }
+    }
+
+    /// The actor at the origin of level `level_id`, seeing the 3x3 square
+    /// around it, which is all floor but `WALL`.
+    fn host(level_id: i64, turn: i64) -> MockHost {
+        let tiles = ORIGIN.ring(1).chain([ORIGIN]).map(|loc| (loc, Tile { passable: loc != WALL }));
+        MockHost::default()
+            .with_actor(ORIGIN, creature(1))
+            .with_tiles(tiles)
+            .with_action(Action { micro_actions: vec![MicroAction::Walk] })
+            .with_game_state(GameState { turn, level_id, level_is_stable: true })
+    }
+
+    #[test]
+    fn paths_to_occupied_items_are_kept() {
+        let mut map = ExplorableMap::default();
+        let gold = Loc { x: 2, y: 0 };
+        let host = Rc::new(host(1, 0).with_creature(gold, creature(1)));
+        with_host(host.clone(), || map.update());
+        let (tiles, seen_items, _) = map.maps.get_mut(&1).unwrap();
+        tiles.insert(gold, true, 0, i64::MAX);
+        seen_items.insert(gold, Some("Gold".to_string()), 0, i64::MAX);
+        let walk = Some(Command::UseAction((0, Some(ActionTarget::Location(Loc { x: 1, y: 0 })))));
+        assert_eq!(with_host(host, || map.move_towards_nearest(&["Gold"])), walk);
+        assert_eq!(map.current_path, Some(VecDeque::from([Loc { x: 1, y: 0 }, gold])));
+    }
+
+    #[test]
+    fn update_maps_what_is_visible() {
+        let mut map = ExplorableMap::default();
+        let host = host(1, 3).with_creature(Loc { x: -1, y: 0 }, creature(2));
+        with_host(Rc::new(host), || map.update());
+
+        let (tiles, _, is_stable) = &map.maps[&1];
+        assert_eq!(tiles.len(), 9);
+        assert_eq!((tiles.get(&WALL), tiles.get(&Loc { x: 1, y: 0 }), *is_stable), (Some(&false), Some(&true), true));
+        assert!(map.unexplored_locs.contains(&Loc { x: 2, y: 0 }));
+        assert!(!map.unexplored_locs.contains(&Loc { x: 2, y: 2 }) && !map.unexplored_locs.contains(&ORIGIN));
+        assert_eq!(map.last_position, Some((1, ORIGIN)));
+        let enemies: Vec<_> = map.enemies_seen(1, 1, 3, 0).into_iter().map(|(loc, _)| loc).collect();
+        assert_eq!(enemies, [Loc { x: -1, y: 0 }]);
+    }
+
+    #[test]
+    fn explore_walks_to_the_frontier() {
+        let mut map = ExplorableMap::default();
+        let command = with_host(Rc::new(host(1, 3)), || {
+            map.update();
+            map.explore()
+        });
+
+        let target = map.explore_target.unwrap();
+        assert!(map.unexplored_locs.contains(&target));
+        let Some(Command::UseAction((0, Some(ActionTarget::Location(step))))) = command else {
+            panic!("expected a step, got {command:?}");
+        };
+        assert_eq!(chebyshev_distance(step, ORIGIN), 1);
+        assert!(step != WALL && chebyshev_distance(step, target) < chebyshev_distance(ORIGIN, target));
+    }
+
+    #[test]
+    fn updates_prune_what_allies_brought_back() {
+        let mut map = ExplorableMap::default().with_limits(1, 9);
+        with_host(Rc::new(host(1, 3)), || map.update());
+        with_host(Rc::new(host(2, 4)), || map.update());
+        assert_eq!(map.maps.keys().collect::<Vec<_>>(), [&2]);
+
+        // The ally knows the level pruned, and more of this one than fits.
+        let mut ally = ExplorableMap::default();
+        with_host(Rc::new(host(1, 3)), || ally.update());
+        with_host(Rc::new(host(2, 4)), || ally.update());
+        let (tiles, _, _) = ally.maps.get_mut(&2).unwrap();
+        for x in 2..6 {
+            tiles.insert(Loc { x, y: 0 }, true, 4, i64::MAX);
+        }
+        map.merge(&ally).unwrap();
+        assert!(map.maps.contains_key(&1));
+        with_host(Rc::new(host(2, 5)), || map.update());
+        assert_eq!(map.maps.keys().collect::<Vec<_>>(), [&2]);
+        let (tiles, seen_items, _) = &map.maps[&2];
+        assert!(tiles.len() + seen_items.len() <= 9);
+    }
+
+    #[test]
+    fn moving_to_another_level_tells_on_portal_from_unreachable() {
+        let mut map = ExplorableMap::default();
+        let there = Loc { x: 5, y: 5 };
+        map.register_portal(1, ORIGIN, 2, there);
+        let moves = with_host(Rc::new(host(1, 3)), || {
+            map.update();
+            [3, 2].map(|level| map.move_towards_level(level, there))
+        });
+
+        let portal = Portal { from_level: 1, from_loc: ORIGIN, to_level: 2, to_loc: there };
+        assert_eq!(moves, [LevelMove::Unreachable, LevelMove::OnPortal(portal)]);
+        assert_eq!(map.travel_plan.unwrap().portals, [portal]);
+    }
+
+    #[test]
+    fn updates_on_another_level_register_a_portal() {
+        let mut map = ExplorableMap::default();
+        with_host(Rc::new(host(1, 3)), || map.update());
+        map.explore_target = Some(Loc { x: 2, y: 0 });
+        with_host(Rc::new(host(2, 4)), || map.update());
+
+        assert_eq!(map.portal_route(1, 2).unwrap().len(), 1);
+        assert_eq!(map.explore_target, None);
+        assert!(map.maps.contains_key(&1) && map.maps.contains_key(&2));
+    }
+}
+
+#[cfg(test)]
+mod portal_tests {
+    use super::*;
+
+    #[test]
+    fn routes_take_fewest_portals() {
+        let mut map = ExplorableMap::default();
+        let loc = |x| Loc { x, y: 0 };
+        map.register_portal(1, loc(1), 2, loc(1));
+        map.register_portal(2, loc(2), 3, loc(2));
+        map.register_portal(3, loc(3), 4, loc(3));
+        map.register_portal(2, loc(4), 4, loc(4));
+        map.register_portal(4, loc(5), 1, loc(5));
+        let route: Vec<_> = map.portal_route(1, 4).unwrap().iter().map(|p| p.from_loc.x).collect();
+        assert_eq!(route, [1, 4]);
+        assert_eq!(map.portal_route(4, 3).unwrap().len(), 3);
+        assert_eq!(map.portal_route(2, 2).unwrap().len(), 0);
+        assert_eq!(map.portal_route(1, 5), None);
+    }
+
+    #[test]
+    fn changing_level_drops_the_old_path_and_target() {
+        let mut map = ExplorableMap::default();
+        let loc = |x| Loc { x, y: 0 };
+        map.current_path = Some(VecDeque::from([loc(1), loc(2)]));
+        map.explore_target = Some(loc(2));
+        map.change_level(1, loc(3), 2, loc(4));
+        assert!(map.current_path.is_none() && map.explore_target.is_none());
+        assert_eq!(map.portal_route(1, 2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pruning_keeps_to_the_limits() {
+        let loc = |x| Loc { x, y: 0 };
+        let mut map = ExplorableMap::default().with_limits(3, 4);
+        for id in 0..10 {
+            let (tiles, seen_items, _) = map.maps.entry(id).or_insert_with(|| (Default::default(), Default::default(), true));
+            for x in 0..3 {
+                tiles.insert(loc(x), true, x.into(), i64::MAX);
+                seen_items.insert(loc(x), None, x.into(), i64::MAX);
+            }
+            map.visit_level(id);
+            map.prune(id);
+            assert!(map.maps.len() <= 3 && map.maps.contains_key(&id));
+        }
+        // Going back to a level keeps it over the others.
+        map.visit_level(7);
+        map.maps.insert(10, Default::default());
+        map.prune(10);
+        let mut kept: Vec<_> = map.maps.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, [7, 9, 10]);
+        assert_eq!(map.recent_levels, [9, 7]);
+
+        // Seen items went before tiles, the oldest first.
+        let (tiles, seen_items, _) = &map.maps[&9];
+        assert_eq!((tiles.len(), seen_items.len()), (3, 1));
+        assert!(seen_items.contains_key(&loc(2)));
+        assert_eq!(map.footprint().levels, 3);
+        assert_eq!(map.footprint().tiles, 6);
+    }
+
+    #[test]
+    fn levels_explored_by_allies_can_be_pathed() {
+        let loc = |x| Loc { x, y: 0 };
+        let mut explorer = ExplorableMap::default();
+        let (tiles, _, _) = explorer.maps.entry(2).or_insert_with(|| (Default::default(), Default::default(), true));
+        for x in 0..6 {
+            tiles.insert(loc(x), true, 1, i64::MAX);
+        }
+        let mut unstable = ExplorableMap::default();
+        unstable.maps.insert(3, Default::default());
+
+        let mut ally = ExplorableMap::default();
+        ally.maps.insert(1, Default::default());
+        ally.merge(&explorer).unwrap();
+        ally.merge(&unstable).unwrap();
+        assert!(!ally.maps.contains_key(&3));
+
+        ally.change_level(1, loc(0), 2, loc(0));
+        let (tiles, _, _) = &ally.maps[&2];
+        let path = crate::astar(loc(0), loc(5), tiles, &EmptyLocSet, &EmptyLocSet).unwrap();
+        assert_eq!(path.len(), 5);
+        assert!(ally.unexplored_locs.contains(&loc(6)) && !ally.unexplored_locs.contains(&loc(3)));
+    }
+}
+
+#[cfg(all(test, panic = "unwind"))]
+mod restore_tests {
+    use super::*;
+    use crate::crdt::CrdtMap;
+
+    #[derive(Default)]
+    struct Panicky {
+        broadcast: CrdtMap<i32, i32, Lww>,
+        panic: bool,
+    }
+
+    impl State<CrdtMap<i32, i32, Lww>> for Panicky {
+        fn run(&mut self) -> Command {
+            self.broadcast.insert(1, 10, 1);
+            self.broadcast.remove(2, 1);
+            if self.panic {
+                panic!("half way through");
+            }
+            Command::UseAction((0, None))
+        }
+
+        fn broadcast(&mut self) -> Option<&mut CrdtMap<i32, i32, Lww>> {
+            Some(&mut self.broadcast)
+        }
+    }
+
+    fn contents(map: &CrdtMap<i32, i32, Lww>) -> String {
+        format!("{:?} {:?} {}", map.entries, map.tombstones, map.version())
+    }
+
+    #[test]
+    fn panicking_runs_leave_the_broadcast_as_it_was() {
+        let mut memory = Panicky { panic: true, ..Default::default() };
+        memory.broadcast.insert(1, 1, 0);
+        memory.broadcast.insert(2, 2, 0);
+        let before = contents(&memory.broadcast);
+        assert_eq!(run_restoring(&mut memory), Err("half way through".to_string()));
+        assert_eq!(contents(&memory.broadcast), before);
+
+        memory.panic = false;
+        assert_eq!(run_restoring(&mut memory), Ok(Command::UseAction((0, None))));
+        assert_eq!(memory.broadcast.get(&1), Some(&10));
+        assert!(!memory.broadcast.contains_key(&2));
+    }
+
+    #[test]
+    fn panics_are_caught_with_their_message() {
+        let empty: Vec<u32> = Vec::new();
+        assert_eq!(catching("test", || empty.len()), Ok(0));
+        assert_eq!(catching("test", || panic!("{} items", empty.len())), Err::<(), _>("0 items".to_string()));
+    }
+}
+
+#[cfg(test)]
+mod step_tests {
+    use super::*;
+    use crate::config::{Config, ConfigSchema, ConfigValue, Configurable};
+    use crate::host::{with_host, MockHost};
+    use bindings::GameState;
+    use std::rc::Rc;
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct Bot {
+        aggression: i64,
+    }
+
+    fn schema() -> ConfigSchema {
+        ConfigSchema::default().with_int("aggression", 0, 10, 5)
+    }
+
+    impl Configurable for Bot {
+        fn config_schema() -> Vec<u8> {
+            schema().to_bytes()
+        }
+
+        fn apply_config(&mut self, bytes: &[u8]) -> Result<()> {
+            self.aggression = schema().read(bytes)?.int("aggression")?;
+            Ok(())
+        }
+    }
+
+    impl State for Bot {
+        crate::configurable_state!();
+
+        fn run(&mut self) -> Command {
+            Command::UseAction((self.aggression as u32, None))
+        }
+    }
+
+    type BotComponent = Component<Bot>;
+
+    #[test]
+    fn steps_apply_the_config_and_keep_it() {
+        assert_eq!(BotComponent::editor_config(), Some(schema().to_bytes()));
+        let config = Config::default().with("aggression", ConfigValue::Int(3)).to_bytes();
+        let host = MockHost::default()
+            .with_store(with_config_prefix(&config, &[]))
+            .with_game_state(GameState { turn: 1, level_id: 1, level_is_stable: true });
+        let host = Rc::new(host);
+        // The second turn reads the config from what the first saved.
+        for _ in 0..2 {
+            assert_eq!(with_host(host.clone(), BotComponent::step), Command::UseAction((3, None)));
+            let saved = host.saved_store();
+            let (kept, store) = split_config_prefix(&saved);
+            assert_eq!(kept, Some(&config[..]));
+            assert!(stored_header(store).is_some());
+        }
+    }
+}
+
+#[cfg(test)]
+mod nearest_tests {
+    use super::*;
+
+    #[test]
+    fn column_scan_matches_a_full_scan() {
+        fastrand::seed(316);
+        let mut seen_items: ExpiringCrdtMap<Loc, Option<String>, Lww> = Default::default();
+        let names = ["coin", "gem", "rock"];
+        for _ in 0..300 {
+            let loc = Loc { x: fastrand::i32(-200..200), y: fastrand::i32(-200..200) };
+            let name = fastrand::usize(0..names.len() + 1);
+            seen_items.insert(loc, names.get(name).map(|n| n.to_string()), 0, 100);
+        }
+        for metric in [Heuristic::Euclidean, Heuristic::Manhattan, Heuristic::Chebyshev] {
+            for _ in 0..50 {
+                let from = Loc { x: fastrand::i32(-250..250), y: fastrand::i32(-250..250) };
+                for tys in [&["gem"][..], &["coin", "gem"], &["missing", "rock"], &["missing"]] {
+                    let expected = seen_items
+                        .iter()
+                        .filter_map(|(loc, ty)| Some((tys.iter().position(|t| Some(*t) == ty.as_deref())?, metric.rank(from, *loc))))
+                        .min();
+                    let found = nearest_seen(&seen_items, from, tys, metric)
+                        .map(|loc| (tys.iter().position(|t| Some(*t) == seen_items.get(&loc).unwrap().as_deref()).unwrap(), metric.rank(from, loc)));
+                    assert_eq!(found, expected, "from {from:?} looking for {tys:?}");
+                }
+            }
+        }
+    }
+
+    /// The actor at the origin of level 1 inside a U of wall open to the
+    /// left, a coin just past its far side and a gem a little further out of
+    /// its mouth.
+    fn u_shaped_wall() -> ExplorableMap {
+        let mut explorable = ExplorableMap::default();
+        let (map, seen_items, _) = explorable.maps.entry(1).or_default();
+        for x in -8..=8 {
+            for y in -6..=6i32 {
+                let wall = (x == 2 && (-2..=2).contains(&y)) || ((-1..=2).contains(&x) && y.abs() == 2);
+                map.insert(Loc { x, y }, !wall, 0, i64::MAX);
+            }
+        }
+        seen_items.insert(Loc { x: 3, y: 0 }, Some("coin".to_string()), 0, i64::MAX);
+        seen_items.insert(Loc { x: -5, y: 0 }, Some("gem".to_string()), 0, i64::MAX);
+        seen_items.insert(Loc { x: -1, y: 0 }, None, 0, i64::MAX);
+        explorable
+    }
+
+    #[test]
+    fn path_distance_sees_around_walls() {
+        let explorable = u_shaped_wall();
+        let (map, seen_items, _) = &explorable.maps[&1];
+        let origin = Loc { x: 0, y: 0 };
+        let any = |_: &str| Some(0);
+        assert_eq!(nearest_seen(seen_items, origin, &["coin", "gem"], Heuristic::Euclidean), Some(Loc { x: 3, y: 0 }));
+        assert_eq!(
+            nearest_seen_by_path(map, seen_items, origin, any, NEAREST_MAX_COST),
+            Some((Loc { x: -5, y: 0 }, "gem".to_string()))
+        );
+    }
+
+    #[test]
+    fn path_distance_ranks_by_class_first() {
+        let explorable = u_shaped_wall();
+        let (map, seen_items, _) = &explorable.maps[&1];
+        let origin = Loc { x: 0, y: 0 };
+        let coins_first = |name: &str| (name == "coin").then_some(0).or(Some(1));
+        assert_eq!(nearest_seen_by_path(map, seen_items, origin, coins_first, NEAREST_MAX_COST).unwrap().1, "coin");
+        let no_coins = |name: &str| (name != "coin").then_some(0);
+        assert_eq!(nearest_seen_by_path(map, seen_items, origin, no_coins, NEAREST_MAX_COST).unwrap().1, "gem");
+        assert_eq!(nearest_seen_by_path(map, seen_items, origin, coins_first, 5.0).unwrap().1, "gem");
+    }
+}
+
+#[cfg(test)]
+mod versioned_tests {
+    use super::*;
+    use crate::crdt::{replica_id, Versioned};
+
+    #[test]
+    fn stale_peers_are_detected() {
+        let (alice, bob) = (replica_id(b"alice"), replica_id(b"bob"));
+        let loc = |x| Loc { x, y: 0 };
+        let mut mine = Versioned::new(ExplorableMap::default());
+        let mut theirs = Versioned::new(ExplorableMap::default());
+
+        theirs.mutate(bob, |map| map.register_portal(1, loc(1), 2, loc(1)));
+        mine.merge(&theirs).unwrap();
+        mine.mutate(alice, |map| map.register_portal(2, loc(2), 3, loc(2)));
+        assert!(mine.is_ahead_of(&theirs) && !theirs.is_ahead_of(&mine));
+
+        theirs.merge(&mine).unwrap();
+        assert!(!mine.is_ahead_of(&theirs));
+        assert_eq!(theirs.value.portal_route(1, 3).unwrap().len(), 2);
+
+        theirs.mutate(bob, |map| map.register_portal(3, loc(3), 4, loc(3)));
+        mine.mutate(alice, |map| map.register_portal(3, loc(4), 1, loc(4)));
+        assert!(mine.clock.is_concurrent(&theirs.clock));
+    }
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::*;
+
+    #[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+    struct Memory {
+        explored: u32,
+    }
+
+    impl State for Memory {
+        const VERSION: u32 = 1;
+
+        fn migrate(version: u32, bytes: &[u8]) -> Option<Self> {
+            let old: u16 = (version == 0).then(|| decode_tagged(bytes).ok())??;
+            Some(Memory { explored: old.into() })
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Unversioned(u32);
+
+    impl State for Unversioned {}
+
+    #[test]
+    fn stores_round_trip() {
+        let bytes = encode_store::<Bincode, _>(&Memory { explored: 7 }, StoreHeader::new(Memory::VERSION, 0, 0));
+        assert_eq!(decode_store::<Memory, _, _>(&bytes).unwrap(), Memory { explored: 7 });
+    }
+
+    #[test]
+    fn headerless_stores_are_version_0() {
+        // What an older client stored, before `explored` was widened.
+        let old = bincode::serialize(&3u16).unwrap();
+        assert_eq!(decode_store::<Memory, _, _>(&old).unwrap(), Memory { explored: 3 });
+        let bare = bincode::serialize(&Unversioned(5)).unwrap();
+        assert_eq!(decode_store::<Unversioned, _, _>(&bare).unwrap(), Unversioned(5));
+    }
+
+    #[test]
+    fn stores_keep_the_level_and_last_broadcast() {
+        let bytes = encode_store::<Bincode, _>(&Memory { explored: 7 }, StoreHeader::new(Memory::VERSION, 3, 9));
+        let header = stored_header(&bytes).unwrap();
+        assert_eq!((header.level_id, header.broadcast_digest), (3, 9));
+        assert!(stored_header(&bincode::serialize(&3u16).unwrap()).is_none());
+    }
+
+    #[test]
+    fn unreadable_stores_fail() {
+        let newer = encode_store::<Bincode, _>(&Memory { explored: 7 }, StoreHeader::new(2, 0, 0));
+        assert!(decode_store::<Memory, _, _>(&newer).is_err());
+        assert!(decode_store::<Memory, _, _>(&[1]).is_err());
+    }
+
+    #[test]
+    fn maps_from_before_writers_still_load() {
+        use crate::crdt::{CrdtMap, DEFAULT_TOMBSTONE_HORIZON};
+
+        #[derive(Default, Serialize, Deserialize)]
+        struct Claims(CrdtMap<i32, i32, Lww>);
+        impl State for Claims {}
+
+        // An older client's store: no header, and a map of just its entries.
+        let old = bincode::serialize(&std::collections::BTreeMap::from([(1i32, (10i32, 3i64))])).unwrap();
+        let mut claims = decode_store::<Claims, _, _>(&old).unwrap();
+        assert_eq!(claims.0.get(&1), Some(&10));
+        assert_eq!((claims.0.writer(&1), claims.0.tombstone_horizon), (None, DEFAULT_TOMBSTONE_HORIZON));
+
+        claims.0.insert_with_writer(1, 20, 3, 7);
+        let bytes = encode_store::<Bincode, _>(&claims, StoreHeader::new(0, 0, 0));
+        let reloaded = decode_store::<Claims, _, _>(&bytes).unwrap();
+        assert_eq!((reloaded.0.get(&1), reloaded.0.writer(&1)), (Some(&20), Some(7)));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_stores_keep_explored_levels() {
+        #[derive(Default, Serialize, Deserialize)]
+        struct Explorer(ExplorableMap);
+        impl State for Explorer {}
+
+        let mut explorer = Explorer::default();
+        let (tiles, _, _) = explorer.0.maps.entry(1).or_default();
+        tiles.insert(Loc { x: 4, y: 0 }, true, 1, 60);
+        let bytes = encode_store::<crate::codec::Json, _>(&explorer, StoreHeader::new(0, 1, 0));
+        let decoded = decode_store::<Explorer, _, _>(&bytes).unwrap();
+        assert_eq!(decoded.0.maps[&1].0.get(&Loc { x: 4, y: 0 }), Some(&true));
+    }
+}
+
+#[cfg(test)]
+mod broadcast_tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_broadcasts_are_skipped() {
+        assert!(should_broadcast(None, 1, 1, false));
+        assert!(should_broadcast(Some(1), 2, 1, false));
+        assert!(!should_broadcast(Some(1), 1, 1, false));
+        assert!(should_broadcast(Some(1), 1, 1, true));
+        assert!(should_broadcast(Some(1), 1, FULL_BROADCAST_INTERVAL, false));
+    }
+
+    #[test]
+    fn bare_states_from_older_clients_merge() {
+        // What clients sent before `GrowOnlySet` and `ExpiringSet` kept
+        // versions.
+        let old = bincode::serialize(&std::collections::BTreeSet::from([1u32, 2])).unwrap();
+        let message = decode_broadcast::<crate::crdt::GrowOnlySet<u32>>(&old).unwrap();
+        assert_eq!(message.state.iter().collect::<Vec<_>>(), [&1, &2]);
+        let old = bincode::serialize(&std::collections::BTreeMap::from([(3u32, 10i64)])).unwrap();
+        let message = decode_broadcast::<crate::crdt::ExpiringSet<u32>>(&old).unwrap();
+        assert_eq!(message.state.get(&3), Some((&3, 10)));
+    }
+
+    #[test]
+    fn broadcasts_leave_out_version_stamps() {
+        let set: crate::crdt::GrowOnlySet<u32> = (0..100).collect();
+        let bytes = encode_broadcast::<Bincode, _>(&set, FULL_BROADCAST_INTERVAL, None);
+        let message = decode_broadcast::<crate::crdt::GrowOnlySet<u32>>(&bytes).unwrap();
+        assert_eq!(message.state.len(), 100);
+        assert!(message.state.1.stamps.is_empty());
+        assert!(bytes.len() < bincode::serialize(&set).unwrap().len());
+    }
+
+    #[test]
+    fn digests_survive_reloading() {
+        let mut map = ExplorableMap::default();
+        for level in 0..20 {
+            let loc = Loc { x: level as i32, y: 0 };
+            map.maps.entry(level).or_default().0.insert(loc, true, 0, i64::MAX);
+            map.seen_creatures.entry(level).or_default().insert(loc, Some(CreatureSighting { faction: 1, turn: 0 }), 0, 100);
+            map.danger.entry(level).or_default().insert(loc, 1.0, 0, 100);
+        }
+        let bytes = bincode::serialize(&map).unwrap();
+        let first: ExplorableMap = bincode::deserialize(&bytes).unwrap();
+        let second: ExplorableMap = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(first.digest(), second.digest());
+        assert_eq!(first.digest(), map.digest());
+    }
+}
+
+#[cfg(test)]
+mod explore_tests {
+    use super::*;
+
+    fn loc(x: i32, y: i32) -> Loc {
+        Loc { x, y }
+    }
+
+    /// Known tiles from (-1, -1) to (5, 3), with a wall along y = 1 up to
+    /// x = 3, so the top row is only reached around its end.
+    fn walled(strategy: ExploreStrategy) -> ExplorableMap {
+        let mut map = ExplorableMap::default().with_explore_strategy(strategy);
+        let (tiles, _, _) = map.maps.entry(1).or_default();
+        for x in -1..=5 {
+            for y in -1..=3 {
+                tiles.insert(loc(x, y), !(y == 1 && x <= 3), 0, i64::MAX);
+            }
+        }
+        map.unexplored_locs.extend([loc(0, 4), loc(6, -1)]);
+        map
+    }
+
+    #[test]
+    fn nearest_by_path_walks_around_walls() {
+        let mut map = walled(ExploreStrategy::Nearest);
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, None), Some(loc(0, 4)));
+        let mut map = walled(ExploreStrategy::NearestByPath);
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, None), Some(loc(6, -1)));
+    }
+
+    #[test]
+    fn largest_frontier_picks_the_middle_of_the_biggest_cluster() {
+        let mut map = ExplorableMap::default().with_explore_strategy(ExploreStrategy::LargestFrontier);
+        map.unexplored_locs.extend([loc(1, 0), loc(20, 0), loc(21, 0), loc(22, 0)]);
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, None), Some(loc(21, 0)));
+    }
+
+    #[test]
+    fn sticky_finishes_the_last_targets_cluster() {
+        let mut map = ExplorableMap::default().with_explore_strategy(ExploreStrategy::Sticky);
+        map.unexplored_locs.extend([loc(1, 0), loc(10, 1), loc(10, 2)]);
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, Some(loc(10, 0))), Some(loc(10, 1)));
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, None), Some(loc(1, 0)));
+    }
+
+    #[test]
+    fn failed_targets_are_left_out_for_a_while() {
+        let mut map = ExplorableMap::default();
+        map.unexplored_locs.extend([loc(1, 0), loc(5, 0)]);
+        map.failed_targets.insert(loc(1, 0), EXPLORE_RETRY_TURNS);
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), 0, None), Some(loc(5, 0)));
+        assert_eq!(map.pick_explore_target(1, loc(0, 0), EXPLORE_RETRY_TURNS, None), Some(loc(1, 0)));
+        assert!(map.failed_targets.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod sighting_tests {
+    use super::*;
+
+    const ENEMY: i64 = 2;
+
+    fn loc(x: i32) -> Loc {
+        Loc { x, y: 0 }
+    }
+
+    #[test]
+    fn sightings_merge_by_age() {
+        let mut scout = ExplorableMap::default();
+        scout.record_sightings(1, 5, loc(0), [(loc(1), ENEMY), (loc(2), ENEMY)], []);
+        let mut guard = ExplorableMap::default();
+        guard.merge(&scout).unwrap();
+        guard.record_sightings(1, 9, loc(0), [(loc(2), 1), (loc(3), ENEMY)], [loc(1), loc(2), loc(3)]);
+
+        // The guard has since seen the first enemy gone and an ally in the
+        // second one's place.
+        scout.merge(&guard).unwrap();
+        let mut enemies: Vec<_> = scout.enemies_seen(1, 1, 10, 10).into_iter().map(|(loc, s)| (loc.x, s.turn)).collect();
+        enemies.sort();
+        assert_eq!(enemies, [(3, 9)]);
+        assert!(scout.enemies_seen(2, 1, 10, 10).is_empty());
+
+        let mut stale = ExplorableMap::default();
+        stale.record_sightings(1, 1, loc(0), [(loc(4), ENEMY)], []);
+        scout.merge(&stale).unwrap();
+        assert_eq!(scout.enemies_seen(1, 1, 10, 5).len(), 1);
+        assert_eq!(scout.enemies_seen(1, 1, 10, 10).len(), 2);
+    }
+
+    #[test]
+    fn sightings_expire() {
+        let mut map = ExplorableMap::default().with_sighting_ttl(10);
+        map.record_sightings(1, 0, loc(0), [(loc(0), ENEMY), (loc(1), ENEMY)], []);
+        assert_eq!(map.enemies_seen(1, 1, 5, i64::MAX).len(), 1);
+        map.record_sightings(1, 10, loc(0), [], []);
+        assert!(map.enemies_seen(1, 1, 10, i64::MAX).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod danger_tests {
+    use super::*;
+    use crate::{astar, crdt::CrdtMap};
+
+    /// A corridor along `y = 0`, and a longer way round it along `y = 2`.
+    fn corridor() -> CrdtMap<Loc, bool, Lww> {
+        let mut tiles = CrdtMap::default();
+        for x in 0..7 {
+            tiles.insert(Loc { x, y: 0 }, true, 0);
+            tiles.insert(Loc { x, y: 2 }, true, 0);
+        }
+        tiles.insert(Loc { x: 0, y: 1 }, true, 0);
+        tiles.insert(Loc { x: 6, y: 1 }, true, 0);
+        tiles
+    }
+
+    fn path_at(map: &ExplorableMap, now: i64) -> VecDeque<Loc> {
+        let (tiles, penalties) = (corridor(), map.danger_penalties(1, now));
+        astar(Loc { x: 0, y: 0 }, Loc { x: 6, y: 0 }, &WithPenalties(&tiles, &penalties), &EmptyLocSet, &EmptyLocSet)
+            .unwrap()
+    }
+
+    #[test]
+    fn paths_avoid_danger_until_it_decays() {
+        let mut scout = ExplorableMap {
+            last_position: Some((1, Loc { x: 0, y: 0 })),
+            ..Default::default()
+        };
+        scout.mark_danger(Loc { x: 3, y: 0 }, 10.0, 0);
+
+        // Only the scout got hurt, but its ally learns from it.
+        let mut ally = ExplorableMap::default();
+        ally.merge(&scout).unwrap();
+        assert!(path_at(&ally, 0).contains(&Loc { x: 3, y: 2 }));
+        assert_eq!(path_at(&ally, 10 * DANGER_HALF_LIFE).len(), 6);
+    }
+
+    #[test]
+    fn danger_adds_up_and_halves() {
+        let mut map = ExplorableMap::default();
+        let loc = Loc { x: 1, y: 1 };
+        map.mark_danger(loc, 1.0, 0);
+        assert!(map.danger.is_empty());
+
+        map.last_position = Some((1, loc));
+        map.mark_danger(loc, 4.0, 0);
+        map.mark_danger(loc, 4.0, 0);
+        assert_eq!(map.danger_at(1, loc, DANGER_HALF_LIFE), 4.0);
+        let expires = map.danger[&1].expires_at(&loc).unwrap();
+        assert!(map.danger_at(1, loc, expires) < DANGER_FLOOR);
+        assert!(map.danger_at(1, loc, expires - 1) >= DANGER_FLOOR);
+    }
+}
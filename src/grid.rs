@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Loc, LocMap, LocSet, LocSetIter, Rect};
+
+/// Dense passability map over a rectangle that grows as tiles are set. Each
+/// tile costs two bits: whether it's known, and whether it's passable.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocGrid {
+    bounds: Option<Rect>,
+    known: Vec<u64>,
+    passable: Vec<u64>,
+}
+
+impl LocGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bounds(bounds: Rect) -> Self {
+        let words = words_for(&bounds);
+        Self {
+            bounds: Some(bounds).filter(|b| !b.is_empty()),
+            known: vec![0; words],
+            passable: vec![0; words],
+        }
+    }
+
+    /// Snapshots any `LocMap`, e.g. the explored `CrdtMap`, into a grid that's
+    /// cheaper to search.
+    pub fn from_locmap(map: &dyn LocMap) -> Self {
+        let mut grid = match Rect::bounding(map.iter()) {
+            Some(bounds) => Self::with_bounds(bounds),
+            None => return Self::default(),
+        };
+        for loc in map.iter() {
+            if let Some(passable) = map.get_loc(&loc) {
+                grid.set(loc, passable);
+            }
+        }
+        grid
+    }
+
+    pub fn bounds(&self) -> Option<Rect> {
+        self.bounds
+    }
+
+    pub fn get(&self, loc: Loc) -> Option<bool> {
+        let i = self.index(loc)?;
+        if bit(&self.known, i) {
+            Some(bit(&self.passable, i))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, loc: Loc, passable: bool) {
+        if self.index(loc).is_none() {
+            self.grow_to(loc);
+        }
+        let i = self.index(loc).expect("grid was grown to contain loc");
+        set_bit(&mut self.known, i, true);
+        set_bit(&mut self.passable, i, passable);
+    }
+
+    pub fn clear(&mut self, loc: Loc) {
+        if let Some(i) = self.index(loc) {
+            set_bit(&mut self.known, i, false);
+            set_bit(&mut self.passable, i, false);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.iter().all(|w| *w == 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Loc, bool)> + '_ {
+        self.bounds
+            .into_iter()
+            .flat_map(Rect::iter)
+            .filter_map(|loc| self.get(loc).map(|passable| (loc, passable)))
+    }
+
+    fn index(&self, loc: Loc) -> Option<usize> {
+        let bounds = self.bounds?;
+        if !bounds.contains(loc) {
+            return None;
+        }
+        let x = (loc.x - bounds.min.x) as usize;
+        let y = (loc.y - bounds.min.y) as usize;
+        Some(y * bounds.width() as usize + x)
+    }
+
+    /// Reallocates with enough slack around `loc` that growing tile by tile
+    /// along an edge doesn't copy the whole grid every time.
+    fn grow_to(&mut self, loc: Loc) {
+        let bounds = match self.bounds {
+            Some(bounds) => {
+                let margin = (bounds.width().max(bounds.height()) / 2).max(8);
+                let grown = Rect::bounding([bounds.min, bounds.max, loc]).expect("not empty");
+                grown.expand(margin)
+            }
+            None => Rect::new(loc, loc).expand(8),
+        };
+        let old = std::mem::replace(self, Self::with_bounds(bounds));
+        for (loc, passable) in old.iter() {
+            self.set(loc, passable);
+        }
+    }
+}
+
+fn words_for(bounds: &Rect) -> usize {
+    (bounds.width() as usize * bounds.height() as usize).div_ceil(64)
+}
+
+fn bit(words: &[u64], i: usize) -> bool {
+    words[i / 64] & (1 << (i % 64)) != 0
+}
+
+fn set_bit(words: &mut [u64], i: usize, value: bool) {
+    if value {
+        words[i / 64] |= 1 << (i % 64);
+    } else {
+        words[i / 64] &= !(1 << (i % 64));
+    }
+}
+
+impl LocSet for LocGrid {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.get(*loc).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        LocGrid::is_empty(self)
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::with_len(LocGrid::iter(self).map(|(loc, _)| loc), self.len())
+    }
+
+    fn len(&self) -> usize {
+        LocGrid::len(self)
+    }
+}
+
+impl LocMap for LocGrid {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.get(*loc)
+    }
+}
+
+#[cfg(test)]
+mod loc_grid_tests {
+    use super::*;
+    use crate::crdt::{CrdtMap, Lww};
+    use crate::{astar_with_stats, AstarOptions, LocExt};
+    use std::collections::HashSet;
+
+    #[test]
+    fn set_get_clear() {
+        let mut grid = LocGrid::new();
+        let a = Loc { x: 3, y: -4 };
+        assert_eq!(grid.get(a), None);
+        grid.set(a, true);
+        grid.set(a.offset(1, 0), false);
+        assert_eq!(grid.get(a), Some(true));
+        assert_eq!(grid.get(a.offset(1, 0)), Some(false));
+        assert_eq!(grid.get(a.offset(2, 0)), None);
+        assert_eq!(grid.len(), 2);
+        grid.clear(a);
+        assert_eq!(grid.get(a), None);
+        assert_eq!(grid.len(), 1);
+    }
+
+    #[test]
+    fn grows_to_fit_far_tiles() {
+        let mut grid = LocGrid::new();
+        grid.set(Loc { x: 0, y: 0 }, true);
+        grid.set(Loc { x: -100, y: 250 }, false);
+        grid.set(Loc { x: 300, y: -7 }, true);
+        assert_eq!(grid.get(Loc { x: 0, y: 0 }), Some(true));
+        assert_eq!(grid.get(Loc { x: -100, y: 250 }), Some(false));
+        assert_eq!(grid.get(Loc { x: 300, y: -7 }), Some(true));
+        assert_eq!(grid.len(), 3);
+    }
+
+    #[test]
+    fn snapshot_of_crdt_map() {
+        let mut map: CrdtMap<Loc, bool, Lww> = CrdtMap::default();
+        for x in 0..10 {
+            map.insert(Loc { x, y: 0 }, x != 5, 0);
+        }
+        let grid = LocGrid::from_locmap(&map);
+        for x in 0..10 {
+            let loc = Loc { x, y: 0 };
+            assert_eq!(grid.get_loc(&loc), map.get_loc(&loc));
+        }
+        assert_eq!(LocSet::iter(&grid).count(), 10);
+    }
+
+    #[test]
+    fn lookups_index_straight_into_the_bits() {
+        let bounds = Rect::new(Loc { x: -128, y: -128 }, Loc { x: 127, y: 127 });
+        let mut grid = LocGrid::with_bounds(bounds);
+        assert_eq!((grid.known.len(), grid.passable.len()), (1024, 1024));
+        for loc in bounds.iter() {
+            grid.set(loc, loc.x != loc.y);
+        }
+        // Every tile is a bit of its own, row by row, and no more were kept.
+        assert_eq!(grid.bounds(), Some(bounds));
+        assert!(grid.known.iter().all(|word| *word == u64::MAX));
+        let loc = Loc { x: 3, y: 5 };
+        assert_eq!(grid.index(loc), Some(133 * 256 + 131));
+        grid.clear(loc);
+        assert_eq!(grid.known[(133 * 256 + 131) / 64].count_zeros(), 1);
+        assert_eq!((grid.get(loc), grid.get(Loc { x: 5, y: 5 })), (None, Some(false)));
+    }
+
+    #[test]
+    fn astar_on_large_grid() {
+        let mut grid = LocGrid::new();
+        for loc in Rect::new(Loc { x: 0, y: 0 }, Loc { x: 255, y: 255 }).iter() {
+            grid.set(loc, true);
+        }
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 255, y: 255 });
+        let (path, stats) = astar_with_stats(start, goal, &grid, &HashSet::new(), &HashSet::new(), &AstarOptions::default());
+        let path = path.unwrap();
+        assert_eq!(path.steps.len(), 255);
+        assert!(path.steps.iter().zip(1..).all(|(loc, i)| *loc == Loc { x: i, y: i }));
+        assert!(stats.expanded <= 2 * 255, "{stats:?}");
+    }
+}
@@ -0,0 +1,317 @@
+//! The calls into the game, behind a `Host` so they can be answered off WASM.
+//! The functions here stand in for the `bindings` ones of the same name,
+//! asking whichever host `with_host` put in place on this thread, or the
+//! game through the bindings when there's none and the `wit-bindings`
+//! feature is on. Tests put a `MockHost` in place to run the framework and
+//! behaviors under plain `cargo test`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+
+use bindings::{Action, Creature, EquipmentState, GameState, Item, Loc, Tile};
+
+pub trait Host {
+    fn actor(&self) -> (Loc, Creature);
+    fn visible_tiles(&self) -> Vec<(Loc, Tile)>;
+    fn visible_creatures(&self) -> Vec<(Loc, Creature)>;
+    fn visible_items(&self) -> Vec<(Loc, Item)>;
+    fn item_at(&self, loc: Loc) -> Option<Item>;
+    fn actions(&self) -> Vec<Action>;
+    fn inventory(&self) -> Vec<Item>;
+    fn get_game_state(&self) -> GameState;
+    fn get_equipment_state(&self) -> EquipmentState;
+    fn load_store(&self) -> Vec<u8>;
+    fn save_store(&self, store: &[u8]);
+    fn broadcast(&self, broadcast: Option<&[u8]>);
+}
+
+/// The game, through the bindings.
+#[cfg(feature = "wit-bindings")]
+pub struct WitHost;
+
+#[cfg(feature = "wit-bindings")]
+impl Host for WitHost {
+    fn actor(&self) -> (Loc, Creature) {
+        bindings::actor()
+    }
+
+    fn visible_tiles(&self) -> Vec<(Loc, Tile)> {
+        bindings::visible_tiles()
+    }
+
+    fn visible_creatures(&self) -> Vec<(Loc, Creature)> {
+        bindings::visible_creatures()
+    }
+
+    fn visible_items(&self) -> Vec<(Loc, Item)> {
+        bindings::visible_items()
+    }
+
+    fn item_at(&self, loc: Loc) -> Option<Item> {
+        bindings::item_at(loc)
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        bindings::actions()
+    }
+
+    fn inventory(&self) -> Vec<Item> {
+        bindings::inventory()
+    }
+
+    fn get_game_state(&self) -> GameState {
+        bindings::get_game_state()
+    }
+
+    fn get_equipment_state(&self) -> EquipmentState {
+        bindings::get_equipment_state()
+    }
+
+    fn load_store(&self) -> Vec<u8> {
+        bindings::load_store()
+    }
+
+    fn save_store(&self, store: &[u8]) {
+        bindings::save_store(store)
+    }
+
+    fn broadcast(&self, broadcast: Option<&[u8]>) {
+        bindings::broadcast(broadcast)
+    }
+}
+
+thread_local! {
+    static HOST: RefCell<Option<Rc<dyn Host>>> = const { RefCell::new(None) };
+}
+
+/// Puts the host back as it was, even if what ran with another panicked.
+struct Restore(Option<Rc<dyn Host>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        HOST.with(|h| *h.borrow_mut() = previous);
+    }
+}
+
+/// Runs `f` with `host` answering this thread's host calls.
+pub fn with_host<T>(host: Rc<dyn Host>, f: impl FnOnce() -> T) -> T {
+    let _restore = Restore(HOST.with(|h| h.borrow_mut().replace(host)));
+    f()
+}
+
+/// What's answering this thread's host calls.
+pub fn current() -> Rc<dyn Host> {
+    HOST.with(|h| h.borrow().clone()).unwrap_or_else(default_host)
+}
+
+#[cfg(feature = "wit-bindings")]
+fn default_host() -> Rc<dyn Host> {
+    Rc::new(WitHost)
+}
+
+#[cfg(not(feature = "wit-bindings"))]
+fn default_host() -> Rc<dyn Host> {
+    panic!("no host: call this inside `host::with_host`, or enable the `wit-bindings` feature")
+}
+
+pub fn actor() -> (Loc, Creature) {
+    current().actor()
+}
+
+pub fn visible_tiles() -> Vec<(Loc, Tile)> {
+    current().visible_tiles()
+}
+
+pub fn visible_creatures() -> Vec<(Loc, Creature)> {
+    current().visible_creatures()
+}
+
+pub fn visible_items() -> Vec<(Loc, Item)> {
+    current().visible_items()
+}
+
+pub fn item_at(loc: Loc) -> Option<Item> {
+    current().item_at(loc)
+}
+
+pub fn actions() -> Vec<Action> {
+    current().actions()
+}
+
+pub fn inventory() -> Vec<Item> {
+    current().inventory()
+}
+
+pub fn get_game_state() -> GameState {
+    current().get_game_state()
+}
+
+pub fn get_equipment_state() -> EquipmentState {
+    current().get_equipment_state()
+}
+
+pub fn load_store() -> Vec<u8> {
+    current().load_store()
+}
+
+pub fn save_store(store: &[u8]) {
+    current().save_store(store)
+}
+
+pub fn broadcast(broadcast: Option<&[u8]>) {
+    current().broadcast(broadcast)
+}
+
+/// A game made up in a test, built with the `with_*` methods. Keeps what's
+/// saved and broadcast, for the test to look at.
+#[derive(Default)]
+pub struct MockHost {
+    pub actor: Option<(Loc, Creature)>,
+    pub tiles: IndexMap<Loc, Tile>,
+    /// Besides the actor, which is always visible.
+    pub creatures: Vec<(Loc, Creature)>,
+    pub items: Vec<(Loc, Item)>,
+    pub actions: Vec<Action>,
+    pub inventory: Vec<Item>,
+    pub game_state: Option<GameState>,
+    pub equipment_state: Option<EquipmentState>,
+    pub store: RefCell<Vec<u8>>,
+    /// Everything broadcast, the latest last.
+    pub broadcasts: RefCell<Vec<Option<Vec<u8>>>>,
+}
+
+impl MockHost {
+    pub fn with_actor(mut self, loc: Loc, creature: Creature) -> Self {
+        self.actor = Some((loc, creature));
+        self
+    }
+
+    pub fn with_tile(mut self, loc: Loc, tile: Tile) -> Self {
+        self.tiles.insert(loc, tile);
+        self
+    }
+
+    pub fn with_tiles(mut self, tiles: impl IntoIterator<Item = (Loc, Tile)>) -> Self {
+        for (loc, tile) in tiles {
+            self.tiles.insert(loc, tile);
+        }
+        self
+    }
+
+    pub fn with_creature(mut self, loc: Loc, creature: Creature) -> Self {
+        self.creatures.push((loc, creature));
+        self
+    }
+
+    pub fn with_item(mut self, loc: Loc, item: Item) -> Self {
+        self.items.push((loc, item));
+        self
+    }
+
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn with_inventory_item(mut self, item: Item) -> Self {
+        self.inventory.push(item);
+        self
+    }
+
+    pub fn with_game_state(mut self, game_state: GameState) -> Self {
+        self.game_state = Some(game_state);
+        self
+    }
+
+    pub fn with_equipment_state(mut self, equipment_state: EquipmentState) -> Self {
+        self.equipment_state = Some(equipment_state);
+        self
+    }
+
+    pub fn with_store(self, store: Vec<u8>) -> Self {
+        *self.store.borrow_mut() = store;
+        self
+    }
+
+    /// What was last saved, or the store it started with.
+    pub fn saved_store(&self) -> Vec<u8> {
+        self.store.borrow().clone()
+    }
+
+    pub fn last_broadcast(&self) -> Option<Option<Vec<u8>>> {
+        self.broadcasts.borrow().last().cloned()
+    }
+}
+
+impl Host for MockHost {
+    fn actor(&self) -> (Loc, Creature) {
+        self.actor.clone().expect("MockHost has no actor, see `with_actor`")
+    }
+
+    fn visible_tiles(&self) -> Vec<(Loc, Tile)> {
+        self.tiles.iter().map(|(loc, tile)| (*loc, tile.clone())).collect()
+    }
+
+    fn visible_creatures(&self) -> Vec<(Loc, Creature)> {
+        self.actor.iter().cloned().chain(self.creatures.iter().cloned()).collect()
+    }
+
+    fn visible_items(&self) -> Vec<(Loc, Item)> {
+        self.items.clone()
+    }
+
+    fn item_at(&self, loc: Loc) -> Option<Item> {
+        self.items.iter().find(|(l, _)| *l == loc).map(|(_, item)| item.clone())
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        self.actions.clone()
+    }
+
+    fn inventory(&self) -> Vec<Item> {
+        self.inventory.clone()
+    }
+
+    fn get_game_state(&self) -> GameState {
+        self.game_state.clone().expect("MockHost has no game state, see `with_game_state`")
+    }
+
+    fn get_equipment_state(&self) -> EquipmentState {
+        self.equipment_state.clone().expect("MockHost has no equipment state, see `with_equipment_state`")
+    }
+
+    fn load_store(&self) -> Vec<u8> {
+        self.saved_store()
+    }
+
+    fn save_store(&self, store: &[u8]) {
+        *self.store.borrow_mut() = store.to_vec();
+    }
+
+    fn broadcast(&self, broadcast: Option<&[u8]>) {
+        self.broadcasts.borrow_mut().push(broadcast.map(<[u8]>::to_vec));
+    }
+}
+
+#[cfg(test)]
+mod host_tests {
+    use super::*;
+
+    #[test]
+    fn mock_hosts_nest() {
+        let outer = Rc::new(MockHost::default().with_store(vec![1]));
+        let inner = Rc::new(MockHost::default().with_store(vec![2]));
+        with_host(outer.clone(), || {
+            assert_eq!(load_store(), [1]);
+            with_host(inner.clone(), || save_store(&[3]));
+            broadcast(Some(&[4]));
+            assert_eq!(load_store(), [1]);
+        });
+        assert_eq!(inner.saved_store(), [3]);
+        assert_eq!(outer.last_broadcast(), Some(Some(vec![4])));
+        assert!(inner.broadcasts.borrow().is_empty());
+    }
+}
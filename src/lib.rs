@@ -1,24 +1,85 @@
 #![feature(let_chains)]
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use ordered_float::OrderedFloat;
 use std::collections::VecDeque;
 
+// Lets `CrdtContainer` name this crate the same way from inside it.
+extern crate self as client_utils;
+
 pub use bindings::Loc;
 pub use bindings;
 
 
 pub mod behaviors;
+pub mod codec;
+pub mod compact;
+pub mod config;
 pub mod crdt;
 pub mod framework;
+pub mod grid;
+pub mod host;
+#[cfg(any(test, feature = "replay"))]
+pub mod replay;
+pub mod scheduler;
+
+pub use grid::LocGrid;
 
+/// Boxed iterator over a `LocSet` that always knows how many locs are left.
+/// Its fields are private so the count can't go wrong; where a
+/// `LocSetIter { inner }` literal was built, use `LocSetIter::from(inner)`,
+/// or `new` or `with_len` when the length is known.
 pub struct LocSetIter<'a> {
-    pub inner: Box<dyn Iterator<Item = Loc> + 'a>,
+    inner: Box<dyn Iterator<Item = Loc> + 'a>,
+    remaining: usize,
+}
+
+impl<'a> LocSetIter<'a> {
+    pub fn new(inner: impl ExactSizeIterator<Item = Loc> + 'a) -> Self {
+        let remaining = inner.len();
+        Self::with_len(inner, remaining)
+    }
+
+    /// `len` must be exactly how many locs `inner` yields.
+    pub fn with_len(inner: impl Iterator<Item = Loc> + 'a, len: usize) -> Self {
+        Self {
+            inner: Box::new(inner),
+            remaining: len,
+        }
+    }
+
+    /// Collects `inner` up front to learn its length, for filtered iterators
+    /// that can't know it otherwise.
+    pub fn counted(inner: impl Iterator<Item = Loc>) -> Self {
+        let locs: Vec<Loc> = inner.collect();
+        Self::new(locs.into_iter())
+    }
+
+    pub fn empty() -> Self {
+        Self::new(std::iter::empty())
+    }
 }
 
 impl<'a> Iterator for LocSetIter<'a> {
     type Item = Loc;
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        let next = self.inner.next();
+        if next.is_some() {
+            self.remaining -= 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for LocSetIter<'_> {}
+
+/// Counts `inner` up front, like `counted`.
+impl<'a> From<Box<dyn Iterator<Item = Loc> + 'a>> for LocSetIter<'a> {
+    fn from(inner: Box<dyn Iterator<Item = Loc> + 'a>) -> Self {
+        Self::counted(inner)
     }
 }
 
@@ -26,6 +87,13 @@ pub trait LocSet {
     fn contains_loc(&self, loc: &Loc) -> bool;
     fn is_empty(&self) -> bool;
     fn iter(&self) -> LocSetIter;
+
+    /// Number of locs `iter` yields. Counts them unless overridden. Sets
+    /// that can't list their members, like `FullLocSet` and `FnLocSet`,
+    /// have a `len` of 0 even though they aren't `is_empty`.
+    fn len(&self) -> usize {
+        self.iter().len()
+    }
 }
 
 impl LocSet for std::collections::HashSet<Loc> {
@@ -38,9 +106,11 @@ impl LocSet for std::collections::HashSet<Loc> {
     }
 
     fn iter(&self) -> LocSetIter {
-        LocSetIter {
-            inner: Box::new(self.iter().copied()),
-        }
+        LocSetIter::new(self.iter().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.len()
     }
 }
 
@@ -54,16 +124,319 @@ impl LocSet for indexmap::IndexSet<Loc> {
     }
 
     fn iter(&self) -> LocSetIter {
-        LocSetIter {
-            inner: Box::new(self.iter().copied()),
+        LocSetIter::new(self.iter().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EmptyLocSet;
+
+impl LocSet for EmptyLocSet {
+    fn contains_loc(&self, _loc: &Loc) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        true
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::empty()
+    }
+}
+
+/// Contains every loc. It can't be enumerated, so `iter` yields nothing and
+/// `len` is 0; intersect it with a `Rect` to iterate a bounded region.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FullLocSet;
+
+impl LocSet for FullLocSet {
+    fn contains_loc(&self, _loc: &Loc) -> bool {
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::empty()
+    }
+}
+
+/// Membership decided by a predicate. As with `FullLocSet`, `iter` yields
+/// nothing, `len` is 0 and `is_empty` is always false; use
+/// `Intersection(&rect, &set)` when the members need to be listed.
+#[derive(Clone, Copy)]
+pub struct FnLocSet<F: Fn(&Loc) -> bool>(pub F);
+
+impl<F: Fn(&Loc) -> bool> LocSet for FnLocSet<F> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        (self.0)(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::empty()
+    }
+}
+
+/// Every loc in either set. `iter` skips locs of the second set that are
+/// also in the first, so it yields no duplicates as long as neither
+/// constituent does.
+#[derive(Clone, Copy)]
+pub struct Union<'a>(pub &'a dyn LocSet, pub &'a dyn LocSet);
+
+impl LocSet for Union<'_> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_loc(loc) || self.1.contains_loc(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty() && self.1.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        let first = self.0;
+        LocSetIter::counted(first.iter().chain(self.1.iter().filter(move |loc| !first.contains_loc(loc))))
+    }
+}
+
+/// Like `Union`, over any number of sets.
+#[derive(Clone, Default)]
+pub struct UnionAll<'a>(pub Vec<&'a dyn LocSet>);
+
+impl LocSet for UnionAll<'_> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.iter().any(|set| set.contains_loc(loc))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|set| set.is_empty())
+    }
+
+    fn iter(&self) -> LocSetIter {
+        let sets = &self.0;
+        LocSetIter::counted(sets.iter().enumerate().flat_map(move |(i, set)| {
+            set.iter().filter(move |loc| !sets[..i].iter().any(|earlier| earlier.contains_loc(loc)))
+        }))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Intersection<'a>(pub &'a dyn LocSet, pub &'a dyn LocSet);
+
+impl LocSet for Intersection<'_> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_loc(loc) && self.1.contains_loc(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        let other = self.1;
+        LocSetIter::counted(self.0.iter().filter(move |loc| other.contains_loc(loc)))
+    }
+}
+
+/// Locs in the first set but not the second.
+#[derive(Clone, Copy)]
+pub struct Difference<'a>(pub &'a dyn LocSet, pub &'a dyn LocSet);
+
+impl LocSet for Difference<'_> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_loc(loc) && !self.1.contains_loc(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        let other = self.1;
+        LocSetIter::counted(self.0.iter().filter(move |loc| !other.contains_loc(loc)))
+    }
+}
+
+#[cfg(test)]
+mod loc_set_combinator_tests {
+    use super::*;
+
+    fn set(xs: &[i32]) -> IndexSet<Loc> {
+        xs.iter().map(|&x| Loc { x, y: 0 }).collect()
+    }
+
+    fn xs(set: &dyn LocSet) -> Vec<i32> {
+        let mut xs: Vec<i32> = set.iter().map(|loc| loc.x).collect();
+        xs.sort();
+        xs
+    }
+
+    #[test]
+    fn union_has_no_duplicates() {
+        let (a, b) = (set(&[1, 2, 3]), set(&[3, 4]));
+        let union = Union(&a, &b);
+        assert_eq!(xs(&union), vec![1, 2, 3, 4]);
+        assert!(union.contains_loc(&Loc { x: 4, y: 0 }));
+        assert!(!union.contains_loc(&Loc { x: 5, y: 0 }));
+        assert!(!union.is_empty());
+    }
+
+    #[test]
+    fn union_all_has_no_duplicates() {
+        let (a, b, c) = (set(&[1, 2]), set(&[2, 3]), set(&[1, 3, 5]));
+        let union = UnionAll(vec![&a, &b, &c]);
+        assert_eq!(xs(&union), vec![1, 2, 3, 5]);
+        assert!(union.contains_loc(&Loc { x: 5, y: 0 }));
+        assert!(UnionAll::default().is_empty());
+    }
+
+    #[test]
+    fn intersection_and_difference() {
+        let (a, b) = (set(&[1, 2, 3]), set(&[2, 3, 4]));
+        assert_eq!(xs(&Intersection(&a, &b)), vec![2, 3]);
+        assert_eq!(xs(&Difference(&a, &b)), vec![1]);
+        assert!(Difference(&a, &a).is_empty());
+        assert!(Intersection(&a, &set(&[7])).is_empty());
+    }
+
+    #[test]
+    fn sentinel_and_fn_sets() {
+        let loc = Loc { x: 3, y: 0 };
+        assert!(!EmptyLocSet.contains_loc(&loc));
+        assert!(EmptyLocSet.is_empty());
+        assert!(FullLocSet.contains_loc(&loc));
+        assert!(!FullLocSet.is_empty());
+        let far = FnLocSet(|l: &Loc| l.x.abs() > 2);
+        assert!(far.contains_loc(&loc));
+        assert!(!far.contains_loc(&Loc { x: 2, y: 0 }));
+        let region = Rect::new(Loc { x: 0, y: 0 }, Loc { x: 4, y: 0 });
+        assert_eq!(xs(&Intersection(&region, &far)), vec![3, 4]);
+    }
+
+    #[test]
+    fn fn_set_bounds_astar() {
+        let mut map = std::collections::HashMap::new();
+        for loc in Rect::new(Loc { x: -10, y: -10 }, Loc { x: 10, y: 10 }).iter() {
+            map.insert(loc, true);
         }
+        let wall = FnLocSet(|l: &Loc| l.x == 0 && l.y < 5);
+        let path = astar(Loc { x: -3, y: 0 }, Loc { x: 3, y: 0 }, &map, &wall, &EmptyLocSet).unwrap();
+        assert!(path.iter().all(|l| !wall.contains_loc(l)));
+        assert_eq!(path.back(), Some(&Loc { x: 3, y: 0 }));
+    }
+
+    #[test]
+    fn lengths_are_exact() {
+        let (a, b) = (set(&[1, 2, 3]), set(&[3, 4]));
+        assert_eq!(LocSet::len(&a), 3);
+        assert_eq!(Union(&a, &b).len(), 4);
+        assert_eq!(Intersection(&a, &b).len(), 1);
+        assert_eq!(EmptyLocSet.len(), 0);
+        let rect = Rect::new(Loc { x: 0, y: 0 }, Loc { x: 2, y: 1 });
+        assert_eq!(rect.len(), 6);
+        let difference = Difference(&rect, &a);
+        let mut iter = difference.iter();
+        assert_eq!(iter.len(), 4);
+        iter.next();
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.count(), 3);
+        let boxed: Box<dyn Iterator<Item = Loc>> = Box::new(LocSet::iter(&a));
+        assert_eq!(LocSetIter::from(boxed).map(|l| l.x).collect::<Vec<_>>(), xs(&a));
+        assert_eq!(FullLocSet.len(), 0);
+        assert!(!FullLocSet.is_empty());
+    }
+
+    #[test]
+    fn combinators_nest() {
+        let (furniture, enemies, no_go) = (set(&[1, 2]), set(&[2, 6]), set(&[9]));
+        let allowed = set(&[6]);
+        let blocked = Difference(&UnionAll(vec![&furniture, &enemies, &no_go]), &allowed);
+        assert_eq!(xs(&blocked), vec![1, 2, 9]);
     }
 }
 
 pub trait LocMap: LocSet {
     fn get_loc(&self, loc: &Loc) -> Option<bool>;
+
+    /// Multiplier applied to the cost of stepping onto `loc`. Pathfinding
+    /// clamps this to at least `MIN_TILE_COST` so searches always terminate.
+    /// Maps with tiles cheaper than 1 need `AstarOptions::min_tile_cost`.
+    fn cost_at(&self, _loc: &Loc) -> f32 {
+        1.0
+    }
+
+    /// Flat cost added on top of the scaled step cost when entering `loc`.
+    fn penalty_at(&self, _loc: &Loc) -> f32 {
+        0.0
+    }
+}
+
+/// Graded costs for walking near things, e.g. from `avoidance_penalties`.
+/// Combine one with a map through `WithPenalties` to have searches use it.
+pub trait PenaltyMap {
+    fn penalty_at(&self, loc: &Loc) -> f32;
+}
+
+impl PenaltyMap for indexmap::IndexMap<Loc, f32> {
+    fn penalty_at(&self, loc: &Loc) -> f32 {
+        self.get(loc).copied().unwrap_or(0.0)
+    }
+}
+
+impl PenaltyMap for std::collections::HashMap<Loc, f32> {
+    fn penalty_at(&self, loc: &Loc) -> f32 {
+        self.get(loc).copied().unwrap_or(0.0)
+    }
+}
+
+/// A map whose step costs include the penalties of a `PenaltyMap`.
+#[derive(Clone, Copy)]
+pub struct WithPenalties<'a>(pub &'a dyn LocMap, pub &'a dyn PenaltyMap);
+
+impl LocSet for WithPenalties<'_> {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.0.contains_loc(loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> LocSetIter {
+        self.0.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl LocMap for WithPenalties<'_> {
+    fn get_loc(&self, loc: &Loc) -> Option<bool> {
+        self.0.get_loc(loc)
+    }
+
+    fn cost_at(&self, loc: &Loc) -> f32 {
+        self.0.cost_at(loc)
+    }
+
+    fn penalty_at(&self, loc: &Loc) -> f32 {
+        self.0.penalty_at(loc) + self.1.penalty_at(loc)
+    }
 }
 
+pub const MIN_TILE_COST: f32 = 0.01;
+
 impl LocSet for std::collections::HashMap<Loc, bool> {
     fn contains_loc(&self, loc: &Loc) -> bool {
         self.contains_key(loc)
@@ -74,9 +447,11 @@ impl LocSet for std::collections::HashMap<Loc, bool> {
     }
 
     fn iter(&self) -> LocSetIter {
-        LocSetIter {
-            inner: Box::new(self.keys().copied()),
-        }
+        LocSetIter::new(self.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.len()
     }
 }
 
@@ -96,9 +471,11 @@ impl LocSet for indexmap::IndexMap<Loc, bool> {
     }
 
     fn iter(&self) -> LocSetIter {
-        LocSetIter {
-            inner: Box::new(self.keys().copied()),
-        }
+        LocSetIter::new(self.keys().copied())
+    }
+
+    fn len(&self) -> usize {
+        self.len()
     }
 }
 impl LocMap for indexmap::IndexMap<Loc, bool> {
@@ -107,68 +484,2808 @@ impl LocMap for indexmap::IndexMap<Loc, bool> {
     }
 }
 
-pub fn distance(a: Loc, b: Loc) -> f32 {
-    (((a.x - b.x) as f32).powi(2) + ((a.y - b.y) as f32).powi(2)).sqrt()
+/// `Loc` comes from the generated bindings, so arithmetic lives on an
+/// extension trait rather than `Add`/`Sub` impls.
+pub trait LocExt: Sized {
+    fn offset(self, dx: i32, dy: i32) -> Loc;
+    fn plus(self, other: Loc) -> Loc;
+    fn minus(self, other: Loc) -> Loc;
+    fn neighbors4(self) -> impl Iterator<Item = Loc>;
+    fn neighbors8(self) -> impl Iterator<Item = Loc>;
+    /// Tiles at exactly `radius` Chebyshev distance; `ring(0)` is just `self`.
+    fn ring(self, radius: u32) -> impl Iterator<Item = Loc>;
 }
 
-pub fn astar(
-    current_location: Loc,
-    goal: Loc,
-    explored_tiles: &dyn LocMap,
-    blocked: &dyn LocSet,
-    avoid: &dyn LocSet,
-) -> Option<VecDeque<Loc>> {
-    let mut open_set = std::collections::BinaryHeap::new();
-    let mut g_scores = IndexMap::new();
-    let mut came_from = IndexMap::new();
-    open_set.push(std::cmp::Reverse((
-        distance(current_location, goal).into(),
-        goal,
-    )));
-    g_scores.insert(goal, 0.0);
-    while let Some(std::cmp::Reverse((_, loc))) = open_set.pop() {
-        if loc == current_location {
-            let mut path = VecDeque::new();
-            let mut current = loc;
-            while came_from.contains_key(&current) {
-                current = came_from[&current];
-                path.push_back(current);
-            }
-            return Some(path);
-        }
-
-        let base_score = g_scores.get(&loc).copied().unwrap_or(std::f32::MAX) + 1.0;
-        for dx in -1..2 {
-            for dy in -1..2 {
-                if dx == 0 && dy == 0 {
-                    continue;
-                }
-                let neighboor = Loc {
-                    x: loc.x + dx,
-                    y: loc.y + dy,
-                };
-                if explored_tiles.get_loc(&neighboor).unwrap_or(false)
-                    && !blocked.contains_loc(&neighboor)
-                {
-                    let mut score = base_score;
-                    if avoid.contains_loc(&neighboor) {
-                        score += 10.0;
-                    }
-                    if score < g_scores.get(&neighboor).copied().unwrap_or(std::f32::MAX) {
-                        came_from.insert(neighboor, loc);
-                        g_scores.insert(neighboor, score);
-                        let f = score + distance(current_location, neighboor);
-                        if open_set
-                            .iter()
-                            .position(|std::cmp::Reverse((_, l))| *l == neighboor)
-                            .is_none()
-                        {
-                            open_set.push(std::cmp::Reverse((OrderedFloat(f), neighboor)));
-                        }
-                    }
-                }
-            }
+impl LocExt for Loc {
+    fn offset(self, dx: i32, dy: i32) -> Loc {
+        Loc {
+            x: self.x + dx,
+            y: self.y + dy,
         }
     }
-    None
+
+    fn plus(self, other: Loc) -> Loc {
+        self.offset(other.x, other.y)
+    }
+
+    fn minus(self, other: Loc) -> Loc {
+        self.offset(-other.x, -other.y)
+    }
+
+    fn neighbors4(self) -> impl Iterator<Item = Loc> {
+        Neighborhood::Four.offsets().iter().map(move |&(dx, dy)| self.offset(dx, dy))
+    }
+
+    fn neighbors8(self) -> impl Iterator<Item = Loc> {
+        Neighborhood::Eight.offsets().iter().map(move |&(dx, dy)| self.offset(dx, dy))
+    }
+
+    fn ring(self, radius: u32) -> impl Iterator<Item = Loc> {
+        let r = radius as i32;
+        (-r..=r).flat_map(move |dx| {
+            // Edge columns are filled in, interior columns only get their
+            // top and bottom tiles.
+            let step = if dx.abs() == r { 1 } else { 2 * r as usize };
+            (-r..=r).step_by(step).map(move |dy| self.offset(dx, dy))
+        })
+    }
+}
+
+/// Every tile outward from `origin`, ring by Chebyshev ring, with each ring
+/// sorted by angle so the order is the same on every call.
+pub fn spiral_from(origin: Loc) -> impl Iterator<Item = Loc> {
+    (0..).flat_map(move |radius| {
+        let mut ring: Vec<Loc> = origin.ring(radius).collect();
+        ring.sort_by_key(|loc| OrderedFloat(((loc.y - origin.y) as f32).atan2((loc.x - origin.x) as f32)));
+        ring
+    })
+}
+
+/// The first tile in `spiral_from` order, so nearest by Chebyshev distance,
+/// that satisfies `pred`.
+pub fn nearest_matching(origin: Loc, max_radius: u32, pred: impl Fn(Loc) -> bool) -> Option<Loc> {
+    spiral_from(origin)
+        .take_while(|loc| chebyshev_distance(origin, *loc) <= max_radius as i32)
+        .find(|loc| pred(*loc))
+}
+
+#[cfg(test)]
+mod loc_ext_tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let a = Loc { x: 3, y: -2 };
+        let b = Loc { x: 1, y: 5 };
+        assert_eq!(a.plus(b), Loc { x: 4, y: 3 });
+        assert_eq!(a.minus(b), Loc { x: 2, y: -7 });
+        assert_eq!(a.offset(-3, 2), Loc { x: 0, y: 0 });
+    }
+
+    #[test]
+    fn neighbors() {
+        let origin = Loc { x: 0, y: 0 };
+        assert_eq!(origin.neighbors4().count(), 4);
+        assert!(origin.neighbors4().all(|n| manhattan_distance(origin, n) == 1));
+        assert_eq!(origin.neighbors8().count(), 8);
+        assert!(origin.neighbors8().all(|n| chebyshev_distance(origin, n) == 1));
+    }
+
+    #[test]
+    fn spiral_visits_rings_in_order() {
+        let origin = Loc { x: 5, y: 5 };
+        let tiles: Vec<Loc> = spiral_from(origin).take(25).collect();
+        assert_eq!(tiles[0], origin);
+        assert!(tiles[1..9].iter().all(|loc| chebyshev_distance(origin, *loc) == 1));
+        assert!(tiles[9..].iter().all(|loc| chebyshev_distance(origin, *loc) == 2));
+        assert_eq!(tiles.iter().collect::<IndexSet<_>>().len(), 25);
+    }
+
+    #[test]
+    fn nearest_matching_respects_radius() {
+        let origin = Loc { x: 0, y: 0 };
+        let target = Loc { x: 3, y: -2 };
+        assert_eq!(nearest_matching(origin, 3, |loc| loc == target), Some(target));
+        assert_eq!(nearest_matching(origin, 2, |loc| loc == target), None);
+        assert_eq!(nearest_matching(origin, 5, |loc| loc.x >= 1), Some(Loc { x: 1, y: -1 }));
+    }
+
+    #[test]
+    fn distances_far_apart() {
+        let (a, b) = (Loc { x: i32::MIN, y: 0 }, Loc { x: i32::MAX, y: 0 });
+        assert_eq!(distance(a, b), u32::MAX as f32);
+        assert_eq!(distance_squared(a, b), i64::MAX);
+        assert_eq!(distance_squared(Loc { x: 0, y: i32::MIN }, Loc { x: 0, y: 0 }), 1 << 62);
+        assert_eq!(distance_squared(Loc { x: 1, y: 2 }, Loc { x: 4, y: 6 }), 25);
+        assert_eq!(distance(Loc { x: 1, y: 2 }, Loc { x: 4, y: 6 }), 5.0);
+    }
+
+    #[test]
+    fn rings() {
+        let origin = Loc { x: 2, y: -1 };
+        assert_eq!(origin.ring(0).collect::<Vec<_>>(), vec![origin]);
+        for radius in 1..5 {
+            let ring: IndexSet<Loc> = origin.ring(radius).collect();
+            assert_eq!(ring.len(), 8 * radius as usize);
+            assert!(ring.iter().all(|n| chebyshev_distance(origin, *n) == radius as i32));
+        }
+    }
+}
+
+/// Inclusive on both corners. A rect with `min` past `max` on either axis
+/// is empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Rect {
+    pub min: Loc,
+    pub max: Loc,
+}
+
+impl Rect {
+    pub fn new(min: Loc, max: Loc) -> Self {
+        Self { min, max }
+    }
+
+    /// The smallest rect containing every loc, or `None` if there are none.
+    pub fn bounding(locs: impl IntoIterator<Item = Loc>) -> Option<Self> {
+        locs.into_iter().fold(None, |rect: Option<Self>, loc| {
+            Some(match rect {
+                Some(Rect { min, max }) => Rect {
+                    min: Loc { x: min.x.min(loc.x), y: min.y.min(loc.y) },
+                    max: Loc { x: max.x.max(loc.x), y: max.y.max(loc.y) },
+                },
+                None => Rect { min: loc, max: loc },
+            })
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y
+    }
+
+    pub fn width(&self) -> i32 {
+        (self.max.x - self.min.x + 1).max(0)
+    }
+
+    pub fn height(&self) -> i32 {
+        (self.max.y - self.min.y + 1).max(0)
+    }
+
+    pub fn contains(&self, loc: Loc) -> bool {
+        (self.min.x..=self.max.x).contains(&loc.x) && (self.min.y..=self.max.y).contains(&loc.y)
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+    }
+
+    /// Grows the rect by `margin` on every side; a negative margin shrinks it.
+    pub fn expand(&self, margin: i32) -> Self {
+        Self {
+            min: self.min.offset(-margin, -margin),
+            max: self.max.offset(margin, margin),
+        }
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Loc> {
+        let (min, max) = (self.min, self.max);
+        (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| Loc { x, y }))
+    }
+}
+
+impl LocSet for Rect {
+    fn contains_loc(&self, loc: &Loc) -> bool {
+        self.contains(*loc)
+    }
+
+    fn is_empty(&self) -> bool {
+        Rect::is_empty(self)
+    }
+
+    fn iter(&self) -> LocSetIter {
+        LocSetIter::with_len(Rect::iter(*self), self.len())
+    }
+
+    fn len(&self) -> usize {
+        self.width() as usize * self.height() as usize
+    }
+}
+
+#[cfg(test)]
+mod rect_tests {
+    use super::*;
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> Rect {
+        Rect::new(Loc { x: x0, y: y0 }, Loc { x: x1, y: y1 })
+    }
+
+    #[test]
+    fn containment_is_inclusive() {
+        let r = rect(0, 0, 2, 3);
+        assert!(r.contains(Loc { x: 0, y: 0 }));
+        assert!(r.contains(Loc { x: 2, y: 3 }));
+        assert!(!r.contains(Loc { x: 3, y: 3 }));
+        assert_eq!(r.iter().count(), 12);
+        assert!(r.iter().all(|loc| r.contains(loc)));
+    }
+
+    #[test]
+    fn degenerate_rects_are_empty() {
+        let r = rect(2, 0, 0, 5);
+        assert!(r.is_empty());
+        assert_eq!(r.iter().count(), 0);
+        assert_eq!(LocSet::iter(&r).count(), 0);
+        assert!(!r.intersects(&r));
+        assert!(rect(0, 0, 2, 2).expand(-2).is_empty());
+    }
+
+    #[test]
+    fn intersection_and_expansion() {
+        let a = rect(0, 0, 2, 2);
+        assert!(a.intersects(&rect(2, 2, 4, 4)));
+        assert!(!a.intersects(&rect(3, 0, 4, 2)));
+        assert!(a.expand(1).intersects(&rect(3, 0, 4, 2)));
+        assert_eq!(a.expand(1), rect(-1, -1, 3, 3));
+    }
+
+    #[test]
+    fn bounding_rect() {
+        let locs = [Loc { x: 3, y: -1 }, Loc { x: -2, y: 4 }, Loc { x: 0, y: 0 }];
+        assert_eq!(Rect::bounding(locs), Some(rect(-2, -1, 3, 4)));
+        assert_eq!(Rect::bounding(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn usable_as_blocked_region() {
+        let mut map = std::collections::HashMap::new();
+        for loc in rect(0, 0, 4, 2).iter() {
+            map.insert(loc, true);
+        }
+        let wall = rect(2, 0, 2, 1);
+        let path = astar(Loc { x: 0, y: 0 }, Loc { x: 4, y: 0 }, &map, &wall, &wall).unwrap();
+        assert!(path.iter().all(|loc| !wall.contains(*loc)));
+        assert_eq!(path.back(), Some(&Loc { x: 4, y: 0 }));
+    }
+}
+
+pub fn distance(a: Loc, b: Loc) -> f32 {
+    let dx = (a.x as i64 - b.x as i64) as f64;
+    let dy = (a.y as i64 - b.y as i64) as f64;
+    (dx * dx + dy * dy).sqrt() as f32
+}
+
+/// Exact, so it's the one to use when only comparing distances. Saturates
+/// rather than overflowing for coordinates at opposite ends of the i32 range.
+pub fn distance_squared(a: Loc, b: Loc) -> i64 {
+    let dx = a.x as i64 - b.x as i64;
+    let dy = a.y as i64 - b.y as i64;
+    dx.saturating_mul(dx).saturating_add(dy.saturating_mul(dy))
+}
+
+/// Computed in i64 and saturated, so far-apart coordinates can't overflow.
+pub fn manhattan_distance(a: Loc, b: Loc) -> i32 {
+    let d = (a.x as i64 - b.x as i64).abs() + (a.y as i64 - b.y as i64).abs();
+    i32::try_from(d).unwrap_or(i32::MAX)
+}
+
+/// Computed in i64 and saturated, so far-apart coordinates can't overflow.
+pub fn chebyshev_distance(a: Loc, b: Loc) -> i32 {
+    let d = (a.x as i64 - b.x as i64).abs().max((a.y as i64 - b.y as i64).abs());
+    i32::try_from(d).unwrap_or(i32::MAX)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Heuristic {
+    #[default]
+    Euclidean,
+    Manhattan,
+    Chebyshev,
+}
+
+impl Heuristic {
+    /// Orders pairs the same way `distance` does, without the float.
+    pub fn rank(&self, a: Loc, b: Loc) -> i64 {
+        match self {
+            Heuristic::Euclidean => distance_squared(a, b),
+            Heuristic::Manhattan => manhattan_distance(a, b) as i64,
+            Heuristic::Chebyshev => chebyshev_distance(a, b) as i64,
+        }
+    }
+
+    pub fn distance(&self, a: Loc, b: Loc) -> f32 {
+        match self {
+            Heuristic::Euclidean => distance(a, b),
+            Heuristic::Manhattan => manhattan_distance(a, b) as f32,
+            Heuristic::Chebyshev => chebyshev_distance(a, b) as f32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Neighborhood {
+    Four,
+    #[default]
+    Eight,
+}
+
+impl Neighborhood {
+    pub fn offsets(&self) -> &'static [(i32, i32)] {
+        match self {
+            Neighborhood::Four => &[(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Neighborhood::Eight => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        }
+    }
+
+    fn default_heuristic(&self) -> Heuristic {
+        match self {
+            Neighborhood::Four => Heuristic::Manhattan,
+            Neighborhood::Eight => Heuristic::Euclidean,
+        }
+    }
+}
+
+/// How searches treat tiles missing from the explored map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum UnknownTiles {
+    #[default]
+    Blocked,
+    Passable,
+    /// Passable, at this much extra cost per unknown tile entered.
+    Penalized(f32),
+}
+
+impl UnknownTiles {
+    pub fn allows(&self, known: Option<bool>) -> bool {
+        match known {
+            Some(passable) => passable,
+            None => *self != UnknownTiles::Blocked,
+        }
+    }
+
+    fn penalty(&self) -> f32 {
+        match self {
+            UnknownTiles::Penalized(penalty) => *penalty,
+            _ => 0.0,
+        }
+    }
+}
+
+/// How the agent walks between the tiles of a path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum MovementKind {
+    /// One tile at a time to an adjacent tile.
+    #[default]
+    Grid,
+    /// Straight to any tile in line of sight, e.g. for flying units.
+    AnyAngle,
+}
+
+/// When a search counts as having reached its goal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum ArrivalCondition {
+    #[default]
+    Exact,
+    /// Any walkable tile other than the goal within this Chebyshev distance
+    /// of it, e.g. `WithinChebyshev(1)` to stand next to something blocked.
+    WithinChebyshev(u32),
+}
+
+impl ArrivalCondition {
+    /// Whether a path ending at `end` arrives at `goal`.
+    pub fn is_met(&self, end: Loc, goal: Loc) -> bool {
+        match self {
+            ArrivalCondition::Exact => end == goal,
+            ArrivalCondition::WithinChebyshev(radius) => {
+                end != goal && chebyshev_distance(end, goal) <= *radius as i32
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AstarOptions {
+    pub neighborhood: Neighborhood,
+    /// Added to the cost of entering a tile in the avoid set. `f32::INFINITY`
+    /// makes avoided tiles behave as if they were blocked.
+    pub avoid_penalty: f32,
+    /// `None` picks Manhattan for four-way movement and Euclidean otherwise.
+    pub heuristic: Option<Heuristic>,
+    /// Makes `move_towards` straighten its paths with `smooth_path`. Searches
+    /// themselves ignore it.
+    pub smooth: bool,
+    pub unknown_tiles: UnknownTiles,
+    /// Only `astar_with_cost` and the functions built on it honour this;
+    /// `IncrementalAstar` and `PathPlanner` always search for the exact goal.
+    pub arrival: ArrivalCondition,
+    /// Give up after expanding this many tiles, treating the goal as
+    /// unreachable. `IncrementalAstar` takes its budget per `step` instead.
+    pub iteration_limit: Option<usize>,
+    /// Makes `move_towards` plan waypoints with `theta_star`. Searches
+    /// themselves ignore it.
+    pub movement: MovementKind,
+    /// The cheapest `LocMap::cost_at` on the map. The distance heuristics
+    /// are scaled by it so they never overestimate; with tiles cheaper than
+    /// this, such as roads at 0.5, paths may not be the cheapest.
+    pub min_tile_cost: f32,
+}
+
+impl AstarOptions {
+    fn heuristic(&self, a: Loc, b: Loc) -> f32 {
+        let distance = self
+            .heuristic
+            .unwrap_or_else(|| self.neighborhood.default_heuristic())
+            .distance(a, b);
+        distance * self.cost_scale()
+    }
+
+    fn cost_scale(&self) -> f32 {
+        self.min_tile_cost.max(MIN_TILE_COST)
+    }
+}
+
+impl Default for AstarOptions {
+    fn default() -> Self {
+        Self {
+            neighborhood: Neighborhood::default(),
+            avoid_penalty: 10.0,
+            heuristic: None,
+            smooth: false,
+            unknown_tiles: UnknownTiles::default(),
+            arrival: ArrivalCondition::default(),
+            iteration_limit: None,
+            movement: MovementKind::default(),
+            min_tile_cost: 1.0,
+        }
+    }
+}
+
+/// Returns the steps to take from `current_location`, ending with `goal`
+/// itself but not including `current_location`. See `Path` for other forms.
+pub fn astar(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+) -> Option<VecDeque<Loc>> {
+    astar_with_options(
+        current_location,
+        goal,
+        explored_tiles,
+        blocked,
+        avoid,
+        &AstarOptions::default(),
+    )
+}
+
+pub fn astar_with_options(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> Option<VecDeque<Loc>> {
+    astar_with_cost(current_location, goal, explored_tiles, blocked, avoid, options)
+        .map(|path| path.steps)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AstarStats {
+    pub expanded: usize,
+    pub queued: usize,
+    pub max_open: usize,
+    /// The search gave up after `AstarOptions::iteration_limit` expansions.
+    pub hit_limit: bool,
+}
+
+impl AstarStats {
+    fn queue(&mut self, open_len: usize) {
+        self.queued += 1;
+        self.max_open = self.max_open.max(open_len);
+    }
+}
+
+/// Totals over several searches, e.g. everything `move_towards` did in a turn.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PathfindingStats {
+    pub searches: usize,
+    pub expanded: usize,
+    pub queued: usize,
+    pub max_open: usize,
+    pub limit_hits: usize,
+}
+
+impl PathfindingStats {
+    pub fn add(&mut self, stats: &AstarStats) {
+        self.searches += 1;
+        self.expanded += stats.expanded;
+        self.queued += stats.queued;
+        self.max_open = self.max_open.max(stats.max_open);
+        if stats.hit_limit {
+            self.limit_hits += 1;
+        }
+    }
+}
+
+impl std::fmt::Display for PathfindingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} searches, {} expanded, {} queued, max open {}, {} hit the limit",
+            self.searches, self.expanded, self.queued, self.max_open, self.limit_hits
+        )
+    }
+}
+
+/// `steps` runs from the first tile after `start` up to and including the
+/// final tile, so it's empty when the search starts on the goal. This is the
+/// form `move_towards` consumes; the accessors cover the other conventions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path {
+    pub start: Loc,
+    pub steps: VecDeque<Loc>,
+    pub cost: f32,
+}
+
+impl Path {
+    pub fn steps(&self) -> &VecDeque<Loc> {
+        &self.steps
+    }
+
+    /// `start` followed by every step, so never empty.
+    pub fn with_start(&self) -> VecDeque<Loc> {
+        let mut locs = self.steps.clone();
+        locs.push_front(self.start);
+        locs
+    }
+
+    /// Every step but the final tile, e.g. for stopping next to the goal.
+    pub fn without_goal(&self) -> VecDeque<Loc> {
+        let mut locs = self.steps.clone();
+        locs.pop_back();
+        locs
+    }
+}
+
+pub fn astar_with_cost(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> Option<Path> {
+    astar_with_stats(current_location, goal, explored_tiles, blocked, avoid, options).0
+}
+
+pub fn astar_with_stats(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> (Option<Path>, AstarStats) {
+    if let ArrivalCondition::WithinChebyshev(radius) = options.arrival {
+        let (search, found) = search_forward(
+            current_location,
+            explored_tiles,
+            blocked,
+            avoid,
+            options,
+            |loc| {
+                options.arrival.is_met(*loc, goal)
+                    && options.unknown_tiles.allows(explored_tiles.get_loc(loc))
+                    && !blocked.contains_loc(loc)
+            },
+            |loc| (chebyshev_distance(loc, goal) - radius as i32).max(0) as f32 * options.cost_scale(),
+        );
+        return (found.map(|end| search.path_to(end)), search.stats);
+    }
+    let mut search = IncrementalAstar::new(current_location, goal, *options);
+    let limit = options.iteration_limit.unwrap_or(usize::MAX);
+    let path = match search.step(explored_tiles, blocked, avoid, limit) {
+        SearchStatus::Found(steps) => Some(Path {
+            start: current_location,
+            steps,
+            cost: search.g_scores[&current_location],
+        }),
+        SearchStatus::InProgress => {
+            search.stats.hit_limit = true;
+            None
+        }
+        SearchStatus::Unreachable => None,
+    };
+    (path, search.stats)
+}
+
+/// Open set entries pop lowest f first. Ties go to the lower heuristic,
+/// the node nearer where the search is heading, and then to the smaller
+/// `Loc`. Nothing depends on how the input sets iterate, so the same inputs
+/// always produce the same path. The g score comes last so an entry can be
+/// keyed again when the heuristic changes.
+type OpenEntry = std::cmp::Reverse<(OrderedFloat<f32>, OrderedFloat<f32>, Loc, OrderedFloat<f32>)>;
+
+fn open_entry(g: f32, h: f32, loc: Loc) -> OpenEntry {
+    std::cmp::Reverse((OrderedFloat(g + h), OrderedFloat(h), loc, OrderedFloat(g)))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchStatus {
+    InProgress,
+    Found(VecDeque<Loc>),
+    Unreachable,
+}
+
+/// The search behind `astar`, paused after a fixed number of expansions so
+/// long searches can be spread over several turns. The maps are passed to
+/// every `step` rather than stored, so the search can be saved with the rest
+/// of the bot's state; tiles that change between steps are seen as they are
+/// when they get expanded.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct IncrementalAstar {
+    start: Loc,
+    goal: Loc,
+    options: AstarOptions,
+    open_set: std::collections::BinaryHeap<OpenEntry>,
+    g_scores: IndexMap<Loc, f32>,
+    came_from: IndexMap<Loc, Loc>,
+    /// Nodes whose g score is final.
+    expanded: IndexSet<Loc>,
+    found: bool,
+    journal: Option<Journal>,
+    stats: AstarStats,
+}
+
+impl IncrementalAstar {
+    pub fn new(current_location: Loc, goal: Loc, options: AstarOptions) -> Self {
+        // Searches run from the goal back to the start so the path can be read
+        // off came_from in walking order.
+        let mut search = Self {
+            start: current_location,
+            goal,
+            options,
+            open_set: std::collections::BinaryHeap::new(),
+            g_scores: IndexMap::new(),
+            came_from: IndexMap::new(),
+            expanded: IndexSet::new(),
+            found: false,
+            journal: None,
+            stats: AstarStats::default(),
+        };
+        search
+            .open_set
+            .push(open_entry(0.0, search.heuristic(goal), goal));
+        search.stats.queue(1);
+        search.g_scores.insert(goal, 0.0);
+        search
+    }
+
+    pub fn start(&self) -> Loc {
+        self.start
+    }
+
+    pub fn goal(&self) -> Loc {
+        self.goal
+    }
+
+    /// Counts since the search was created, across every `step`.
+    pub fn stats(&self) -> AstarStats {
+        self.stats
+    }
+
+    fn heuristic(&self, loc: Loc) -> f32 {
+        self.options.heuristic(self.start, loc)
+    }
+
+    fn path(&self) -> VecDeque<Loc> {
+        let mut steps = VecDeque::new();
+        let mut current = self.start;
+        while let Some(&next) = self.came_from.get(&current) {
+            current = next;
+            steps.push_back(current);
+        }
+        steps
+    }
+
+    /// Expands at most `max_expansions` nodes. Once the search has finished
+    /// further calls keep returning the same result.
+    pub fn step(
+        &mut self,
+        explored_tiles: &dyn LocMap,
+        blocked: &dyn LocSet,
+        avoid: &dyn LocSet,
+        max_expansions: usize,
+    ) -> SearchStatus {
+        if self.found {
+            return SearchStatus::Found(self.path());
+        }
+        let mut expansions = 0;
+        while expansions < max_expansions {
+            let Some(popped) = self.open_set.pop() else {
+                return SearchStatus::Unreachable;
+            };
+            let std::cmp::Reverse((f, _, loc, _)) = popped;
+            if let Some(journal) = &mut self.journal {
+                journal.entries.push(JournalEntry {
+                    popped,
+                    changes: Vec::new(),
+                    expanded: false,
+                });
+            }
+            // Nodes are pushed again whenever their score improves rather than
+            // being updated in place, so skip entries that have gone stale.
+            let g = self.g_scores.get(&loc).copied().unwrap_or(f32::MAX);
+            if f.0 > g + self.heuristic(loc) {
+                continue;
+            }
+            if loc == self.start {
+                // Put back, as if only peeked at, so the search can carry on
+                // through here if the start moves.
+                self.open_set.push(popped);
+                if let Some(journal) = &mut self.journal {
+                    journal.entries.pop();
+                }
+                self.found = true;
+                return SearchStatus::Found(self.path());
+            }
+            expansions += 1;
+            self.stats.expanded += 1;
+            self.expanded.insert(loc);
+            if let Some(journal) = &mut self.journal {
+                journal.entries.last_mut().unwrap().expanded = true;
+                // Every step onto `loc` costs what it reads now. Only the goal
+                // hasn't been read already, as a neighbour.
+                let step = journal.entries.len() - 1;
+                journal
+                    .touched
+                    .entry(loc)
+                    .or_insert_with(|| (step, TileInputs::read(loc, explored_tiles, blocked, avoid)));
+            }
+
+            for &offset in self.options.neighborhood.offsets() {
+                let neighboor = loc.offset(offset.0, offset.1);
+                if let Some(journal) = &mut self.journal {
+                    let step = journal.entries.len() - 1;
+                    journal
+                        .touched
+                        .entry(neighboor)
+                        .or_insert_with(|| (step, TileInputs::read(neighboor, explored_tiles, blocked, avoid)));
+                }
+                if !self.options.unknown_tiles.allows(explored_tiles.get_loc(&neighboor))
+                    || blocked.contains_loc(&neighboor)
+                {
+                    continue;
+                }
+                // Walking the other way, from `neighboor` onto `loc`.
+                if let Some(cost) = step_cost(explored_tiles, avoid, &self.options, offset, loc) {
+                    let score = g + cost;
+                    let previous = self.g_scores.get(&neighboor).copied();
+                    if score < previous.unwrap_or(f32::MAX) {
+                        let pushed = open_entry(score, self.heuristic(neighboor), neighboor);
+                        let came_from = self.came_from.insert(neighboor, loc);
+                        self.g_scores.insert(neighboor, score);
+                        self.open_set.push(pushed);
+                        self.stats.queue(self.open_set.len());
+                        if let Some(journal) = &mut self.journal
+                            && let Some(entry) = journal.entries.last_mut()
+                        {
+                            entry.changes.push(JournalChange {
+                                loc: neighboor,
+                                g_score: previous,
+                                came_from,
+                                pushed,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        if self.open_set.is_empty() {
+            SearchStatus::Unreachable
+        } else {
+            SearchStatus::InProgress
+        }
+    }
+}
+
+/// Everything the search read about a tile when it first looked at it.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct TileInputs {
+    known: Option<bool>,
+    blocked: bool,
+    avoided: bool,
+    cost: f32,
+    penalty: f32,
+}
+
+impl TileInputs {
+    fn read(loc: Loc, explored_tiles: &dyn LocMap, blocked: &dyn LocSet, avoid: &dyn LocSet) -> Self {
+        Self {
+            known: explored_tiles.get_loc(&loc),
+            blocked: blocked.contains_loc(&loc),
+            avoided: avoid.contains_loc(&loc),
+            cost: explored_tiles.cost_at(&loc),
+            penalty: explored_tiles.penalty_at(&loc),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct JournalChange {
+    loc: Loc,
+    g_score: Option<f32>,
+    came_from: Option<Loc>,
+    pushed: OpenEntry,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct JournalEntry {
+    popped: OpenEntry,
+    changes: Vec<JournalChange>,
+    /// Whether `popped` was expanded rather than skipped as stale.
+    expanded: bool,
+}
+
+/// One entry per node popped from the open set, recording how to undo it,
+/// plus the first entry that read each tile. Once `dropped`, the entries
+/// are gone and the journal can only tell that a tile changed, not undo it.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Journal {
+    entries: Vec<JournalEntry>,
+    touched: IndexMap<Loc, (usize, TileInputs)>,
+    dropped: bool,
+}
+
+impl IncrementalAstar {
+    /// The first journal entry that read a tile which reads differently now.
+    fn first_invalidated(&self, explored_tiles: &dyn LocMap, blocked: &dyn LocSet, avoid: &dyn LocSet) -> Option<usize> {
+        let journal = self.journal.as_ref()?;
+        journal
+            .touched
+            .iter()
+            .filter(|(loc, (_, inputs))| TileInputs::read(**loc, explored_tiles, blocked, avoid) != *inputs)
+            .map(|(_, (step, _))| *step)
+            .min()
+    }
+
+    /// Undoes journal entries from `step` onwards, leaving the search exactly
+    /// as it was before that node was popped.
+    fn rewind(&mut self, step: usize) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let mut pushed: std::collections::HashMap<OpenEntry, usize> = std::collections::HashMap::new();
+        let mut open_set = std::mem::take(&mut self.open_set).into_vec();
+        for entry in journal.entries.drain(step..).rev() {
+            if entry.expanded {
+                self.expanded.shift_remove(&entry.popped.0 .2);
+            }
+            for change in entry.changes.into_iter().rev() {
+                match change.g_score {
+                    Some(g) => self.g_scores.insert(change.loc, g),
+                    None => self.g_scores.shift_remove(&change.loc),
+                };
+                match change.came_from {
+                    Some(from) => self.came_from.insert(change.loc, from),
+                    None => self.came_from.shift_remove(&change.loc),
+                };
+                *pushed.entry(change.pushed).or_default() += 1;
+            }
+            open_set.push(entry.popped);
+        }
+        open_set.retain(|item| match pushed.get_mut(item) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        });
+        self.open_set = open_set.into();
+        journal.touched.retain(|_, (first, _)| *first < step);
+        self.found = false;
+    }
+
+    /// Searches on towards `start` instead. Everything already expanded
+    /// keeps its score, since those are distances to the goal; only the
+    /// open set is keyed again for the new heuristic.
+    fn move_start(&mut self, start: Loc) {
+        if start == self.start {
+            return;
+        }
+        self.start = start;
+        let rekey = |std::cmp::Reverse((_, _, loc, g)): OpenEntry| open_entry(g.0, self.options.heuristic(start, loc), loc);
+        self.open_set = std::mem::take(&mut self.open_set).into_iter().map(rekey).collect();
+        if let Some(journal) = &mut self.journal {
+            for entry in &mut journal.entries {
+                entry.popped = rekey(entry.popped);
+                for change in &mut entry.changes {
+                    change.pushed = rekey(change.pushed);
+                }
+            }
+        }
+        self.found = self.expanded.contains(&start);
+    }
+}
+
+/// Keeps the last search around so that when blockers or newly explored
+/// tiles change what it saw, only the part of the search after the first
+/// affected expansion is redone. The result is always the path a fresh
+/// `astar` call would return. The search runs from the goal back towards
+/// the agent, so tiles near the agent are expanded last and changes there
+/// are cheap to repair. The scores it keeps are distances to the goal, so
+/// the agent can move without the search starting over: walking along the
+/// path, the new start has usually been expanded already. Picking a new
+/// goal starts over. After the start moves, the path is as cheap as a fresh
+/// `astar` call's, though ties may be broken differently.
+///
+/// Searches that pop more than `max_journal_len` nodes drop their journal
+/// once done, so they're started over rather than repaired when something
+/// they saw changes.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PathPlanner {
+    search: Option<IncrementalAstar>,
+    pub path: Option<VecDeque<Loc>>,
+    pub max_journal_len: usize,
+    last_search_len: usize,
+}
+
+impl Default for PathPlanner {
+    fn default() -> Self {
+        Self {
+            search: None,
+            path: None,
+            max_journal_len: 4096,
+            last_search_len: 0,
+        }
+    }
+}
+
+impl PathPlanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_journal_len(mut self, max_journal_len: usize) -> Self {
+        self.max_journal_len = max_journal_len;
+        self
+    }
+
+    pub fn plan(
+        &mut self,
+        current_location: Loc,
+        goal: Loc,
+        explored_tiles: &dyn LocMap,
+        blocked: &dyn LocSet,
+        avoid: &dyn LocSet,
+        options: &AstarOptions,
+    ) -> Option<VecDeque<Loc>> {
+        let reusable = self.search.as_ref().is_some_and(|search| search.goal == goal && search.options == *options);
+        let search = match self.search.take() {
+            Some(mut search) if reusable => match search.first_invalidated(explored_tiles, blocked, avoid) {
+                None => Some(search),
+                Some(_) if search.journal.as_ref().is_some_and(|j| j.dropped) => None,
+                Some(step) => {
+                    search.rewind(step);
+                    Some(search)
+                }
+            },
+            _ => None,
+        };
+        let search = self.search.insert(search.unwrap_or_else(|| {
+            let mut search = IncrementalAstar::new(current_location, goal, *options);
+            search.journal = Some(Journal::default());
+            search
+        }));
+        search.move_start(current_location);
+        let popped = |search: &IncrementalAstar| search.journal.as_ref().map(|j| j.entries.len()).unwrap_or(0);
+        let before = popped(search);
+        self.path = match search.step(explored_tiles, blocked, avoid, usize::MAX) {
+            SearchStatus::Found(path) => Some(path),
+            _ => None,
+        };
+        self.last_search_len = popped(search) - before;
+        if let Some(journal) = &mut search.journal
+            && journal.entries.len() > self.max_journal_len
+        {
+            journal.entries = Vec::new();
+            journal.dropped = true;
+        }
+        self.path.clone()
+    }
+
+    /// How many nodes the last `plan` call popped, which is the whole search
+    /// when starting over and only the redone tail after a repair.
+    pub fn last_search_len(&self) -> usize {
+        self.last_search_len
+    }
+
+    pub fn clear(&mut self) {
+        self.search = None;
+        self.path = None;
+        self.last_search_len = 0;
+    }
+}
+
+fn step_cost(
+    explored_tiles: &dyn LocMap,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+    (dx, dy): (i32, i32),
+    to: Loc,
+) -> Option<f32> {
+    let step = if dx != 0 && dy != 0 {
+        std::f32::consts::SQRT_2
+    } else {
+        1.0
+    };
+    let mut cost = step * explored_tiles.cost_at(&to).max(MIN_TILE_COST) + explored_tiles.penalty_at(&to).max(0.0);
+    if avoid.contains_loc(&to) {
+        cost += options.avoid_penalty;
+    }
+    if explored_tiles.get_loc(&to).is_none() {
+        cost += options.unknown_tiles.penalty();
+    }
+    if cost.is_finite() {
+        Some(cost)
+    } else {
+        None
+    }
+}
+
+struct ForwardSearch {
+    g_scores: IndexMap<Loc, f32>,
+    came_from: IndexMap<Loc, Loc>,
+    stats: AstarStats,
+}
+
+impl ForwardSearch {
+    fn path_to(&self, end: Loc) -> Path {
+        let mut steps = VecDeque::new();
+        let mut current = end;
+        while let Some(&previous) = self.came_from.get(&current) {
+            steps.push_front(current);
+            current = previous;
+        }
+        Path {
+            start: current,
+            steps,
+            cost: self.g_scores[&end],
+        }
+    }
+}
+
+/// Searches outward from `start` until a tile satisfying `is_goal` is popped.
+/// Goal tiles may be entered even if they aren't passable, mirroring how
+/// `astar` treats its goal.
+fn search_forward(
+    start: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+    is_goal: impl Fn(&Loc) -> bool,
+    heuristic: impl Fn(Loc) -> f32,
+) -> (ForwardSearch, Option<Loc>) {
+    let mut open_set = std::collections::BinaryHeap::new();
+    let mut search = ForwardSearch {
+        g_scores: IndexMap::new(),
+        came_from: IndexMap::new(),
+        stats: AstarStats::default(),
+    };
+    let limit = options.iteration_limit.unwrap_or(usize::MAX);
+    open_set.push(open_entry(0.0, heuristic(start), start));
+    search.stats.queue(1);
+    search.g_scores.insert(start, 0.0);
+    while let Some(std::cmp::Reverse((f, _, loc, _))) = open_set.pop() {
+        let g = search.g_scores.get(&loc).copied().unwrap_or(f32::MAX);
+        if f.0 > g + heuristic(loc) {
+            continue;
+        }
+        if is_goal(&loc) {
+            return (search, Some(loc));
+        }
+        if search.stats.expanded >= limit {
+            search.stats.hit_limit = true;
+            break;
+        }
+        search.stats.expanded += 1;
+
+        for &offset in options.neighborhood.offsets() {
+            let neighboor = loc.offset(offset.0, offset.1);
+            if !is_goal(&neighboor)
+                && (!options.unknown_tiles.allows(explored_tiles.get_loc(&neighboor)) || blocked.contains_loc(&neighboor))
+            {
+                continue;
+            }
+            if let Some(cost) = step_cost(explored_tiles, avoid, options, offset, neighboor) {
+                let score = g + cost;
+                if score < search.g_scores.get(&neighboor).copied().unwrap_or(f32::MAX) {
+                    search.came_from.insert(neighboor, loc);
+                    search.g_scores.insert(neighboor, score);
+                    open_set.push(open_entry(score, heuristic(neighboor), neighboor));
+                    search.stats.queue(open_set.len());
+                }
+            }
+        }
+    }
+    (search, None)
+}
+
+/// Searches forward from `current_location` and, if `goal` turns out to be
+/// unreachable, returns the path to the reachable tile closest to it instead.
+/// The flag is `true` only when the path actually ends at `goal`.
+pub fn astar_partial(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> (Path, bool) {
+    let heuristic = |loc| options.heuristic(goal, loc);
+    let (search, found) = search_forward(
+        current_location,
+        explored_tiles,
+        blocked,
+        avoid,
+        options,
+        |loc| *loc == goal,
+        heuristic,
+    );
+    if let Some(goal) = found {
+        return (search.path_to(goal), true);
+    }
+    let closest = search
+        .g_scores
+        .iter()
+        .min_by_key(|(loc, g)| (OrderedFloat(heuristic(**loc)), OrderedFloat(**g)))
+        .map(|(loc, _)| *loc)
+        .unwrap_or(current_location);
+    (search.path_to(closest), false)
+}
+
+/// Finds the path to whichever of `goals` is cheapest to walk to, using a
+/// single Dijkstra search rather than one `astar` call per goal.
+pub fn astar_multi(
+    current_location: Loc,
+    goals: &dyn LocSet,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> Option<(Loc, VecDeque<Loc>)> {
+    if goals.is_empty() {
+        return None;
+    }
+    let (search, found) = search_forward(
+        current_location,
+        explored_tiles,
+        blocked,
+        avoid,
+        options,
+        |loc| goals.contains_loc(loc),
+        |_| 0.0,
+    );
+    found.map(|goal| (goal, search.path_to(goal).steps))
+}
+
+#[derive(Clone, Debug)]
+pub struct DistanceField {
+    pub origin: Loc,
+    pub costs: IndexMap<Loc, f32>,
+}
+
+impl DistanceField {
+    pub fn get(&self, loc: &Loc) -> Option<f32> {
+        self.costs.get(loc).copied()
+    }
+
+    /// The member of `locs` that is cheapest to walk to, ignoring any that
+    /// the field didn't reach.
+    pub fn cheapest_in(&self, locs: &dyn LocSet) -> Option<(Loc, f32)> {
+        locs.iter()
+            .filter_map(|loc| self.get(&loc).map(|cost| (loc, cost)))
+            .min_by_key(|(loc, cost)| (OrderedFloat(*cost), *loc))
+    }
+}
+
+/// Walking cost from `origin` to every passable tile of `map` that can be
+/// reached for at most `max_cost`.
+pub fn distance_field(origin: Loc, map: &dyn LocMap, blocked: &dyn LocSet, max_cost: f32) -> DistanceField {
+    let options = AstarOptions::default();
+    let mut open_set = std::collections::BinaryHeap::new();
+    let mut costs = IndexMap::new();
+    open_set.push(std::cmp::Reverse((OrderedFloat(0.0), origin)));
+    costs.insert(origin, 0.0);
+    while let Some(std::cmp::Reverse((OrderedFloat(g), loc))) = open_set.pop() {
+        if g > costs.get(&loc).copied().unwrap_or(f32::MAX) {
+            continue;
+        }
+        for &offset in options.neighborhood.offsets() {
+            let neighboor = loc.offset(offset.0, offset.1);
+            if !map.get_loc(&neighboor).unwrap_or(false) || blocked.contains_loc(&neighboor) {
+                continue;
+            }
+            if let Some(cost) = step_cost(map, &EmptyLocSet, &options, offset, neighboor) {
+                let score = g + cost;
+                if score <= max_cost && score < costs.get(&neighboor).copied().unwrap_or(f32::MAX) {
+                    costs.insert(neighboor, score);
+                    open_set.push(std::cmp::Reverse((OrderedFloat(score), neighboor)));
+                }
+            }
+        }
+    }
+    DistanceField { origin, costs }
+}
+
+/// Directions to one goal from everywhere that can reach it, so many agents
+/// heading to the same place can share a single search.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FlowField {
+    pub goal: Loc,
+    pub next: IndexMap<Loc, Loc>,
+    pub costs: IndexMap<Loc, f32>,
+    /// How many changes to tiles the field covers have been reported through
+    /// `mark_changed` since it was built. Rebuild once it gets too high.
+    pub staleness: u32,
+}
+
+impl FlowField {
+    /// Where to step from `from`, or `None` at the goal or off the field.
+    pub fn next_step(&self, from: Loc) -> Option<Loc> {
+        self.next.get(&from).copied()
+    }
+
+    pub fn cost(&self, from: Loc) -> Option<f32> {
+        self.costs.get(&from).copied()
+    }
+
+    pub fn mark_changed(&mut self, loc: Loc) {
+        if self.costs.contains_key(&loc) || loc.neighbors8().any(|n| self.costs.contains_key(&n)) {
+            self.staleness += 1;
+        }
+    }
+}
+
+/// Searches backward from `goal` over every tile that can reach it for at
+/// most `max_cost`. As with `astar`, the goal itself needn't be passable.
+pub fn flow_field(goal: Loc, map: &dyn LocMap, blocked: &dyn LocSet, max_cost: f32) -> FlowField {
+    let options = AstarOptions::default();
+    let mut open_set = std::collections::BinaryHeap::new();
+    let mut field = FlowField {
+        goal,
+        next: IndexMap::new(),
+        costs: IndexMap::new(),
+        staleness: 0,
+    };
+    open_set.push(std::cmp::Reverse((OrderedFloat(0.0), goal)));
+    field.costs.insert(goal, 0.0);
+    while let Some(std::cmp::Reverse((OrderedFloat(g), loc))) = open_set.pop() {
+        if g > field.costs.get(&loc).copied().unwrap_or(f32::MAX) {
+            continue;
+        }
+        for &offset in options.neighborhood.offsets() {
+            let neighboor = loc.offset(offset.0, offset.1);
+            if !map.get_loc(&neighboor).unwrap_or(false) || blocked.contains_loc(&neighboor) {
+                continue;
+            }
+            // Walking the other way, from `neighboor` onto `loc`.
+            if let Some(cost) = step_cost(map, &EmptyLocSet, &options, offset, loc) {
+                let score = g + cost;
+                if score <= max_cost && score < field.costs.get(&neighboor).copied().unwrap_or(f32::MAX) {
+                    field.costs.insert(neighboor, score);
+                    field.next.insert(neighboor, loc);
+                    open_set.push(std::cmp::Reverse((OrderedFloat(score), neighboor)));
+                }
+            }
+        }
+    }
+    field
+}
+
+#[cfg(test)]
+mod flow_field_tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn steps_follow_shortest_paths() {
+        let mut map: HashMap<Loc, bool> = Rect::new(Loc { x: 0, y: 0 }, Loc { x: 9, y: 9 }).iter().map(|l| (l, true)).collect();
+        for y in 0..8 {
+            map.insert(Loc { x: 5, y }, false);
+        }
+        let goal = Loc { x: 8, y: 1 };
+        let field = flow_field(goal, &map, &HashSet::new(), f32::INFINITY);
+        for start in [Loc { x: 0, y: 0 }, Loc { x: 2, y: 7 }, Loc { x: 9, y: 9 }] {
+            let expected = astar_with_cost(start, goal, &map, &HashSet::new(), &HashSet::new(), &AstarOptions::default()).unwrap();
+            let mut steps = 0;
+            let mut loc = start;
+            while let Some(next) = field.next_step(loc) {
+                assert!(map[&next]);
+                loc = next;
+                steps += 1;
+            }
+            assert_eq!(loc, goal);
+            assert_eq!(steps, expected.steps.len());
+            assert!((field.cost(start).unwrap() - expected.cost).abs() < 1e-3);
+        }
+        assert_eq!(field.next_step(goal), None);
+        assert_eq!(field.next_step(Loc { x: 5, y: 0 }), None);
+    }
+
+    #[test]
+    fn reports_staleness_near_the_field() {
+        let map: HashMap<Loc, bool> = Rect::new(Loc { x: 0, y: 0 }, Loc { x: 3, y: 3 }).iter().map(|l| (l, true)).collect();
+        let mut field = flow_field(Loc { x: 0, y: 0 }, &map, &HashSet::new(), f32::INFINITY);
+        field.mark_changed(Loc { x: 4, y: 2 });
+        field.mark_changed(Loc { x: 10, y: 10 });
+        assert_eq!(field.staleness, 1);
+    }
+}
+
+#[cfg(test)]
+mod astar_tests {
+    use super::*;
+    use crate::crdt::{CrdtMap, Lww};
+    use std::collections::{HashMap, HashSet};
+
+    fn open_grid(width: i32, height: i32) -> HashMap<Loc, bool> {
+        let mut map = HashMap::new();
+        for x in 0..width {
+            for y in 0..height {
+                map.insert(Loc { x, y }, true);
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn path_ends_at_goal() {
+        let map = open_grid(10, 10);
+        let path = astar(Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 }, &map, &HashSet::new(), &HashSet::new()).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(path.back(), Some(&Loc { x: 5, y: 0 }));
+        assert!(!path.contains(&Loc { x: 0, y: 0 }));
+    }
+
+    #[test]
+    fn path_through_gap_in_wall() {
+        let map = open_grid(10, 10);
+        let blocked: HashSet<Loc> = (0..10).filter(|y| *y != 7).map(|y| Loc { x: 5, y }).collect();
+        let path = astar(Loc { x: 0, y: 0 }, Loc { x: 9, y: 0 }, &map, &blocked, &HashSet::new()).unwrap();
+        assert!(path.iter().all(|l| !blocked.contains(l)));
+        assert!(path.contains(&Loc { x: 5, y: 7 }));
+        assert_eq!(path.back(), Some(&Loc { x: 9, y: 0 }));
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let map = open_grid(10, 10);
+        let blocked: HashSet<Loc> = (0..10).map(|y| Loc { x: 5, y }).collect();
+        assert!(astar(Loc { x: 0, y: 0 }, Loc { x: 9, y: 0 }, &map, &blocked, &HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn prefers_straight_corridor() {
+        // With a flat step cost the staircase (1,1) -> (2,0) -> (3,1) is just as cheap.
+        let map = open_grid(4, 2);
+        let blocked: HashSet<Loc> = [Loc { x: 1, y: 0 }].into_iter().collect();
+        let path = astar(Loc { x: 0, y: 0 }, Loc { x: 3, y: 1 }, &map, &blocked, &HashSet::new()).unwrap();
+        assert_eq!(path, VecDeque::from([Loc { x: 1, y: 1 }, Loc { x: 2, y: 1 }, Loc { x: 3, y: 1 }]));
+    }
+
+    #[test]
+    fn cardinal_paths_go_around_corners() {
+        // The only gap in the wall is reachable diagonally from (1,1), which
+        // four-way movement may not use.
+        let map = open_grid(4, 4);
+        let blocked: HashSet<Loc> = [Loc { x: 2, y: 0 }, Loc { x: 2, y: 1 }, Loc { x: 1, y: 2 }, Loc { x: 1, y: 3 }].into_iter().collect();
+        let options = AstarOptions { neighborhood: Neighborhood::Four, ..Default::default() };
+        let path = astar_with_options(Loc { x: 1, y: 1 }, Loc { x: 2, y: 2 }, &map, &blocked, &HashSet::new(), &options);
+        assert!(path.is_none());
+
+        let path = astar(Loc { x: 1, y: 1 }, Loc { x: 2, y: 2 }, &map, &blocked, &HashSet::new()).unwrap();
+        assert_eq!(path, VecDeque::from([Loc { x: 2, y: 2 }]));
+
+        let blocked: HashSet<Loc> = [Loc { x: 2, y: 1 }, Loc { x: 1, y: 2 }].into_iter().collect();
+        let path = astar_with_options(Loc { x: 1, y: 1 }, Loc { x: 2, y: 2 }, &map, &blocked, &HashSet::new(), &options).unwrap();
+        let mut prev = Loc { x: 1, y: 1 };
+        for loc in &path {
+            assert_eq!((loc.x - prev.x).abs() + (loc.y - prev.y).abs(), 1);
+            prev = *loc;
+        }
+        assert_eq!(path.back(), Some(&Loc { x: 2, y: 2 }));
+    }
+
+    #[test]
+    fn path_cost_includes_avoid_penalty() {
+        let map = open_grid(6, 1);
+        let options = AstarOptions::default();
+        let path = astar_with_cost(Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 }, &map, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert_eq!(path.cost, 5.0);
+
+        let avoid: HashSet<Loc> = [Loc { x: 2, y: 0 }].into_iter().collect();
+        let path = astar_with_cost(Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 }, &map, &HashSet::new(), &avoid, &options).unwrap();
+        assert_eq!(path.cost, 15.0);
+        assert_eq!(path.steps.len(), 5);
+    }
+
+    #[test]
+    fn path_cost_counts_the_goal_not_the_start() {
+        let mut terrain: CrdtMap<Loc, f32, Lww> = CrdtMap::default();
+        for loc in open_grid(8, 3).into_keys() {
+            terrain.insert(loc, 1.0, 0);
+        }
+        let (start, goal) = (Loc { x: 0, y: 1 }, Loc { x: 7, y: 1 });
+        terrain.insert(start, 50.0, 0);
+        terrain.insert(goal, 3.0, 0);
+        let penalties: IndexMap<Loc, f32> = [(start, 20.0), (goal, 2.0), (Loc { x: 3, y: 1 }, 4.0)].into_iter().collect();
+        let map = WithPenalties(&terrain, &penalties);
+        let avoid: HashSet<Loc> = [start, Loc { x: 4, y: 1 }].into_iter().collect();
+        let options = AstarOptions::default();
+        let path = astar_with_cost(start, goal, &map, &HashSet::new(), &avoid, &options).unwrap();
+        let (forward, reached) = astar_partial(start, goal, &map, &HashSet::new(), &avoid, &options);
+        assert!(reached);
+        assert!((path.cost - forward.cost).abs() < 1e-4, "{} vs {}", path.cost, forward.cost);
+        // Around the penalty and the avoided tile, then 3 and the 2 penalty
+        // for the goal. Nothing for the start.
+        let expected = 4.0 + 2.0 * std::f32::consts::SQRT_2 + 5.0;
+        assert!((path.cost - expected).abs() < 1e-4, "{}", path.cost);
+        assert!(!path.steps.contains(&Loc { x: 3, y: 1 }) && !path.steps.contains(&Loc { x: 4, y: 1 }));
+    }
+
+    #[test]
+    fn avoid_penalty_controls_detours() {
+        let map = open_grid(20, 7);
+        let avoid: HashSet<Loc> = (0..6).map(|y| Loc { x: 10, y }).collect();
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 19, y: 0 };
+
+        let options = AstarOptions { avoid_penalty: 100.0, ..Default::default() };
+        let path = astar_with_options(start, goal, &map, &HashSet::new(), &avoid, &options).unwrap();
+        assert!(path.iter().all(|l| !avoid.contains(l)));
+
+        let options = AstarOptions { avoid_penalty: 0.0, ..Default::default() };
+        let path = astar_with_options(start, goal, &map, &HashSet::new(), &avoid, &options).unwrap();
+        assert_eq!(path.len(), 19);
+        assert!(path.contains(&Loc { x: 10, y: 0 }));
+
+        let avoid: HashSet<Loc> = (0..7).map(|y| Loc { x: 10, y }).collect();
+        let options = AstarOptions { avoid_penalty: f32::INFINITY, ..Default::default() };
+        assert!(astar_with_options(start, goal, &map, &HashSet::new(), &avoid, &options).is_none());
+    }
+
+    #[test]
+    fn terrain_costs_are_applied() {
+        let mut terrain: CrdtMap<Loc, f32, Lww> = CrdtMap::default();
+        for loc in open_grid(10, 5).into_keys() {
+            terrain.insert(loc, 1.0, 0);
+        }
+        for y in 0..4 {
+            terrain.insert(Loc { x: 5, y }, 5.0, 0);
+        }
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 9, y: 0 };
+        let options = AstarOptions::default();
+        let path = astar_with_cost(start, goal, &terrain, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert!(path.steps.contains(&Loc { x: 5, y: 4 }));
+
+        for (cost, _) in terrain.entries.values_mut() {
+            *cost = -1.0;
+        }
+        let path = astar_with_cost(start, goal, &terrain, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert_eq!(path.steps.back(), Some(&goal));
+        assert!(path.cost > 0.0);
+    }
+
+    #[test]
+    fn cheap_roads_are_taken_with_a_lower_min_tile_cost() {
+        let mut terrain: CrdtMap<Loc, f32, Lww> = CrdtMap::default();
+        for loc in open_grid(20, 5).into_keys() {
+            terrain.insert(loc, if loc.y == 4 { 0.5 } else { 1.0 }, 0);
+        }
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 19, y: 0 };
+        let options = AstarOptions { min_tile_cost: 0.5, ..Default::default() };
+        let path = astar_with_cost(start, goal, &terrain, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        let (forward, _) = astar_partial(start, goal, &terrain, &HashSet::new(), &HashSet::new(), &options);
+        assert!(path.steps.iter().any(|loc| loc.y == 4));
+        assert!(path.cost < 19.0);
+        assert!((path.cost - forward.cost).abs() < 1e-4, "{} vs {}", path.cost, forward.cost);
+        let mut flat = terrain.clone();
+        for (cost, _) in flat.entries.values_mut() {
+            *cost = 0.5;
+        }
+        let path = astar_with_cost(start, goal, &flat, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert_eq!(path.cost, 9.5);
+    }
+
+    #[test]
+    fn partial_path_stops_at_wall() {
+        let map = open_grid(10, 10);
+        let mut blocked = HashSet::new();
+        for x in 5..10 {
+            blocked.insert(Loc { x, y: 5 });
+        }
+        for y in 5..10 {
+            blocked.insert(Loc { x: 5, y });
+        }
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 8, y: 8 };
+        let options = AstarOptions::default();
+        assert!(astar(start, goal, &map, &blocked, &HashSet::new()).is_none());
+
+        let (path, reached_goal) = astar_partial(start, goal, &map, &blocked, &HashSet::new(), &options);
+        assert!(!reached_goal);
+        let end = *path.steps.back().unwrap();
+        assert!(path.steps.iter().all(|l| !blocked.contains(l)));
+        assert!(blocked.iter().any(|b| (b.x - end.x).abs() <= 1 && (b.y - end.y).abs() <= 1));
+    }
+
+    #[test]
+    fn partial_path_reaches_reachable_goal() {
+        let map = open_grid(10, 10);
+        let blocked: HashSet<Loc> = (0..9).map(|y| Loc { x: 5, y }).collect();
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 9, y: 0 };
+        let options = AstarOptions::default();
+        let (path, reached_goal) = astar_partial(start, goal, &map, &blocked, &HashSet::new(), &options);
+        assert!(reached_goal);
+        let expected = astar_with_cost(start, goal, &map, &blocked, &HashSet::new(), &options).unwrap();
+        assert_eq!(path.steps.back(), Some(&goal));
+        assert!((path.cost - expected.cost).abs() < 1e-4);
+    }
+
+    #[test]
+    fn multi_goal_picks_nearest_by_path() {
+        let map = open_grid(10, 10);
+        // A wall separates the start from the goal that is closer in a straight line.
+        let blocked: HashSet<Loc> = (0..9).map(|y| Loc { x: 2, y }).collect();
+        let near = Loc { x: 3, y: 0 };
+        let far = Loc { x: 0, y: 6 };
+        let goals: HashSet<Loc> = [near, far].into_iter().collect();
+        let start = Loc { x: 0, y: 0 };
+        let options = AstarOptions::default();
+        let (goal, path) = astar_multi(start, &goals, &map, &blocked, &HashSet::new(), &options).unwrap();
+        assert_eq!(goal, far);
+        assert_eq!(path, astar(start, far, &map, &blocked, &HashSet::new()).unwrap());
+
+        assert!(astar_multi(start, &HashSet::new(), &map, &blocked, &HashSet::new(), &options).is_none());
+    }
+
+    #[test]
+    fn distance_field_follows_walls() {
+        let map = open_grid(10, 10);
+        // A U-shaped wall opening to the south, with the origin inside it.
+        let mut blocked = HashSet::new();
+        for x in 2..8 {
+            blocked.insert(Loc { x, y: 2 });
+        }
+        for y in 2..8 {
+            blocked.insert(Loc { x: 2, y });
+            blocked.insert(Loc { x: 7, y });
+        }
+        let origin = Loc { x: 4, y: 3 };
+        let field = distance_field(origin, &map, &blocked, f32::INFINITY);
+        assert_eq!(field.get(&origin), Some(0.0));
+        assert_eq!(field.get(&Loc { x: 5, y: 3 }), Some(1.0));
+        let outside = Loc { x: 4, y: 1 };
+        let cost = field.get(&outside).unwrap();
+        assert!(cost > distance(origin, outside) + 8.0);
+        assert!(field.get(&Loc { x: 2, y: 2 }).is_none());
+
+        let field = distance_field(origin, &map, &blocked, 3.0);
+        assert!(field.get(&outside).is_none());
+        assert!(field.costs.values().all(|c| *c <= 3.0));
+
+        let candidates: HashSet<Loc> = [outside, Loc { x: 4, y: 7 }].into_iter().collect();
+        let field = distance_field(origin, &map, &blocked, f32::INFINITY);
+        assert_eq!(field.cheapest_in(&candidates).map(|(l, _)| l), Some(Loc { x: 4, y: 7 }));
+    }
+
+    #[test]
+    fn integer_distances() {
+        let a = Loc { x: -3, y: 4 };
+        let b = Loc { x: 2, y: -1 };
+        assert_eq!(manhattan_distance(a, b), 10);
+        assert_eq!(chebyshev_distance(a, b), 5);
+        let far = Loc { x: i32::MIN, y: i32::MIN };
+        let other = Loc { x: i32::MAX, y: i32::MAX };
+        assert_eq!(manhattan_distance(far, other), i32::MAX);
+        assert_eq!(chebyshev_distance(far, other), i32::MAX);
+    }
+
+    #[test]
+    fn heuristics_find_optimal_paths() {
+        let map = open_grid(12, 12);
+        let blocked: HashSet<Loc> = (0..10).map(|y| Loc { x: 5, y }).collect();
+        let start = Loc { x: 0, y: 0 };
+        let goal = Loc { x: 11, y: 0 };
+        let reference = astar_with_cost(start, goal, &map, &blocked, &HashSet::new(), &AstarOptions::default()).unwrap();
+        let options = AstarOptions { heuristic: Some(Heuristic::Chebyshev), ..Default::default() };
+        let path = astar_with_cost(start, goal, &map, &blocked, &HashSet::new(), &options).unwrap();
+        assert!((path.cost - reference.cost).abs() < 1e-4);
+    }
+
+    #[test]
+    fn incremental_search_matches_astar() {
+        let mut map = open_grid(30, 30);
+        for y in 0..25 {
+            map.insert(Loc { x: 10, y }, false);
+            map.insert(Loc { x: 20, y: 29 - y }, false);
+        }
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 29, y: 0 });
+        let (blocked, avoid) = (HashSet::new(), HashSet::new());
+        let expected = astar(start, goal, &map, &blocked, &avoid).unwrap();
+        let mut search = IncrementalAstar::new(start, goal, AstarOptions::default());
+        let mut steps = 0;
+        let path = loop {
+            match search.step(&map, &blocked, &avoid, 5) {
+                SearchStatus::InProgress => steps += 1,
+                SearchStatus::Found(path) => break path,
+                SearchStatus::Unreachable => panic!("goal should be reachable"),
+            }
+        };
+        assert!(steps > 10);
+        assert_eq!(path, expected);
+        assert_eq!(search.step(&map, &blocked, &avoid, 5), SearchStatus::Found(expected));
+    }
+
+    #[test]
+    fn incremental_search_reports_unreachable() {
+        let mut map = open_grid(10, 10);
+        for y in 0..10 {
+            map.insert(Loc { x: 5, y }, false);
+        }
+        let mut search = IncrementalAstar::new(Loc { x: 0, y: 0 }, Loc { x: 9, y: 9 }, AstarOptions::default());
+        let status = loop {
+            match search.step(&map, &HashSet::new(), &HashSet::new(), 3) {
+                SearchStatus::InProgress => continue,
+                status => break status,
+            }
+        };
+        assert_eq!(status, SearchStatus::Unreachable);
+    }
+
+    #[test]
+    fn repaired_paths_match_fresh_astar() {
+        let mut map = open_grid(40, 40);
+        for y in 5..40 {
+            map.insert(Loc { x: 20, y }, false);
+        }
+        let (start, goal) = (Loc { x: 2, y: 30 }, Loc { x: 37, y: 30 });
+        let mut blocked = HashSet::new();
+        let avoid = HashSet::new();
+        let options = AstarOptions::default();
+        let mut planner = PathPlanner::new();
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(path, astar(start, goal, &map, &blocked, &avoid));
+
+        // A creature steps onto the path next to the agent.
+        let on_path = path.unwrap()[1];
+        blocked.insert(on_path);
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(path, astar(start, goal, &map, &blocked, &avoid));
+        assert!(!path.unwrap().contains(&on_path));
+
+        // Blockers further along and newly explored walls.
+        blocked.insert(Loc { x: 20, y: 3 });
+        map.insert(Loc { x: 20, y: 4 }, false);
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(path, astar(start, goal, &map, &blocked, &avoid));
+
+        blocked.clear();
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(path, astar(start, goal, &map, &blocked, &avoid));
+
+        planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(planner.last_search_len(), 0);
+    }
+
+    #[test]
+    fn repairs_near_the_agent_are_local() {
+        let map = open_grid(60, 60);
+        let (start, goal) = (Loc { x: 0, y: 30 }, Loc { x: 59, y: 30 });
+        let mut blocked = HashSet::new();
+        let avoid = HashSet::new();
+        let options = AstarOptions::default();
+        let mut planner = PathPlanner::new();
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options).unwrap();
+        blocked.insert(path[0]);
+        let repaired = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        let mut fresh = PathPlanner::new();
+        assert_eq!(repaired, fresh.plan(start, goal, &map, &blocked, &avoid, &options));
+        assert_eq!(repaired, astar(start, goal, &map, &blocked, &avoid));
+        assert!(
+            planner.last_search_len() < fresh.last_search_len(),
+            "{} vs {}",
+            planner.last_search_len(),
+            fresh.last_search_len()
+        );
+    }
+
+    #[test]
+    fn walking_along_the_path_repairs_instead_of_restarting() {
+        let mut map = open_grid(40, 40);
+        for y in 5..40 {
+            map.insert(Loc { x: 20, y }, false);
+        }
+        let goal = Loc { x: 37, y: 30 };
+        let mut blocked = HashSet::new();
+        let avoid = HashSet::new();
+        let options = AstarOptions::default();
+        let cost = |start: Loc, path: &VecDeque<Loc>| {
+            let mut from = start;
+            path.iter()
+                .map(|&to| {
+                    let step = step_cost(&map, &avoid, &options, (to.x - from.x, to.y - from.y), to).unwrap();
+                    from = to;
+                    step
+                })
+                .sum::<f32>()
+        };
+        let mut planner = PathPlanner::new();
+        let mut start = Loc { x: 2, y: 30 };
+        let mut path = planner.plan(start, goal, &map, &blocked, &avoid, &options).unwrap();
+        for _ in 0..3 {
+            start = path.pop_front().unwrap();
+            assert_eq!(planner.plan(start, goal, &map, &blocked, &avoid, &options), Some(path.clone()));
+            assert_eq!(planner.last_search_len(), 0);
+        }
+
+        // A creature steps onto the path just ahead, and the agent walks on
+        // around it.
+        blocked.insert(path[1]);
+        for _ in 0..3 {
+            let repaired = planner.plan(start, goal, &map, &blocked, &avoid, &options).unwrap();
+            let mut fresh = PathPlanner::new();
+            fresh.plan(start, goal, &map, &blocked, &avoid, &options);
+            assert!(planner.last_search_len() < fresh.last_search_len(), "{} vs {}", planner.last_search_len(), fresh.last_search_len());
+            let expected = astar_with_cost(start, goal, &map, &blocked, &avoid, &options).unwrap();
+            assert!((cost(start, &repaired) - expected.cost).abs() < 1e-3, "{} vs {}", cost(start, &repaired), expected.cost);
+            assert!(repaired.iter().all(|loc| !blocked.contains(loc)));
+            start = repaired[0];
+        }
+    }
+
+    #[test]
+    fn long_searches_drop_their_journal() {
+        let map = open_grid(30, 30);
+        let (start, goal) = (Loc { x: 0, y: 15 }, Loc { x: 29, y: 15 });
+        let mut blocked = HashSet::new();
+        let avoid = HashSet::new();
+        let options = AstarOptions::default();
+        let mut planner = PathPlanner::new().with_max_journal_len(10);
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options).unwrap();
+        let full = planner.last_search_len();
+        assert!(full > 10);
+        assert!(planner.search.as_ref().unwrap().journal.as_ref().unwrap().entries.is_empty());
+
+        planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(planner.last_search_len(), 0);
+
+        blocked.insert(path[0]);
+        let path = planner.plan(start, goal, &map, &blocked, &avoid, &options);
+        assert_eq!(path, astar(start, goal, &map, &blocked, &avoid));
+        assert!(planner.last_search_len() > 10);
+    }
+
+    #[test]
+    fn unknown_tiles_policy() {
+        // A known route over the top of a wall, and a one tile unknown
+        // gap straight along the bottom.
+        let mut map = open_grid(9, 3);
+        for x in 1..8 {
+            map.insert(Loc { x, y: 1 }, false);
+        }
+        map.remove(&Loc { x: 4, y: 2 });
+        let (start, goal) = (Loc { x: 0, y: 2 }, Loc { x: 8, y: 2 });
+        let (blocked, avoid) = (HashSet::new(), HashSet::new());
+        let path = |unknown_tiles| {
+            let options = AstarOptions { unknown_tiles, ..Default::default() };
+            astar_with_options(start, goal, &map, &blocked, &avoid, &options).unwrap()
+        };
+
+        let known_only = path(UnknownTiles::Blocked);
+        assert!(known_only.iter().all(|loc| map.contains_key(loc)));
+
+        let through_gap = path(UnknownTiles::Passable);
+        assert!(through_gap.contains(&Loc { x: 4, y: 2 }));
+        assert!(through_gap.len() < known_only.len());
+
+        // A small penalty still takes the short cut, a large one goes around.
+        assert!(path(UnknownTiles::Penalized(0.5)).contains(&Loc { x: 4, y: 2 }));
+        assert_eq!(path(UnknownTiles::Penalized(50.0)), known_only);
+    }
+
+    #[test]
+    fn ties_are_broken_deterministically() {
+        // Every monotone route across an open 4x4 grid costs the same, so
+        // the path is decided entirely by the tie-break.
+        let map = open_grid(4, 4);
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 3, y: 3 });
+        let options = AstarOptions { neighborhood: Neighborhood::Four, ..Default::default() };
+        let hash_sets = (HashSet::from([Loc { x: 9, y: 9 }]), HashSet::from([Loc { x: 8, y: 8 }]));
+        let index_sets = (IndexSet::from_iter([Loc { x: 9, y: 9 }]), IndexSet::from_iter([Loc { x: 8, y: 8 }]));
+        let expected = astar_with_options(start, goal, &map, &hash_sets.0, &hash_sets.1, &options).unwrap();
+        assert_eq!(
+            expected,
+            [(0, 1), (0, 2), (0, 3), (1, 3), (2, 3), (3, 3)]
+                .into_iter()
+                .map(|(x, y)| Loc { x, y })
+                .collect::<VecDeque<_>>()
+        );
+        for _ in 0..10 {
+            assert_eq!(astar_with_options(start, goal, &map, &hash_sets.0, &hash_sets.1, &options).unwrap(), expected);
+            assert_eq!(astar_with_options(start, goal, &map, &index_sets.0, &index_sets.1, &options).unwrap(), expected);
+        }
+        let (_, steps) = astar_multi(start, &HashSet::from([goal]), &map, &hash_sets.0, &hash_sets.1, &options).unwrap();
+        let (_, index_steps) = astar_multi(start, &IndexSet::from_iter([goal]), &map, &index_sets.0, &index_sets.1, &options).unwrap();
+        assert_eq!(steps, index_steps);
+    }
+
+    #[test]
+    fn adjacent_arrival_finds_the_open_side() {
+        // The target is walled in apart from its south-east neighbour, which
+        // is only reachable the long way round.
+        let mut map = open_grid(9, 9);
+        let target = Loc { x: 4, y: 4 };
+        map.insert(target, false);
+        for n in target.neighbors8() {
+            map.insert(n, false);
+        }
+        let open_side = Loc { x: 5, y: 5 };
+        map.insert(open_side, true);
+        let start = Loc { x: 0, y: 0 };
+        let options = AstarOptions { arrival: ArrivalCondition::WithinChebyshev(1), ..Default::default() };
+        let path = astar_with_options(start, target, &map, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert_eq!(path.back(), Some(&open_side));
+        assert!(!path.contains(&target));
+        assert!(path.iter().all(|loc| map[loc]));
+
+        // Already adjacent means there is nowhere to go.
+        let path = astar_with_options(open_side, target, &map, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert!(path.is_empty());
+
+        map.insert(open_side, false);
+        assert!(astar_with_options(start, target, &map, &HashSet::new(), &HashSet::new(), &options).is_none());
+    }
+
+    #[test]
+    fn graded_penalties_prefer_the_weaker_threat() {
+        use crate::behaviors::creature_penalties;
+
+        // Two ways round a wall, each passing one creature. The stronger
+        // threat should be given the wider berth.
+        let mut map = open_grid(9, 5);
+        for x in 1..8 {
+            map.insert(Loc { x, y: 2 }, false);
+        }
+        let (start, goal) = (Loc { x: 0, y: 2 }, Loc { x: 8, y: 2 });
+        let dragon = (Loc { x: 4, y: 0 }, 100.0);
+        let rat = (Loc { x: 4, y: 4 }, 1.0);
+        let penalties = creature_penalties([dragon, rat], 2, None, |threat| *threat);
+        assert!(penalties[&Loc { x: 4, y: 0 }] > penalties[&Loc { x: 4, y: 1 }]);
+        assert_eq!(penalties.get(&Loc { x: 4, y: 3 }), Some(&(1.0 * 2.0 / 3.0)));
+        let weighted = WithPenalties(&map, &penalties);
+        let path = astar(start, goal, &weighted, &HashSet::new(), &HashSet::new()).unwrap();
+        assert!(path.iter().all(|loc| loc.y >= 2));
+    }
+
+    #[test]
+    fn stats_and_iteration_limit() {
+        let map = open_grid(30, 30);
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 29, y: 17 });
+        let (blocked, avoid) = (HashSet::new(), HashSet::new());
+        let (path, stats) = astar_with_stats(start, goal, &map, &blocked, &avoid, &AstarOptions::default());
+        assert!(path.is_some());
+        assert!(stats.expanded >= 29);
+        assert!(stats.queued >= stats.expanded);
+        assert!(stats.max_open > 0 && stats.max_open <= stats.queued);
+        assert!(!stats.hit_limit);
+
+        for arrival in [ArrivalCondition::Exact, ArrivalCondition::WithinChebyshev(1)] {
+            let options = AstarOptions { iteration_limit: Some(5), arrival, ..Default::default() };
+            let (path, stats) = astar_with_stats(start, goal, &map, &blocked, &avoid, &options);
+            assert_eq!(path, None);
+            assert!(stats.hit_limit);
+            assert_eq!(stats.expanded, 5);
+        }
+
+        let mut totals = PathfindingStats::default();
+        totals.add(&stats);
+        totals.add(&AstarStats { hit_limit: true, ..stats });
+        assert_eq!(totals.searches, 2);
+        assert_eq!(totals.expanded, 2 * stats.expanded);
+        assert_eq!(totals.limit_hits, 1);
+    }
+
+    #[test]
+    fn path_endpoints() {
+        let map = open_grid(5, 1);
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 3, y: 0 });
+        let options = AstarOptions::default();
+        let path = astar_with_cost(start, goal, &map, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        let xs = |locs: &VecDeque<Loc>| locs.iter().map(|l| l.x).collect::<Vec<_>>();
+        assert_eq!(xs(path.steps()), [1, 2, 3]);
+        assert_eq!(xs(&path.with_start()), [0, 1, 2, 3]);
+        assert_eq!(xs(&path.without_goal()), [1, 2]);
+
+        let (partial, _) = astar_partial(start, goal, &map, &HashSet::new(), &HashSet::new(), &options);
+        assert_eq!(partial.start, start);
+        let here = astar_with_cost(start, start, &map, &HashSet::new(), &HashSet::new(), &options).unwrap();
+        assert!(here.steps().is_empty());
+        assert_eq!(xs(&here.with_start()), [0]);
+    }
+
+    #[test]
+    fn large_open_grid_is_fast() {
+        let map = open_grid(200, 200);
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 199, y: 199 });
+        let (path, stats) = astar_with_stats(start, goal, &map, &HashSet::new(), &HashSet::new(), &AstarOptions::default());
+        let path = path.unwrap();
+        assert_eq!(path.steps.len(), 199);
+        // Straight down the diagonal, rather than flooding the grid.
+        assert!(stats.expanded <= 2 * 199, "{stats:?}");
+    }
+}
+
+/// Every tile on the line from `a` to `b`, both endpoints included.
+pub fn bresenham_line(a: Loc, b: Loc) -> impl Iterator<Item = Loc> {
+    let dx = (b.x - a.x).abs();
+    let dy = -(b.y - a.y).abs();
+    let sx = if a.x < b.x { 1 } else { -1 };
+    let sy = if a.y < b.y { 1 } else { -1 };
+    let mut err = dx + dy;
+    let mut current = Some(a);
+    std::iter::from_fn(move || {
+        let loc = current?;
+        if loc == b {
+            current = None;
+        } else {
+            let mut next = loc;
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                next.x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                next.y += sy;
+            }
+            current = Some(next);
+        }
+        Some(loc)
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CornerClipping {
+    /// Only tiles on the line itself can block it.
+    #[default]
+    Permissive,
+    /// A diagonal step is also blocked if either tile beside it is blocking.
+    Strict,
+}
+
+pub fn line_of_sight(a: Loc, b: Loc, blocking: &dyn LocSet) -> bool {
+    line_of_sight_with(a, b, blocking, CornerClipping::default())
+}
+
+/// Whether `b` can be seen from `a`. The endpoints themselves never block.
+pub fn line_of_sight_with(a: Loc, b: Loc, blocking: &dyn LocSet, clipping: CornerClipping) -> bool {
+    let mut previous: Option<Loc> = None;
+    for loc in bresenham_line(a, b) {
+        if loc != a && loc != b && blocking.contains_loc(&loc) {
+            return false;
+        }
+        if clipping == CornerClipping::Strict
+            && let Some(p) = previous
+            && p.x != loc.x
+            && p.y != loc.y
+            && (blocking.contains_loc(&Loc { x: loc.x, y: p.y }) || blocking.contains_loc(&Loc { x: p.x, y: loc.y }))
+        {
+            return false;
+        }
+        previous = Some(loc);
+    }
+    true
+}
+
+#[cfg(test)]
+mod line_of_sight_tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn line_includes_endpoints() {
+        let line: Vec<Loc> = bresenham_line(Loc { x: 0, y: 0 }, Loc { x: 4, y: 2 }).collect();
+        assert_eq!(line.first(), Some(&Loc { x: 0, y: 0 }));
+        assert_eq!(line.last(), Some(&Loc { x: 4, y: 2 }));
+        assert_eq!(line.len(), 5);
+        let single: Vec<Loc> = bresenham_line(Loc { x: 3, y: 3 }, Loc { x: 3, y: 3 }).collect();
+        assert_eq!(single, vec![Loc { x: 3, y: 3 }]);
+        let reversed: Vec<Loc> = bresenham_line(Loc { x: 0, y: 5 }, Loc { x: 0, y: 0 }).collect();
+        assert_eq!(reversed.len(), 6);
+    }
+
+    #[test]
+    fn walls_block_sight() {
+        let wall: HashSet<Loc> = (-3..4).map(|y| Loc { x: 2, y }).collect();
+        assert!(!line_of_sight(Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 }, &wall));
+        assert!(line_of_sight(Loc { x: 0, y: 0 }, Loc { x: 0, y: 5 }, &wall));
+        // Endpoints don't count as blocking.
+        assert!(line_of_sight(Loc { x: 0, y: 0 }, Loc { x: 2, y: 0 }, &wall));
+        assert!(line_of_sight(Loc { x: 2, y: 0 }, Loc { x: 3, y: 0 }, &wall));
+    }
+
+    #[test]
+    fn corner_clipping() {
+        let corners: HashSet<Loc> = [Loc { x: 1, y: 0 }].into_iter().collect();
+        let a = Loc { x: 0, y: 0 };
+        let b = Loc { x: 1, y: 1 };
+        assert!(line_of_sight_with(a, b, &corners, CornerClipping::Permissive));
+        assert!(!line_of_sight_with(a, b, &corners, CornerClipping::Strict));
+    }
+}
+
+/// Drops waypoints wherever the walk between the surrounding ones is a
+/// clear straight line, keeping only the corners. Lines are checked with
+/// strict corner clipping, so `blocking` should hold every tile that can't
+/// be walked, including impassable map tiles. Consecutive steps of the
+/// input are trusted as they are.
+pub fn smooth_path(path: &VecDeque<Loc>, blocking: &dyn LocSet) -> VecDeque<Loc> {
+    let mut corners = VecDeque::new();
+    let Some(&first) = path.front() else {
+        return corners;
+    };
+    corners.push_back(first);
+    let mut anchor = 0;
+    for i in 2..path.len() {
+        if !line_of_sight_with(path[anchor], path[i], blocking, CornerClipping::Strict) {
+            anchor = i - 1;
+            corners.push_back(path[anchor]);
+        }
+    }
+    if path.len() > 1 {
+        corners.push_back(path[path.len() - 1]);
+    }
+    corners
+}
+
+/// Expands a list of corners back into one tile per step.
+pub fn resample_path(corners: &VecDeque<Loc>) -> VecDeque<Loc> {
+    let mut steps: VecDeque<Loc> = corners.front().copied().into_iter().collect();
+    for (a, b) in corners.iter().zip(corners.iter().skip(1)) {
+        steps.extend(bresenham_line(*a, *b).skip(1));
+    }
+    steps
+}
+
+#[cfg(test)]
+mod smooth_path_tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    fn parse(rows: &[&str]) -> HashMap<Loc, bool> {
+        let mut map = HashMap::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                map.insert(Loc { x: x as i32, y: y as i32 }, c != '#');
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn straight_walks_collapse_to_endpoints() {
+        let path: VecDeque<Loc> = (0..6).map(|x| Loc { x, y: 0 }).collect();
+        let corners = smooth_path(&path, &EmptyLocSet);
+        assert_eq!(corners, VecDeque::from([Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 }]));
+        assert_eq!(resample_path(&corners), path);
+    }
+
+    #[test]
+    fn smoothed_paths_avoid_walls_and_corners() {
+        let map = parse(&[
+            "..........",
+            "....#.....",
+            "....#.....",
+            "....#.....",
+            "..........",
+        ]);
+        let walls = FnLocSet(|l: &Loc| map.get(l) != Some(&true));
+        let start = Loc { x: 0, y: 2 };
+        let goal = Loc { x: 9, y: 2 };
+        let mut path = astar(start, goal, &map, &HashSet::new(), &HashSet::new()).unwrap();
+        path.push_front(start);
+        let corners = smooth_path(&path, &walls);
+        assert!(corners.len() < path.len());
+        assert_eq!(corners.front(), Some(&start));
+        assert_eq!(corners.back(), Some(&goal));
+        let steps = resample_path(&corners);
+        assert_eq!(steps.front(), Some(&start));
+        assert_eq!(steps.back(), Some(&goal));
+        let original: HashSet<(Loc, Loc)> = path.iter().copied().zip(path.iter().copied().skip(1)).collect();
+        for (a, b) in steps.iter().zip(steps.iter().skip(1)) {
+            assert!(map[b], "{b:?} is a wall");
+            assert!(chebyshev_distance(*a, *b) == 1);
+            // astar itself may cut corners; smoothing must not add new cuts.
+            if a.x != b.x && a.y != b.y && !original.contains(&(*a, *b)) {
+                assert!(map[&Loc { x: a.x, y: b.y }] && map[&Loc { x: b.x, y: a.y }], "cut the corner at {a:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn short_paths_are_unchanged() {
+        assert!(smooth_path(&VecDeque::new(), &EmptyLocSet).is_empty());
+        let one = VecDeque::from([Loc { x: 1, y: 1 }]);
+        assert_eq!(smooth_path(&one, &EmptyLocSet), one);
+    }
+}
+
+/// Any-angle search: like `astar`, but each tile may be reached straight
+/// from an earlier waypoint it has line of sight to, so the result is a list
+/// of waypoints rather than adjacent steps. Blocked, avoided and impassable
+/// tiles block sight; a shortcut costs its straight-line length, ignoring
+/// the costs of the tiles it crosses.
+pub fn theta_star(
+    current_location: Loc,
+    goal: Loc,
+    explored_tiles: &dyn LocMap,
+    blocked: &dyn LocSet,
+    avoid: &dyn LocSet,
+    options: &AstarOptions,
+) -> Option<VecDeque<Loc>> {
+    let impassable = FnLocSet(|loc: &Loc| !options.unknown_tiles.allows(explored_tiles.get_loc(loc)));
+    let blocking = UnionAll(vec![blocked, avoid, &impassable]);
+    let heuristic = |loc| distance(loc, goal) * options.cost_scale();
+    let limit = options.iteration_limit.unwrap_or(usize::MAX);
+    let mut open_set = std::collections::BinaryHeap::new();
+    let mut g_scores: IndexMap<Loc, f32> = IndexMap::new();
+    let mut parents: IndexMap<Loc, Loc> = IndexMap::new();
+    let mut expanded = 0;
+    open_set.push(open_entry(0.0, heuristic(current_location), current_location));
+    g_scores.insert(current_location, 0.0);
+    while let Some(std::cmp::Reverse((f, _, loc, _))) = open_set.pop() {
+        let g = g_scores[&loc];
+        if f.0 > g + heuristic(loc) {
+            continue;
+        }
+        if loc == goal {
+            let mut waypoints = VecDeque::new();
+            let mut current = goal;
+            while let Some(&parent) = parents.get(&current) {
+                waypoints.push_front(current);
+                current = parent;
+            }
+            return Some(waypoints);
+        }
+        if expanded >= limit {
+            break;
+        }
+        expanded += 1;
+
+        for &offset in options.neighborhood.offsets() {
+            let neighboor = loc.offset(offset.0, offset.1);
+            if neighboor != goal
+                && (!options.unknown_tiles.allows(explored_tiles.get_loc(&neighboor)) || blocked.contains_loc(&neighboor))
+            {
+                continue;
+            }
+            let Some(cost) = step_cost(explored_tiles, avoid, options, offset, neighboor) else {
+                continue;
+            };
+            let (parent, score) = match parents.get(&loc) {
+                Some(&parent)
+                    if !avoid.contains_loc(&neighboor)
+                        && line_of_sight_with(parent, neighboor, &blocking, CornerClipping::Strict) =>
+                {
+                    (parent, g_scores[&parent] + distance(parent, neighboor))
+                }
+                _ => (loc, g + cost),
+            };
+            if score < g_scores.get(&neighboor).copied().unwrap_or(f32::MAX) {
+                parents.insert(neighboor, parent);
+                g_scores.insert(neighboor, score);
+                open_set.push(open_entry(score, heuristic(neighboor), neighboor));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod theta_star_tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    fn open_grid(width: i32, height: i32) -> HashMap<Loc, bool> {
+        Rect::new(Loc { x: 0, y: 0 }, Loc { x: width - 1, y: height - 1 })
+            .iter()
+            .map(|loc| (loc, true))
+            .collect()
+    }
+
+    #[test]
+    fn open_ground_is_one_segment() {
+        let map = open_grid(12, 8);
+        let goal = Loc { x: 11, y: 4 };
+        let path = theta_star(Loc { x: 0, y: 0 }, goal, &map, &HashSet::new(), &HashSet::new(), &AstarOptions::default());
+        assert_eq!(path, Some(VecDeque::from([goal])));
+    }
+
+    #[test]
+    fn goes_around_a_pillar_in_two_segments() {
+        let mut map = open_grid(11, 7);
+        map.insert(Loc { x: 5, y: 3 }, false);
+        let (start, goal) = (Loc { x: 0, y: 3 }, Loc { x: 10, y: 3 });
+        let path = theta_star(start, goal, &map, &HashSet::new(), &HashSet::new(), &AstarOptions::default()).unwrap();
+        assert_eq!(path.len(), 2, "{path:?}");
+        assert_eq!(path.back(), Some(&goal));
+        let walls = FnLocSet(|loc: &Loc| map.get(loc) != Some(&true));
+        let mut from = start;
+        for &waypoint in &path {
+            assert!(line_of_sight_with(from, waypoint, &walls, CornerClipping::Strict));
+            from = waypoint;
+        }
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let mut map = open_grid(5, 5);
+        for y in 0..5 {
+            map.insert(Loc { x: 2, y }, false);
+        }
+        let path = theta_star(Loc { x: 0, y: 0 }, Loc { x: 4, y: 4 }, &map, &HashSet::new(), &HashSet::new(), &AstarOptions::default());
+        assert_eq!(path, None);
+    }
+}
+
+struct Shadowcast<'a> {
+    origin: Loc,
+    radius: i32,
+    is_opaque: &'a dyn Fn(&Loc) -> bool,
+    visible: IndexSet<Loc>,
+}
+
+impl Shadowcast<'_> {
+    // Scans one octant row by row, `transform` mapping octant coordinates
+    // back onto the grid, and recurses past each run of opaque tiles.
+    fn cast(&mut self, row: i32, mut start: f32, end: f32, transform: [i32; 4]) {
+        if start < end {
+            return;
+        }
+        let [xx, xy, yx, yy] = transform;
+        let mut new_start = 0.0;
+        for j in row..=self.radius {
+            let dy = -j;
+            let mut blocked = false;
+            for dx in -j..=0 {
+                let loc = Loc {
+                    x: self.origin.x + dx * xx + dy * xy,
+                    y: self.origin.y + dx * yx + dy * yy,
+                };
+                let l_slope = (dx as f32 - 0.5) / (dy as f32 + 0.5);
+                let r_slope = (dx as f32 + 0.5) / (dy as f32 - 0.5);
+                if start < r_slope {
+                    continue;
+                } else if end > l_slope {
+                    break;
+                }
+                if dx * dx + dy * dy <= self.radius * self.radius {
+                    self.visible.insert(loc);
+                }
+                let opaque = (self.is_opaque)(&loc);
+                if blocked {
+                    if opaque {
+                        new_start = r_slope;
+                    } else {
+                        blocked = false;
+                        start = new_start;
+                    }
+                } else if opaque && j < self.radius {
+                    blocked = true;
+                    self.cast(j + 1, start, l_slope, transform);
+                    new_start = r_slope;
+                }
+            }
+            if blocked {
+                break;
+            }
+        }
+    }
+}
+
+fn shadowcast(origin: Loc, radius: u32, is_opaque: &dyn Fn(&Loc) -> bool) -> IndexSet<Loc> {
+    const OCTANTS: [[i32; 4]; 8] = [
+        [1, 0, 0, 1],
+        [0, 1, 1, 0],
+        [0, -1, 1, 0],
+        [-1, 0, 0, 1],
+        [-1, 0, 0, -1],
+        [0, -1, -1, 0],
+        [0, 1, -1, 0],
+        [1, 0, 0, -1],
+    ];
+    let mut cast = Shadowcast {
+        origin,
+        radius: radius as i32,
+        is_opaque,
+        visible: IndexSet::new(),
+    };
+    cast.visible.insert(origin);
+    for transform in OCTANTS {
+        cast.cast(1, 1.0, 0.0, transform);
+    }
+    cast.visible
+}
+
+/// Tiles visible from `origin` within `radius`, using recursive shadowcasting.
+/// Opaque tiles are themselves visible; the origin always is.
+pub fn field_of_view(origin: Loc, radius: u32, opaque: &dyn LocSet) -> IndexSet<Loc> {
+    shadowcast(origin, radius, &|loc| opaque.contains_loc(loc))
+}
+
+/// Like `field_of_view` with impassable tiles of `map` treated as opaque.
+/// Tiles missing from the map are opaque if `unknown_opaque` is set.
+pub fn field_of_view_on_map(origin: Loc, radius: u32, map: &dyn LocMap, unknown_opaque: bool) -> IndexSet<Loc> {
+    shadowcast(origin, radius, &|loc| match map.get_loc(loc) {
+        Some(passable) => !passable,
+        None => unknown_opaque,
+    })
+}
+
+#[cfg(test)]
+mod field_of_view_tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    // '#' is a wall, '@' the origin, anything else open floor.
+    fn parse(rows: &[&str]) -> (Loc, HashMap<Loc, bool>) {
+        let mut origin = None;
+        let mut map = HashMap::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let loc = Loc { x: x as i32, y: y as i32 };
+                map.insert(loc, c != '#');
+                if c == '@' {
+                    origin = Some(loc);
+                }
+            }
+        }
+        (origin.unwrap(), map)
+    }
+
+    fn walls(map: &HashMap<Loc, bool>) -> HashSet<Loc> {
+        map.iter().filter(|(_, p)| !**p).map(|(l, _)| *l).collect()
+    }
+
+    #[test]
+    fn open_room_is_a_disc() {
+        let (origin, map) = parse(&[
+            ".......",
+            ".......",
+            ".......",
+            "...@...",
+            ".......",
+            ".......",
+            ".......",
+        ]);
+        let visible = field_of_view(origin, 2, &walls(&map));
+        for loc in map.keys() {
+            let d2 = (loc.x - origin.x).pow(2) + (loc.y - origin.y).pow(2);
+            assert_eq!(visible.contains(loc), d2 <= 4, "{loc:?}");
+        }
+    }
+
+    #[test]
+    fn pillar_casts_shadow() {
+        let (origin, map) = parse(&[
+            "@......",
+            ".......",
+            "..#....",
+            ".......",
+            ".......",
+        ]);
+        let visible = field_of_view(origin, 10, &walls(&map));
+        assert!(visible.contains(&origin));
+        assert!(visible.contains(&Loc { x: 2, y: 2 }));
+        assert!(!visible.contains(&Loc { x: 3, y: 3 }));
+        assert!(!visible.contains(&Loc { x: 4, y: 4 }));
+        assert!(visible.contains(&Loc { x: 4, y: 0 }));
+        assert!(visible.contains(&Loc { x: 0, y: 4 }));
+    }
+
+    #[test]
+    fn corridor_hides_side_rooms() {
+        let (origin, map) = parse(&[
+            "###.###",
+            "###.###",
+            "###.###",
+            "...@...",
+            "###.###",
+            "###.###",
+        ]);
+        let visible = field_of_view(origin, 10, &walls(&map));
+        assert!(visible.contains(&Loc { x: 6, y: 3 }));
+        assert!(visible.contains(&Loc { x: 3, y: 0 }));
+        assert!(visible.contains(&Loc { x: 2, y: 2 }));
+        assert!(!visible.contains(&Loc { x: 0, y: 0 }));
+        assert!(!visible.contains(&Loc { x: 6, y: 5 }));
+    }
+
+    #[test]
+    fn unknown_tiles_follow_flag() {
+        let (origin, mut map) = parse(&["@...."]);
+        map.remove(&Loc { x: 2, y: 0 });
+        let visible = field_of_view_on_map(origin, 10, &map, true);
+        assert!(visible.contains(&Loc { x: 2, y: 0 }));
+        assert!(!visible.contains(&Loc { x: 4, y: 0 }));
+        let visible = field_of_view_on_map(origin, 10, &map, false);
+        assert!(visible.contains(&Loc { x: 4, y: 0 }));
+    }
+}
+
+/// Connected walkable regions of a map, found by flood fill. Rebuilding is
+/// a single pass over the map, cheap enough to do every turn.
+#[derive(Clone, Debug, Default)]
+pub struct Regions {
+    regions: IndexMap<Loc, u32>,
+    sizes: Vec<usize>,
+}
+
+impl Regions {
+    pub fn new(map: &dyn LocMap, blocked: &dyn LocSet) -> Self {
+        Self::with_neighborhood(map, blocked, Neighborhood::default())
+    }
+
+    pub fn with_neighborhood(map: &dyn LocMap, blocked: &dyn LocSet, neighborhood: Neighborhood) -> Self {
+        let walkable = |loc: &Loc| map.get_loc(loc).unwrap_or(false) && !blocked.contains_loc(loc);
+        let mut regions = Self::default();
+        for seed in map.iter() {
+            if !walkable(&seed) || regions.regions.contains_key(&seed) {
+                continue;
+            }
+            let id = regions.sizes.len() as u32;
+            let mut size = 0;
+            let mut stack = vec![seed];
+            regions.regions.insert(seed, id);
+            while let Some(loc) = stack.pop() {
+                size += 1;
+                for &(dx, dy) in neighborhood.offsets() {
+                    let n = loc.offset(dx, dy);
+                    if walkable(&n) && !regions.regions.contains_key(&n) {
+                        regions.regions.insert(n, id);
+                        stack.push(n);
+                    }
+                }
+            }
+            regions.sizes.push(size);
+        }
+        regions
+    }
+
+    pub fn region_of(&self, loc: Loc) -> Option<u32> {
+        self.regions.get(&loc).copied()
+    }
+
+    pub fn same_region(&self, a: Loc, b: Loc) -> bool {
+        self.region_of(a).is_some() && self.region_of(a) == self.region_of(b)
+    }
+
+    pub fn region_size(&self, region: u32) -> usize {
+        self.sizes.get(region as usize).copied().unwrap_or(0)
+    }
+
+    pub fn region_count(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// Whether `loc` is in `region` or can be stepped onto from it, which is
+    /// what makes an unexplored frontier tile reachable.
+    pub fn borders(&self, loc: Loc, region: u32) -> bool {
+        self.region_of(loc) == Some(region) || loc.neighbors8().any(|n| self.region_of(n) == Some(region))
+    }
+}
+
+#[cfg(test)]
+mod regions_tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(rows: &[&str]) -> HashMap<Loc, bool> {
+        let mut map = HashMap::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c != ' ' {
+                    map.insert(Loc { x: x as i32, y: y as i32 }, c != '#');
+                }
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn two_disconnected_rooms() {
+        let map = parse(&[
+            "#########",
+            "#...#...#",
+            "#...#...#",
+            "#########",
+        ]);
+        let regions = Regions::new(&map, &EmptyLocSet);
+        let (left, right) = (Loc { x: 1, y: 1 }, Loc { x: 7, y: 2 });
+        assert_eq!(regions.region_count(), 2);
+        assert!(regions.same_region(left, Loc { x: 3, y: 2 }));
+        assert!(!regions.same_region(left, right));
+        assert_eq!(regions.region_size(regions.region_of(left).unwrap()), 6);
+        assert_eq!(regions.region_of(Loc { x: 4, y: 1 }), None);
+        assert!(!regions.same_region(Loc { x: 0, y: 0 }, Loc { x: 0, y: 0 }));
+    }
+
+    #[test]
+    fn blockers_split_regions() {
+        let map = parse(&[
+            "#####",
+            "#...#",
+            "#####",
+        ]);
+        let door = IndexSet::from_iter([Loc { x: 2, y: 1 }]);
+        assert_eq!(Regions::new(&map, &EmptyLocSet).region_count(), 1);
+        let regions = Regions::new(&map, &door);
+        assert_eq!(regions.region_count(), 2);
+        assert!(!regions.same_region(Loc { x: 1, y: 1 }, Loc { x: 3, y: 1 }));
+    }
+
+    #[test]
+    fn frontier_reachability() {
+        // The unknown tile at (4, 1) is only next to the right room.
+        let map = parse(&[
+            "#### ",
+            "#..# ",
+            "####.",
+        ]);
+        let regions = Regions::new(&map, &EmptyLocSet);
+        let left = regions.region_of(Loc { x: 1, y: 1 }).unwrap();
+        let right = regions.region_of(Loc { x: 4, y: 2 }).unwrap();
+        assert!(regions.borders(Loc { x: 4, y: 1 }, right));
+        assert!(!regions.borders(Loc { x: 4, y: 1 }, left));
+    }
+}
+
+/// Recently computed paths keyed by start and goal, least recently used
+/// first. Paths are always checked against the blockers passed to `get`.
+/// Callers bump `revision` whenever the map changes; entries from older
+/// revisions are also checked for steps no longer walkable. The
+/// cache doesn't know which `AstarOptions` a path was planned with, so keep
+/// one cache per set of options.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PathCache {
+    pub capacity: usize,
+    pub revision: u64,
+    entries: IndexMap<(Loc, Loc, ArrivalCondition), (u64, VecDeque<Loc>)>,
+}
+
+impl Default for PathCache {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl PathCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            revision: 0,
+            entries: IndexMap::new(),
+        }
+    }
+
+    pub fn bump(&mut self) {
+        self.revision += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The cached path from `start` to `goal`, if there is one, none of its
+    /// steps is blocked and, when it predates the current revision, none is
+    /// no longer walkable. Invalid entries are dropped.
+    pub fn get(
+        &mut self,
+        start: Loc,
+        goal: Loc,
+        explored_tiles: &dyn LocMap,
+        blocked: &dyn LocSet,
+        options: &AstarOptions,
+    ) -> Option<VecDeque<Loc>> {
+        let key = (start, goal, options.arrival);
+        let (revision, path) = self.entries.shift_remove(&key)?;
+        if path.iter().any(|loc| blocked.contains_loc(loc)) {
+            return None;
+        }
+        if revision != self.revision {
+            let walkable = |loc: &Loc| *loc == goal || options.unknown_tiles.allows(explored_tiles.get_loc(loc));
+            if !path.iter().all(walkable) {
+                return None;
+            }
+        }
+        self.entries.insert(key, (self.revision, path.clone()));
+        Some(path)
+    }
+
+    pub fn insert(&mut self, start: Loc, goal: Loc, options: &AstarOptions, path: VecDeque<Loc>) {
+        let key = (start, goal, options.arrival);
+        self.entries.shift_remove(&key);
+        self.entries.insert(key, (self.revision, path));
+        while self.entries.len() > self.capacity {
+            let Some((&oldest, _)) = self.entries.first() else {
+                break;
+            };
+            self.entries.shift_remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod path_cache_tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn line(y: i32, xs: std::ops::Range<i32>) -> VecDeque<Loc> {
+        xs.map(|x| Loc { x, y }).collect()
+    }
+
+    #[test]
+    fn reuses_paths_until_blocked() {
+        let map: HashMap<Loc, bool> = line(0, 0..6).into_iter().map(|l| (l, true)).collect();
+        let options = AstarOptions::default();
+        let (start, goal) = (Loc { x: 0, y: 0 }, Loc { x: 5, y: 0 });
+        let mut cache = PathCache::new(4);
+        cache.insert(start, goal, &options, line(0, 1..6));
+        assert_eq!(cache.get(start, goal, &map, &EmptyLocSet, &options), Some(line(0, 1..6)));
+
+        // Blocked at the same revision it was stored at.
+        let blocker = IndexSet::from_iter([Loc { x: 3, y: 0 }]);
+        assert_eq!(cache.get(start, goal, &map, &blocker, &options), None);
+        assert!(cache.is_empty());
+
+        cache.insert(start, goal, &options, line(0, 1..6));
+        cache.bump();
+        assert_eq!(cache.get(start, goal, &map, &EmptyLocSet, &options), Some(line(0, 1..6)));
+        cache.bump();
+        let mut map = map;
+        map.insert(Loc { x: 4, y: 0 }, false);
+        assert_eq!(cache.get(start, goal, &map, &EmptyLocSet, &options), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let map: HashMap<Loc, bool> = HashMap::new();
+        let options = AstarOptions { unknown_tiles: UnknownTiles::Passable, ..Default::default() };
+        let loc = |x| Loc { x, y: 0 };
+        let mut cache = PathCache::new(2);
+        cache.insert(loc(0), loc(1), &options, line(0, 1..2));
+        cache.insert(loc(0), loc(2), &options, line(0, 1..3));
+        assert!(cache.get(loc(0), loc(1), &map, &EmptyLocSet, &options).is_some());
+        cache.insert(loc(0), loc(3), &options, line(0, 1..4));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(loc(0), loc(1), &map, &EmptyLocSet, &options).is_some());
+        assert!(cache.get(loc(0), loc(2), &map, &EmptyLocSet, &options).is_none());
+        assert!(cache.get(loc(0), loc(3), &map, &EmptyLocSet, &options).is_some());
+    }
+}
+
+/// A small PCG generator to keep in the `State`, so random choices carry on
+/// from turn to turn instead of starting over whenever the component is
+/// reloaded, and replay the same from the same seed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeterministicRng {
+    state: u64,
+}
+
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+const PCG_INCREMENT: u64 = 1442695040888963407;
+
+impl DeterministicRng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    /// Seeded from something stable about the replica, e.g. the actor's id
+    /// with the level's.
+    pub fn from_key(key: &[u8]) -> Self {
+        Self::new(crdt::digest_bytes(key))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(PCG_INCREMENT);
+        let shifted = (((old >> 18) ^ old) >> 27) as u32;
+        shifted.rotate_right((old >> 59) as u32)
+    }
+
+    /// Below `n`, which must be more than 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0, "nothing to pick from");
+        ((self.next_u32() as u64 * n as u64) >> 32) as usize
+    }
+
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        (!items.is_empty()).then(|| &items[self.below(items.len())])
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod deterministic_rng_tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = DeterministicRng::from_key(b"seed");
+        let mut b = a.clone();
+        let draws: Vec<u32> = (0..10).map(|_| a.next_u32()).collect();
+        assert_eq!(draws, (0..10).map(|_| b.next_u32()).collect::<Vec<_>>());
+        let mut rng = DeterministicRng::new(1);
+        let long: IndexSet<u32> = (0..1000).map(|_| rng.next_u32()).collect();
+        assert_eq!(long.len(), 1000, "draws shouldn't repeat this soon");
+
+        let mut items: Vec<u32> = (0..10).collect();
+        a.shuffle(&mut items);
+        let mut again: Vec<u32> = (0..10).collect();
+        b.shuffle(&mut again);
+        assert_eq!(items, again);
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+        assert_eq!(a.choose(&items), b.choose(&again));
+        assert_eq!(a.choose::<u32>(&[]), None);
+    }
+
+    #[test]
+    fn picks_cover_the_range() {
+        let mut rng = DeterministicRng::new(323);
+        let mut seen = [0; 8];
+        for _ in 0..800 {
+            seen[rng.below(8)] += 1;
+        }
+        assert!(seen.iter().all(|n| *n > 50), "{seen:?}");
+    }
 }
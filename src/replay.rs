@@ -0,0 +1,464 @@
+//! Recording the host calls of a turn, to replay it under `cargo test`.
+//! `recording` wraps a live turn, e.g. `Component::step`, and prints its
+//! `Trace` as a line of the log, which `Trace::from_log` reads back for a
+//! `ReplayHost` to answer the same calls with. Behind the `replay` feature.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use bindings::{Action, Creature, EquipmentState, GameState, Item, Loc, Tile};
+
+use crate::crdt::digest_bytes;
+use crate::host::{self, with_host, Host};
+
+/// Starts the log line a `Trace` is printed as, followed by its bincode in
+/// hex.
+pub const TRACE_PREFIX: &str = "client_utils trace: ";
+
+/// A host call, with what it returned.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HostCall {
+    Actor((Loc, Creature)),
+    VisibleTiles(Vec<(Loc, Tile)>),
+    VisibleCreatures(Vec<(Loc, Creature)>),
+    VisibleItems(Vec<(Loc, Item)>),
+    ItemAt(Loc, Option<Item>),
+    Actions(Vec<Action>),
+    Inventory(Vec<Item>),
+    GameState(GameState),
+    EquipmentState(EquipmentState),
+    /// The store's digest, and the store itself if it fit the recording's
+    /// budget for it.
+    LoadStore { digest: u64, bytes: Option<Vec<u8>> },
+    /// What's saved and broadcast comes from the turn, so only that it
+    /// happened is kept.
+    SaveStore,
+    Broadcast,
+}
+
+/// The host calls of a turn, in order.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Trace {
+    pub calls: Vec<HostCall>,
+    /// Roughly what `calls` take encoded, leaving out the store.
+    pub bytes: u64,
+    /// Whether calls were left out for going over the recording's budget,
+    /// so replaying it stops short.
+    pub truncated: bool,
+}
+
+impl Trace {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    pub fn to_log_line(&self) -> String {
+        let hex: String = self.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        format!("{TRACE_PREFIX}{hex}")
+    }
+
+    /// The last trace printed in `log`.
+    pub fn from_log(log: &str) -> Result<Self> {
+        let Some(hex) = log.lines().rev().find_map(|line| line.split_once(TRACE_PREFIX).map(|(_, hex)| hex.trim())) else {
+            bail!("no trace in the log");
+        };
+        if hex.len() % 2 != 0 {
+            bail!("trace has an odd number of hex digits");
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| anyhow!("bad trace hex: {e}")))
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Answers calls with `inner`, noting each in a `Trace` until it'd take
+/// more than `max_bytes`. The store loaded has a budget of its own,
+/// `max_store_bytes`, so a large one doesn't crowd out the rest of the
+/// turn; one over it is only noted by its digest.
+pub struct RecordingHost {
+    pub inner: Rc<dyn Host>,
+    pub max_bytes: u64,
+    pub max_store_bytes: u64,
+    trace: RefCell<Trace>,
+}
+
+impl RecordingHost {
+    /// Keeps stores up to `max_bytes` too.
+    pub fn new(inner: Rc<dyn Host>, max_bytes: u64) -> Self {
+        Self {
+            inner,
+            max_bytes,
+            max_store_bytes: max_bytes,
+            trace: RefCell::default(),
+        }
+    }
+
+    pub fn with_max_store_bytes(mut self, max_store_bytes: u64) -> Self {
+        self.max_store_bytes = max_store_bytes;
+        self
+    }
+
+    /// What's been recorded so far.
+    pub fn trace(&self) -> Trace {
+        self.trace.borrow().clone()
+    }
+
+    fn record<T: Clone>(&self, value: T, call: impl FnOnce(T) -> HostCall) -> T {
+        if !self.trace.borrow().truncated {
+            let call = call(value.clone());
+            self.push(size_of(&call), call);
+        }
+        value
+    }
+
+    /// Notes `call` if `size` of it fits in what's left of `max_bytes`.
+    fn push(&self, size: u64, call: HostCall) {
+        let mut trace = self.trace.borrow_mut();
+        if trace.truncated {
+            return;
+        }
+        if trace.bytes.saturating_add(size) > self.max_bytes {
+            trace.truncated = true;
+        } else {
+            trace.bytes += size;
+            trace.calls.push(call);
+        }
+    }
+}
+
+/// Anything bincode can't size fails once the trace is encoded.
+fn size_of(call: &HostCall) -> u64 {
+    bincode::serialized_size(call).unwrap_or_default()
+}
+
+impl Host for RecordingHost {
+    fn actor(&self) -> (Loc, Creature) {
+        self.record(self.inner.actor(), HostCall::Actor)
+    }
+
+    fn visible_tiles(&self) -> Vec<(Loc, Tile)> {
+        self.record(self.inner.visible_tiles(), HostCall::VisibleTiles)
+    }
+
+    fn visible_creatures(&self) -> Vec<(Loc, Creature)> {
+        self.record(self.inner.visible_creatures(), HostCall::VisibleCreatures)
+    }
+
+    fn visible_items(&self) -> Vec<(Loc, Item)> {
+        self.record(self.inner.visible_items(), HostCall::VisibleItems)
+    }
+
+    fn item_at(&self, loc: Loc) -> Option<Item> {
+        self.record(self.inner.item_at(loc), |item| HostCall::ItemAt(loc, item))
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        self.record(self.inner.actions(), HostCall::Actions)
+    }
+
+    fn inventory(&self) -> Vec<Item> {
+        self.record(self.inner.inventory(), HostCall::Inventory)
+    }
+
+    fn get_game_state(&self) -> GameState {
+        self.record(self.inner.get_game_state(), HostCall::GameState)
+    }
+
+    fn get_equipment_state(&self) -> EquipmentState {
+        self.record(self.inner.get_equipment_state(), HostCall::EquipmentState)
+    }
+
+    fn load_store(&self) -> Vec<u8> {
+        let store = self.inner.load_store();
+        let digest = digest_bytes(&store);
+        let size = size_of(&HostCall::LoadStore { digest, bytes: None });
+        let bytes = (store.len() as u64 <= self.max_store_bytes).then(|| store.clone());
+        self.push(size, HostCall::LoadStore { digest, bytes });
+        store
+    }
+
+    fn save_store(&self, store: &[u8]) {
+        self.inner.save_store(store);
+        self.record((), |_| HostCall::SaveStore)
+    }
+
+    fn broadcast(&self, broadcast: Option<&[u8]>) {
+        self.inner.broadcast(broadcast);
+        self.record((), |_| HostCall::Broadcast)
+    }
+}
+
+/// Runs `f` recording the host calls it makes, up to `max_bytes` of them
+/// and of the store, and prints the trace for `Trace::from_log`.
+pub fn recording<T>(max_bytes: u64, f: impl FnOnce() -> T) -> (T, Trace) {
+    let recorder = Rc::new(RecordingHost::new(host::current(), max_bytes));
+    let result = with_host(recorder.clone(), f);
+    let trace = recorder.trace();
+    println!("{}", trace.to_log_line());
+    (result, trace)
+}
+
+/// Answers calls from a `Trace`, panicking if they aren't the calls it
+/// recorded, in the same order. Keeps what's saved and broadcast, like
+/// `MockHost`.
+pub struct ReplayHost {
+    calls: RefCell<VecDeque<HostCall>>,
+    truncated: bool,
+    loaded: Option<Vec<u8>>,
+    pub store: RefCell<Vec<u8>>,
+    pub broadcasts: RefCell<Vec<Option<Vec<u8>>>>,
+}
+
+impl ReplayHost {
+    pub fn new(trace: Trace) -> Self {
+        Self {
+            calls: RefCell::new(trace.calls.into()),
+            truncated: trace.truncated,
+            loaded: None,
+            store: RefCell::default(),
+            broadcasts: RefCell::default(),
+        }
+    }
+
+    /// The store to load where the trace only has its digest, for one
+    /// that was over the recording's budget, e.g. saved from the game.
+    pub fn with_store(mut self, store: Vec<u8>) -> Self {
+        self.loaded = Some(store);
+        self
+    }
+
+    /// How many recorded calls haven't been made yet.
+    pub fn remaining(&self) -> usize {
+        self.calls.borrow().len()
+    }
+
+    fn next(&self, what: &str) -> HostCall {
+        self.calls.borrow_mut().pop_front().unwrap_or_else(|| {
+            let why = if self.truncated { "the trace was truncated" } else { "the turn made more calls" };
+            panic!("replay ran out of calls at {what}: {why}")
+        })
+    }
+}
+
+fn diverged(what: &str, recorded: HostCall) -> ! {
+    panic!("replay diverged: called {what} where {recorded:?} was recorded")
+}
+
+impl Host for ReplayHost {
+    fn actor(&self) -> (Loc, Creature) {
+        match self.next("actor") {
+            HostCall::Actor(actor) => actor,
+            call => diverged("actor", call),
+        }
+    }
+
+    fn visible_tiles(&self) -> Vec<(Loc, Tile)> {
+        match self.next("visible_tiles") {
+            HostCall::VisibleTiles(tiles) => tiles,
+            call => diverged("visible_tiles", call),
+        }
+    }
+
+    fn visible_creatures(&self) -> Vec<(Loc, Creature)> {
+        match self.next("visible_creatures") {
+            HostCall::VisibleCreatures(creatures) => creatures,
+            call => diverged("visible_creatures", call),
+        }
+    }
+
+    fn visible_items(&self) -> Vec<(Loc, Item)> {
+        match self.next("visible_items") {
+            HostCall::VisibleItems(items) => items,
+            call => diverged("visible_items", call),
+        }
+    }
+
+    fn item_at(&self, loc: Loc) -> Option<Item> {
+        match self.next("item_at") {
+            HostCall::ItemAt(l, item) if l == loc => item,
+            call => diverged(&format!("item_at({loc:?})"), call),
+        }
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        match self.next("actions") {
+            HostCall::Actions(actions) => actions,
+            call => diverged("actions", call),
+        }
+    }
+
+    fn inventory(&self) -> Vec<Item> {
+        match self.next("inventory") {
+            HostCall::Inventory(items) => items,
+            call => diverged("inventory", call),
+        }
+    }
+
+    fn get_game_state(&self) -> GameState {
+        match self.next("get_game_state") {
+            HostCall::GameState(game_state) => game_state,
+            call => diverged("get_game_state", call),
+        }
+    }
+
+    fn get_equipment_state(&self) -> EquipmentState {
+        match self.next("get_equipment_state") {
+            HostCall::EquipmentState(equipment_state) => equipment_state,
+            call => diverged("get_equipment_state", call),
+        }
+    }
+
+    fn load_store(&self) -> Vec<u8> {
+        match self.next("load_store") {
+            HostCall::LoadStore { bytes: Some(store), .. } => store,
+            HostCall::LoadStore { digest, bytes: None } => match &self.loaded {
+                Some(store) if digest_bytes(store) == digest => store.clone(),
+                Some(_) => panic!("replay's store isn't the one recorded, digest {digest}"),
+                None => panic!("the recorded store was over budget, so only its digest was kept: see `ReplayHost::with_store`"),
+            },
+            call => diverged("load_store", call),
+        }
+    }
+
+    fn save_store(&self, store: &[u8]) {
+        match self.next("save_store") {
+            HostCall::SaveStore => *self.store.borrow_mut() = store.to_vec(),
+            call => diverged("save_store", call),
+        }
+    }
+
+    fn broadcast(&self, broadcast: Option<&[u8]>) {
+        match self.next("broadcast") {
+            HostCall::Broadcast => self.broadcasts.borrow_mut().push(broadcast.map(<[u8]>::to_vec)),
+            call => diverged("broadcast", call),
+        }
+    }
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+    use crate::framework::{Component, DummyBroadcast, ExplorableMap, Map, State};
+    use crate::host::MockHost;
+    use crate::LocExt;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use bindings::{Command, Guest, MicroAction};
+
+    fn host() -> Rc<MockHost> {
+        let origin = Loc { x: 0, y: 0 };
+        let tiles = origin.ring(1).chain([origin]).map(|loc| (loc, Tile { passable: loc.x < 1 }));
+        Rc::new(
+            MockHost::default()
+                .with_actor(origin, Creature { faction: 1, broadcast: None })
+                .with_tiles(tiles)
+                .with_action(Action { micro_actions: vec![MicroAction::Walk] })
+                .with_game_state(GameState { turn: 4000, level_id: 1, level_is_stable: false }),
+        )
+    }
+
+    fn turn(map: &mut ExplorableMap) -> Option<Command> {
+        map.update();
+        map.explore()
+    }
+
+    #[test]
+    fn replayed_turns_give_the_same_command() {
+        let recorder = Rc::new(RecordingHost::new(host(), u64::MAX));
+        let recorded = with_host(recorder.clone(), || turn(&mut ExplorableMap::default()));
+        assert!(recorded.is_some());
+
+        let replay = Rc::new(ReplayHost::new(recorder.trace()));
+        let replayed = with_host(replay.clone(), || turn(&mut ExplorableMap::default()));
+        assert_eq!(replayed, recorded);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn replays_panic_where_the_turn_diverges() {
+        let recorder = Rc::new(RecordingHost::new(host(), u64::MAX));
+        with_host(recorder.clone(), || turn(&mut ExplorableMap::default()));
+        let replay = Rc::new(ReplayHost::new(recorder.trace()));
+        let diverged = catch_unwind(AssertUnwindSafe(|| with_host(replay, crate::host::inventory)));
+        assert!(diverged.is_err());
+    }
+
+    #[test]
+    fn traces_stop_at_the_budget() {
+        let recorder = Rc::new(RecordingHost::new(host(), 64));
+        with_host(recorder.clone(), || turn(&mut ExplorableMap::default()));
+        let trace = recorder.trace();
+        assert!(trace.truncated && trace.bytes <= 64);
+        let replay = Rc::new(ReplayHost::new(trace));
+        let replayed = catch_unwind(AssertUnwindSafe(|| with_host(replay, || turn(&mut ExplorableMap::default()))));
+        assert!(replayed.is_err());
+    }
+
+    #[test]
+    fn stores_over_their_budget_keep_only_a_digest() {
+        let store = vec![7; 1000];
+        let mock = Rc::new(MockHost::default().with_store(store.clone()));
+        let turn = || (crate::host::load_store().len(), crate::host::inventory().len());
+        let recorder = Rc::new(RecordingHost::new(mock.clone(), 256));
+        with_host(recorder.clone(), turn);
+        let trace = recorder.trace();
+        assert!(!trace.truncated && trace.bytes <= 256);
+        assert!(matches!(trace.calls[..], [HostCall::LoadStore { bytes: None, .. }, HostCall::Inventory(_)]));
+
+        let replayed = catch_unwind(AssertUnwindSafe(|| with_host(Rc::new(ReplayHost::new(trace.clone())), turn)));
+        assert!(replayed.is_err());
+        let wrong = catch_unwind(AssertUnwindSafe(|| with_host(Rc::new(ReplayHost::new(trace.clone()).with_store(vec![7])), turn)));
+        assert!(wrong.is_err());
+        assert_eq!(with_host(Rc::new(ReplayHost::new(trace).with_store(store)), turn), (1000, 0));
+
+        let recorder = Rc::new(RecordingHost::new(mock, 256).with_max_store_bytes(1000));
+        with_host(recorder.clone(), turn);
+        assert!(matches!(&recorder.trace().calls[0], HostCall::LoadStore { bytes: Some(bytes), .. } if bytes.len() == 1000));
+    }
+
+    #[test]
+    fn log_lines_round_trips() {
+        let recorder = Rc::new(RecordingHost::new(host(), u64::MAX));
+        with_host(recorder.clone(), || turn(&mut ExplorableMap::default()));
+        let log = format!("no path\n{}\nanother line\n", recorder.trace().to_log_line());
+        assert_eq!(Trace::from_log(&log).unwrap().calls.len(), recorder.trace().calls.len());
+        assert!(Trace::from_log("no path").is_err());
+    }
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct Explorer {
+        map: ExplorableMap,
+    }
+
+    impl State<DummyBroadcast, ExplorableMap> for Explorer {
+        fn run(&mut self) -> Command {
+            self.map.explore().unwrap_or(Command::Nothing)
+        }
+
+        fn map(&mut self) -> Option<&mut ExplorableMap> {
+            Some(&mut self.map)
+        }
+    }
+
+    #[test]
+    fn replayed_steps_give_the_same_command() {
+        type Bot = Component<Explorer, DummyBroadcast, ExplorableMap>;
+        let mock = host();
+        let (recorded, trace) = with_host(mock.clone(), || recording(u64::MAX, Bot::step));
+
+        let replay = Rc::new(ReplayHost::new(trace));
+        let replayed = with_host(replay.clone(), Bot::step);
+        assert_eq!(replayed, recorded);
+        assert!(!mock.saved_store().is_empty() && !replay.store.borrow().is_empty());
+        assert_eq!(replay.remaining(), 0);
+    }
+}
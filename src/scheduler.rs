@@ -0,0 +1,440 @@
+//! Runs a bot as behaviors tried in priority order, in place of a chain of
+//! `if let Some(command) = ...` in `State::run`. Behaviors are kept in the
+//! state, so what they keep track of, like cooldowns, is stored with it.
+
+use serde::{Deserialize, Serialize};
+
+use bindings::Command;
+
+use crate::{
+    behaviors::{attack_nearest, convert, wander, wander_with_rng},
+    framework::{DummyBroadcast, ExplorableMap},
+    DeterministicRng,
+};
+
+/// What behaviors share while a `BehaviorList` runs.
+pub struct Ctx<'a, B = DummyBroadcast> {
+    pub map: &'a mut ExplorableMap,
+    pub broadcast: Option<&'a mut B>,
+    pub turn: i64,
+}
+
+impl<'a, B> Ctx<'a, B> {
+    pub fn new(map: &'a mut ExplorableMap, broadcast: Option<&'a mut B>, turn: i64) -> Self {
+        Self { map, broadcast, turn }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BehaviorResult {
+    Command(Command),
+    /// Lets the next behavior try.
+    Pass,
+    /// Keeps the behaviors after this one from running this turn, so the
+    /// list does nothing.
+    Block,
+}
+
+impl From<Option<Command>> for BehaviorResult {
+    fn from(command: Option<Command>) -> Self {
+        command.map_or(BehaviorResult::Pass, BehaviorResult::Command)
+    }
+}
+
+pub trait Behavior<B = DummyBroadcast> {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult;
+}
+
+/// Tries each behavior in order, stopping at the first that doesn't pass.
+impl<B, T: Behavior<B>> Behavior<B> for Vec<T> {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+        for behavior in self {
+            match behavior.tick(ctx) {
+                BehaviorResult::Pass => {}
+                result => return result,
+            }
+        }
+        BehaviorResult::Pass
+    }
+}
+
+impl<B, T: Behavior<B> + ?Sized> Behavior<B> for Box<T> {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+        (**self).tick(ctx)
+    }
+}
+
+macro_rules! tuple_behavior {
+    ($($name:ident $behavior:ident)+) => {
+        /// Tries each behavior in order, stopping at the first that doesn't
+        /// pass.
+        impl<B, $($name: Behavior<B>),+> Behavior<B> for ($($name,)+) {
+            fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+                let ($($behavior,)+) = self;
+                $(
+                    match $behavior.tick(ctx) {
+                        BehaviorResult::Pass => {}
+                        result => return result,
+                    }
+                )+
+                BehaviorResult::Pass
+            }
+        }
+    };
+}
+
+tuple_behavior!(T1 b1);
+tuple_behavior!(T1 b1 T2 b2);
+tuple_behavior!(T1 b1 T2 b2 T3 b3);
+tuple_behavior!(T1 b1 T2 b2 T3 b3 T4 b4);
+tuple_behavior!(T1 b1 T2 b2 T3 b3 T4 b4 T5 b5);
+tuple_behavior!(T1 b1 T2 b2 T3 b3 T4 b4 T5 b5 T6 b6);
+tuple_behavior!(T1 b1 T2 b2 T3 b3 T4 b4 T5 b5 T6 b6 T7 b7);
+tuple_behavior!(T1 b1 T2 b2 T3 b3 T4 b4 T5 b5 T6 b6 T7 b7 T8 b8);
+
+/// Behaviors in priority order: a tuple of them, or a `Vec` of one type or
+/// of `Box<dyn Behavior>`, though those can't be stored.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BehaviorList<T>(pub T);
+
+impl<T> BehaviorList<T> {
+    /// The command of the first behavior that has one, the highest priority
+    /// first. `Command::Nothing` if all pass or one blocks.
+    pub fn run<B>(&mut self, ctx: &mut Ctx<B>) -> Command
+    where
+        T: Behavior<B>,
+    {
+        match self.0.tick(ctx) {
+            BehaviorResult::Command(command) => command,
+            BehaviorResult::Pass | BehaviorResult::Block => Command::Nothing,
+        }
+    }
+}
+
+impl<B, T: Behavior<B>> Behavior<B> for BehaviorList<T> {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+        self.0.tick(ctx)
+    }
+}
+
+/// Runs `behavior` at most once every `turns` turns, passing meanwhile.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cooldown<T> {
+    pub behavior: T,
+    pub turns: i64,
+    /// The turn `behavior` can next run.
+    pub ready_at: i64,
+}
+
+impl<T> Cooldown<T> {
+    pub fn new(behavior: T, turns: i64) -> Self {
+        Self {
+            behavior,
+            turns,
+            ready_at: i64::MIN,
+        }
+    }
+}
+
+impl<B, T: Behavior<B>> Behavior<B> for Cooldown<T> {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+        if ctx.turn < self.ready_at {
+            return BehaviorResult::Pass;
+        }
+        let result = self.behavior.tick(ctx);
+        if matches!(result, BehaviorResult::Command(_)) {
+            self.ready_at = ctx.turn.saturating_add(self.turns);
+        }
+        result
+    }
+}
+
+/// `behaviors::attack_nearest`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AttackNearest {
+    pub exclude_factions: Vec<i64>,
+}
+
+impl<B> Behavior<B> for AttackNearest {
+    fn tick(&mut self, _ctx: &mut Ctx<B>) -> BehaviorResult {
+        attack_nearest(&self.exclude_factions).into()
+    }
+}
+
+/// `behaviors::convert`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Convert;
+
+impl<B> Behavior<B> for Convert {
+    fn tick(&mut self, _ctx: &mut Ctx<B>) -> BehaviorResult {
+        convert().into()
+    }
+}
+
+/// `ExplorableMap::explore`, on the `Ctx`'s map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Explore;
+
+impl<B> Behavior<B> for Explore {
+    fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+        ctx.map.explore().into()
+    }
+}
+
+/// `behaviors::wander`, or `wander_with_rng` with `rng` if set so wandering
+/// replays the same.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Wander {
+    pub rng: Option<DeterministicRng>,
+}
+
+impl<B> Behavior<B> for Wander {
+    fn tick(&mut self, _ctx: &mut Ctx<B>) -> BehaviorResult {
+        match &mut self.rng {
+            Some(rng) => wander_with_rng(rng),
+            None => wander(),
+        }
+        .into()
+    }
+}
+
+/// Which of the states `S` a bot is in, and since when. Kept in the state,
+/// and run each turn by an `FsmRunner`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fsm<S> {
+    pub state: S,
+    /// The turn `state` was entered.
+    pub entered: i64,
+    pub previous: Option<S>,
+}
+
+impl<S: Copy + Eq> Fsm<S> {
+    pub fn new(state: S, now: i64) -> Self {
+        Self {
+            state,
+            entered: now,
+            previous: None,
+        }
+    }
+
+    /// Goes to `state`, unless already in it. Returns whether it did.
+    pub fn transition_to(&mut self, state: S, now: i64) -> bool {
+        if state == self.state {
+            return false;
+        }
+        self.previous = Some(std::mem::replace(&mut self.state, state));
+        self.entered = now;
+        true
+    }
+
+    pub fn turns_in_state(&self, now: i64) -> i64 {
+        now.saturating_sub(self.entered)
+    }
+}
+
+type Handler<'f, C> = Box<dyn FnMut(&mut C) -> BehaviorResult + 'f>;
+/// Given the turns spent in the state so far.
+type Guard<'f, C> = Box<dyn Fn(&C, i64) -> bool + 'f>;
+
+/// What an `Fsm` does in each state, given a context `C`, such as a `Ctx`
+/// or what the bot's seen this turn. Built each turn, as its handlers can't
+/// be stored.
+pub struct FsmRunner<'f, S, C> {
+    handlers: Vec<(S, Handler<'f, C>)>,
+    transitions: Vec<(S, S, Guard<'f, C>)>,
+}
+
+impl<'f, S: Copy + Eq, C> Default for FsmRunner<'f, S, C> {
+    fn default() -> Self {
+        Self {
+            handlers: Vec::new(),
+            transitions: Vec::new(),
+        }
+    }
+}
+
+impl<'f, S: Copy + Eq, C> FsmRunner<'f, S, C> {
+    pub fn with_handler(mut self, state: S, handler: impl FnMut(&mut C) -> BehaviorResult + 'f) -> Self {
+        self.handlers.push((state, Box::new(handler)));
+        self
+    }
+
+    /// Goes from `from` to `to` when `guard` holds. Transitions are tried
+    /// in the order they were added.
+    pub fn with_transition(mut self, from: S, to: S, guard: impl Fn(&C, i64) -> bool + 'f) -> Self {
+        self.transitions.push((from, to, Box::new(guard)));
+        self
+    }
+
+    /// Takes the first transition out of `fsm`'s state whose guard holds, if
+    /// any, then runs the handler of the state it's in. Passes in states
+    /// without one.
+    pub fn run(&mut self, fsm: &mut Fsm<S>, ctx: &mut C, now: i64) -> BehaviorResult {
+        let turns = fsm.turns_in_state(now);
+        let taken = self.transitions.iter().find(|(from, _, guard)| *from == fsm.state && guard(ctx, turns));
+        if let Some((_, to, _)) = taken {
+            fsm.transition_to(*to, now);
+        }
+        match self.handlers.iter_mut().find(|(state, _)| *state == fsm.state) {
+            Some((_, handler)) => handler(ctx),
+            None => BehaviorResult::Pass,
+        }
+    }
+}
+
+impl<'f, 'a, S: Copy + Eq, B> FsmRunner<'f, S, Ctx<'a, B>> {
+    /// Runs `behavior` in `state`, e.g. a `BehaviorList`. Borrowed from the
+    /// bot's state, so what it keeps, like a `Cooldown`'s, is saved with it.
+    pub fn with_behavior(self, state: S, behavior: &'f mut impl Behavior<B>) -> Self {
+        self.with_handler(state, move |ctx| behavior.tick(ctx))
+    }
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+
+    /// Uses action `id`, but only on turns `every` divides.
+    struct Every {
+        id: u32,
+        every: i64,
+    }
+
+    impl<B> Behavior<B> for Every {
+        fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+            if ctx.turn % self.every == 0 {
+                BehaviorResult::Command(Command::UseAction((self.id, None)))
+            } else {
+                BehaviorResult::Pass
+            }
+        }
+    }
+
+    /// Blocks once the map has no unexplored tiles left.
+    struct BlockWhenExplored;
+
+    impl<B> Behavior<B> for BlockWhenExplored {
+        fn tick(&mut self, ctx: &mut Ctx<B>) -> BehaviorResult {
+            if ctx.map.unexplored_locs.is_empty() {
+                BehaviorResult::Block
+            } else {
+                BehaviorResult::Pass
+            }
+        }
+    }
+
+    #[test]
+    fn first_command_wins() {
+        // Attacks every third turn at most, picks up on even turns, and
+        // otherwise wanders.
+        let mut bot = BehaviorList((
+            Cooldown::new(Every { id: 1, every: 1 }, 3),
+            Every { id: 2, every: 2 },
+            Every { id: 3, every: 1 },
+        ));
+        let mut map = ExplorableMap::default();
+        let ids: Vec<_> = (0..7)
+            .map(|turn| match bot.run(&mut Ctx::<DummyBroadcast>::new(&mut map, None, turn)) {
+                Command::UseAction((id, _)) => id,
+                _ => 0,
+            })
+            .collect();
+        assert_eq!(ids, [1, 3, 2, 1, 2, 3, 1]);
+        let (attack, _, _) = &bot.0;
+        assert_eq!(attack.ready_at, 9);
+    }
+
+    #[test]
+    fn blocking_stops_the_list() {
+        let mut bot = BehaviorList(vec![
+            Box::new(BlockWhenExplored) as Box<dyn Behavior>,
+            Box::new(Every { id: 3, every: 1 }),
+        ]);
+        let mut map = ExplorableMap::default();
+        assert_eq!(bot.run(&mut Ctx::new(&mut map, None, 0)), Command::Nothing);
+        map.unexplored_locs.insert(bindings::Loc { x: 1, y: 0 });
+        assert_eq!(bot.run(&mut Ctx::new(&mut map, None, 0)), Command::UseAction((3, None)));
+    }
+}
+
+#[cfg(test)]
+mod fsm_tests {
+    use super::*;
+    use bindings::Loc;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    enum Guard {
+        Patrol,
+        Chase,
+    }
+
+    /// Where enemies were seen this turn, as `visible_creatures` would say.
+    struct Seen {
+        enemies: Vec<Loc>,
+    }
+
+    fn guard<'f>() -> FsmRunner<'f, Guard, Seen> {
+        let walk = |id| BehaviorResult::Command(Command::UseAction((id, None)));
+        FsmRunner::default()
+            .with_transition(Guard::Patrol, Guard::Chase, |seen: &Seen, _| !seen.enemies.is_empty())
+            // Chases for four turns at least, even out of sight.
+            .with_transition(Guard::Chase, Guard::Patrol, |seen: &Seen, turns| seen.enemies.is_empty() && turns >= 4)
+            .with_handler(Guard::Patrol, move |_| walk(1))
+            .with_handler(Guard::Chase, move |seen| match seen.enemies.first() {
+                Some(_) => walk(2),
+                None => walk(3),
+            })
+    }
+
+    #[test]
+    fn patrols_until_it_sees_enemies() {
+        let enemy = Loc { x: 3, y: 0 };
+        let sightings = [vec![], vec![enemy], vec![enemy], vec![], vec![], vec![], vec![]];
+        let mut fsm = Fsm::new(Guard::Patrol, 0);
+        let mut runner = guard();
+        let mut ids = Vec::new();
+        for (turn, enemies) in sightings.into_iter().enumerate() {
+            let result = runner.run(&mut fsm, &mut Seen { enemies }, turn as i64);
+            if let BehaviorResult::Command(Command::UseAction((id, _))) = result {
+                ids.push(id);
+            }
+        }
+        assert_eq!(ids, [1, 2, 2, 3, 3, 1, 1]);
+        assert_eq!(fsm, Fsm { state: Guard::Patrol, entered: 5, previous: Some(Guard::Chase) });
+        assert_eq!(fsm.turns_in_state(8), 3);
+    }
+
+    #[test]
+    fn transitions_only_change_state() {
+        let mut fsm = Fsm::new(Guard::Patrol, 0);
+        assert!(!fsm.transition_to(Guard::Patrol, 4));
+        assert_eq!((fsm.entered, fsm.previous), (0, None));
+        assert!(fsm.transition_to(Guard::Chase, 4));
+        assert_eq!((fsm.entered, fsm.previous), (4, Some(Guard::Patrol)));
+    }
+
+    /// Always uses action `0`.
+    struct Act;
+
+    impl<B> Behavior<B> for Act {
+        fn tick(&mut self, _ctx: &mut Ctx<B>) -> BehaviorResult {
+            BehaviorResult::Command(Command::UseAction((0, None)))
+        }
+    }
+
+    #[test]
+    fn states_can_run_behaviors() {
+        let mut map = ExplorableMap::default();
+        let mut fsm = Fsm::new(Guard::Patrol, 0);
+        // Kept in the bot's state, the runner rebuilt each turn.
+        let mut patrol = Cooldown::new(Act, 2);
+        let mut results = Vec::new();
+        for turn in 0..3 {
+            let mut runner = FsmRunner::<_, Ctx>::default().with_behavior(Guard::Patrol, &mut patrol);
+            results.push(runner.run(&mut fsm, &mut Ctx::new(&mut map, None, turn), turn));
+        }
+        let act = BehaviorResult::Command(Command::UseAction((0, None)));
+        assert_eq!(results, [act.clone(), BehaviorResult::Pass, act]);
+        assert_eq!(patrol.ready_at, 4);
+    }
+}
@@ -0,0 +1,10 @@
+//! Compiles the files in tests/ui to check what `#[derive(CrdtContainer)]`
+//! accepts and the errors it gives otherwise. Run with `TRYBUILD=overwrite`
+//! to update the expected errors after changing them.
+
+#[test]
+fn derive_ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/pass_*.rs");
+    t.compile_fail("tests/ui/fail_*.rs");
+}
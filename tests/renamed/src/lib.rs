@@ -0,0 +1,40 @@
+//! Derives `CrdtContainer` where `client_utils` is only reachable as `cu`.
+
+#[cfg(test)]
+mod renamed_tests {
+    use cu::crdt::{Crdt, CrdtContainer, CrdtSnapshot, GrowOnlySet};
+
+    // The manifest says where the crate is.
+    #[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+    #[crdt(delta)]
+    struct Found {
+        #[crdt(priority = 1)]
+        seen: GrowOnlySet<u32>,
+    }
+
+    // Or it's given.
+    #[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+    #[crdt(crate = "::cu")]
+    struct Given {
+        #[crdt]
+        seen: GrowOnlySet<u32>,
+    }
+
+    #[test]
+    fn derives_under_a_renamed_crate() {
+        let mut a = Found::default();
+        let mut b = Found::default();
+        b.seen.insert(1);
+        let snap = a.snapshot();
+        assert!(a.merge_changed(&b).unwrap());
+        assert!(b.delta_since(0).is_some());
+        a.restore(snap);
+        assert!(a.seen.is_empty());
+
+        let mut a = Given::default();
+        let mut b = Given::default();
+        b.seen.insert(1);
+        a.merge(&b).unwrap();
+        assert_eq!(a.digest(), b.digest());
+    }
+}
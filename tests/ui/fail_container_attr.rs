@@ -0,0 +1,10 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+#[crdt]
+struct Broadcast {
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+}
+
+fn main() {}
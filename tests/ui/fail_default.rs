@@ -0,0 +1,11 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+#[crdt(default)]
+#[derive(Default)]
+struct Broadcast {
+    #[crdt(default = "not an expression +")]
+    seen: GrowOnlySet<u32>,
+}
+
+fn main() {}
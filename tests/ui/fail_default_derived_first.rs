@@ -0,0 +1,10 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(default)]
+struct Broadcast {
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use client_utils::crdt::{CrdtContainer, SizedFWWExpiringSet};
+
+#[derive(CrdtContainer)]
+struct Broadcast {
+    #[crdt(priority = 1, default = "SizedFWWExpiringSet::new(32)")]
+    claims: SizedFWWExpiringSet<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+#[crdt(on_variant_mismatch = "not a path")]
+enum Phase {
+    Scouting(#[crdt(priority = 1)] GrowOnlySet<u32>),
+    Done,
+}
+
+fn main() {}
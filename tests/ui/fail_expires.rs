@@ -0,0 +1,14 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+struct State {
+    #[crdt(expires_with = "missing")]
+    seen: GrowOnlySet<u32>,
+    #[crdt(local)]
+    cached: Option<u32>,
+    #[crdt(local, priority = 1, expires_with = "turn")]
+    path: Option<u32>,
+    turn: i64,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+struct Broadcast {
+    #[crdt(foo)]
+    seen: GrowOnlySet<u32>,
+    #[crdt = 1]
+    claimed: GrowOnlySet<u32>,
+    #[crdt(priority = "high")] visited: GrowOnlySet<u32>,
+}
+
+fn main() {}
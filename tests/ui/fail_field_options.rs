@@ -0,0 +1,11 @@
+use client_utils::crdt::{CrdtContainer, GrowOnlySet};
+
+#[derive(CrdtContainer)]
+struct Broadcast {
+    #[crdt(merge_with = "not a path")]
+    deepest: i64,
+    #[crdt(skip_cleanup, cleanup_with = "tidy")]
+    seen: GrowOnlySet<u32>,
+}
+
+fn main() {}
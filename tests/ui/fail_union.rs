@@ -0,0 +1,9 @@
+use client_utils::crdt::CrdtContainer;
+
+#[derive(CrdtContainer)]
+union Bits {
+    int: u32,
+    float: f32,
+}
+
+fn main() {}
@@ -0,0 +1,35 @@
+use client_utils::crdt::{Crdt, CrdtContainer, GrowOnlySet, SizedFWWExpiringSet};
+
+#[derive(serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(default)]
+struct Broadcast {
+    #[crdt]
+    #[crdt(default = "SizedFWWExpiringSet::new(32)")]
+    claims: SizedFWWExpiringSet<u32>,
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+    #[crdt(default = "String::from(\"scout\")")]
+    role: String,
+    turn: i64,
+}
+
+#[derive(CrdtContainer)]
+#[crdt(default)]
+struct Generic<T: Ord> {
+    #[crdt(default = "SizedFWWExpiringSet::new(4)")]
+    claims: SizedFWWExpiringSet<T>,
+    last: Option<T>,
+}
+
+fn main() {
+    let mut state = Broadcast::default();
+    assert_eq!(state.claims.capacity(), 32);
+    assert!(state.seen.is_empty());
+    assert_eq!((state.role.as_str(), state.turn), ("scout", 0));
+    state.merge(&Broadcast::default()).unwrap();
+    assert_eq!(state.claims.capacity(), 32);
+
+    // `T` needn't be `Default`, only `Option<T>` does.
+    let generic = Generic::<std::num::NonZeroU32>::default();
+    assert!(generic.last.is_none());
+}
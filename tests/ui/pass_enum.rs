@@ -0,0 +1,92 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot, ExpiringSet, GrowOnlySet};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+enum Phase {
+    Scouting {
+        #[crdt]
+        seen: GrowOnlySet<u32>,
+        #[crdt]
+        claimed: ExpiringSet<u32>,
+        target: u32,
+    },
+    Assault(#[crdt] GrowOnlySet<u32>, u32),
+    Done,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(on_variant_mismatch = "prefer_other")]
+enum Latest {
+    A(#[crdt] GrowOnlySet<u32>),
+    B,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(on_variant_mismatch = "later_stage")]
+enum Stage {
+    First(#[crdt] GrowOnlySet<u32>),
+    Second(#[crdt] GrowOnlySet<u32>),
+}
+
+fn later_stage(this: &mut Stage, other: &Stage) -> anyhow::Result<bool> {
+    if let (Stage::First(_), Stage::Second(_)) = (&*this, other) {
+        *this = other.clone();
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn set(items: &[u32]) -> GrowOnlySet<u32> {
+    items.iter().copied().collect()
+}
+
+fn scouting(seen: &[u32], target: u32) -> Phase {
+    Phase::Scouting { seen: set(seen), claimed: ExpiringSet::default(), target }
+}
+
+fn main() {
+    // The same variant merges its #[crdt] fields and keeps the rest.
+    let mut a = scouting(&[1], 5);
+    assert!(a.merge_changed(&scouting(&[2], 6)).unwrap());
+    assert!(!a.merge_changed(&scouting(&[2], 6)).unwrap());
+    let Phase::Scouting { seen, claimed, target } = &mut a else { panic!() };
+    assert!(seen.contains(&1) && seen.contains(&2) && *target == 5);
+    claimed.insert(9, 10);
+    a.cleanup(20);
+    let Phase::Scouting { claimed, .. } = &a else { panic!() };
+    assert!(!claimed.contains(&9));
+
+    // By default a different variant leaves self alone, either way round.
+    let mut assault = Phase::Assault(set(&[3]), 1);
+    assert!(!a.merge_changed(&assault).unwrap());
+    assert!(!assault.merge_changed(&a).unwrap());
+    assert!(matches!(a, Phase::Scouting { .. }) && matches!(assault, Phase::Assault(..)));
+    let mut done = Phase::Done;
+    assert!(!done.merge_changed(&Phase::Done).unwrap());
+    assert!(!done.merge_changed(&assault).unwrap());
+    assert_ne!(Phase::Assault(set(&[]), 0).digest(), Phase::Done.digest());
+
+    // Deltas keep the variant, with fields that aren't merged defaulted.
+    let Some(Phase::Assault(seen, 0)) = assault.delta_since(0) else { panic!() };
+    assert!(seen.contains(&3));
+
+    let snap = assault.snapshot();
+    assault = Phase::Done;
+    assault.restore(snap);
+    assert!(matches!(assault, Phase::Assault(..)));
+
+    // prefer_other takes the other variant, either way round.
+    let mut latest = Latest::A(set(&[1]));
+    assert!(latest.merge_changed(&Latest::B).unwrap());
+    assert!(matches!(latest, Latest::B));
+    assert!(latest.merge_changed(&Latest::A(set(&[2]))).unwrap());
+    assert!(matches!(&latest, Latest::A(seen) if seen.contains(&2)));
+
+    // A function can settle it, here by only ever moving forward.
+    let mut first = Stage::First(set(&[1]));
+    let mut second = Stage::Second(set(&[2]));
+    assert!(!second.merge_changed(&first).unwrap());
+    assert!(matches!(second, Stage::Second(_)));
+    assert!(first.merge_changed(&second).unwrap());
+    assert!(matches!(&first, Stage::Second(seen) if seen.contains(&2)));
+}
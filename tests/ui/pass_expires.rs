@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot, ExpiringFWWRegister, GrowOnlySet};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+struct State {
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+    // Merged, and also forgotten at the deadline.
+    #[crdt(expires_with = "target_expires")]
+    target: ExpiringFWWRegister<u32>,
+    target_expires: i64,
+    // Only ever local.
+    #[crdt(local, expires_with = "cached_path_expires")]
+    cached_path: Option<VecDeque<(i64, i64)>>,
+    cached_path_expires: i64,
+}
+
+fn main() {
+    let mut state = State { target_expires: 5, cached_path_expires: 3, ..Default::default() };
+    state.seen.insert(1);
+    state.target.set(9, 0, 100);
+    state.cached_path = Some(VecDeque::from([(0, 0), (0, 1)]));
+
+    // Local caches aren't merged, snapshotted or sent.
+    let mut other = State::default();
+    other.merge(&state).unwrap();
+    assert!(other.seen.contains(&1) && other.target.get() == Some(&9));
+    assert!(other.cached_path.is_none());
+    assert!(state.delta_since(0).unwrap().cached_path.is_none());
+    let snap = state.snapshot();
+    state.cached_path = None;
+    state.restore(snap);
+    assert!(state.cached_path.is_none());
+
+    state.cached_path = Some(VecDeque::from([(0, 0)]));
+    state.cleanup(2);
+    assert!(state.cached_path.is_some() && state.target.get() == Some(&9));
+    state.cleanup(3);
+    assert!(state.cached_path.is_none() && state.target.get() == Some(&9));
+    state.cleanup(5);
+    assert!(state.target.get().is_none());
+    assert!(state.seen.contains(&1));
+}
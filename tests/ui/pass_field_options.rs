@@ -0,0 +1,54 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot, ExpiringSet};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+struct Broadcast {
+    #[crdt(merge_with = "max_of")]
+    deepest: i64,
+    #[crdt(merge_with = "max_of", priority = 1)]
+    latest: i64,
+    #[crdt(skip_cleanup)]
+    kept: ExpiringSet<u32>,
+    #[crdt(cleanup_with = "cleanup_at_half")]
+    late: ExpiringSet<u32>,
+    #[crdt]
+    dropped: ExpiringSet<u32>,
+}
+
+fn max_of(this: &mut i64, other: &i64) -> anyhow::Result<()> {
+    *this = (*this).max(*other);
+    Ok(())
+}
+
+/// Keeps entries for twice as long as `cleanup` would.
+fn cleanup_at_half(set: &mut ExpiringSet<u32>, now: i64) {
+    set.cleanup(now / 2);
+}
+
+fn main() {
+    // Plain values merged by a function.
+    let mut a = Broadcast { deepest: 3, latest: 10, ..Default::default() };
+    assert!(a.merge_changed(&Broadcast { deepest: 5, latest: 4, ..Default::default() }).unwrap());
+    assert_eq!((a.deepest, a.latest), (5, 10));
+    a.merge(&Broadcast::default()).unwrap();
+    assert_eq!((a.deepest, a.latest), (5, 10));
+    assert_ne!(a.digest(), Broadcast::default().digest());
+
+    for set in [&mut a.kept, &mut a.late, &mut a.dropped] {
+        set.insert(1, 10);
+    }
+    a.cleanup(15);
+    assert!(a.kept.contains(&1) && a.late.contains(&1) && !a.dropped.contains(&1));
+    a.cleanup(30);
+    assert!(a.kept.contains(&1) && !a.late.contains(&1));
+
+    let snap = a.snapshot();
+    a.deepest = 0;
+    a.restore(snap);
+    assert_eq!(a.deepest, 5);
+    assert_eq!(a.delta_since(0).unwrap().deepest, 5);
+
+    let mut b = Broadcast::default();
+    assert!(b.merge_partial(&a.serialize_within(1024)));
+    assert_eq!((b.deepest, b.latest), (5, 10));
+}
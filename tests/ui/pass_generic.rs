@@ -0,0 +1,58 @@
+use std::marker::PhantomData;
+
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot, ExpiringFWWRegister, GrowOnlySet};
+
+// Bounds are inferred for `reg` alone, since it's the field naming `T`.
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+struct Shared<T> {
+    #[crdt(priority = 1)]
+    reg: ExpiringFWWRegister<T>,
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+}
+
+// Lifetimes and const generics pass through.
+#[derive(CrdtContainer)]
+struct Tagged<'a, T: Clone, const N: usize> {
+    #[crdt]
+    regs: Vec<ExpiringFWWRegister<T>>,
+    name: &'a str,
+    size: PhantomData<[(); N]>,
+}
+
+// Anything else goes in `bound`.
+#[derive(Default, CrdtContainer)]
+#[crdt(delta, bound = "T: Clone + PartialOrd + Default + serde::Serialize")]
+struct Delta<T> {
+    #[crdt]
+    reg: ExpiringFWWRegister<T>,
+    last: T,
+}
+
+#[derive(Clone, serde::Serialize, CrdtContainer)]
+enum Phase<T> {
+    Claiming(#[crdt] ExpiringFWWRegister<T>),
+    Done,
+}
+
+fn main() {
+    let mut a = Shared::<String>::default();
+    let mut b = Shared::<String>::default();
+    b.reg.set("b".to_string(), 1, 10);
+    assert!(a.merge_changed(&b).unwrap());
+    assert_eq!(a.reg.get().map(String::as_str), Some("b"));
+    let mut c = Shared::<String>::default();
+    assert!(c.merge_partial(&a.serialize_within(1024)));
+    a.restore(c.snapshot());
+
+    let mut tagged = Tagged::<u8, 4> { regs: vec![ExpiringFWWRegister::default()], name: "scout", size: PhantomData };
+    tagged.cleanup(0);
+    assert_eq!(tagged.name, "scout");
+
+    let delta = Delta::<u8> { last: 7, ..Default::default() }.delta_since(0).unwrap();
+    assert_eq!(delta.last, 0);
+
+    let mut phase = Phase::Claiming(ExpiringFWWRegister::default());
+    assert!(!phase.merge_changed(&Phase::<u8>::Done).unwrap());
+    assert_ne!(phase.digest(), Phase::<u8>::Done.digest());
+}
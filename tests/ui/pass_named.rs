@@ -0,0 +1,26 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtMap, CrdtSnapshot, GrowOnlySet, HorizonTracker, Lww};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+struct Broadcast {
+    #[crdt(priority = 2)]
+    seen: GrowOnlySet<u32>,
+    #[crdt]
+    claims: CrdtMap<u32, u32, Lww>,
+    #[crdt]
+    tracker: HorizonTracker,
+    scratch: u32,
+}
+
+fn main() {
+    let mut a = Broadcast::default();
+    let mut b = Broadcast::default();
+    b.seen.insert(1);
+    b.claims.insert(1, 2, 0);
+    let snap = a.snapshot();
+    assert!(a.merge_changed(&b).unwrap());
+    assert!(a.horizon_tracker().is_some());
+    a.restore(snap);
+    assert!(a.seen.is_empty() && a.scratch == 0);
+    assert!(b.delta_since(0).is_some());
+}
@@ -0,0 +1,54 @@
+use client_utils::crdt::{Crdt, CrdtContainer, ExpiringFWWRegister, GrowOnlySet};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(report)]
+struct Target {
+    #[crdt]
+    reg: ExpiringFWWRegister<u32>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(report)]
+struct Squad {
+    #[crdt(container)]
+    target: Target,
+    #[crdt]
+    members: GrowOnlySet<u32>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(report)]
+struct Broadcast {
+    #[crdt(container)]
+    squad: Squad,
+    #[crdt]
+    seen: GrowOnlySet<u32>,
+}
+
+// Without `report`, a container field is merged like any other.
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+struct Plain {
+    #[crdt(container)]
+    squad: Squad,
+}
+
+fn main() {
+    let mut a = Broadcast::default();
+    let mut b = Broadcast::default();
+    b.squad.target.reg.set(7, 5, 10);
+    assert_eq!(a.merge_report(&b).unwrap(), ["squad.target.reg"]);
+    assert_eq!(a.squad.target.reg.get(), Some(&7));
+
+    b.squad.target.reg.set(8, 2, 10);
+    b.squad.members.insert(3);
+    b.seen.insert(4);
+    assert_eq!(a.merge_report(&b).unwrap(), ["squad.target.reg", "squad.members", "seen"]);
+    assert_eq!(a.squad.target.reg.get(), Some(&8));
+    assert!(a.merge_report(&b).unwrap().is_empty());
+
+    let mut plain = Plain::default();
+    assert!(plain.merge_changed(&Plain { squad: b.squad }).unwrap());
+    assert_eq!(plain.squad.target.reg.get(), Some(&8));
+    a.cleanup(20);
+    assert_eq!(a.squad.target.reg.get(), None);
+}
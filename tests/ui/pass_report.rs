@@ -0,0 +1,49 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtMap, FieldPath, GrowOnlySet, Lww};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(report)]
+struct Broadcast {
+    #[crdt]
+    seen_items: GrowOnlySet<u32>,
+    #[crdt]
+    claims: CrdtMap<u32, u32, Lww>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(report)]
+struct Versioned {
+    #[crdt]
+    seen_items: GrowOnlySet<u32>,
+    #[crdt(merge_with = "same_version")]
+    version: u32,
+}
+
+fn same_version(mine: &mut u32, theirs: &u32) -> anyhow::Result<()> {
+    anyhow::ensure!(mine == theirs, "version {theirs} isn't {mine}");
+    Ok(())
+}
+
+fn main() {
+    let mut a = Broadcast::default();
+    let mut b = Broadcast::default();
+    b.seen_items.insert(1);
+    assert_eq!(a.merge_report(&b).unwrap(), [FieldPath(vec!["seen_items"])]);
+    assert!(a.merge_report(&b).unwrap().is_empty());
+
+    b.seen_items.insert(2);
+    b.claims.insert(1, 7, 0);
+    assert_eq!(a.merge_report(&b).unwrap(), ["seen_items", "claims"]);
+
+    let mut c = Broadcast::default();
+    c.claims.insert(2, 8, 1);
+    assert_eq!(a.merge_report(&c).unwrap(), ["claims"]);
+    assert!(!a.merge_changed(&c).unwrap());
+
+    let mut mine = Versioned::default();
+    let mut theirs = Versioned { version: 2, ..Default::default() };
+    theirs.seen_items.insert(1);
+    let error = mine.merge_report(&theirs).unwrap_err();
+    assert_eq!(error.field, "version");
+    assert_eq!(error.source.to_string(), "version 2 isn't 0");
+    assert!(mine.seen_items.contains(&1));
+}
@@ -0,0 +1,23 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot, GrowOnlySet};
+use client_utils::framework::ExplorableMap;
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+struct Shared(#[crdt] pub ExplorableMap);
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+struct Seen(#[crdt(priority = 1)] GrowOnlySet<u32>, u32);
+
+fn main() {
+    let mut a = Shared::default();
+    a.merge(&Shared::default()).unwrap();
+    a.restore(a.snapshot());
+
+    let mut b = Seen::default();
+    let mut c = Seen(GrowOnlySet::default(), 7);
+    c.0.insert(1);
+    assert!(b.merge_changed(&c).unwrap());
+    assert!(b.0.contains(&1) && b.1 == 0);
+    let delta = c.delta_since(0).unwrap();
+    assert_eq!(delta.1, 0);
+}
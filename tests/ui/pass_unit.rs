@@ -0,0 +1,14 @@
+use client_utils::crdt::{Crdt, CrdtContainer, CrdtSnapshot};
+
+#[derive(Default, serde::Serialize, serde::Deserialize, CrdtContainer)]
+#[crdt(delta)]
+struct Nothing;
+
+fn main() {
+    let mut a = Nothing;
+    assert!(!a.merge_changed(&Nothing).unwrap());
+    a.cleanup(0);
+    assert_eq!(a.version(), 0);
+    a.restore(a.snapshot());
+    assert!(a.delta_since(0).is_some());
+}